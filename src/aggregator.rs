@@ -1,54 +1,711 @@
-use crate::scanner::{read_file_content, UsageRuleSubFile, UsageRules};
-use anyhow::Result;
+use crate::{
+  encoding::decode_output,
+  hashing::HashAlgorithm,
+  logger,
+  markdown::{
+    canonicalize_whitespace, normalize_line_endings, shift_headings, strip_frontmatter,
+    AnchorFlavor, FenceTracker, LineEnding,
+  },
+  patterns,
+  scanner::{read_file_content, UsageRuleSubFile, UsageRules},
+  warnings::{warn, WarningCategory},
+  writer::INDEX_FILENAME,
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use encoding_rs::Encoding;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
 use std::{
+  cmp::Ordering,
+  collections::HashMap,
   fs,
   path::{Path, PathBuf},
 };
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct PackageContent {
   pub main_file: Option<PathBuf>,
   pub sub_files: Vec<UsageRuleSubFile>, // (relative_path, source_path)
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct PackageContentInfo {
   pub name: String,
+  pub version: String,
   pub content: PackageContent,
+  /// Conditions under which the package applies, e.g. "feature `rt`
+  /// enabled".
+  pub conditions: Vec<String>,
+  /// The package's repository URL, if known.
+  pub repository: Option<String>,
+  /// The category the package declares in its front matter, if any.
+  pub category: Option<String>,
+  /// Name of an earlier package with identical rules, whose section this
+  /// package refers to instead of repeating them.
+  pub duplicate_of: Option<String>,
+  /// Whether the package's rules are inlined in the main file even in linked
+  /// mode.
+  pub inline: bool,
+  /// Whether another selected package has the same name, so this one is
+  /// told apart by its version.
+  pub versioned: bool,
+}
+
+/// Transformations applied to each rule file's content as it is aggregated.
+#[derive(Debug, Clone, Default)]
+pub struct ContentOptions {
+  /// Strip trailing spaces and collapse runs of blank lines outside of code
+  /// fences.
+  pub canonicalize: bool,
+  /// Remove a leading YAML front-matter block.
+  pub strip_frontmatter: bool,
+  /// Shift headings so inlined content nests below its package section.
+  pub normalize_headings: bool,
+  /// Line endings of the output. Unless `Keep`, content is read with `\n`
+  /// line endings, and the writer converts the rendered files.
+  pub line_ending: LineEnding,
+  /// Truncate a package's aggregated content beyond this many bytes.
+  pub max_bytes: Option<usize>,
+  /// Render nested sub-file paths as nested headings, one level per
+  /// directory, instead of a single heading holding the whole path.
+  pub nested_headings: bool,
+  /// Fail on rule files that are not valid UTF-8 instead of decoding them
+  /// lossily.
+  pub strict_encoding: bool,
+  /// Digest used to compare content and to hash sections.
+  pub hash_algorithm: HashAlgorithm,
+}
+
+impl ContentOptions {
+  /// Reads a rule file and applies the configured transformations.
+  pub fn read(&self, path: &Path) -> Result<String> {
+    let mut content = read_file_content(path, self.strict_encoding)?;
+    if self.line_ending != LineEnding::Keep {
+      content = normalize_line_endings(&content, LineEnding::Lf);
+    }
+    if self.strip_frontmatter {
+      content = strip_frontmatter(&content).to_string();
+    }
+    Ok(if self.canonicalize {
+      canonicalize_whitespace(&content)
+    } else {
+      content
+    })
+  }
 }
 
 impl PackageContentInfo {
-  pub fn get_aggregated_content(&self) -> Result<String> {
+  /// Returns the name identifying the package in the output: its name, plus
+  /// its version if another package shares the name.
+  pub fn label(&self) -> String {
+    if self.versioned {
+      format!("{} v{}", self.name, self.version)
+    } else {
+      self.name.clone()
+    }
+  }
+
+  /// Returns the name of the package's folder in linked mode, such as
+  /// `serde`, or `serde-1.0.203` if another package shares the name.
+  pub fn folder_name(&self) -> String {
+    if self.versioned {
+      format!("{}-{}", self.name, self.version)
+    } else {
+      self.name.clone()
+    }
+  }
+
+  /// Returns the package's rules as inlined in the output: its main file
+  /// followed by each sub-file under a heading of its own.
+  ///
+  /// Content over `options.max_bytes` is cut at the last UTF-8 character
+  /// boundary within the limit, has a code block left open by the cut closed,
+  /// and ends with a notice linking to the full rules.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a rule file cannot be read.
+  pub fn get_aggregated_content(&self, options: &ContentOptions) -> Result<String> {
+    let content = self.get_full_content(options)?;
+    let Some(max_bytes) = options.max_bytes.filter(|&max| content.len() > max) else {
+      return Ok(content);
+    };
+
+    let mut end = max_bytes;
+    while !content.is_char_boundary(end) {
+      end -= 1;
+    }
+    logger::detail(format_args!(
+      "{}: truncating {} bytes of rules to {}",
+      self.label(),
+      content.len(),
+      end
+    ));
+    let mut truncated = content[..end].trim_end().to_string();
+    let mut fences = FenceTracker::default();
+    for line in truncated.lines() {
+      fences.is_code(line);
+    }
+    if let Some(fence) = fences.open_fence() {
+      truncated = format!("{}\n{}", truncated, fence);
+    }
+    Ok(format!(
+      "{}\n\n> ...truncated, see full rules at <{}>",
+      truncated,
+      self.full_rules_link()
+    ))
+  }
+
+  /// Returns true if the package's rules exceed `options.max_bytes`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a rule file cannot be read.
+  pub fn is_oversized(&self, options: &ContentOptions) -> Result<bool> {
+    match options.max_bytes {
+      Some(max_bytes) => Ok(self.get_full_content(options)?.len() > max_bytes),
+      None => Ok(false),
+    }
+  }
+
+  /// Returns the link given in the truncation notice: the package's main
+  /// file in the docs.rs source view.
+  fn full_rules_link(&self) -> String {
+    let path = self
+      .content
+      .main_file
+      .as_deref()
+      .and_then(|file| {
+        package_relative_path(file)
+          .or_else(|| Some(file.file_name()?.to_string_lossy().into_owned()))
+      })
+      .unwrap_or_else(|| "usage-rules.md".to_string());
+    format!(
+      "https://docs.rs/crate/{}/{}/source/{}",
+      self.name, self.version, path
+    )
+  }
+
+  /// Returns the package's rules like `get_aggregated_content`, without
+  /// truncation.
+  ///
+  /// Sub-files with the same content, e.g. symlinked or copied under several
+  /// paths, are included once, under the first path, noting the others.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a rule file cannot be read.
+  pub fn get_full_content(&self, options: &ContentOptions) -> Result<String> {
     let mut parts = Vec::new();
 
+    // The package section itself is a level 2 heading
+    let sub_file_level = if options.normalize_headings { 3 } else { 2 };
+    let nest = |content: String, level: usize| {
+      if options.normalize_headings {
+        shift_headings(&content, level)
+      } else {
+        content
+      }
+    };
+
     if let Some(path) = &self.content.main_file {
-      let content = read_file_content(path)?;
-      parts.push(content);
+      let content = options.read(path)?;
+      parts.push(nest(content, 3));
     }
 
+    let sub_files = self.unique_sub_files(options)?;
+
+    // Path components of the last sub-file heading, whose directories need
+    // no heading of their own again
+    let mut open: Vec<&str> = Vec::new();
+    for (relative_path_name, content, aliases) in &sub_files {
+      let (level, title) = if options.nested_headings {
+        let components: Vec<&str> = relative_path_name.split(['/', '\\']).collect();
+        let (file, dirs) = components.split_last().unwrap_or((&"", &[]));
+        let shared = open
+          .iter()
+          .zip(dirs)
+          .take_while(|(open, dir)| open == dir)
+          .count();
+        for (depth, dir) in dirs.iter().enumerate().skip(shared) {
+          parts.push(format!(
+            "\n{} {}",
+            "#".repeat((sub_file_level + depth).min(6)),
+            dir
+          ));
+        }
+        open = components.clone();
+        ((sub_file_level + dirs.len()).min(6), *file)
+      } else {
+        (sub_file_level, *relative_path_name)
+      };
+
+      let content = nest(content.clone(), level + 1);
+      let heading = format!("\n{} {}", "#".repeat(level), title);
+      if aliases.is_empty() {
+        parts.push(format!("{}\n\n{}", heading, content));
+      } else {
+        let aliases: Vec<String> = aliases.iter().map(|alias| format!("`{}`", alias)).collect();
+        parts.push(format!(
+          "{}\n\n_Also at {}._\n\n{}",
+          heading,
+          aliases.join(", "),
+          content
+        ));
+      }
+    }
+
+    Ok(parts.join("\n\n"))
+  }
+
+  /// Reads the package's sub-files, collapsing those with identical content
+  /// into the first, and returns each remaining sub-file's relative path,
+  /// content and the relative paths of its duplicates.
+  fn unique_sub_files(&self, options: &ContentOptions) -> Result<Vec<(&str, String, Vec<&str>)>> {
+    let mut sub_files: Vec<(&str, String, Vec<&str>)> = Vec::new();
+    let mut index_by_hash: HashMap<String, usize> = HashMap::new();
+
     for UsageRuleSubFile {
       relative_path_name,
       full_path,
+      ..
     } in &self.content.sub_files
     {
-      let content = read_file_content(full_path)?;
-      parts.push(format!("\n## {}\n\n{}", relative_path_name, content));
+      let content = options.read(full_path)?;
+      let hash = options
+        .hash_algorithm
+        .hex_digest(canonicalize_whitespace(&content).trim().as_bytes());
+      match index_by_hash.get(&hash) {
+        Some(&index) => sub_files[index].2.push(relative_path_name),
+        None => {
+          index_by_hash.insert(hash, sub_files.len());
+          sub_files.push((relative_path_name, content, Vec::new()));
+        }
+      }
     }
 
-    Ok(parts.join("\n\n"))
+    Ok(sub_files)
+  }
+
+  /// Returns true if the package's main file and sub-files are all blank.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a rule file cannot be read.
+  pub fn is_blank(&self, options: &ContentOptions) -> Result<bool> {
+    for path in self
+      .content
+      .main_file
+      .iter()
+      .chain(self.content.sub_files.iter().map(|f| &f.full_path))
+    {
+      if !options.read(path)?.trim().is_empty() {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Returns the opening of the package's main file: at most `max_lines`
+  /// lines, stopping early before the first heading after the opening line.
+  pub fn get_excerpt(&self, max_lines: usize, options: &ContentOptions) -> Result<String> {
+    let Some(path) = &self.content.main_file else {
+      return Ok(String::new());
+    };
+
+    let content = options.read(path)?;
+    let mut lines = Vec::new();
+    for (index, line) in content.lines().take(max_lines).enumerate() {
+      if index > 0 && line.trim_start().starts_with('#') {
+        break;
+      }
+      lines.push(line);
+    }
+
+    Ok(lines.join("\n").trim_end().to_string())
+  }
+}
+
+/// Settings controlling how each package section is formatted.
+#[derive(Debug, Clone, Default)]
+pub struct SectionOptions {
+  /// In linked mode, the number of main-file lines to include inline before
+  /// each package's link.
+  pub excerpt_lines: Option<usize>,
+  /// Whether to note the conditions under which each package applies.
+  pub show_conditions: bool,
+  /// Whether to include the package version in each section header.
+  pub show_versions: bool,
+  /// Whether to end each section with links to the package's docs.rs page
+  /// and repository.
+  pub with_links: bool,
+  /// How linked mode links to each package's rules.
+  pub link_style: LinkStyle,
+  /// Whether to note a hash of each package's aggregated content below its
+  /// heading.
+  pub with_hashes: bool,
+  /// Format of each package's section heading.
+  pub template: SectionTemplate,
+  /// Renderer whose anchors links to package sections target.
+  pub anchor_flavor: AnchorFlavor,
+  /// Transformations applied to inlined file content.
+  pub content: ContentOptions,
+}
+
+/// Default `--section-template`.
+pub const DEFAULT_SECTION_TEMPLATE: &str = "## {name} usage";
+
+/// A package section heading with `{name}` and `{version}` placeholders, such
+/// as `## {name} usage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionTemplate {
+  /// The heading level, from the leading `#`s.
+  level: usize,
+  /// The heading text after the `#`s.
+  text: String,
+}
+
+impl SectionTemplate {
+  /// Parses a template.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the template is not a markdown heading, lacks a
+  /// `{name}` placeholder, or has an unknown or unclosed placeholder.
+  pub fn parse(template: &str) -> Result<Self, String> {
+    let text = template.trim_start_matches('#');
+    let level = template.len() - text.len();
+    let Some(text) = text.strip_prefix(' ').filter(|_| (1..=6).contains(&level)) else {
+      return Err(format!(
+        "section template '{}' must start with a markdown heading such as '## '",
+        template
+      ));
+    };
+
+    let mut rest = text;
+    let mut has_name = false;
+    while let Some(open) = rest.find('{') {
+      let Some(close) = rest[open..].find('}') else {
+        return Err(format!(
+          "unclosed placeholder in section template '{}'",
+          template
+        ));
+      };
+      match &rest[open + 1..open + close] {
+        "name" => has_name = true,
+        "version" => {}
+        other => {
+          return Err(format!(
+            "unknown placeholder {{{}}} in section template '{}' (use {{name}} and {{version}})",
+            other, template
+          ))
+        }
+      }
+      rest = &rest[open + close + 1..];
+    }
+    if !has_name {
+      return Err(format!(
+        "section template '{}' must contain {{name}}",
+        template
+      ));
+    }
+
+    Ok(Self {
+      level,
+      text: text.to_string(),
+    })
+  }
+
+  /// Returns the `#`s starting the heading.
+  pub fn heading_marker(&self) -> String {
+    "#".repeat(self.level)
+  }
+
+  /// Fills in the placeholders for `package`. When the version must be shown
+  /// but the template has no `{version}`, it follows the name as `v{version}`.
+  fn render(&self, package: &PackageContentInfo, show_version: bool) -> String {
+    let name = if show_version && !self.text.contains("{version}") {
+      format!("{} v{}", package.name, package.version)
+    } else {
+      package.name.clone()
+    };
+    self
+      .text
+      .replace("{version}", &package.version)
+      .replace("{name}", &name)
+  }
+}
+
+impl Default for SectionTemplate {
+  fn default() -> Self {
+    Self::parse(DEFAULT_SECTION_TEMPLATE).expect("the default section template is valid")
+  }
+}
+
+/// How the main file links to each package's rules in linked mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkStyle {
+  /// A path relative to the output file, such as
+  /// `./usage_rules/serde/serde.md`.
+  #[default]
+  Relative,
+  /// An absolute path, resolved against the output file's directory.
+  Absolute,
+  /// An anchor into the linked folder's `index.md`, which then holds every
+  /// package's rules in a single file.
+  Anchor,
+}
+
+/// Returns `path` as a link target, with Windows path separators replaced by
+/// `/`.
+pub fn link_target(path: &str) -> String {
+  path.replace('\\', "/")
+}
+
+/// Returns the path of `file` relative to the root of the package holding it,
+/// the nearest ancestor directory with a `Cargo.toml`, with `/` separators.
+fn package_relative_path(file: &Path) -> Option<String> {
+  let root = file
+    .ancestors()
+    .skip(1)
+    .find(|dir| dir.join("Cargo.toml").is_file())?;
+  let relative = file.strip_prefix(root).ok()?;
+  Some(link_target(&relative.to_string_lossy()))
+}
+
+/// Package selection settings applied while aggregating content.
+#[derive(Debug, Clone, Default)]
+pub struct SelectionOptions {
+  /// Package names or globs to restrict the output to; empty means every
+  /// package.
+  pub include: Vec<String>,
+  /// Package names or globs to exclude from the output.
+  pub remove: Vec<String>,
+  /// Package names whose rules are inlined even in linked mode.
+  pub inline: Vec<String>,
+  /// Whether package names are matched case-insensitively.
+  pub ignore_case: bool,
+  /// Version requirements that packages with a matching name must satisfy.
+  pub version_filters: Vec<VersionFilter>,
+}
+
+/// A `--version-filter` requirement, such as `tokio@>=1`: packages whose name
+/// matches `name` are kept only if their version satisfies `requirement`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionFilter {
+  /// Package name or glob; `*` applies the requirement to every package.
+  pub name: String,
+  /// The semver requirement the package version must satisfy.
+  pub requirement: VersionReq,
+}
+
+impl VersionFilter {
+  /// Parses a `NAME@REQ` filter, such as `tokio@>=1` or `*@>=1.0`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the value has no `@`, an empty name, or an invalid
+  /// semver requirement.
+  pub fn parse(value: &str) -> Result<Self, String> {
+    let (name, requirement) = value
+      .split_once('@')
+      .ok_or_else(|| format!("expected NAME@REQ, such as `tokio@>=1`, got `{}`", value))?;
+    if name.trim().is_empty() {
+      return Err(format!(
+        "missing package name in `{}`; use `*@{}` to filter every package",
+        value, requirement
+      ));
+    }
+    let requirement = VersionReq::parse(requirement.trim())
+      .map_err(|err| format!("invalid version requirement `{}`: {}", requirement, err))?;
+    Ok(Self {
+      name: name.trim().to_string(),
+      requirement,
+    })
+  }
+}
+
+impl SelectionOptions {
+  /// Returns true if `name` refers to the package `package_name`, honoring
+  /// `ignore_case`. A `name` with glob metacharacters, such as `tokio-*`, is
+  /// matched as a glob.
+  pub fn name_matches(&self, name: &str, package_name: &str) -> bool {
+    patterns::name_matches(name, package_name, self.ignore_case)
+  }
+
+  /// Returns true if any name in `names` refers to `package_name`.
+  pub fn any_matches(&self, names: &[String], package_name: &str) -> bool {
+    names
+      .iter()
+      .any(|name| self.name_matches(name, package_name))
+  }
+
+  /// Returns true if `package_name` passes the `include` allowlist.
+  pub fn is_included(&self, package_name: &str) -> bool {
+    self.include.is_empty() || self.any_matches(&self.include, package_name)
+  }
+
+  /// Returns true if `version` satisfies every version filter whose name
+  /// matches `package_name`. Versions that are not valid semver pass.
+  pub fn version_allowed(&self, package_name: &str, version: &str) -> bool {
+    let Ok(version) = Version::parse(version) else {
+      return true;
+    };
+    self
+      .version_filters
+      .iter()
+      .filter(|filter| self.name_matches(&filter.name, package_name))
+      .all(|filter| filter.requirement.matches(&version))
+  }
+}
+
+/// Order in which packages appear in the generated output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortOrder {
+  /// Alphabetically by package name, then by version.
+  #[default]
+  Alpha,
+  /// The order reported by `cargo metadata`.
+  None,
+  /// By distance from the root package in the dependency graph (direct
+  /// dependencies first), then alphabetically.
+  Depth,
+}
+
+/// Sorts usage rules into the requested output order.
+pub fn sort_usage_rules(usage_rules: &mut [UsageRules], order: SortOrder) {
+  let alphabetical = |a: &UsageRules, b: &UsageRules| {
+    a.package_name
+      .cmp(&b.package_name)
+      .then_with(|| compare_versions(&a.package_version, &b.package_version))
+  };
+  match order {
+    SortOrder::Alpha => usage_rules.sort_by(alphabetical),
+    SortOrder::None => {}
+    SortOrder::Depth => {
+      usage_rules.sort_by(|a, b| a.depth.cmp(&b.depth).then_with(|| alphabetical(a, b)))
+    }
+  }
+}
+
+/// Compares version strings component by component, numerically where both
+/// components are numbers, so `1.10.0` sorts after `1.9.0`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+  let split = |v: &str| {
+    v.split(['.', '-', '+'])
+      .map(|part| part.to_string())
+      .collect::<Vec<_>>()
+  };
+
+  for (x, y) in split(a).iter().zip(split(b).iter()) {
+    let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+      (Ok(x), Ok(y)) => x.cmp(&y),
+      _ => x.cmp(y),
+    };
+    if ordering != Ordering::Equal {
+      return ordering;
+    }
+  }
+  split(a).len().cmp(&split(b).len())
+}
+
+/// Records the final disposition of every discovered package as the
+/// selection steps run, so each exclusion can be attributed to its cause.
+#[derive(Debug, Clone, Default)]
+pub struct SelectionLog {
+  /// Package names with the reason they were excluded, or None if included.
+  decisions: Vec<(String, Option<String>)>,
+}
+
+impl SelectionLog {
+  /// Starts a log in which every discovered package is included.
+  pub fn new<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+    Self {
+      decisions: names
+        .into_iter()
+        .map(|name| (name.to_string(), None))
+        .collect(),
+    }
+  }
+
+  /// Marks every still-included package missing from `remaining` as excluded
+  /// for `reason`.
+  pub fn record_step<'a>(&mut self, remaining: impl IntoIterator<Item = &'a str>, reason: &str) {
+    let remaining: Vec<&str> = remaining.into_iter().collect();
+    for (name, disposition) in &mut self.decisions {
+      if disposition.is_none() && !remaining.contains(&name.as_str()) {
+        *disposition = Some(reason.to_string());
+      }
+    }
+  }
+
+  /// Renders the log as a table of packages and their dispositions.
+  pub fn render(&self) -> String {
+    let width = self
+      .decisions
+      .iter()
+      .map(|(name, _)| name.len())
+      .max()
+      .unwrap_or(0)
+      .max("package".len());
+
+    let mut lines = vec![format!("  {:<width$}  disposition", "package")];
+    for (name, disposition) in &self.decisions {
+      lines.push(format!(
+        "  {:<width$}  {}",
+        name,
+        disposition.as_deref().unwrap_or("included")
+      ));
+    }
+    lines.join("\n")
   }
 }
 
-/// Aggregates usage rules content from multiple packages, excluding any
-/// packages specified in the `remove_packages` list.
+/// Aggregates usage rules content from multiple packages, keeping only the
+/// packages in the selection's `include` list (if any) and then excluding the
+/// packages in its `remove` list.
+///
+/// Packages in the selection's `inline` list are marked to be inlined.
+///
+/// A warning is printed for each `include` or `inline` name that matches no
+/// package.
 pub fn aggregate_content(
   usage_rules: Vec<UsageRules>,
-  remove_packages: &[String],
+  selection: &SelectionOptions,
 ) -> Result<Vec<PackageContentInfo>> {
+  for (flag, names) in [
+    ("--include", &selection.include),
+    ("--inline", &selection.inline),
+  ] {
+    for name in names {
+      if !usage_rules
+        .iter()
+        .any(|rule| selection.name_matches(name, &rule.package_name))
+      {
+        warn(
+          WarningCategory::UnknownPackage,
+          format_args!("{} {} matches no package with usage rules", flag, name),
+        );
+      }
+    }
+  }
+
   let mut results = Vec::new();
 
   for rule in usage_rules {
-    if remove_packages.contains(&rule.package_name) {
+    if !selection.is_included(&rule.package_name)
+      || selection.any_matches(&selection.remove, &rule.package_name)
+    {
+      continue;
+    }
+    if !selection.version_allowed(&rule.package_name, &rule.package_version) {
+      logger::detail(format_args!(
+        "skipping {} v{} (does not match --version-filter)",
+        rule.package_name, rule.package_version
+      ));
       continue;
     }
 
@@ -60,63 +717,409 @@ pub fn aggregate_content(
     results.push(PackageContentInfo {
       name: rule.package_name.clone(),
       content: package_content,
+      conditions: rule.conditions.clone(),
+      repository: rule.repository.clone(),
+      category: rule.category.clone(),
+      version: rule.package_version.clone(),
+      duplicate_of: None,
+      inline: selection.any_matches(&selection.inline, &rule.package_name),
+      versioned: false,
     });
   }
 
+  // Different versions of one crate would otherwise share a section heading
+  // and linked folder
+  let names: Vec<String> = results.iter().map(|pkg| pkg.name.clone()).collect();
+  for pkg in &mut results {
+    pkg.versioned = names.iter().filter(|name| **name == pkg.name).count() > 1;
+  }
+
   Ok(results)
 }
 
-/// Extracts the preamble from an existing output file if it exists.
+/// Marks each package whose aggregated content matches an earlier package's
+/// as a duplicate of that package.
+///
+/// Content is compared after whitespace canonicalization, so differences in
+/// trailing spaces or blank lines do not prevent deduplication.
+///
+/// # Errors
+///
+/// Returns an error if a rule file cannot be read.
+pub fn mark_duplicates(
+  packages: &mut [PackageContentInfo],
+  options: &ContentOptions,
+) -> Result<()> {
+  let mut first_by_hash: HashMap<String, String> = HashMap::new();
+
+  for package in packages.iter_mut() {
+    let content = package.get_full_content(options)?;
+    let hash = options
+      .hash_algorithm
+      .hex_digest(canonicalize_whitespace(&content).trim().as_bytes());
+    match first_by_hash.get(&hash) {
+      Some(first) => package.duplicate_of = Some(first.clone()),
+      None => {
+        first_by_hash.insert(hash, package.label());
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Drops packages whose rule files are all blank, warning about each, so
+/// their sections do not appear as headings with nothing under them.
+///
+/// # Errors
+///
+/// Returns an error if a rule file cannot be read.
+pub fn remove_blank_packages(
+  packages: Vec<PackageContentInfo>,
+  options: &ContentOptions,
+) -> Result<Vec<PackageContentInfo>> {
+  let mut kept = Vec::with_capacity(packages.len());
+  for package in packages {
+    if package.is_blank(options)? {
+      warn(
+        WarningCategory::EmptyRules,
+        format_args!(
+          "{} ships empty usage rules; leaving it out (pass --keep-empty to keep it)",
+          package.label()
+        ),
+      );
+    } else {
+      kept.push(package);
+    }
+  }
+  Ok(kept)
+}
+
+/// Default prefix of the section markers.
+pub const DEFAULT_MARKER_PREFIX: &str = "cargo-usage-rules";
+
+/// The HTML comments delimiting the generated section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionMarkers {
+  pub start: String,
+  pub end: String,
+}
+
+impl SectionMarkers {
+  /// Derives `<!-- {prefix}-start -->` and `<!-- {prefix}-end -->`.
+  pub fn from_prefix(prefix: &str) -> Self {
+    Self {
+      start: format!("<!-- {}-start -->", prefix),
+      end: format!("<!-- {}-end -->", prefix),
+    }
+  }
+
+  /// Derives the markers of a named block, `<!-- {prefix}:{name}-start -->`
+  /// and `<!-- {prefix}:{name}-end -->`, or the unnamed markers if `name` is
+  /// None. Blocks with different names can coexist in one file.
+  pub fn named(prefix: &str, name: Option<&str>) -> Self {
+    match name {
+      Some(name) => Self::from_prefix(&format!("{}:{}", prefix, name)),
+      None => Self::from_prefix(prefix),
+    }
+  }
+}
+
+/// Parses a `--section-name`: letters, digits, `-`, `_` and `.` only, so it
+/// cannot break out of the marker comment.
+pub fn parse_section_name(value: &str) -> Result<String, String> {
+  if !value.is_empty()
+    && value
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+  {
+    Ok(value.to_string())
+  } else {
+    Err(format!(
+      "'{}' is not a valid section name (use letters, digits, '-', '_' and '.')",
+      value
+    ))
+  }
+}
+
+impl Default for SectionMarkers {
+  fn default() -> Self {
+    Self::from_prefix(DEFAULT_MARKER_PREFIX)
+  }
+}
+
+/// Extracts the preamble and footer from an existing output file if it
+/// exists.
 ///
 /// This function reads an existing output file and removes the entire
-/// cargo-usage-rules section (between `<!-- cargo-usage-rules-start -->` and
-/// `<!-- cargo-usage-rules-end -->` markers), preserving everything else as
-/// the preamble. This allows users to add custom content that will be preserved
-/// across regenerations.
+/// cargo-usage-rules section (between the start and end `markers`, by default
+/// `<!-- cargo-usage-rules-start -->` and `<!-- cargo-usage-rules-end -->`),
+/// preserving the text before it as the preamble and the text after it as the
+/// footer. This allows users to add custom content that will be preserved
+/// across regenerations, in place.
 ///
 /// # Arguments
 ///
 /// * `output_path` - Path to the existing output file
+/// * `encoding` - Encoding the output file was written with
+/// * `markers` - Markers delimiting the generated section
+/// * `strict_markers` - Whether a lone or out-of-order marker is an error
+///   rather than being kept as part of the preamble
 ///
 /// # Returns
 ///
-/// The preamble text from the existing file with the cargo-usage-rules section
-/// removed, or an empty string if the file doesn't exist.
+/// The trimmed text before and after the cargo-usage-rules section. Without
+/// a section, the whole file is the preamble; without a file, both are empty.
 ///
 /// # Errors
 ///
-/// Returns an error if the file exists but cannot be read.
-pub fn extract_agents_md_preamble(output_path: &Path) -> Result<String> {
-  let existing_content = if output_path.exists() {
-    fs::read_to_string(output_path).ok()
-  } else {
-    None
+/// Returns an error if the file exists but cannot be read, or if
+/// `strict_markers` is set and the markers are malformed.
+pub fn extract_agents_md_preamble(
+  output_path: &Path,
+  encoding: &'static Encoding,
+  markers: &SectionMarkers,
+  strict_markers: bool,
+) -> Result<(String, String)> {
+  let Some(existing) = read_existing_output(output_path, encoding) else {
+    return Ok((String::new(), String::new()));
   };
 
-  let mut preamble = String::new();
-
-  if let Some(existing) = existing_content {
-    // Find the cargo-usage-rules section markers
-    match (
-      existing.find("<!-- cargo-usage-rules-start -->"),
-      existing.find("<!-- cargo-usage-rules-end -->"),
-    ) {
-      (Some(start_pos), Some(end_pos)) => {
-        // Both markers found - remove everything between them (inclusive)
-        let before = &existing[..start_pos];
-        let after_end_marker = end_pos + "<!-- cargo-usage-rules-end -->".len();
-        let after = &existing[after_end_marker..];
-        preamble = format!("{}{}", before.trim(), after.trim());
-        preamble = preamble.trim().to_string();
+  Ok(
+    match find_managed_block(&existing, output_path, markers, strict_markers)? {
+      // Both markers found - remove everything between them (inclusive)
+      Some((start_pos, end_pos)) => (
+        existing[..start_pos].trim().to_string(),
+        existing[end_pos..].trim().to_string(),
+      ),
+      // No or malformed markers found - keep entire content as preamble
+      None => (existing.trim().to_string(), String::new()),
+    },
+  )
+}
+
+/// Returns the text between the section markers of an existing output file,
+/// or None if the file does not exist or its markers are missing or
+/// malformed.
+pub fn read_generated_block(
+  path: &Path,
+  encoding: &'static Encoding,
+  markers: &SectionMarkers,
+) -> Option<String> {
+  let existing = read_existing_output(path, encoding)?;
+  let start = existing.find(&markers.start)? + markers.start.len();
+  let end = existing.find(&markers.end)?;
+  (start <= end).then(|| existing[start..end].to_string())
+}
+
+/// Splits the package sections out of a generated block whose package
+/// sections come last.
+///
+/// A section starts at a line reading `marker` followed by one of `headings`,
+/// outside code blocks, and runs up to the next such line or the end of the
+/// block.
+///
+/// # Returns
+///
+/// Each section found, trimmed, keyed by its heading. None if no heading is
+/// found or one is found more than once, as the block cannot be split
+/// reliably.
+pub fn split_package_sections(
+  block: &str,
+  marker: &str,
+  headings: &[String],
+) -> Option<HashMap<String, String>> {
+  let mut fences = FenceTracker::default();
+  let mut starts: Vec<(usize, &String)> = Vec::new();
+  let mut offset = 0;
+  for line in block.split_inclusive('\n') {
+    let text = line.trim_end();
+    if !fences.is_code(text) {
+      if let Some(heading) = text
+        .strip_prefix(marker)
+        .and_then(|rest| rest.strip_prefix(' '))
+        .and_then(|rest| headings.iter().find(|h| h.as_str() == rest))
+      {
+        if starts.iter().any(|(_, seen)| *seen == heading) {
+          return None;
+        }
+        starts.push((offset, heading));
       }
-      _ => {
-        // No or malformed markers found - keep entire content as preamble
-        preamble = existing.trim().to_string();
+    }
+    offset += line.len();
+  }
+  if starts.is_empty() {
+    return None;
+  }
+
+  let ends = starts.iter().skip(1).map(|(start, _)| *start);
+  Some(
+    starts
+      .iter()
+      .zip(ends.chain([block.len()]))
+      .map(|((start, heading), end)| ((*heading).clone(), block[*start..end].trim().to_string()))
+      .collect(),
+  )
+}
+
+/// Checks that an existing output file's section markers are either both
+/// absent or both present in order. A missing file is valid.
+///
+/// # Errors
+///
+/// Returns a descriptive error if a marker is missing or they are out of
+/// order.
+pub fn validate_output_markers(
+  path: &Path,
+  encoding: &'static Encoding,
+  markers: &SectionMarkers,
+) -> Result<()> {
+  match read_existing_output(path, encoding) {
+    Some(existing) => find_managed_block(&existing, path, markers, true).map(|_| ()),
+    None => Ok(()),
+  }
+}
+
+/// Removes the cargo-usage-rules block from an existing output file's content,
+/// keeping the text around it.
+///
+/// # Returns
+///
+/// The remaining content, ending with a newline unless empty, or None if the
+/// file does not exist or has no block.
+///
+/// # Errors
+///
+/// Returns an error if the markers are malformed.
+pub fn strip_generated_section(
+  path: &Path,
+  encoding: &'static Encoding,
+  markers: &SectionMarkers,
+) -> Result<Option<String>> {
+  let Some(existing) = read_existing_output(path, encoding) else {
+    return Ok(None);
+  };
+
+  Ok(
+    find_managed_block(&existing, path, markers, true)?.map(|(start_pos, end_pos)| {
+      let before = existing[..start_pos].trim_end();
+      let after = existing[end_pos..].trim();
+      match (before.is_empty(), after.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{}\n", before),
+        (true, false) => format!("{}\n", after),
+        (false, false) => format!("{}\n\n{}\n", before, after),
       }
+    }),
+  )
+}
+
+/// Splits an existing document around its cargo-usage-rules block so the
+/// generated section can be re-inserted in place.
+///
+/// Unlike `extract_agents_md_preamble`, the surrounding content is kept
+/// exactly. If the document has no block, the section is placed at its end.
+///
+/// # Errors
+///
+/// Returns an error if the document does not exist or cannot be read, or if
+/// `strict_markers` is set and the markers are malformed.
+pub fn extract_surrounding_content(
+  path: &Path,
+  encoding: &'static Encoding,
+  markers: &SectionMarkers,
+  strict_markers: bool,
+) -> Result<ExistingContent> {
+  if !path.is_file() {
+    anyhow::bail!("Merge target does not exist: {}", path.display());
+  }
+  let existing = read_existing_output(path, encoding)
+    .with_context(|| format!("Failed to read merge target: {}", path.display()))?;
+
+  Ok(
+    match find_managed_block(&existing, path, markers, strict_markers)? {
+      Some((start_pos, end_pos)) => ExistingContent::Surrounding {
+        before: existing[..start_pos].to_string(),
+        after: existing[end_pos..].to_string(),
+      },
+      None => {
+        let mut before = existing;
+        if !before.is_empty() {
+          before.push_str(if before.ends_with('\n') { "\n" } else { "\n\n" });
+        }
+        ExistingContent::Surrounding {
+          before,
+          after: "\n".to_string(),
+        }
+      }
+    },
+  )
+}
+
+/// Content of an existing file kept around the generated section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExistingContent {
+  /// Text placed above and below the generated section, each separated from
+  /// it by a blank line.
+  Preamble { preamble: String, footer: String },
+  /// Exact text before and after the section's position in a larger document.
+  Surrounding { before: String, after: String },
+}
+
+fn read_existing_output(path: &Path, encoding: &'static Encoding) -> Option<String> {
+  fs::read(path)
+    .ok()
+    .map(|bytes| decode_output(&bytes, encoding))
+}
+
+/// Locates the cargo-usage-rules block, returning the offset of the start
+/// marker and the offset just past the end marker, or None if the markers are
+/// missing or malformed.
+fn find_managed_block(
+  existing: &str,
+  path: &Path,
+  markers: &SectionMarkers,
+  strict_markers: bool,
+) -> Result<Option<(usize, usize)>> {
+  let start = existing.find(&markers.start);
+  let end = existing.find(&markers.end);
+
+  if let Err(e) =
+    validate_markers(start, end).with_context(|| format!("Malformed markers in {}", path.display()))
+  {
+    if strict_markers {
+      return Err(e);
     }
+    // Keeping the content means the stale block ends up in the next output,
+    // so make sure the user hears about it
+    warn(
+      WarningCategory::MalformedMarkers,
+      format!(
+        "{:#}; treating the whole file as hand-written content (pass --strict-markers to \
+         fail, or --force to overwrite it)",
+        e
+      ),
+    );
   }
 
-  Ok(preamble)
+  Ok(match (start, end) {
+    (Some(start_pos), Some(end_pos)) if start_pos < end_pos => {
+      Some((start_pos, end_pos + markers.end.len()))
+    }
+    _ => None,
+  })
+}
+
+/// Checks that the section markers are either both absent or both present in
+/// order.
+fn validate_markers(start: Option<usize>, end: Option<usize>) -> Result<()> {
+  match (start, end) {
+    (None, None) => Ok(()),
+    (Some(start_pos), Some(end_pos)) if start_pos < end_pos => Ok(()),
+    (Some(_), Some(_)) => anyhow::bail!("the end marker precedes the start marker"),
+    (Some(_), None) => anyhow::bail!("found a start marker without an end marker"),
+    (None, Some(_)) => anyhow::bail!("found an end marker without a start marker"),
+  }
 }
 
 /// Formats a package's content into a marked section with MD headers, either
@@ -126,19 +1129,146 @@ pub fn extract_agents_md_preamble(output_path: &Path) -> Result<String> {
 ///
 /// * `package` - The package content to format
 /// * `link_folder_name` - Optional folder name for linked mode (e.g.,
-///   "usage_rules"). If None, content is inlined.
+///   "usage_rules"), or the folder's absolute path with
+///   `LinkStyle::Absolute`. If None, or if the package is marked `inline`,
+///   content is inlined.
+/// * `options` - Excerpt, condition note and content settings for the section
 pub fn format_package_section(
   package: &PackageContentInfo,
   link_folder_name: Option<&str>,
+  options: &SectionOptions,
 ) -> Result<String> {
-  let content = if let Some(folder) = link_folder_name {
-    // Generate relative path to the linked file
-    let relative_path = format!("./{}/{}/{}.md", folder, package.name, package.name);
-    format!("[{} usage rules]({})", package.name, relative_path)
+  let content = if let Some(first) = &package.duplicate_of {
+    format!("Same usage rules as {}.", first)
+  } else if let Some(folder) = link_folder_name.filter(|_| !package.inline) {
+    let target = match options.link_style {
+      LinkStyle::Relative => format!("./{}/{}/{}.md", folder, package.folder_name(), package.name),
+      LinkStyle::Absolute => format!("{}/{}/{}.md", folder, package.folder_name(), package.name),
+      LinkStyle::Anchor => format!(
+        "./{}/{}#{}",
+        folder,
+        INDEX_FILENAME,
+        options
+          .anchor_flavor
+          .slug(&section_heading(package, options))
+      ),
+    };
+    let link = format!(
+      "[{} usage rules]({})",
+      package.label(),
+      link_target(&target)
+    );
+    match options.excerpt_lines {
+      Some(max_lines) => {
+        let excerpt = package.get_excerpt(max_lines, &options.content)?;
+        if excerpt.is_empty() {
+          link
+        } else {
+          format!("{}\n\n{}", excerpt, link)
+        }
+      }
+      None => link,
+    }
+  } else {
+    package.get_aggregated_content(&options.content)?
+  };
+
+  let content = if options.with_links && package.duplicate_of.is_none() {
+    format!("{}\n\n{}", content.trim_end(), package_links(package))
   } else {
-    package.get_aggregated_content()?
+    content
   };
-  Ok(format!("## {} usage\n{}", package.name, content))
+
+  let mut heading = section_heading(package, options);
+  if options.with_hashes {
+    let rules = package.get_full_content(&options.content)?;
+    heading = format!(
+      "{}\n{}{} -->",
+      heading,
+      HASH_COMMENT_PREFIX,
+      options
+        .content
+        .hash_algorithm
+        .tagged_digest(rules.as_bytes())
+    );
+  }
+
+  let marker = options.template.heading_marker();
+  if options.show_conditions && !package.conditions.is_empty() {
+    return Ok(format!(
+      "{} {}\n> Applies when: {}\n\n{}",
+      marker,
+      heading,
+      package.conditions.join("; "),
+      content
+    ));
+  }
+  Ok(format!("{} {}\n{}", marker, heading, content))
+}
+
+/// Start of the comment holding a section's content hash.
+const HASH_COMMENT_PREFIX: &str = "<!-- hash: ";
+
+/// Returns the heading and content hash of every section of a generated
+/// file written with `with_hashes`, in order.
+pub fn section_hashes(content: &str) -> Vec<(String, String)> {
+  let mut hashes = Vec::new();
+  let mut heading = None;
+  for line in content.lines() {
+    if let Some(hash) = line
+      .strip_prefix(HASH_COMMENT_PREFIX)
+      .and_then(|rest| rest.strip_suffix(" -->"))
+    {
+      if let Some(heading) = heading.take() {
+        hashes.push((heading, hash.to_string()));
+      }
+      continue;
+    }
+    heading = line
+      .trim_start_matches('#')
+      .strip_prefix(' ')
+      .filter(|_| line.starts_with('#'))
+      .map(str::to_string);
+  }
+  hashes
+}
+
+/// Returns the headings of the sections whose content hash differs between
+/// `old` and `new`, including sections only one of them has.
+pub fn changed_sections(old: &str, new: &str) -> Vec<String> {
+  let old = section_hashes(old);
+  let new = section_hashes(new);
+  let mut changed: Vec<String> = new
+    .iter()
+    .filter(|section| !old.contains(section))
+    .map(|(heading, _)| heading.clone())
+    .collect();
+  for (heading, _) in &old {
+    if !new.iter().any(|(h, _)| h == heading) {
+      changed.push(heading.clone());
+    }
+  }
+  changed
+}
+
+/// Returns a line linking to the package's docs.rs page and, if known, its
+/// repository.
+fn package_links(package: &PackageContentInfo) -> String {
+  let docs = format!(
+    "[docs.rs](https://docs.rs/{}/{})",
+    package.name, package.version
+  );
+  match &package.repository {
+    Some(repository) => format!("See also: {}, [repository]({})", docs, repository),
+    None => format!("See also: {}", docs),
+  }
+}
+
+/// Returns the text of a package section's heading, such as `serde usage`.
+pub fn section_heading(package: &PackageContentInfo, options: &SectionOptions) -> String {
+  options
+    .template
+    .render(package, options.show_versions || package.versioned)
 }
 
 #[cfg(test)]
@@ -146,6 +1276,16 @@ mod tests {
   use super::*;
   use tempfile::TempDir;
 
+  fn selection(remove: &[&str], ignore_case: bool) -> SelectionOptions {
+    SelectionOptions {
+      remove: remove.iter().map(|s| s.to_string()).collect(),
+      ignore_case,
+      version_filters: vec![],
+      include: vec![],
+      inline: vec![],
+    }
+  }
+
   fn create_test_usage_rules(name: &str, version: &str, main_content: Option<&str>) -> UsageRules {
     let temp = TempDir::new().unwrap();
     let pkg_path = temp.path();
@@ -163,6 +1303,10 @@ mod tests {
       package_version: version.to_string(),
       main_file,
       sub_files: vec![],
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
     }
   }
 
@@ -174,8 +1318,7 @@ mod tests {
       create_test_usage_rules("pkg3", "3.0.0", Some("Content 3")),
     ];
 
-    let remove = vec!["pkg2".to_string()];
-    let result = aggregate_content(rules, &remove).unwrap();
+    let result = aggregate_content(rules, &selection(&["pkg2"], false)).unwrap();
 
     assert_eq!(result.len(), 2);
     assert_eq!(result[0].name, "pkg1");
@@ -183,65 +1326,585 @@ mod tests {
   }
 
   #[test]
-  fn test_aggregate_content_with_empty_remove_list() {
-    let rules = vec![
-      create_test_usage_rules("pkg1", "1.0.0", Some("Content 1")),
-      create_test_usage_rules("pkg2", "2.0.0", Some("Content 2")),
-    ];
+  fn test_aggregate_content_removes_packages_by_glob() {
+    let rules = vec![
+      create_test_usage_rules("tokio", "1.0.0", Some("Tokio")),
+      create_test_usage_rules("tokio-util", "1.0.0", Some("Util")),
+      create_test_usage_rules("tokio-macros", "1.0.0", Some("Macros")),
+    ];
+
+    let result = aggregate_content(rules, &selection(&["tokio-*"], false)).unwrap();
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].name, "tokio");
+  }
+
+  #[test]
+  fn test_aggregate_content_applies_version_filters() {
+    let rules = vec![
+      create_test_usage_rules("tokio", "1.38.0", Some("Tokio 1")),
+      create_test_usage_rules("tokio", "0.2.25", Some("Tokio 0.2")),
+      create_test_usage_rules("serde", "0.9.0", Some("Serde")),
+      create_test_usage_rules("local", "not-semver", Some("Local")),
+    ];
+    let filtered = |filters: &[&str]| {
+      let selection = SelectionOptions {
+        version_filters: filters
+          .iter()
+          .map(|filter| VersionFilter::parse(filter).unwrap())
+          .collect(),
+        ..SelectionOptions::default()
+      };
+      aggregate_content(rules.clone(), &selection)
+        .unwrap()
+        .iter()
+        .map(|pkg| format!("{}@{}", pkg.name, pkg.version))
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+      filtered(&["tokio@>=1"]),
+      vec!["tokio@1.38.0", "serde@0.9.0", "local@not-semver"]
+    );
+    assert_eq!(
+      filtered(&["*@>=1.0"]),
+      vec!["tokio@1.38.0", "local@not-semver"]
+    );
+  }
+
+  #[test]
+  fn test_version_filter_parse() {
+    let filter = VersionFilter::parse("tokio@>=1, <2").unwrap();
+    assert_eq!(filter.name, "tokio");
+    assert_eq!(filter.requirement, VersionReq::parse(">=1, <2").unwrap());
+
+    assert!(VersionFilter::parse("tokio").is_err());
+    assert!(VersionFilter::parse("@>=1").is_err());
+    assert!(VersionFilter::parse("tokio@not a req").is_err());
+  }
+
+  #[test]
+  fn test_aggregate_content_include_then_remove() {
+    let rules = vec![
+      create_test_usage_rules("pkg1", "1.0.0", Some("Content 1")),
+      create_test_usage_rules("pkg2", "2.0.0", Some("Content 2")),
+      create_test_usage_rules("pkg3", "3.0.0", Some("Content 3")),
+    ];
+    let selection = SelectionOptions {
+      include: vec!["pkg1".to_string(), "pkg2".to_string(), "typo".to_string()],
+      ..selection(&["pkg2"], false)
+    };
+
+    let result = aggregate_content(rules, &selection).unwrap();
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].name, "pkg1");
+  }
+
+  #[test]
+  fn test_aggregate_content_versions_packages_sharing_a_name() {
+    let rules = vec![
+      create_test_usage_rules("serde", "1.0.0", Some("Old")),
+      create_test_usage_rules("serde", "2.0.0", Some("New")),
+      create_test_usage_rules("tokio", "1.0.0", Some("Tokio")),
+    ];
+
+    let result = aggregate_content(rules, &selection(&[], false)).unwrap();
+
+    assert_eq!(result[0].folder_name(), "serde-1.0.0");
+    assert_eq!(result[1].label(), "serde v2.0.0");
+    assert_eq!(result[2].folder_name(), "tokio");
+    assert_eq!(
+      section_heading(&result[0], &SectionOptions::default()),
+      "serde v1.0.0 usage"
+    );
+    assert_eq!(
+      section_heading(&result[2], &SectionOptions::default()),
+      "tokio usage"
+    );
+  }
+
+  #[test]
+  fn test_aggregate_content_marks_inline_packages() {
+    let rules = vec![
+      create_test_usage_rules("pkg1", "1.0.0", Some("Content 1")),
+      create_test_usage_rules("Pkg2", "2.0.0", Some("Content 2")),
+    ];
+    let selection = SelectionOptions {
+      inline: vec!["pkg2".to_string()],
+      ..selection(&[], true)
+    };
+
+    let result = aggregate_content(rules, &selection).unwrap();
+
+    assert!(!result[0].inline);
+    assert!(result[1].inline);
+  }
+
+  #[test]
+  fn test_aggregate_content_with_empty_remove_list() {
+    let rules = vec![
+      create_test_usage_rules("pkg1", "1.0.0", Some("Content 1")),
+      create_test_usage_rules("pkg2", "2.0.0", Some("Content 2")),
+    ];
+
+    let result = aggregate_content(rules, &selection(&[], false)).unwrap();
+
+    assert_eq!(result.len(), 2);
+  }
+
+  #[test]
+  fn test_aggregate_content_remove_is_case_sensitive_by_default() {
+    let rules = vec![create_test_usage_rules("tokio", "1.0.0", Some("Tokio"))];
+
+    let result = aggregate_content(rules, &selection(&["TOKIO"], false)).unwrap();
+
+    assert_eq!(result.len(), 1);
+  }
+
+  #[test]
+  fn test_aggregate_content_remove_with_ignore_case() {
+    let rules = vec![
+      create_test_usage_rules("tokio", "1.0.0", Some("Tokio")),
+      create_test_usage_rules("serde", "1.0.0", Some("Serde")),
+    ];
+
+    let result = aggregate_content(rules, &selection(&["TOKIO"], true)).unwrap();
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].name, "serde");
+  }
+
+  #[test]
+  fn test_aggregate_content_handles_empty_input() {
+    let result = aggregate_content(vec![], &selection(&[], false)).unwrap();
+    assert_eq!(result.len(), 0);
+  }
+
+  #[test]
+  fn test_get_aggregated_content_main_only() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Main content").unwrap();
+
+    let package = PackageContentInfo {
+      name: "test".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+
+    let content = package
+      .get_aggregated_content(&ContentOptions::default())
+      .unwrap();
+    assert_eq!(content, "Main content");
+  }
+
+  #[test]
+  fn test_get_aggregated_content_truncates_at_char_boundary() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Use é everywhere").unwrap();
+
+    let package = PackageContentInfo {
+      name: "big".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.2.3".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    // "é" spans bytes 4 and 5, so a limit of 5 cuts before it
+    let options = ContentOptions {
+      max_bytes: Some(5),
+      ..ContentOptions::default()
+    };
+
+    assert_eq!(
+      package.get_aggregated_content(&options).unwrap(),
+      "Use\n\n> ...truncated, see full rules at \
+       <https://docs.rs/crate/big/1.2.3/source/usage-rules.md>"
+    );
+    assert!(package.is_oversized(&options).unwrap());
+    assert_eq!(
+      package.get_full_content(&options).unwrap(),
+      "Use é everywhere"
+    );
+
+    let options = ContentOptions {
+      max_bytes: Some(100),
+      ..ContentOptions::default()
+    };
+    assert_eq!(
+      package.get_aggregated_content(&options).unwrap(),
+      "Use é everywhere"
+    );
+    assert!(!package.is_oversized(&options).unwrap());
+  }
+
+  #[test]
+  fn test_get_aggregated_content_truncation_closes_fence_and_links_nested_file() {
+    let temp = TempDir::new().unwrap();
+    fs::write(
+      temp.path().join("Cargo.toml"),
+      "[package]\nname = \"big\"\n",
+    )
+    .unwrap();
+    fs::create_dir(temp.path().join("docs")).unwrap();
+    let main_file = temp.path().join("docs").join("agent.md");
+    fs::write(
+      &main_file,
+      "Example:\n\n```rust\nlet x = 1;\nlet y = 2;\n```\n",
+    )
+    .unwrap();
+
+    let package = PackageContentInfo {
+      name: "big".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.2.3".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let options = ContentOptions {
+      max_bytes: Some(30),
+      ..ContentOptions::default()
+    };
+
+    assert_eq!(
+      package.get_aggregated_content(&options).unwrap(),
+      "Example:\n\n```rust\nlet x = 1;\nl\n```\n\n> ...truncated, see full rules at \
+       <https://docs.rs/crate/big/1.2.3/source/docs/agent.md>"
+    );
+  }
+
+  #[test]
+  fn test_get_aggregated_content_with_sub_files() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Main content").unwrap();
+
+    let sub_file = temp.path().join("async.md");
+    fs::write(&sub_file, "Async content").unwrap();
+
+    let package = PackageContentInfo {
+      name: "test".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file.clone()),
+        sub_files: vec![UsageRuleSubFile {
+          relative_path_name: "async".to_string(),
+          full_path: sub_file,
+          order: None,
+        }],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+
+    let content = package
+      .get_aggregated_content(&ContentOptions::default())
+      .unwrap();
+    assert!(content.contains("Main content"));
+    assert!(content.contains("## async"));
+    assert!(content.contains("Async content"));
+  }
+
+  #[test]
+  fn test_get_aggregated_content_collapses_duplicate_sub_files() {
+    let temp = TempDir::new().unwrap();
+    let sub_files = [
+      ("async", "Async content"),
+      ("tokio", "Async content\n"),
+      ("sync", "Sync content"),
+    ]
+    .iter()
+    .map(|(name, content)| {
+      let full_path = temp.path().join(format!("{}.md", name));
+      fs::write(&full_path, content).unwrap();
+      UsageRuleSubFile {
+        relative_path_name: name.to_string(),
+        full_path,
+        order: None,
+      }
+    })
+    .collect();
+
+    let package = PackageContentInfo {
+      name: "test".to_string(),
+      content: PackageContent {
+        main_file: None,
+        sub_files,
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+
+    let content = package
+      .get_aggregated_content(&ContentOptions::default())
+      .unwrap();
+    assert_eq!(content.matches("Async content").count(), 1);
+    assert!(content.contains("## async\n\n_Also at `tokio`._\n\nAsync content"));
+    assert!(!content.contains("## tokio"));
+    assert!(content.contains("## sync\n\nSync content"));
+  }
+
+  #[test]
+  fn test_get_aggregated_content_nested_headings() {
+    let temp = TempDir::new().unwrap();
+    let sub_files = ["patterns", "patterns/builder", "patterns/factory", "setup"]
+      .iter()
+      .map(|name| {
+        let full_path = temp.path().join(format!("{}.md", name.replace('/', "-")));
+        fs::write(&full_path, format!("{} content", name)).unwrap();
+        UsageRuleSubFile {
+          relative_path_name: name.to_string(),
+          full_path,
+          order: None,
+        }
+      })
+      .collect();
+    let package = PackageContentInfo {
+      name: "test".to_string(),
+      content: PackageContent {
+        main_file: None,
+        sub_files,
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
 
-    let result = aggregate_content(rules, &[]).unwrap();
+    let flat = package
+      .get_aggregated_content(&ContentOptions::default())
+      .unwrap();
+    assert!(flat.contains("## patterns/builder\n"));
 
-    assert_eq!(result.len(), 2);
-  }
+    let nested = package
+      .get_aggregated_content(&ContentOptions {
+        nested_headings: true,
+        ..ContentOptions::default()
+      })
+      .unwrap();
+    let headings: Vec<&str> = nested.lines().filter(|l| l.starts_with('#')).collect();
+    assert_eq!(
+      headings,
+      vec!["## patterns", "### builder", "### factory", "## setup"]
+    );
+    assert!(nested.contains("### builder\n\npatterns/builder content"));
 
-  #[test]
-  fn test_aggregate_content_handles_empty_input() {
-    let result = aggregate_content(vec![], &[]).unwrap();
-    assert_eq!(result.len(), 0);
+    let package = PackageContentInfo {
+      content: PackageContent {
+        main_file: None,
+        sub_files: package.content.sub_files[1..].to_vec(),
+      },
+      ..package
+    };
+    let nested = package
+      .get_aggregated_content(&ContentOptions {
+        nested_headings: true,
+        ..ContentOptions::default()
+      })
+      .unwrap();
+    let headings: Vec<&str> = nested.lines().filter(|l| l.starts_with('#')).collect();
+    assert_eq!(
+      headings,
+      vec!["## patterns", "### builder", "### factory", "## setup"]
+    );
   }
 
   #[test]
-  fn test_get_aggregated_content_main_only() {
+  fn test_get_aggregated_content_normalizes_headings() {
     let temp = TempDir::new().unwrap();
     let main_file = temp.path().join("usage-rules.md");
-    fs::write(&main_file, "Main content").unwrap();
+    fs::write(&main_file, "# Pkg\n## Setup\n").unwrap();
+    let sub_file = temp.path().join("async.md");
+    fs::write(&sub_file, "# Async\n```sh\n# shell comment\n```\n").unwrap();
 
     let package = PackageContentInfo {
       name: "test".to_string(),
       content: PackageContent {
         main_file: Some(main_file),
-        sub_files: vec![],
+        sub_files: vec![UsageRuleSubFile {
+          relative_path_name: "async".to_string(),
+          full_path: sub_file,
+          order: None,
+        }],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     };
 
-    let content = package.get_aggregated_content().unwrap();
-    assert_eq!(content, "Main content");
+    let content = package
+      .get_aggregated_content(&ContentOptions {
+        normalize_headings: true,
+        ..ContentOptions::default()
+      })
+      .unwrap();
+
+    assert!(content.starts_with("### Pkg\n#### Setup\n"));
+    assert!(content.contains("\n### async\n\n#### Async\n```sh\n# shell comment\n```"));
   }
 
   #[test]
-  fn test_get_aggregated_content_with_sub_files() {
+  fn test_mark_duplicates_refers_to_first_package() {
     let temp = TempDir::new().unwrap();
-    let main_file = temp.path().join("usage-rules.md");
-    fs::write(&main_file, "Main content").unwrap();
+    let package = |name: &str, content: &str| {
+      let main_file = temp.path().join(format!("{}.md", name));
+      fs::write(&main_file, content).unwrap();
+      PackageContentInfo {
+        name: name.to_string(),
+        version: "1.0.0".to_string(),
+        content: PackageContent {
+          main_file: Some(main_file),
+          sub_files: vec![],
+        },
+        conditions: vec![],
+        repository: None,
+        category: None,
+        duplicate_of: None,
+        inline: false,
+        versioned: false,
+      }
+    };
+    let mut packages = vec![
+      package("org-core", "Shared rules\n"),
+      package("org-extra", "Shared rules  \n\n\n\n"),
+      package("other", "Other rules\n"),
+    ];
 
-    let sub_file = temp.path().join("async.md");
-    fs::write(&sub_file, "Async content").unwrap();
+    mark_duplicates(&mut packages, &ContentOptions::default()).unwrap();
+
+    assert_eq!(packages[0].duplicate_of, None);
+    assert_eq!(packages[1].duplicate_of.as_deref(), Some("org-core"));
+    assert_eq!(packages[2].duplicate_of, None);
+
+    let section = format_package_section(&packages[1], None, &SectionOptions::default()).unwrap();
+    assert_eq!(section, "## org-extra usage\nSame usage rules as org-core.");
+  }
+
+  #[test]
+  fn test_remove_blank_packages() {
+    let temp = TempDir::new().unwrap();
+    let package = |name: &str, main: &str, sub_file: Option<&str>| {
+      let main_file = temp.path().join(format!("{}.md", name));
+      fs::write(&main_file, main).unwrap();
+      let sub_files = sub_file
+        .map(|content| {
+          let full_path = temp.path().join(format!("{}-sub.md", name));
+          fs::write(&full_path, content).unwrap();
+          UsageRuleSubFile {
+            relative_path_name: "sub".to_string(),
+            full_path,
+            order: None,
+          }
+        })
+        .into_iter()
+        .collect();
+      PackageContentInfo {
+        name: name.to_string(),
+        version: "1.0.0".to_string(),
+        content: PackageContent {
+          main_file: Some(main_file),
+          sub_files,
+        },
+        conditions: vec![],
+        repository: None,
+        category: None,
+        duplicate_of: None,
+        inline: false,
+        versioned: false,
+      }
+    };
+    let packages = vec![
+      package("empty", "", None),
+      package("blank", " \n\n", Some("\t\n")),
+      package("sub-only", "", Some("Sub rules")),
+      package("main", "Main rules", None),
+    ];
+
+    let kept = remove_blank_packages(packages, &ContentOptions::default()).unwrap();
+
+    let names: Vec<_> = kept.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["sub-only", "main"]);
+  }
+
+  #[test]
+  fn test_get_aggregated_content_canonicalizes_whitespace() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(
+      &main_file,
+      "Intro   \n\n\n\n\nBody\n```rust\nlet x = 1;  \n\n\n\n```\n",
+    )
+    .unwrap();
 
     let package = PackageContentInfo {
       name: "test".to_string(),
       content: PackageContent {
-        main_file: Some(main_file.clone()),
-        sub_files: vec![UsageRuleSubFile {
-          relative_path_name: "async".to_string(),
-          full_path: sub_file,
-        }],
+        main_file: Some(main_file),
+        sub_files: vec![],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     };
 
-    let content = package.get_aggregated_content().unwrap();
-    assert!(content.contains("Main content"));
-    assert!(content.contains("## async"));
-    assert!(content.contains("Async content"));
+    let raw = package
+      .get_aggregated_content(&ContentOptions::default())
+      .unwrap();
+    let canonical = package
+      .get_aggregated_content(&ContentOptions {
+        canonicalize: true,
+        ..ContentOptions::default()
+      })
+      .unwrap();
+
+    assert!(raw.contains("Intro   \n\n\n\n\nBody"));
+    assert!(canonical.starts_with("Intro\n\n\nBody\n"));
+    assert!(canonical.contains("```rust\nlet x = 1;  \n\n\n\n```"));
   }
 
   #[test]
@@ -255,13 +1918,45 @@ mod tests {
 
     fs::write(&output_file, existing_content).unwrap();
 
-    let preamble = extract_agents_md_preamble(&output_file).unwrap();
+    let (preamble, footer) = extract_agents_md_preamble(
+      &output_file,
+      encoding_rs::UTF_8,
+      &SectionMarkers::default(),
+      false,
+    )
+    .unwrap();
+
+    assert_eq!(preamble, "# Custom Header\n\nMy preamble");
+    assert_eq!(footer, "Footer content");
+  }
+
+  #[test]
+  fn test_extract_preamble_removes_only_named_block() {
+    let temp = TempDir::new().unwrap();
+    let output_file = temp.path().join("Agents.md");
+    fs::write(
+      &output_file,
+      "Preamble\n\n<!-- cargo-usage-rules:claude-start -->\nClaude\n<!-- \
+       cargo-usage-rules:claude-end -->\n\n<!-- cargo-usage-rules:cursor-start -->\nCursor\n<!-- \
+       cargo-usage-rules:cursor-end -->\n",
+    )
+    .unwrap();
+
+    let (preamble, _) = extract_agents_md_preamble(
+      &output_file,
+      encoding_rs::UTF_8,
+      &SectionMarkers::named(DEFAULT_MARKER_PREFIX, Some("cursor")),
+      true,
+    )
+    .unwrap();
 
-    assert!(preamble.contains("Custom Header"));
-    assert!(preamble.contains("My preamble"));
-    assert!(preamble.contains("Footer content"));
-    assert!(!preamble.contains("cargo-usage-rules"));
-    assert!(!preamble.contains("Old generated content"));
+    assert_eq!(
+      preamble,
+      "Preamble\n\n<!-- cargo-usage-rules:claude-start -->\nClaude\n<!-- \
+       cargo-usage-rules:claude-end -->"
+    );
+    assert!(parse_section_name("claude").is_ok());
+    assert!(parse_section_name("a -->").is_err());
   }
 
   #[test]
@@ -272,7 +1967,13 @@ mod tests {
     let existing_content = "# No markers here\n\nJust regular content";
     fs::write(&output_file, existing_content).unwrap();
 
-    let preamble = extract_agents_md_preamble(&output_file).unwrap();
+    let (preamble, _) = extract_agents_md_preamble(
+      &output_file,
+      encoding_rs::UTF_8,
+      &SectionMarkers::default(),
+      false,
+    )
+    .unwrap();
 
     // Should keep entire content as preamble when no markers found
     assert_eq!(preamble, "# No markers here\n\nJust regular content");
@@ -286,20 +1987,192 @@ mod tests {
     let existing_content = "Preamble\n\n<!-- cargo-usage-rules-start -->\nContent";
     fs::write(&output_file, existing_content).unwrap();
 
-    let preamble = extract_agents_md_preamble(&output_file).unwrap();
+    let (preamble, _) = extract_agents_md_preamble(
+      &output_file,
+      encoding_rs::UTF_8,
+      &SectionMarkers::default(),
+      false,
+    )
+    .unwrap();
 
     // Malformed markers - should keep entire content
     assert!(preamble.contains("Preamble"));
     assert!(preamble.contains("Content"));
   }
 
+  #[test]
+  fn test_extract_preamble_strict_rejects_malformed_markers() {
+    let temp = TempDir::new().unwrap();
+    let output_file = temp.path().join("Agents.md");
+
+    for existing_content in [
+      "Preamble\n\n<!-- cargo-usage-rules-start -->\nContent",
+      "Preamble\n\n<!-- cargo-usage-rules-end -->\nContent",
+      "<!-- cargo-usage-rules-end -->\nContent\n<!-- cargo-usage-rules-start -->",
+    ] {
+      fs::write(&output_file, existing_content).unwrap();
+
+      let result = extract_agents_md_preamble(
+        &output_file,
+        encoding_rs::UTF_8,
+        &SectionMarkers::default(),
+        true,
+      );
+
+      assert!(
+        result.is_err(),
+        "Accepted malformed markers: {}",
+        existing_content
+      );
+    }
+  }
+
+  #[test]
+  fn test_validate_output_markers_reports_reversed_markers() {
+    let temp = TempDir::new().unwrap();
+    let output_file = temp.path().join("Agents.md");
+    fs::write(
+      &output_file,
+      "<!-- cargo-usage-rules-end -->\nOld\n<!-- cargo-usage-rules-start -->\n",
+    )
+    .unwrap();
+
+    let error =
+      validate_output_markers(&output_file, encoding_rs::UTF_8, &SectionMarkers::default())
+        .unwrap_err();
+
+    assert_eq!(
+      format!("{:#}", error),
+      format!(
+        "Malformed markers in {}: the end marker precedes the start marker",
+        output_file.display()
+      )
+    );
+    assert!(validate_output_markers(
+      &temp.path().join("missing.md"),
+      encoding_rs::UTF_8,
+      &SectionMarkers::default()
+    )
+    .is_ok());
+  }
+
+  #[test]
+  fn test_extract_preamble_strict_accepts_well_formed_markers() {
+    let temp = TempDir::new().unwrap();
+    let output_file = temp.path().join("Agents.md");
+    fs::write(
+      &output_file,
+      "Preamble\n<!-- cargo-usage-rules-start -->\nOld\n<!-- cargo-usage-rules-end -->",
+    )
+    .unwrap();
+
+    let (preamble, _) = extract_agents_md_preamble(
+      &output_file,
+      encoding_rs::UTF_8,
+      &SectionMarkers::default(),
+      true,
+    )
+    .unwrap();
+
+    assert_eq!(preamble, "Preamble");
+  }
+
   #[test]
   fn test_extract_preamble_non_existent_file() {
     let non_existent = PathBuf::from("/tmp/nonexistent-file.md");
-    let preamble = extract_agents_md_preamble(&non_existent).unwrap();
+    let (preamble, _) = extract_agents_md_preamble(
+      &non_existent,
+      encoding_rs::UTF_8,
+      &SectionMarkers::default(),
+      false,
+    )
+    .unwrap();
     assert_eq!(preamble, "");
   }
 
+  #[test]
+  fn test_sort_usage_rules_alpha_with_version_tiebreak() {
+    let mut rules = vec![
+      create_test_usage_rules("zeta", "1.0.0", None),
+      create_test_usage_rules("alpha", "1.10.0", None),
+      create_test_usage_rules("alpha", "1.9.0", None),
+    ];
+
+    sort_usage_rules(&mut rules, SortOrder::None);
+    assert_eq!(rules[0].package_name, "zeta");
+
+    sort_usage_rules(&mut rules, SortOrder::Alpha);
+    let order: Vec<_> = rules
+      .iter()
+      .map(|r| format!("{}@{}", r.package_name, r.package_version))
+      .collect();
+    assert_eq!(order, vec!["alpha@1.9.0", "alpha@1.10.0", "zeta@1.0.0"]);
+  }
+
+  #[test]
+  fn test_sort_usage_rules_depth_then_alpha() {
+    let mut rules = vec![
+      create_test_usage_rules("alpha", "1.0.0", None),
+      create_test_usage_rules("zeta", "1.0.0", None),
+      create_test_usage_rules("beta", "1.0.0", None),
+    ];
+    rules[0].depth = 3;
+    rules[2].depth = 2;
+
+    sort_usage_rules(&mut rules, SortOrder::Depth);
+    let order: Vec<_> = rules.iter().map(|r| r.package_name.as_str()).collect();
+    assert_eq!(order, vec!["zeta", "beta", "alpha"]);
+  }
+
+  #[test]
+  fn test_selection_log_attributes_first_exclusion() {
+    let mut log = SelectionLog::new(["a", "b", "c"]);
+
+    log.record_step(["a", "b"], "not listed in --dependencies-file");
+    log.record_step(["a"], "removed by --remove");
+    // Already-excluded packages keep their first reason
+    log.record_step([], "no usage-rules.md");
+
+    assert_eq!(
+      log.render(),
+      "  package  disposition\n  a        no usage-rules.md\n  b        removed by \
+       --remove\n  c        not listed in --dependencies-file"
+    );
+  }
+
+  #[test]
+  fn test_extract_surrounding_content_appends_without_markers() {
+    let temp = TempDir::new().unwrap();
+    let doc = temp.path().join("doc.md");
+    fs::write(&doc, "# Doc\n\nBody\n").unwrap();
+
+    let existing =
+      extract_surrounding_content(&doc, encoding_rs::UTF_8, &SectionMarkers::default(), false)
+        .unwrap();
+
+    assert_eq!(
+      existing,
+      ExistingContent::Surrounding {
+        before: "# Doc\n\nBody\n\n".to_string(),
+        after: "\n".to_string(),
+      }
+    );
+  }
+
+  #[test]
+  fn test_extract_surrounding_content_missing_file() {
+    let temp = TempDir::new().unwrap();
+
+    let result = extract_surrounding_content(
+      &temp.path().join("missing.md"),
+      encoding_rs::UTF_8,
+      &SectionMarkers::default(),
+      false,
+    );
+
+    assert!(result.is_err());
+  }
+
   #[test]
   fn test_format_package_section_inline() {
     let temp = TempDir::new().unwrap();
@@ -312,9 +2185,16 @@ mod tests {
         main_file: Some(main_file),
         sub_files: vec![],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     };
 
-    let formatted = format_package_section(&package, None).unwrap();
+    let formatted = format_package_section(&package, None, &SectionOptions::default()).unwrap();
 
     assert!(formatted.contains("## test-pkg usage"));
     assert!(formatted.contains("Test content"));
@@ -332,9 +2212,17 @@ mod tests {
         main_file: Some(main_file),
         sub_files: vec![],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     };
 
-    let formatted = format_package_section(&package, Some("usage_rules")).unwrap();
+    let formatted =
+      format_package_section(&package, Some("usage_rules"), &SectionOptions::default()).unwrap();
 
     assert!(formatted.contains("## test-pkg usage"));
     assert!(formatted.contains("[test-pkg usage rules]"));
@@ -342,4 +2230,342 @@ mod tests {
     assert!(!formatted.contains("Test content")); // Content not included in
                                                   // linked mode
   }
+
+  #[test]
+  fn test_format_package_section_link_styles() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Test content").unwrap();
+
+    let package = PackageContentInfo {
+      name: "test-pkg".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let mut options = SectionOptions {
+      link_style: LinkStyle::Absolute,
+      ..SectionOptions::default()
+    };
+
+    let formatted = format_package_section(&package, Some("C:\\docs\\rules"), &options).unwrap();
+    assert!(formatted.contains("(C:/docs/rules/test-pkg/test-pkg.md)"));
+
+    options.link_style = LinkStyle::Anchor;
+    let formatted = format_package_section(&package, Some("usage_rules"), &options).unwrap();
+    assert!(formatted.contains("(./usage_rules/index.md#test-pkg-usage)"));
+  }
+
+  #[test]
+  fn test_format_package_section_with_hashes() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Test content").unwrap();
+
+    let package = PackageContentInfo {
+      name: "test-pkg".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file.clone()),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let options = SectionOptions {
+      with_hashes: true,
+      ..SectionOptions::default()
+    };
+
+    let inline = format_package_section(&package, None, &options).unwrap();
+    let linked = format_package_section(&package, Some("usage_rules"), &options).unwrap();
+    let hash = HashAlgorithm::Sha256.tagged_digest(b"Test content");
+    assert!(inline.starts_with(&format!("## test-pkg usage\n<!-- hash: {} -->\n", hash)));
+    assert_eq!(
+      section_hashes(&linked),
+      vec![("test-pkg usage".to_string(), hash)]
+    );
+    assert!(changed_sections(&inline, &linked).is_empty());
+
+    fs::write(&main_file, "Changed").unwrap();
+    let changed = format_package_section(&package, None, &options).unwrap();
+    assert_eq!(
+      changed_sections(&inline, &changed),
+      vec!["test-pkg usage".to_string()]
+    );
+
+    let options = SectionOptions {
+      content: ContentOptions {
+        hash_algorithm: HashAlgorithm::Blake3,
+        ..ContentOptions::default()
+      },
+      ..options
+    };
+    let blake3 = format_package_section(&package, None, &options).unwrap();
+    assert_eq!(
+      section_hashes(&blake3)[0].1,
+      HashAlgorithm::Blake3.tagged_digest(b"Changed")
+    );
+  }
+
+  #[test]
+  fn test_section_template() {
+    let package = PackageContentInfo {
+      name: "serde".to_string(),
+      content: PackageContent {
+        main_file: None,
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let mut options = SectionOptions {
+      template: SectionTemplate::parse("## Usage rules: {name} ({version})").unwrap(),
+      ..SectionOptions::default()
+    };
+    assert_eq!(
+      section_heading(&package, &options),
+      "Usage rules: serde (1.0.0)"
+    );
+
+    options.template = SectionTemplate::parse("### 📦 {name}").unwrap();
+    options.show_versions = true;
+    assert_eq!(section_heading(&package, &options), "📦 serde v1.0.0");
+    assert_eq!(options.template.heading_marker(), "###");
+
+    assert!(SectionTemplate::parse("## {version}").is_err());
+    assert!(SectionTemplate::parse("## {name} {crate}").is_err());
+    assert!(SectionTemplate::parse("{name} usage").is_err());
+    assert!(SectionTemplate::parse("## {name").is_err());
+  }
+
+  #[test]
+  fn test_get_excerpt_stops_at_heading() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "# Title\n\nIntro line\n\n## Details\n\nMore").unwrap();
+
+    let package = PackageContentInfo {
+      name: "test-pkg".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+
+    assert_eq!(
+      package.get_excerpt(10, &ContentOptions::default()).unwrap(),
+      "# Title\n\nIntro line"
+    );
+    assert_eq!(
+      package.get_excerpt(1, &ContentOptions::default()).unwrap(),
+      "# Title"
+    );
+  }
+
+  #[test]
+  fn test_format_package_section_linked_with_excerpt() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(
+      &main_file,
+      "# Test\n\nQuick reference\n\n## Details\n\nFull body",
+    )
+    .unwrap();
+
+    let package = PackageContentInfo {
+      name: "test-pkg".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+
+    let formatted = format_package_section(
+      &package,
+      Some("usage_rules"),
+      &SectionOptions {
+        excerpt_lines: Some(5),
+        ..SectionOptions::default()
+      },
+    )
+    .unwrap();
+
+    assert!(formatted.contains("Quick reference"));
+    assert!(formatted.contains("[test-pkg usage rules](./usage_rules/test-pkg/test-pkg.md)"));
+    assert!(!formatted.contains("Full body"));
+    assert!(formatted.find("Quick reference") < formatted.find("[test-pkg usage rules]"));
+  }
+
+  #[test]
+  fn test_format_package_section_shows_version() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Rules").unwrap();
+
+    let package = PackageContentInfo {
+      name: "serde".to_string(),
+      version: "1.0.210".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let options = SectionOptions {
+      show_versions: true,
+      ..SectionOptions::default()
+    };
+
+    let formatted = format_package_section(&package, None, &options).unwrap();
+
+    assert!(formatted.starts_with("## serde v1.0.210 usage\n"));
+  }
+
+  #[test]
+  fn test_format_package_section_shows_conditions() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Runtime rules").unwrap();
+
+    let package = PackageContentInfo {
+      name: "tokio".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec!["feature `rt` enabled".to_string()],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let options = SectionOptions {
+      show_conditions: true,
+      ..SectionOptions::default()
+    };
+
+    let formatted = format_package_section(&package, None, &options).unwrap();
+    assert_eq!(
+      formatted,
+      "## tokio usage\n> Applies when: feature `rt` enabled\n\nRuntime rules"
+    );
+
+    let formatted = format_package_section(&package, None, &SectionOptions::default()).unwrap();
+    assert!(!formatted.contains("Applies when"));
+  }
+
+  #[test]
+  fn test_format_package_section_with_links() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Rules\n").unwrap();
+
+    let mut package = PackageContentInfo {
+      name: "serde".to_string(),
+      version: "1.0.210".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: Some("https://github.com/serde-rs/serde".to_string()),
+      category: None,
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let options = SectionOptions {
+      with_links: true,
+      ..SectionOptions::default()
+    };
+
+    let formatted = format_package_section(&package, None, &options).unwrap();
+    assert_eq!(
+      formatted,
+      "## serde usage\nRules\n\nSee also: [docs.rs](https://docs.rs/serde/1.0.210), \
+       [repository](https://github.com/serde-rs/serde)"
+    );
+
+    package.repository = None;
+    let formatted = format_package_section(&package, None, &options).unwrap();
+    assert!(formatted.ends_with("See also: [docs.rs](https://docs.rs/serde/1.0.210)"));
+  }
+
+  #[test]
+  fn test_read_generated_block() {
+    let temp = TempDir::new().unwrap();
+    let doc = temp.path().join("AGENTS.md");
+    let markers = SectionMarkers::default();
+    fs::write(
+      &doc,
+      format!("# Notes\n\n{}\n\nbody\n{}\n", markers.start, markers.end),
+    )
+    .unwrap();
+    assert_eq!(
+      read_generated_block(&doc, encoding_rs::UTF_8, &markers).as_deref(),
+      Some("\n\nbody\n")
+    );
+
+    fs::write(&doc, format!("{}\nbody\n", markers.end)).unwrap();
+    assert!(read_generated_block(&doc, encoding_rs::UTF_8, &markers).is_none());
+  }
+
+  #[test]
+  fn test_split_package_sections() {
+    let block = "\n\n# Header\n\n## anyhow usage\nUse context.\n\n```md\n## serde usage\n```\n\n\
+                 ## serde usage\nDerive it.\n\n## Sub heading\nMore.\n";
+    let headings = vec!["anyhow usage".to_string(), "serde usage".to_string()];
+    let sections = split_package_sections(block, "##", &headings).unwrap();
+    assert_eq!(
+      sections["anyhow usage"],
+      "## anyhow usage\nUse context.\n\n```md\n## serde usage\n```"
+    );
+    assert_eq!(
+      sections["serde usage"],
+      "## serde usage\nDerive it.\n\n## Sub heading\nMore."
+    );
+
+    assert!(split_package_sections(block, "##", &["tokio usage".to_string()]).is_none());
+    let repeated = format!("{}## anyhow usage\n", block);
+    assert!(split_package_sections(&repeated, "##", &headings).is_none());
+  }
 }
@@ -0,0 +1,170 @@
+use crate::pipeline::{self, SyncOptions};
+use anyhow::Result;
+use std::{
+  fmt,
+  time::{Duration, Instant},
+};
+
+/// Names of the pipeline phases timed by `run_bench`, in execution order.
+pub const PHASES: [&str; 4] = ["metadata", "scan", "aggregate", "render"];
+
+/// Durations recorded for one pipeline phase across all iterations.
+#[derive(Debug, Clone)]
+pub struct PhaseSamples {
+  pub name: &'static str,
+  pub samples: Vec<Duration>,
+}
+
+impl PhaseSamples {
+  /// Returns the minimum, median and maximum sample, or None if no samples
+  /// were recorded.
+  pub fn summary(&self) -> Option<(Duration, Duration, Duration)> {
+    let mut sorted = self.samples.clone();
+    sorted.sort();
+    Some((*sorted.first()?, sorted[sorted.len() / 2], *sorted.last()?))
+  }
+}
+
+/// Per-phase timings of repeated runs of the scan pipeline.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+  pub iterations: usize,
+  pub phases: Vec<PhaseSamples>,
+}
+
+impl BenchReport {
+  fn new(iterations: usize) -> Self {
+    Self {
+      iterations,
+      phases: PHASES
+        .iter()
+        .map(|&name| PhaseSamples {
+          name,
+          samples: Vec::with_capacity(iterations),
+        })
+        .collect(),
+    }
+  }
+
+  fn record(&mut self, phase: &str, duration: Duration) {
+    if let Some(samples) = self.phases.iter_mut().find(|p| p.name == phase) {
+      samples.samples.push(duration);
+    }
+  }
+}
+
+impl fmt::Display for BenchReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Benchmark results ({} runs):\n", self.iterations)?;
+    writeln!(
+      f,
+      "  {:<10} {:>12} {:>12} {:>12}",
+      "phase", "min", "median", "max"
+    )?;
+    for phase in &self.phases {
+      let Some((min, median, max)) = phase.summary() else {
+        continue;
+      };
+      writeln!(
+        f,
+        "  {:<10} {:>12} {:>12} {:>12}",
+        phase.name,
+        format_duration(min),
+        format_duration(median),
+        format_duration(max)
+      )?;
+    }
+    Ok(())
+  }
+}
+
+fn format_duration(duration: Duration) -> String {
+  format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Runs `f` and records how long it took under `phase`.
+fn timed<T>(report: &mut BenchReport, phase: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+  let start = Instant::now();
+  let result = f()?;
+  report.record(phase, start.elapsed());
+  Ok(result)
+}
+
+/// Runs the steps of a sync `iterations` times over the project selected by
+/// `options.metadata` and records the duration of each step: reading the
+/// dependency graph, scanning, selecting and aggregating packages, and
+/// planning the output. Nothing is written.
+///
+/// Dependencies are expected to have been fetched already, so network time is
+/// not measured.
+///
+/// # Errors
+///
+/// Returns an error if any phase fails.
+pub fn run_bench(iterations: usize, options: &SyncOptions) -> Result<BenchReport> {
+  let mut report = BenchReport::new(iterations);
+
+  for _ in 0..iterations {
+    let project = timed(&mut report, "metadata", || {
+      pipeline::load_dependencies(&options.metadata, options.metadata_cache.as_ref())
+    })?;
+
+    let discovery = timed(&mut report, "scan", || {
+      let scanned = pipeline::scanned_dependencies(&project.dependencies, options)?;
+      pipeline::discover(scanned, &options.scan)
+    })?;
+
+    let selection = timed(&mut report, "aggregate", || {
+      pipeline::select_packages(
+        discovery.usage_rules,
+        &project.dependencies,
+        &discovery.scanned,
+        options,
+      )
+    })?;
+
+    timed(&mut report, "render", || {
+      pipeline::plan_sync(options, selection.packages)
+    })?;
+  }
+
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_phase_summary() {
+    let phase = PhaseSamples {
+      name: "scan",
+      samples: [30, 10, 20].map(Duration::from_millis).to_vec(),
+    };
+
+    assert_eq!(
+      phase.summary(),
+      Some((
+        Duration::from_millis(10),
+        Duration::from_millis(20),
+        Duration::from_millis(30)
+      ))
+    );
+  }
+
+  #[test]
+  fn test_report_lists_every_phase() {
+    let mut report = BenchReport::new(2);
+    for phase in PHASES {
+      report.record(phase, Duration::from_millis(1));
+      report.record(phase, Duration::from_millis(2));
+    }
+
+    let output = report.to_string();
+
+    assert!(output.contains("(2 runs)"));
+    for phase in PHASES {
+      assert!(output.contains(&format!("  {:<10}", phase)));
+    }
+  }
+}
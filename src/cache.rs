@@ -0,0 +1,453 @@
+use crate::{
+  hashing::HashAlgorithm,
+  metadata::{Dependency, MetadataOptions, ProjectMetadata},
+  scanner::UsageRuleSubFile,
+  warnings::{warn, WarningCategory},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A content cache of scanned sub-file lists shared across projects.
+///
+/// Entries are keyed by `package@version` plus a hash of the scanned
+/// sub-file directory and the main usage rules file, so the same crate
+/// resolved from the same registry path in another project is a cache hit.
+/// Each entry records the hash algorithm used for its key.
+#[derive(Debug)]
+pub struct ScanCache {
+  dir: PathBuf,
+  hash_algorithm: HashAlgorithm,
+  hits: AtomicUsize,
+  misses: AtomicUsize,
+}
+
+impl ScanCache {
+  /// Creates a cache that stores its entries in `dir`, keyed with
+  /// `hash_algorithm`.
+  pub fn new(dir: PathBuf, hash_algorithm: HashAlgorithm) -> Self {
+    Self {
+      dir,
+      hash_algorithm,
+      hits: AtomicUsize::new(0),
+      misses: AtomicUsize::new(0),
+    }
+  }
+
+  /// Returns the default cache directory under the OS cache dir.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the OS cache directory cannot be determined.
+  pub fn default_dir() -> Result<PathBuf> {
+    Ok(
+      dirs::cache_dir()
+        .context("Could not determine the OS cache directory; pass --cache-dir <DIR>")?
+        .join("cargo-usage-rules"),
+    )
+  }
+
+  /// Returns the number of lookups served from the cache.
+  pub fn hits(&self) -> usize {
+    self.hits.load(Ordering::Relaxed)
+  }
+
+  /// Returns the number of lookups that required a fresh scan.
+  pub fn misses(&self) -> usize {
+    self.misses.load(Ordering::Relaxed)
+  }
+
+  /// Returns the cached sub-files for `dep` found in `sub_dirs` with one of
+  /// `extensions`, or runs `scan` and caches its result.
+  ///
+  /// Entries whose files no longer exist are treated as misses. Failing to
+  /// write a cache entry only produces a warning.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the main file cannot be read or `scan` fails.
+  pub fn get_or_scan(
+    &self,
+    dep: &Dependency,
+    main_file: &Path,
+    sub_dirs: &[PathBuf],
+    extensions: &[String],
+    scan: impl FnOnce() -> Result<Vec<UsageRuleSubFile>>,
+  ) -> Result<Vec<UsageRuleSubFile>> {
+    let entry_path = self.entry_path(dep, main_file, sub_dirs, extensions)?;
+
+    if let Some(sub_files) = read_entry(&entry_path, self.hash_algorithm) {
+      self.hits.fetch_add(1, Ordering::Relaxed);
+      return Ok(sub_files);
+    }
+
+    self.misses.fetch_add(1, Ordering::Relaxed);
+    let sub_files = scan()?;
+
+    let entry = CacheEntry {
+      hash_algorithm: self.hash_algorithm,
+      sub_files,
+    };
+    if let Err(e) = write_entry(&entry_path, &entry) {
+      warn(
+        WarningCategory::CacheWrite,
+        format_args!("failed to write scan cache entry: {:?}", e),
+      );
+    }
+
+    Ok(entry.sub_files)
+  }
+
+  fn entry_path(
+    &self,
+    dep: &Dependency,
+    main_file: &Path,
+    sub_dirs: &[PathBuf],
+    extensions: &[String],
+  ) -> Result<PathBuf> {
+    let main_content = fs::read(main_file)
+      .with_context(|| format!("Failed to read file {}", main_file.display()))?;
+
+    let mut keyed = Vec::new();
+    for sub_dir in sub_dirs {
+      keyed.extend_from_slice(sub_dir.to_string_lossy().as_bytes());
+      keyed.push(0);
+    }
+    keyed.extend_from_slice(extensions.join(",").as_bytes());
+    keyed.push(0);
+    keyed.extend_from_slice(&main_content);
+
+    let hash = self.hash_algorithm.hex_digest(&keyed);
+    Ok(
+      self
+        .dir
+        .join(format!("{}@{}-{}.json", dep.name, dep.version, &hash[..16])),
+    )
+  }
+}
+
+/// A cached sub-file listing and the algorithm its key was hashed with.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+  hash_algorithm: HashAlgorithm,
+  sub_files: Vec<UsageRuleSubFile>,
+}
+
+fn read_entry(entry_path: &Path, hash_algorithm: HashAlgorithm) -> Option<Vec<UsageRuleSubFile>> {
+  let content = fs::read(entry_path).ok()?;
+  let entry: CacheEntry = serde_json::from_slice(&content).ok()?;
+
+  if entry.hash_algorithm == hash_algorithm && entry.sub_files.iter().all(|f| f.full_path.is_file())
+  {
+    Some(entry.sub_files)
+  } else {
+    None
+  }
+}
+
+fn write_entry(entry_path: &Path, entry: &impl Serialize) -> Result<()> {
+  if let Some(parent) = entry_path.parent() {
+    fs::create_dir_all(parent)
+      .with_context(|| format!("Failed to create cache dir: {}", parent.display()))?;
+  }
+
+  fs::write(entry_path, serde_json::to_vec(entry)?)
+    .with_context(|| format!("Failed to write cache entry: {}", entry_path.display()))
+}
+
+/// A per-project cache of the dependency graph read by `cargo metadata`.
+///
+/// Each project and set of `MetadataOptions` has a single entry, keyed by a
+/// hash of the project's Cargo.lock and root manifest, so it is refreshed
+/// whenever either changes.
+#[derive(Debug)]
+pub struct MetadataCache {
+  dir: PathBuf,
+  hash_algorithm: HashAlgorithm,
+}
+
+impl MetadataCache {
+  /// Creates a cache that stores its entries in `dir/metadata`, keyed with
+  /// `hash_algorithm`.
+  pub fn new(dir: PathBuf, hash_algorithm: HashAlgorithm) -> Self {
+    Self {
+      dir: dir.join("metadata"),
+      hash_algorithm,
+    }
+  }
+
+  /// Returns the cached metadata for the project selected by `options`, or
+  /// runs `load` and caches its result.
+  ///
+  /// Projects without a Cargo.lock are never cached. Entries naming
+  /// dependency directories that no longer exist are treated as misses.
+  /// Failing to write a cache entry only produces a warning.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `load` fails.
+  pub fn get_or_load(
+    &self,
+    options: &MetadataOptions,
+    load: impl FnOnce() -> Result<ProjectMetadata>,
+  ) -> Result<ProjectMetadata> {
+    let Some((entry_path, key)) = self.entry_key(options) else {
+      return load();
+    };
+
+    if let Some(metadata) = read_metadata_entry(&entry_path, &key) {
+      return Ok(metadata);
+    }
+
+    let entry = MetadataEntry {
+      key,
+      metadata: load()?,
+    };
+    if let Err(e) = write_entry(&entry_path, &entry) {
+      warn(
+        WarningCategory::CacheWrite,
+        format_args!("failed to write metadata cache entry: {:?}", e),
+      );
+    }
+
+    Ok(entry.metadata)
+  }
+
+  /// Returns the entry path for the project and the key its entry must
+  /// carry, or None if the project has no Cargo.lock.
+  fn entry_key(&self, options: &MetadataOptions) -> Option<(PathBuf, String)> {
+    let manifest_path = match &options.manifest_path {
+      Some(path) => path.clone(),
+      None => std::env::current_dir().ok()?.join("Cargo.toml"),
+    };
+    let manifest_path = manifest_path.canonicalize().ok()?;
+    let lock_file = manifest_path
+      .ancestors()
+      .skip(1)
+      .map(|dir| dir.join("Cargo.lock"))
+      .find(|path| path.is_file())?;
+
+    let mut project = manifest_path.to_string_lossy().into_owned().into_bytes();
+    project.push(0);
+    project.extend_from_slice(format!("{:?}", options).as_bytes());
+    let project_hash = self.hash_algorithm.hex_digest(&project);
+
+    let mut keyed = fs::read(&lock_file).ok()?;
+    keyed.push(0);
+    keyed.extend_from_slice(&fs::read(&manifest_path).ok()?);
+
+    Some((
+      self.dir.join(format!("{}.json", &project_hash[..16])),
+      self.hash_algorithm.hex_digest(&keyed),
+    ))
+  }
+}
+
+/// A cached dependency graph and the key it was stored under.
+#[derive(Serialize, Deserialize)]
+struct MetadataEntry {
+  key: String,
+  metadata: ProjectMetadata,
+}
+
+fn read_metadata_entry(entry_path: &Path, key: &str) -> Option<ProjectMetadata> {
+  let content = fs::read(entry_path).ok()?;
+  let entry: MetadataEntry = serde_json::from_slice(&content).ok()?;
+
+  if entry.key == key && entry.metadata.dependencies.iter().all(|d| d.path.is_dir()) {
+    Some(entry.metadata)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::scanner::{scan_for_usage_rules, ScanOptions};
+  use tempfile::TempDir;
+
+  fn dependency(name: &str, path: &Path) -> Dependency {
+    Dependency {
+      name: name.to_string(),
+      version: "1.0.0".to_string(),
+      path: path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    }
+  }
+
+  #[test]
+  fn test_cross_project_cache_hit() {
+    let registry = TempDir::new().unwrap();
+    let cache_dir = TempDir::new().unwrap();
+
+    // A dependency shared by both projects, plus one unique to each
+    let shared = registry.path().join("shared-1.0.0");
+    let only_a = registry.path().join("only-a-1.0.0");
+    let only_b = registry.path().join("only-b-1.0.0");
+    for pkg in [&shared, &only_a, &only_b] {
+      fs::create_dir_all(pkg.join("usage_rules")).unwrap();
+      fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+      fs::write(pkg.join("usage_rules/async.md"), "Async").unwrap();
+    }
+
+    let project_a = [dependency("shared", &shared), dependency("only-a", &only_a)];
+    let project_b = [dependency("shared", &shared), dependency("only-b", &only_b)];
+
+    let options_a = ScanOptions {
+      cache: Some(ScanCache::new(
+        cache_dir.path().to_path_buf(),
+        HashAlgorithm::default(),
+      )),
+      ..ScanOptions::default()
+    };
+    scan_for_usage_rules(&project_a, &options_a).unwrap();
+    let cache_a = options_a.cache.unwrap();
+    assert_eq!(cache_a.hits(), 0);
+    assert_eq!(cache_a.misses(), 2);
+
+    let options_b = ScanOptions {
+      cache: Some(ScanCache::new(
+        cache_dir.path().to_path_buf(),
+        HashAlgorithm::default(),
+      )),
+      ..ScanOptions::default()
+    };
+    let results = scan_for_usage_rules(&project_b, &options_b).unwrap();
+    let cache_b = options_b.cache.unwrap();
+    assert_eq!(cache_b.hits(), 1);
+    assert_eq!(cache_b.misses(), 1);
+
+    assert_eq!(results[0].package_name, "shared");
+    assert_eq!(results[0].sub_files.len(), 1);
+    assert_eq!(results[0].sub_files[0].relative_path_name, "async");
+  }
+
+  #[test]
+  fn test_changed_main_file_is_a_miss() {
+    let registry = TempDir::new().unwrap();
+    let cache_dir = TempDir::new().unwrap();
+    let cache = ScanCache::new(cache_dir.path().to_path_buf(), HashAlgorithm::default());
+
+    let pkg = registry.path().join("pkg");
+    fs::create_dir_all(&pkg).unwrap();
+    let main_file = pkg.join("usage-rules.md");
+    fs::write(&main_file, "Version one").unwrap();
+    let dep = dependency("pkg", &pkg);
+
+    cache
+      .get_or_scan(&dep, &main_file, std::slice::from_ref(&pkg), &[], || {
+        Ok(vec![])
+      })
+      .unwrap();
+    fs::write(&main_file, "Version two").unwrap();
+    cache
+      .get_or_scan(&dep, &main_file, std::slice::from_ref(&pkg), &[], || {
+        Ok(vec![])
+      })
+      .unwrap();
+
+    assert_eq!(cache.hits(), 0);
+    assert_eq!(cache.misses(), 2);
+  }
+
+  #[test]
+  fn test_entry_records_hash_algorithm() {
+    let registry = TempDir::new().unwrap();
+    let cache_dir = TempDir::new().unwrap();
+    let pkg = registry.path().join("pkg");
+    fs::create_dir_all(&pkg).unwrap();
+    let main_file = pkg.join("usage-rules.md");
+    fs::write(&main_file, "Main").unwrap();
+    let dep = dependency("pkg", &pkg);
+
+    for algorithm in [HashAlgorithm::Sha512, HashAlgorithm::Blake3] {
+      let cache = ScanCache::new(cache_dir.path().to_path_buf(), algorithm);
+      cache
+        .get_or_scan(&dep, &main_file, std::slice::from_ref(&pkg), &[], || {
+          Ok(vec![])
+        })
+        .unwrap();
+      cache
+        .get_or_scan(&dep, &main_file, std::slice::from_ref(&pkg), &[], || {
+          Ok(vec![])
+        })
+        .unwrap();
+      assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+      let entry_path = cache
+        .entry_path(&dep, &main_file, std::slice::from_ref(&pkg), &[])
+        .unwrap();
+      let entry: CacheEntry = serde_json::from_slice(&fs::read(entry_path).unwrap()).unwrap();
+      assert_eq!(entry.hash_algorithm, algorithm);
+    }
+  }
+
+  #[test]
+  fn test_metadata_cache_refreshes_when_lock_file_changes() {
+    let project = TempDir::new().unwrap();
+    let cache_dir = TempDir::new().unwrap();
+    let manifest_path = project.path().join("Cargo.toml");
+    fs::write(&manifest_path, "[package]\nname = \"app\"\n").unwrap();
+    fs::write(project.path().join("Cargo.lock"), "version = 3\n").unwrap();
+
+    let cache = MetadataCache::new(cache_dir.path().to_path_buf(), HashAlgorithm::default());
+    let options = MetadataOptions {
+      manifest_path: Some(manifest_path),
+      ..MetadataOptions::default()
+    };
+    let loads = AtomicUsize::new(0);
+    let load = || {
+      loads.fetch_add(1, Ordering::Relaxed);
+      Ok(ProjectMetadata {
+        dependencies: vec![dependency("dep", project.path())],
+        settings: Default::default(),
+      })
+    };
+
+    cache.get_or_load(&options, load).unwrap();
+    let cached = cache.get_or_load(&options, load).unwrap();
+    assert_eq!(loads.load(Ordering::Relaxed), 1);
+    assert_eq!(cached.dependencies[0].name, "dep");
+
+    fs::write(project.path().join("Cargo.lock"), "version = 4\n").unwrap();
+    cache.get_or_load(&options, load).unwrap();
+    assert_eq!(loads.load(Ordering::Relaxed), 2);
+  }
+
+  #[test]
+  fn test_metadata_cache_skips_projects_without_lock_file() {
+    let project = TempDir::new().unwrap();
+    let cache_dir = TempDir::new().unwrap();
+    let manifest_path = project.path().join("Cargo.toml");
+    fs::write(&manifest_path, "[package]\nname = \"app\"\n").unwrap();
+
+    let cache = MetadataCache::new(cache_dir.path().to_path_buf(), HashAlgorithm::default());
+    let options = MetadataOptions {
+      manifest_path: Some(manifest_path),
+      ..MetadataOptions::default()
+    };
+    let loads = AtomicUsize::new(0);
+    let load = || {
+      loads.fetch_add(1, Ordering::Relaxed);
+      Ok(ProjectMetadata {
+        dependencies: vec![],
+        settings: Default::default(),
+      })
+    };
+
+    cache.get_or_load(&options, load).unwrap();
+    cache.get_or_load(&options, load).unwrap();
+
+    assert_eq!(loads.load(Ordering::Relaxed), 2);
+    assert!(!cache_dir.path().join("metadata").exists());
+  }
+}
@@ -0,0 +1,207 @@
+use crate::{
+  aggregator::{strip_generated_section, SectionMarkers},
+  encoding::encode_output,
+  warnings::{warn, WarningCategory},
+  writer::INDEX_FILENAME,
+};
+use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+/// Settings for removing the artifacts of a sync.
+#[derive(Debug, Clone)]
+pub struct CleanOptions {
+  /// The files the generated section was written to.
+  pub outputs: Vec<PathBuf>,
+  /// The linked mode folder, deleted if it holds the generated index or an
+  /// output had a generated section.
+  pub link_folder: PathBuf,
+  /// Markers delimiting the generated section.
+  pub markers: SectionMarkers,
+  /// Encoding the output file was written with.
+  pub encoding: &'static Encoding,
+  /// Whether to delete the whole output file instead of only the generated
+  /// section.
+  pub remove_file: bool,
+}
+
+/// The outcome of a clean.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanReport {
+  /// Files and folders deleted.
+  pub removed: Vec<PathBuf>,
+  /// Files whose generated section was removed, keeping the rest.
+  pub updated: Vec<PathBuf>,
+}
+
+/// Removes what a sync generated: the marked section of each output file and
+/// the linked folder.
+///
+/// Content around the section is kept, and an output file left empty is
+/// deleted. A file without a generated section is left untouched. With
+/// `remove_file`, the whole output file is deleted instead.
+///
+/// The link folder is only deleted if it looks generated: it holds the
+/// `index.md` a linked sync writes, or an output had a generated section.
+/// Otherwise it is kept with a warning, so a mistyped `--link-folder` never
+/// removes a user's directory.
+///
+/// # Errors
+///
+/// Returns an error if the output file has malformed markers, or a file or
+/// folder cannot be written or deleted.
+pub fn clean(options: &CleanOptions) -> Result<CleanReport> {
+  let mut report = CleanReport::default();
+  let mut found_section = false;
+
+  for output in &options.outputs {
+    if options.remove_file {
+      if output.is_file() {
+        remove_file(output)?;
+        report.removed.push(output.clone());
+      }
+    } else if let Some(rest) = strip_generated_section(output, options.encoding, &options.markers)?
+    {
+      found_section = true;
+      if rest.is_empty() {
+        remove_file(output)?;
+        report.removed.push(output.clone());
+      } else {
+        fs::write(output, encode_output(&rest, options.encoding)?)
+          .with_context(|| format!("Failed to write file: {}", output.display()))?;
+        report.updated.push(output.clone());
+      }
+    }
+  }
+
+  if options.link_folder.is_dir()
+    && !found_section
+    && !options.link_folder.join(INDEX_FILENAME).is_file()
+  {
+    warn(
+      WarningCategory::UnknownLinkFolder,
+      format_args!(
+        "kept {}: it holds no generated {}",
+        options.link_folder.display(),
+        INDEX_FILENAME
+      ),
+    );
+  } else if options.link_folder.is_dir() {
+    fs::remove_dir_all(&options.link_folder).with_context(|| {
+      format!(
+        "Failed to remove directory: {}",
+        options.link_folder.display()
+      )
+    })?;
+    report.removed.push(options.link_folder.clone());
+  }
+
+  Ok(report)
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+  fs::remove_file(path).with_context(|| format!("Failed to remove file: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use encoding_rs::UTF_8;
+  use tempfile::TempDir;
+
+  fn options(temp: &TempDir, remove_file: bool) -> CleanOptions {
+    CleanOptions {
+      outputs: vec![temp.path().join("Agents.md")],
+      link_folder: temp.path().join("usage_rules"),
+      markers: SectionMarkers::default(),
+      encoding: UTF_8,
+      remove_file,
+    }
+  }
+
+  #[test]
+  fn test_clean_keeps_preamble_and_removes_folder() {
+    let temp = TempDir::new().unwrap();
+    let options = options(&temp, false);
+    fs::write(
+      &options.outputs[0],
+      "# My rules\n\n<!-- cargo-usage-rules-start -->\nGenerated\n<!-- cargo-usage-rules-end -->\n",
+    )
+    .unwrap();
+    fs::create_dir_all(options.link_folder.join("serde")).unwrap();
+
+    let report = clean(&options).unwrap();
+
+    assert_eq!(
+      fs::read_to_string(&options.outputs[0]).unwrap(),
+      "# My rules\n"
+    );
+    assert!(!options.link_folder.exists());
+    assert_eq!(report.updated, vec![options.outputs[0].clone()]);
+    assert_eq!(report.removed, vec![options.link_folder.clone()]);
+  }
+
+  #[test]
+  fn test_clean_deletes_output_left_empty_or_with_remove_file() {
+    let temp = TempDir::new().unwrap();
+    let generated = "<!-- cargo-usage-rules-start -->\nGenerated\n<!-- cargo-usage-rules-end -->\n";
+
+    let options = options(&temp, false);
+    fs::write(&options.outputs[0], generated).unwrap();
+    clean(&options).unwrap();
+    assert!(!options.outputs[0].exists());
+
+    let options = CleanOptions {
+      remove_file: true,
+      ..options
+    };
+    fs::write(&options.outputs[0], format!("# My rules\n\n{}", generated)).unwrap();
+    clean(&options).unwrap();
+    assert!(!options.outputs[0].exists());
+  }
+
+  #[test]
+  fn test_clean_removes_folder_with_generated_index() {
+    let temp = TempDir::new().unwrap();
+    let options = options(&temp, true);
+    fs::create_dir_all(&options.link_folder).unwrap();
+    fs::write(options.link_folder.join(INDEX_FILENAME), "# Usage rules\n").unwrap();
+
+    let report = clean(&options).unwrap();
+
+    assert!(!options.link_folder.exists());
+    assert_eq!(report.removed, vec![options.link_folder.clone()]);
+  }
+
+  #[test]
+  fn test_clean_keeps_folder_without_generated_index() {
+    let temp = TempDir::new().unwrap();
+    let options = options(&temp, false);
+    fs::write(&options.outputs[0], "# Hand-written\n").unwrap();
+    fs::create_dir_all(&options.link_folder).unwrap();
+    fs::write(options.link_folder.join("notes.md"), "Mine").unwrap();
+
+    let report = clean(&options).unwrap();
+
+    assert_eq!(report, CleanReport::default());
+    assert!(options.link_folder.join("notes.md").is_file());
+  }
+
+  #[test]
+  fn test_clean_leaves_file_without_section_untouched() {
+    let temp = TempDir::new().unwrap();
+    let options = options(&temp, false);
+    fs::write(&options.outputs[0], "# Hand-written\n").unwrap();
+
+    let report = clean(&options).unwrap();
+
+    assert_eq!(report, CleanReport::default());
+    assert_eq!(
+      fs::read_to_string(&options.outputs[0]).unwrap(),
+      "# Hand-written\n"
+    );
+  }
+}
@@ -1,5 +1,33 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use crate::{
+  aggregator::{
+    parse_section_name, ContentOptions, LinkStyle, SectionMarkers, SectionOptions, SectionTemplate,
+    SelectionOptions, SortOrder, VersionFilter, DEFAULT_MARKER_PREFIX, DEFAULT_SECTION_TEMPLATE,
+  },
+  cache::{MetadataCache, ScanCache},
+  clean::CleanOptions,
+  config::SyncConfig,
+  encoding::parse_encoding,
+  hashing::HashAlgorithm,
+  logger::Verbosity,
+  markdown::{AnchorFlavor, LineEnding},
+  metadata::{ManifestSettings, MetadataOptions, SourceFilter},
+  patterns::compile_globs,
+  pipeline::{OutputRelativeTo, SyncOptions},
+  scanner::{
+    ListFormat, ScanOptions, DEFAULT_EXTENSIONS, DEFAULT_RULES_DIR, DEFAULT_RULES_FILENAME,
+  },
+  style::ColorChoice,
+  warnings::WarningCategory,
+  writer::{parse_mode, LayoutBlock, OutputFormat, Preset, RenderOptions, DEFAULT_LAYOUT},
+};
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use encoding_rs::Encoding;
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+};
 
 #[derive(Parser)]
 #[command(name = "cargo-usage-rules")]
@@ -22,28 +50,463 @@ pub enum Commands {
 
 #[derive(Parser)]
 pub struct UsageRulesArgs {
+  /// Match package names case-insensitively in all selection flags
+  #[arg(long, global = true)]
+  pub ignore_case: bool,
+
+  /// Share scan results across projects in a cache directory (defaults to the
+  /// OS cache dir when given without a value)
+  #[arg(long, global = true, value_name = "DIR", num_args = 0..=1)]
+  pub cache_dir: Option<Option<PathBuf>>,
+
+  /// Always run `cargo metadata` instead of reusing the dependency graph
+  /// cached for an unchanged Cargo.lock
+  #[arg(long, global = true)]
+  pub no_cache: bool,
+
+  /// Prefer translated rules (`usage-rules.<LANG>.md`, `usage_rules/<LANG>/`)
+  /// where packages ship them
+  #[arg(long, global = true, value_name = "LANG")]
+  pub locale: Option<String>,
+
+  /// Digest used for content hashes, such as scan cache keys and the section
+  /// hashes of --with-hashes
+  #[arg(long, global = true, value_enum, default_value_t = HashAlgorithm::Sha256)]
+  pub hash_algorithm: HashAlgorithm,
+
+  /// Suppress warnings of a category (repeatable)
+  #[arg(long, global = true, value_enum, value_name = "CATEGORY")]
+  pub allow: Vec<WarningCategory>,
+
+  /// When to color output; `auto` colors when stdout is a terminal and
+  /// NO_COLOR is not set
+  #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+  pub color: ColorChoice,
+
+  /// Print only the final result line; repeat (-qq) to print nothing on
+  /// success
+  #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+  pub quiet: u8,
+
+  /// Print per-dependency scan details, and the full path of each sub-file
+  /// in list-subfiles
+  #[arg(short, long, global = true)]
+  pub verbose: bool,
+
   #[command(subcommand)]
   pub subcommand: SubCommands,
 }
 
+impl UsageRulesArgs {
+  /// Returns the verbosity selected by `--quiet` and `--verbose`.
+  pub fn verbosity(&self) -> Verbosity {
+    Verbosity::from_flags(self.quiet, self.verbose)
+  }
+
+  /// Builds the scan settings selected by the global and subcommand flags.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `--cache-dir` is given without a value and the OS
+  /// cache directory cannot be determined.
+  pub fn scan_options(&self) -> Result<ScanOptions> {
+    let rules = self.rules_args().cloned().unwrap_or_default();
+    Ok(ScanOptions {
+      cache: match &self.cache_dir {
+        Some(Some(dir)) => Some(ScanCache::new(dir.clone(), self.hash_algorithm)),
+        Some(None) => Some(ScanCache::new(
+          ScanCache::default_dir()?,
+          self.hash_algorithm,
+        )),
+        None => None,
+      },
+      locale: self.locale.clone(),
+      rules_filename: rules.rules_filename,
+      rules_dir: rules.rules_dir,
+      extensions: rules.extensions,
+      subfile_patterns: match rules.subfile_pattern.as_slice() {
+        [] => None,
+        patterns => Some(compile_globs(patterns).context("Invalid --subfile-pattern")?),
+      },
+      respect_gitignore: rules.respect_gitignore,
+      deep_scan: rules.deep_scan,
+    })
+  }
+
+  /// Builds the dependency graph cache, stored in `--cache-dir` or the OS
+  /// cache dir. None with `--no-cache`, or if no cache directory is
+  /// available.
+  pub fn metadata_cache(&self) -> Option<MetadataCache> {
+    if self.no_cache {
+      return None;
+    }
+    let dir = match &self.cache_dir {
+      Some(Some(dir)) => dir.clone(),
+      _ => ScanCache::default_dir().ok()?,
+    };
+    Some(MetadataCache::new(dir, self.hash_algorithm))
+  }
+
+  /// Returns the rules location options of the selected subcommand, if it
+  /// has any.
+  pub fn rules_args(&self) -> Option<&RulesArgs> {
+    match &self.subcommand {
+      SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) => Some(&sync_args.rules),
+      SubCommands::List(list_args) => Some(&list_args.rules),
+      SubCommands::ListSubfiles(list_args) => Some(&list_args.rules),
+      SubCommands::Bench(_)
+      | SubCommands::Init(_)
+      | SubCommands::Clean(_)
+      | SubCommands::Completions(_) => None,
+    }
+  }
+
+  /// Returns true if the selected subcommand fails when no dependency ships
+  /// usage rules.
+  pub fn fail_on_empty(&self) -> bool {
+    match &self.subcommand {
+      SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) => sync_args.fail_on_empty,
+      SubCommands::List(list_args) => list_args.fail_on_empty,
+      SubCommands::ListSubfiles(_)
+      | SubCommands::Bench(_)
+      | SubCommands::Init(_)
+      | SubCommands::Clean(_)
+      | SubCommands::Completions(_) => false,
+    }
+  }
+
+  /// Returns the cargo options of the selected subcommand, if it has any.
+  pub fn cargo_args(&self) -> Option<&CargoArgs> {
+    match &self.subcommand {
+      SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) => Some(&sync_args.cargo),
+      SubCommands::List(list_args) => Some(&list_args.cargo),
+      SubCommands::ListSubfiles(list_args) => Some(&list_args.cargo),
+      SubCommands::Bench(bench_args) => Some(&bench_args.cargo),
+      SubCommands::Init(_) | SubCommands::Clean(_) | SubCommands::Completions(_) => None,
+    }
+  }
+
+  /// Dependency graph settings selected by the subcommand's flags.
+  pub fn metadata_options(&self) -> MetadataOptions {
+    match &self.subcommand {
+      SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) => sync_args.metadata_options(),
+      _ => match self.cargo_args() {
+        Some(cargo) => cargo.metadata_options(),
+        None => MetadataOptions::default(),
+      },
+    }
+  }
+}
+
+// Parsed once per run, so boxing the large sync arguments buys nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum SubCommands {
   /// Sync usage rules from dependencies into output file
+  ///
+  /// Flags not given on the command line are read from
+  /// `[package.metadata.usage-rules]` in Cargo.toml, then from
+  /// `usage-rules.toml`; a key set in both takes the Cargo.toml value.
   Sync(SyncArgs),
 
+  /// Check that the output file is up to date without writing it
+  Check(SyncArgs),
+
   /// List all dependencies that have usage-rules.md files
-  List,
+  List(ListArgs),
+
+  /// List the sub-files of a single package's usage rules
+  ListSubfiles(ListSubfilesArgs),
+
+  /// Time the metadata, scan and aggregation phases over repeated runs
+  Bench(BenchArgs),
+
+  /// Create a template usage-rules.md in the current crate
+  Init(InitArgs),
+
+  /// Remove the generated section from the output file and the linked folder
+  Clean(CleanArgs),
+
+  /// Print a shell completion script for `cargo-usage-rules`
+  #[command(hide = true)]
+  Completions(CompletionsArgs),
+}
+
+#[derive(Parser)]
+pub struct CompletionsArgs {
+  /// Shell to generate the completion script for
+  #[arg(value_enum)]
+  pub shell: Shell,
+}
+
+#[derive(Parser)]
+pub struct BenchArgs {
+  /// Number of times to run the pipeline
+  #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+  pub iterations: u32,
+
+  #[command(flatten)]
+  pub cargo: CargoArgs,
+}
+
+#[derive(Parser)]
+pub struct InitArgs {
+  /// Also create a usage_rules/ directory with an example sub-file
+  #[arg(long)]
+  pub with_subdir: bool,
+
+  /// Overwrite existing rules files
+  #[arg(long)]
+  pub force: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CleanArgs {
+  /// Remove the whole output file instead of only the generated section
+  #[arg(long, conflicts_with = "merge_into")]
+  pub all: bool,
+
+  /// Output file path; repeat or separate with commas to write the same
+  /// content to several files, each keeping its own preamble [default: the
+  /// --preset's file, Agents.md]
+  #[arg(long, short = 'o', value_delimiter = ',')]
+  pub output: Vec<PathBuf>,
+
+  /// Agent tool whose default output file to clean
+  #[arg(long, value_enum, default_value_t = Preset::Agents)]
+  pub preset: Preset,
+
+  /// Document whose managed block to remove, leaving the rest of the file
+  #[arg(long, value_name = "FILE", conflicts_with = "output")]
+  pub merge_into: Option<PathBuf>,
+
+  /// Folder of linked mode files to delete, kept unless it holds the
+  /// generated index or an output had a generated section
+  #[arg(long, default_value = "usage_rules")]
+  pub link_folder: PathBuf,
+
+  /// Prefix of the markers delimiting the generated section, as in
+  /// `<!-- PREFIX-start -->`
+  #[arg(long, value_name = "STR", default_value = DEFAULT_MARKER_PREFIX)]
+  pub marker_prefix: String,
+
+  /// Name of the generated block, giving markers such as
+  /// `<!-- PREFIX:NAME-start -->`, so several blocks can share one file
+  #[arg(long, value_name = "NAME", value_parser = parse_section_name)]
+  pub section_name: Option<String>,
+
+  /// Encoding of the output file (e.g. utf-8, utf-16le, windows-1252)
+  #[arg(long, value_name = "LABEL", default_value = "utf-8", value_parser = parse_encoding)]
+  pub output_encoding: &'static Encoding,
+}
+
+impl CleanArgs {
+  /// Builds the settings for `clean::clean`.
+  pub fn clean_options(&self) -> CleanOptions {
+    CleanOptions {
+      outputs: match (&self.merge_into, self.output.is_empty()) {
+        (Some(merge_into), _) => vec![merge_into.clone()],
+        (None, true) => vec![self.preset.default_output()],
+        (None, false) => self.output.clone(),
+      },
+      link_folder: self.link_folder.clone(),
+      markers: SectionMarkers::named(&self.marker_prefix, self.section_name.as_deref()),
+      encoding: self.output_encoding,
+      remove_file: self.all,
+    }
+  }
+
+  /// Fills in the output settings from `usage-rules.toml` for every flag that
+  /// was not given explicitly on the command line, so `clean` removes what
+  /// `sync` wrote.
+  ///
+  /// `matches` must be the argument matches of the `clean` subcommand.
+  pub fn apply_config(&mut self, config: SyncConfig, matches: &ArgMatches) {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    if let (Some(output), false) = (config.output, from_cli("output")) {
+      self.output = output;
+    }
+    if let (Some(preset), false) = (config.preset, from_cli("preset")) {
+      self.preset = preset;
+    }
+    if let (Some(merge_into), false) = (config.merge_into, from_cli("merge_into")) {
+      self.merge_into = Some(merge_into);
+    }
+    if let (Some(link_folder), false) = (config.link_folder, from_cli("link_folder")) {
+      self.link_folder = link_folder;
+    }
+    if let (Some(prefix), false) = (config.marker_prefix, from_cli("marker_prefix")) {
+      self.marker_prefix = prefix;
+    }
+    if let (Some(name), false) = (config.section_name, from_cli("section_name")) {
+      self.section_name = Some(name);
+    }
+    if let (Some(encoding), false) = (config.output_encoding, from_cli("output_encoding")) {
+      self.output_encoding = encoding;
+    }
+  }
+}
+
+/// Options forwarded to the underlying cargo invocations.
+#[derive(Parser, Debug, Clone, Default)]
+pub struct CargoArgs {
+  /// Skip `cargo fetch` and run cargo without network access
+  #[arg(long)]
+  pub offline: bool,
+
+  /// Path to the Cargo.toml of the project to inspect
+  #[arg(long, value_name = "PATH")]
+  pub manifest_path: Option<PathBuf>,
+
+  /// Comma-separated list of features to activate when resolving
+  /// dependencies, so optional dependencies follow the active features
+  #[arg(long, short = 'F', value_delimiter = ',')]
+  pub features: Vec<String>,
+
+  /// Activate all available features when resolving dependencies
+  #[arg(long)]
+  pub all_features: bool,
+
+  /// Do not activate the `default` feature when resolving dependencies
+  #[arg(long)]
+  pub no_default_features: bool,
+}
+
+impl CargoArgs {
+  /// Dependency graph settings selected by the cargo flags alone.
+  pub fn metadata_options(&self) -> MetadataOptions {
+    MetadataOptions {
+      offline: self.offline,
+      manifest_path: self.manifest_path.clone(),
+      features: self.features.clone(),
+      all_features: self.all_features,
+      no_default_features: self.no_default_features,
+      ..MetadataOptions::default()
+    }
+  }
+}
+
+/// Where rules are looked for inside each package.
+#[derive(Parser, Debug, Clone)]
+pub struct RulesArgs {
+  /// Name of the main rules file in each package
+  #[arg(long, value_name = "NAME", default_value = DEFAULT_RULES_FILENAME)]
+  pub rules_filename: String,
+
+  /// Name of the sub-file directory in each package
+  #[arg(long, value_name = "NAME", default_value = DEFAULT_RULES_DIR)]
+  pub rules_dir: String,
+
+  /// File extensions accepted for sub-files (comma-separated, e.g.
+  /// `md,markdown,mdx`)
+  #[arg(
+    long,
+    value_name = "EXT",
+    value_delimiter = ',',
+    default_values_t = DEFAULT_EXTENSIONS.map(String::from)
+  )]
+  pub extensions: Vec<String>,
+
+  /// Only keep sub-files whose name relative to the sub-file directory
+  /// (without extension) matches this glob, e.g. `async*` or `patterns/**`
+  /// (repeatable)
+  #[arg(long, value_name = "GLOB")]
+  pub subfile_pattern: Vec<String>,
+
+  /// Skip sub-files excluded by `.gitignore` or `.ignore` files in the
+  /// sub-file directory or its parents
+  #[arg(long)]
+  pub respect_gitignore: bool,
+
+  /// For packages without a main rules file at their root, use the first one
+  /// found at most DEPTH directories down (e.g. in `docs/`), with its
+  /// sub-file directory next to it
+  #[arg(long, value_name = "DEPTH", num_args = 0..=1, default_missing_value = "3")]
+  pub deep_scan: Option<usize>,
+}
+
+impl Default for RulesArgs {
+  fn default() -> Self {
+    Self {
+      rules_filename: DEFAULT_RULES_FILENAME.to_string(),
+      rules_dir: DEFAULT_RULES_DIR.to_string(),
+      extensions: DEFAULT_EXTENSIONS.map(String::from).to_vec(),
+      subfile_pattern: Vec::new(),
+      respect_gitignore: false,
+      deep_scan: None,
+    }
+  }
+}
+
+#[derive(Parser)]
+pub struct ListArgs {
+  #[command(flatten)]
+  pub cargo: CargoArgs,
+
+  #[command(flatten)]
+  pub rules: RulesArgs,
+
+  /// Output format; `json` prints only the package list on stdout
+  #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+  pub format: ListFormat,
+
+  /// Also list dependencies that have no usage-rules.md
+  #[arg(long)]
+  pub show_missing: bool,
+
+  /// Exit with an error when no dependency ships usage rules
+  #[arg(long)]
+  pub fail_on_empty: bool,
+}
+
+#[derive(Parser)]
+pub struct ListSubfilesArgs {
+  /// Name of the package to inspect
+  pub package: String,
+
+  #[command(flatten)]
+  pub cargo: CargoArgs,
+
+  #[command(flatten)]
+  pub rules: RulesArgs,
 }
 
 #[derive(Parser)]
 pub struct SyncArgs {
+  #[command(flatten)]
+  pub cargo: CargoArgs,
+
+  #[command(flatten)]
+  pub rules: RulesArgs,
+
   /// Include all dependencies (default if no specific packages given)
   #[arg(long)]
   pub all: bool,
 
-  /// Output file path
-  #[arg(long, short = 'o', default_value = "Agents.md")]
-  pub output: PathBuf,
+  /// Exit with an error when no dependency ships usage rules
+  #[arg(long)]
+  pub fail_on_empty: bool,
+
+  /// Output file path; repeat or separate with commas to write the same
+  /// content to several files, each keeping its own preamble [default: the
+  /// --preset's file, Agents.md]
+  #[arg(long, short = 'o', value_delimiter = ',')]
+  pub output: Vec<PathBuf>,
+
+  /// Output format; `json` writes every package's rules as one JSON document
+  /// (name, version, content and sub-files) instead of markdown
+  #[arg(long, value_enum, default_value_t = OutputFormat::Markdown, conflicts_with = "merge_into")]
+  pub format: OutputFormat,
+
+  /// Agent tool whose file name and header wording to use
+  #[arg(long, value_enum, default_value_t = Preset::Agents)]
+  pub preset: Preset,
+
+  /// Replace only the managed block of an existing document (appending it if
+  /// the document has none), leaving the rest of the file untouched
+  #[arg(long, value_name = "FILE", conflicts_with = "output")]
+  pub merge_into: Option<PathBuf>,
 
   /// Use linked mode (create separate files in folder)
   #[arg(long, action = clap::ArgAction::Set, default_value_t = true, value_parser = clap::value_parser!(bool))]
@@ -53,11 +516,679 @@ pub struct SyncArgs {
   #[arg(long, default_value = "usage_rules")]
   pub link_folder: PathBuf,
 
+  /// What relative --output, --merge-into and --link-folder paths are
+  /// resolved against: the directory of --manifest-path (`manifest`, the
+  /// current directory without one) or the current directory (`cwd`)
+  #[arg(long, value_enum, default_value_t = OutputRelativeTo::Manifest)]
+  pub output_relative_to: OutputRelativeTo,
+
+  /// How linked mode links to each package: `relative` paths, `absolute`
+  /// paths, or `anchor` links into the linked folder's index.md, which then
+  /// holds every package's rules
+  #[arg(long, value_enum, default_value_t = LinkStyle::Relative)]
+  pub link_style: LinkStyle,
+
+  /// Markdown renderer whose heading anchors the table of contents and
+  /// `--link-style anchor` links target
+  #[arg(long, value_enum, default_value_t = AnchorFlavor::Github)]
+  pub anchor_flavor: AnchorFlavor,
+
+  /// In linked mode, include the first N lines of each package's main file
+  /// (up to its first heading) inline before the link
+  #[arg(long, value_name = "N")]
+  pub linked_with_excerpt: Option<usize>,
+
   /// Comma-separated list of package names to inline (even in folder mode)
   #[arg(long, value_delimiter = ',')]
   pub inline: Vec<String>,
 
-  /// Comma-separated list of package names to exclude
+  /// Truncate each inlined package's rules after N bytes, ending them with a
+  /// link to the full rules; in linked mode, such packages are linked even if
+  /// listed in --inline
+  #[arg(long, value_name = "N")]
+  pub max_bytes_per_package: Option<usize>,
+
+  /// Comma-separated list of package names or globs (e.g. `tokio-*`) to
+  /// restrict the output to (applied before --remove)
+  #[arg(long, value_delimiter = ',')]
+  pub include: Vec<String>,
+
+  /// Comma-separated list of package names or globs (e.g. `tokio-*`) to
+  /// exclude
   #[arg(long, value_delimiter = ',')]
   pub remove: Vec<String>,
+
+  /// Keep packages named NAME (a name or glob; `*` for every package) only if
+  /// their version satisfies the semver requirement REQ, e.g. `*@>=1.0`
+  /// (repeatable)
+  #[arg(long, value_name = "NAME@REQ", value_parser = VersionFilter::parse)]
+  pub version_filter: Vec<VersionFilter>,
+
+  /// Also scan transitive dependencies (each package name appears once)
+  #[arg(long)]
+  pub transitive: bool,
+
+  /// Also include the root package's dev-dependencies (excluded by default)
+  #[arg(long)]
+  pub include_dev: bool,
+
+  /// Also include the root package's build-dependencies
+  #[arg(long)]
+  pub include_build: bool,
+
+  /// Collect the dependencies of every workspace member
+  #[arg(long)]
+  pub workspace: bool,
+
+  /// Only collect dependencies from these sources: `registry` packages,
+  /// `local` path dependencies, or `all` (git dependencies only match `all`)
+  #[arg(long, value_enum, default_value_t = SourceFilter::All)]
+  pub source: SourceFilter,
+
+  /// Order of the packages in the output
+  #[arg(long, value_enum, default_value_t = SortOrder::Alpha)]
+  pub sort: SortOrder,
+
+  /// Print how each discovered package was selected or excluded, and why, to
+  /// stderr
+  #[arg(long)]
+  pub explain_selection: bool,
+
+  /// File listing the exact packages (`name` or `name@version`) to scan; a
+  /// pinned package missing from the dependency graph is an error
+  #[arg(long)]
+  pub dependencies_file: Option<PathBuf>,
+
+  /// Drop rule files containing relative links that escape the package folder
+  #[arg(long)]
+  pub no_escape_links: bool,
+
+  /// Warn when usage rules refer to crates that are not dependencies
+  /// (heuristic)
+  #[arg(long)]
+  pub lint_crate_refs: bool,
+
+  /// Skip packages and sub-files whose rule files cannot be read, with a
+  /// warning, instead of failing the run
+  #[arg(long)]
+  pub keep_going: bool,
+
+  /// Strip trailing spaces and collapse 3+ blank lines to 2 in inlined content
+  /// (code fences are left untouched)
+  #[arg(long)]
+  pub canonicalize: bool,
+
+  /// Remove a leading YAML front-matter block (`---` ... `---`) from each rule
+  /// file before inlining it
+  #[arg(long)]
+  pub strip_frontmatter: bool,
+
+  /// Shift headings in inlined content so it nests below its package section
+  /// (code blocks are left untouched)
+  #[arg(long)]
+  pub normalize_headings: bool,
+
+  /// Render sub-files in nested directories under nested headings (e.g.
+  /// `patterns/builder` as `## patterns` then `### builder`) instead of one
+  /// heading holding the whole path
+  #[arg(long)]
+  pub nested_headings: bool,
+
+  /// Line endings of the output and linked copies; `keep` leaves each rule
+  /// file's line endings as they are
+  #[arg(long, value_enum, default_value_t = LineEnding::Lf)]
+  pub line_ending: LineEnding,
+
+  /// Replace a package's rules with a reference to an earlier package that
+  /// ships identical rules
+  #[arg(long)]
+  pub dedupe: bool,
+
+  /// Keep sections of packages whose usage rules are blank instead of
+  /// leaving them out with a warning
+  #[arg(long)]
+  pub keep_empty: bool,
+
+  /// Note under each package heading when its rules apply (for optional or
+  /// target-specific dependencies, e.g. "feature `rt` enabled")
+  #[arg(long)]
+  pub show_conditions: bool,
+
+  /// Include each package's version in its section header
+  #[arg(long)]
+  pub show_versions: bool,
+
+  /// Format of each package's section heading, with `{name}` and `{version}`
+  /// placeholders, e.g. "### 📦 {name}"
+  #[arg(long, value_name = "STR", default_value = DEFAULT_SECTION_TEMPLATE, value_parser = SectionTemplate::parse)]
+  pub section_template: SectionTemplate,
+
+  /// End each package section with links to its docs.rs page and repository
+  #[arg(long)]
+  pub with_links: bool,
+
+  /// Note a hash of each package's rules below its section heading, computed
+  /// with --hash-algorithm, so `check` can report which packages changed
+  #[arg(long)]
+  pub with_hashes: bool,
+
+  /// Add a table of contents linking to each package section
+  #[arg(long)]
+  pub toc: bool,
+
+  /// Group package sections under a `# {Category}` heading per category
+  /// declared in their usage-rules.md front matter (e.g. `category: async`)
+  #[arg(long)]
+  pub group_by_category: bool,
+
+  /// Enable every strict correctness check (each can still be overridden
+  /// individually, e.g. `--strict-markers=false`)
+  #[arg(long)]
+  pub strict: bool,
+
+  /// Fail on a lone or out-of-order section marker in the existing output file
+  /// instead of keeping it as preamble [default: value of --strict]
+  #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+  pub strict_markers: Option<bool>,
+
+  /// Fail on a rule file that is not valid UTF-8 instead of replacing its
+  /// invalid bytes with a warning [default: value of --strict]
+  #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+  pub strict_encoding: Option<bool>,
+
+  /// Overwrite an output file whose section markers are malformed instead of
+  /// keeping its content as preamble
+  #[arg(long, conflicts_with = "merge_into")]
+  pub force: bool,
+
+  /// Only render again the sections of packages whose version in Cargo.lock
+  /// changed since this git revision, keeping the others as they are
+  #[arg(long, value_name = "REF")]
+  pub since: Option<String>,
+
+  /// Prefix of the markers delimiting the generated section, as in
+  /// `<!-- PREFIX-start -->`
+  #[arg(long, value_name = "STR", default_value = DEFAULT_MARKER_PREFIX)]
+  pub marker_prefix: String,
+
+  /// Name of the generated block, giving markers such as
+  /// `<!-- PREFIX:NAME-start -->`, so several blocks can share one file
+  #[arg(long, value_name = "NAME", value_parser = parse_section_name)]
+  pub section_name: Option<String>,
+
+  /// Encoding of the output file (e.g. utf-8, utf-16le, windows-1252)
+  #[arg(long, value_name = "LABEL", default_value = "utf-8", value_parser = parse_encoding)]
+  pub output_encoding: &'static Encoding,
+
+  /// Octal permission mode for generated files, e.g. 664 (Unix only)
+  #[arg(long, value_name = "OCTAL", value_parser = parse_mode)]
+  pub output_mode: Option<u32>,
+
+  /// Print the generated output, and the files linked mode would create,
+  /// without writing anything
+  #[arg(long)]
+  pub dry_run: bool,
+
+  /// Print the main output file to stdout instead of writing it; progress
+  /// messages go to stderr
+  #[arg(long)]
+  pub stdout: bool,
+
+  /// Print a summary of the sync (dependencies scanned, packages with usage
+  /// rules, sub-files, removals and bytes written), as text or json; always
+  /// printed as text with --verbose
+  #[arg(
+    long,
+    value_enum,
+    value_name = "FORMAT",
+    num_args = 0..=1,
+    default_missing_value = "text"
+  )]
+  pub stats: Option<ListFormat>,
+
+  /// After syncing, keep regenerating the output whenever Cargo.toml,
+  /// Cargo.lock or a dependency's usage rules change
+  #[arg(long, conflicts_with_all = ["dry_run", "stdout"])]
+  pub watch: bool,
+
+  /// After scanning, pick the packages to include, and in linked mode the
+  /// packages to inline, from a checklist; requires a terminal
+  #[arg(long)]
+  pub interactive: bool,
+
+  /// Print a unified diff between the existing output and the regenerated
+  /// output; with --dry-run, the diff replaces the full preview
+  #[arg(long)]
+  pub diff: bool,
+
+  /// Markdown file to use as the general Rust guidance instead of the bundled
+  /// rules
+  #[arg(long, value_name = "PATH")]
+  pub base_file: Option<PathBuf>,
+
+  /// Comma-separated order of the blocks within the generated section
+  #[arg(
+    long,
+    value_enum,
+    value_delimiter = ',',
+    default_values_t = DEFAULT_LAYOUT
+  )]
+  pub layout: Vec<LayoutBlock>,
+}
+
+impl SyncArgs {
+  /// Dependency graph settings selected by the sync flags.
+  pub fn metadata_options(&self) -> MetadataOptions {
+    MetadataOptions {
+      transitive: self.transitive,
+      include_dev: self.include_dev,
+      include_build: self.include_build,
+      workspace: self.workspace,
+      source: self.source,
+      ..self.cargo.metadata_options()
+    }
+  }
+
+  /// The output file: the first `--output` if given, else the preset's
+  /// default.
+  pub fn output_path(&self) -> PathBuf {
+    self
+      .output
+      .first()
+      .cloned()
+      .unwrap_or_else(|| self.preset.default_output())
+  }
+
+  /// Resolves a relative output path as `--output-relative-to` selects.
+  fn resolve_output(&self, path: &Path) -> PathBuf {
+    self
+      .output_relative_to
+      .resolve(path, self.cargo.manifest_path.as_deref())
+  }
+
+  /// Whether malformed section markers are an error.
+  pub fn strict_markers(&self) -> bool {
+    self.strict_markers.unwrap_or(self.strict)
+  }
+
+  /// Whether rule files that are not valid UTF-8 are an error.
+  pub fn strict_encoding(&self) -> bool {
+    self.strict_encoding.unwrap_or(self.strict)
+  }
+
+  /// Builds the library sync settings selected by the sync flags.
+  ///
+  /// # Arguments
+  ///
+  /// * `scan` - Scan settings from [`UsageRulesArgs::scan_options`]
+  /// * `ignore_case` - Whether package names are matched case-insensitively
+  /// * `hash_algorithm` - Digest used to compare and hash package content
+  pub fn sync_options(
+    &self,
+    scan: ScanOptions,
+    ignore_case: bool,
+    hash_algorithm: HashAlgorithm,
+  ) -> SyncOptions {
+    SyncOptions {
+      metadata: self.metadata_options(),
+      // Only used by `sync`; the binary reads the graph before building these
+      metadata_cache: None,
+      scan,
+      dependencies_file: self.dependencies_file.clone(),
+      selection: SelectionOptions {
+        include: self.include.clone(),
+        remove: self.remove.clone(),
+        inline: self.inline.clone(),
+        ignore_case,
+        version_filters: self.version_filter.clone(),
+      },
+      sort: self.sort,
+      keep_going: self.keep_going,
+      lint_crate_refs: self.lint_crate_refs,
+      no_escape_links: self.no_escape_links,
+      dedupe: self.dedupe,
+      keep_empty: self.keep_empty,
+      all: self.all,
+      output: self.resolve_output(&self.output_path()),
+      additional_outputs: self
+        .output
+        .iter()
+        .skip(1)
+        .map(|path| self.resolve_output(path))
+        .collect(),
+      merge_into: self
+        .merge_into
+        .as_deref()
+        .map(|path| self.resolve_output(path)),
+      linked: self.linked,
+      link_folder: self.resolve_output(&self.link_folder),
+      format: self.format,
+      strict_markers: self.strict_markers(),
+      force: self.force,
+      since: self.since.clone(),
+      render: RenderOptions {
+        layout: self.layout.clone(),
+        encoding: self.output_encoding,
+        section: SectionOptions {
+          excerpt_lines: self.linked_with_excerpt,
+          show_conditions: self.show_conditions,
+          show_versions: self.show_versions,
+          with_links: self.with_links,
+          link_style: self.link_style,
+          anchor_flavor: self.anchor_flavor,
+          with_hashes: self.with_hashes,
+          template: self.section_template.clone(),
+          content: ContentOptions {
+            canonicalize: self.canonicalize,
+            strip_frontmatter: self.strip_frontmatter,
+            normalize_headings: self.normalize_headings,
+            line_ending: self.line_ending,
+            max_bytes: self.max_bytes_per_package,
+            nested_headings: self.nested_headings,
+            strict_encoding: self.strict_encoding(),
+            hash_algorithm,
+          },
+        },
+        mode: self.output_mode,
+        toc: self.toc,
+        group_by_category: self.group_by_category,
+        preset: self.preset,
+        markers: SectionMarkers::named(&self.marker_prefix, self.section_name.as_deref()),
+        base_file: self.base_file.clone(),
+        kept_sections: HashMap::new(),
+      },
+    }
+  }
+
+  /// Fills in settings from `usage-rules.toml` for every flag that was not
+  /// given explicitly on the command line.
+  ///
+  /// `matches` must be the argument matches of the `sync` subcommand.
+  pub fn apply_config(&mut self, config: SyncConfig, matches: &ArgMatches) {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    macro_rules! apply {
+      ($($target:ident).+ = $field:ident) => {
+        if let (Some(value), false) = (config.$field, from_cli(stringify!($field))) {
+          self.$($target).+ = value;
+        }
+      };
+      ($($target:ident).+ = Some($field:ident)) => {
+        if let (Some(value), false) = (config.$field, from_cli(stringify!($field))) {
+          self.$($target).+ = Some(value);
+        }
+      };
+    }
+
+    apply!(cargo.offline = offline);
+    apply!(cargo.features = features);
+    apply!(cargo.all_features = all_features);
+    apply!(cargo.no_default_features = no_default_features);
+    apply!(rules.rules_filename = rules_filename);
+    apply!(rules.rules_dir = rules_dir);
+    apply!(rules.extensions = extensions);
+    apply!(rules.subfile_pattern = subfile_pattern);
+    apply!(rules.respect_gitignore = respect_gitignore);
+    apply!(rules.deep_scan = Some(deep_scan));
+    apply!(all = all);
+    apply!(fail_on_empty = fail_on_empty);
+    apply!(output = output);
+    apply!(format = format);
+    apply!(preset = preset);
+    apply!(merge_into = Some(merge_into));
+    apply!(linked = linked);
+    apply!(link_folder = link_folder);
+    apply!(output_relative_to = output_relative_to);
+    apply!(link_style = link_style);
+    apply!(anchor_flavor = anchor_flavor);
+    apply!(linked_with_excerpt = Some(linked_with_excerpt));
+    apply!(inline = inline);
+    apply!(max_bytes_per_package = Some(max_bytes_per_package));
+    apply!(include = include);
+    apply!(remove = remove);
+    apply!(version_filter = version_filter);
+    apply!(transitive = transitive);
+    apply!(include_dev = include_dev);
+    apply!(include_build = include_build);
+    apply!(workspace = workspace);
+    apply!(source = source);
+    apply!(sort = sort);
+    apply!(explain_selection = explain_selection);
+    apply!(dependencies_file = Some(dependencies_file));
+    apply!(no_escape_links = no_escape_links);
+    apply!(lint_crate_refs = lint_crate_refs);
+    apply!(keep_going = keep_going);
+    apply!(canonicalize = canonicalize);
+    apply!(strip_frontmatter = strip_frontmatter);
+    apply!(normalize_headings = normalize_headings);
+    apply!(nested_headings = nested_headings);
+    apply!(line_ending = line_ending);
+    apply!(dedupe = dedupe);
+    apply!(keep_empty = keep_empty);
+    apply!(since = Some(since));
+    apply!(show_conditions = show_conditions);
+    apply!(show_versions = show_versions);
+    apply!(section_template = section_template);
+    apply!(with_links = with_links);
+    apply!(with_hashes = with_hashes);
+    apply!(toc = toc);
+    apply!(group_by_category = group_by_category);
+    apply!(strict = strict);
+    apply!(strict_markers = Some(strict_markers));
+    apply!(strict_encoding = Some(strict_encoding));
+    apply!(force = force);
+    apply!(marker_prefix = marker_prefix);
+    apply!(section_name = Some(section_name));
+    apply!(output_encoding = output_encoding);
+    apply!(output_mode = Some(output_mode));
+    apply!(dry_run = dry_run);
+    apply!(stdout = stdout);
+    apply!(stats = Some(stats));
+    apply!(watch = watch);
+    apply!(interactive = interactive);
+    apply!(diff = diff);
+    apply!(base_file = Some(base_file));
+    apply!(layout = layout);
+  }
+
+  /// Fills in settings from `[package.metadata.usage-rules]` for every flag
+  /// that was not given explicitly on the command line.
+  ///
+  /// Called after [`SyncArgs::apply_config`], so these settings replace the
+  /// ones from `usage-rules.toml`.
+  ///
+  /// `matches` must be the argument matches of the `sync` subcommand.
+  pub fn apply_manifest_settings(&mut self, settings: ManifestSettings, matches: &ArgMatches) {
+    let ManifestSettings {
+      remove,
+      inline,
+      linked,
+      output,
+    } = settings;
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    if let (Some(remove), false) = (remove, from_cli("remove")) {
+      self.remove = remove;
+    }
+    if let (Some(inline), false) = (inline, from_cli("inline")) {
+      self.inline = inline;
+    }
+    if let (Some(linked), false) = (linked, from_cli("linked")) {
+      self.linked = linked;
+    }
+    if let (Some(output), false) = (output, from_cli("output")) {
+      self.output = vec![output];
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap::{CommandFactory, FromArgMatches};
+
+  fn parse_sync(args: &[&str]) -> (SyncArgs, ArgMatches) {
+    let matches = Cli::command()
+      .try_get_matches_from(["cargo", "usage-rules", "sync"].iter().chain(args))
+      .unwrap();
+    let sync_matches = matches
+      .subcommand_matches("usage-rules")
+      .and_then(|m| m.subcommand_matches("sync"))
+      .unwrap()
+      .clone();
+    let sync_args = SyncArgs::from_arg_matches(&sync_matches).unwrap();
+    (sync_args, sync_matches)
+  }
+
+  fn settings() -> ManifestSettings {
+    ManifestSettings {
+      remove: Some(vec!["old-crate".to_string()]),
+      inline: Some(vec!["serde".to_string()]),
+      linked: Some(false),
+      output: Some(PathBuf::from("CLAUDE.md")),
+    }
+  }
+
+  #[test]
+  fn test_strict_enables_sub_checks_unless_overridden() {
+    assert!(!parse_sync(&[]).0.strict_markers());
+    assert!(parse_sync(&["--strict"]).0.strict_markers());
+    assert!(parse_sync(&["--strict-markers"]).0.strict_markers());
+    assert!(parse_sync(&["--strict"]).0.strict_encoding());
+    assert!(!parse_sync(&["--strict", "--strict-encoding=false"])
+      .0
+      .strict_encoding());
+    assert!(!parse_sync(&["--strict", "--strict-markers=false"])
+      .0
+      .strict_markers());
+  }
+
+  #[test]
+  fn test_manifest_settings_replace_defaults() {
+    let (mut sync_args, matches) = parse_sync(&[]);
+
+    sync_args.apply_manifest_settings(settings(), &matches);
+
+    assert_eq!(sync_args.remove, vec!["old-crate"]);
+    assert_eq!(sync_args.inline, vec!["serde"]);
+    assert!(!sync_args.linked);
+    assert_eq!(sync_args.output_path(), PathBuf::from("CLAUDE.md"));
+  }
+
+  #[test]
+  fn test_cli_flags_override_manifest_settings() {
+    let (mut sync_args, matches) = parse_sync(&["--remove", "tokio", "-o", "AI.md"]);
+
+    sync_args.apply_manifest_settings(settings(), &matches);
+
+    assert_eq!(sync_args.remove, vec!["tokio"]);
+    assert_eq!(sync_args.output_path(), PathBuf::from("AI.md"));
+    assert!(!sync_args.linked);
+  }
+
+  #[test]
+  fn test_cli_flags_override_config_file() {
+    let config = SyncConfig {
+      output: Some(vec![PathBuf::from("CLAUDE.md")]),
+      link_folder: Some(PathBuf::from("rules")),
+      offline: Some(true),
+      sort: Some(SortOrder::None),
+      strict_markers: Some(true),
+      ..SyncConfig::default()
+    };
+    let (mut sync_args, matches) = parse_sync(&["-o", "AI.md"]);
+
+    sync_args.apply_config(config, &matches);
+
+    assert_eq!(sync_args.output_path(), PathBuf::from("AI.md"));
+    assert_eq!(sync_args.link_folder, PathBuf::from("rules"));
+    assert!(sync_args.cargo.offline);
+    assert_eq!(sync_args.sort, SortOrder::None);
+    assert!(sync_args.strict_markers());
+  }
+
+  #[test]
+  fn test_manifest_settings_override_config_file() {
+    let config = SyncConfig {
+      remove: Some(vec!["from-config".to_string()]),
+      linked: Some(true),
+      ..SyncConfig::default()
+    };
+    let (mut sync_args, matches) = parse_sync(&["--linked=true"]);
+
+    sync_args.apply_config(config, &matches);
+    sync_args.apply_manifest_settings(settings(), &matches);
+
+    assert_eq!(sync_args.remove, vec!["old-crate"]);
+    assert_eq!(sync_args.output_path(), PathBuf::from("CLAUDE.md"));
+    // The command line still wins over both
+    assert!(sync_args.linked);
+  }
+
+  #[test]
+  fn test_preset_picks_default_output() {
+    assert_eq!(parse_sync(&[]).0.output_path(), PathBuf::from("Agents.md"));
+    assert_eq!(
+      parse_sync(&["--preset", "claude"]).0.output_path(),
+      PathBuf::from("CLAUDE.md")
+    );
+    assert_eq!(
+      parse_sync(&["--preset", "cursor", "-o", "rules.md"])
+        .0
+        .output_path(),
+      PathBuf::from("rules.md")
+    );
+  }
+
+  #[test]
+  fn test_repeated_output_adds_outputs() {
+    let sync_args = parse_sync(&["-o", "Agents.md,CLAUDE.md", "-o", ".cursorrules"]).0;
+    let options = sync_args.sync_options(ScanOptions::default(), false, HashAlgorithm::default());
+
+    assert_eq!(options.output, PathBuf::from("Agents.md"));
+    assert_eq!(
+      options.additional_outputs,
+      vec![PathBuf::from("CLAUDE.md"), PathBuf::from(".cursorrules")]
+    );
+  }
+
+  #[test]
+  fn test_list_subfiles_accepts_cargo_flags() {
+    let matches = Cli::command()
+      .try_get_matches_from([
+        "cargo",
+        "usage-rules",
+        "list-subfiles",
+        "serde",
+        "--manifest-path",
+        "app/Cargo.toml",
+        "--offline",
+      ])
+      .unwrap();
+    let Commands::UsageRules(args) = Cli::from_arg_matches(&matches).unwrap().command;
+
+    let options = args.metadata_options();
+    assert_eq!(options.manifest_path, Some(PathBuf::from("app/Cargo.toml")));
+    assert!(options.offline);
+  }
+
+  #[test]
+  fn test_bench_accepts_cargo_flags() {
+    let matches = Cli::command()
+      .try_get_matches_from([
+        "cargo",
+        "usage-rules",
+        "bench",
+        "--manifest-path",
+        "app/Cargo.toml",
+        "--features",
+        "json",
+      ])
+      .unwrap();
+    let Commands::UsageRules(args) = Cli::from_arg_matches(&matches).unwrap().command;
+
+    let options = args.metadata_options();
+    assert_eq!(options.manifest_path, Some(PathBuf::from("app/Cargo.toml")));
+    assert_eq!(options.features, vec!["json".to_string()]);
+  }
 }
@@ -0,0 +1,270 @@
+use crate::{
+  aggregator::{parse_section_name, LinkStyle, SectionTemplate, SortOrder, VersionFilter},
+  encoding::parse_encoding,
+  markdown::{AnchorFlavor, LineEnding},
+  metadata::SourceFilter,
+  pipeline::OutputRelativeTo,
+  scanner::ListFormat,
+  writer::{parse_mode, LayoutBlock, OutputFormat, Preset},
+};
+use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use serde::{Deserialize, Deserializer};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+/// Name of the project config file.
+pub const CONFIG_FILENAME: &str = "usage-rules.toml";
+
+/// Defaults for the `sync` flags read from `usage-rules.toml`.
+///
+/// Keys are the long flag names (e.g. `link-folder`). Every field mirrors the
+/// `SyncArgs` field of the same name; unset fields keep their clap default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SyncConfig {
+  pub offline: Option<bool>,
+  pub features: Option<Vec<String>>,
+  pub all_features: Option<bool>,
+  pub no_default_features: Option<bool>,
+  pub rules_filename: Option<String>,
+  pub rules_dir: Option<String>,
+  pub extensions: Option<Vec<String>>,
+  pub subfile_pattern: Option<Vec<String>>,
+  pub respect_gitignore: Option<bool>,
+  pub deep_scan: Option<usize>,
+  pub all: Option<bool>,
+  pub fail_on_empty: Option<bool>,
+  #[serde(default, deserialize_with = "deserialize_paths")]
+  pub output: Option<Vec<PathBuf>>,
+  pub format: Option<OutputFormat>,
+  pub preset: Option<Preset>,
+  pub merge_into: Option<PathBuf>,
+  pub linked: Option<bool>,
+  pub link_folder: Option<PathBuf>,
+  pub output_relative_to: Option<OutputRelativeTo>,
+  pub link_style: Option<LinkStyle>,
+  pub anchor_flavor: Option<AnchorFlavor>,
+  pub linked_with_excerpt: Option<usize>,
+  pub inline: Option<Vec<String>>,
+  pub max_bytes_per_package: Option<usize>,
+  pub include: Option<Vec<String>>,
+  pub remove: Option<Vec<String>>,
+  #[serde(default, deserialize_with = "deserialize_version_filters")]
+  pub version_filter: Option<Vec<VersionFilter>>,
+  pub transitive: Option<bool>,
+  pub include_dev: Option<bool>,
+  pub include_build: Option<bool>,
+  pub workspace: Option<bool>,
+  pub source: Option<SourceFilter>,
+  pub sort: Option<SortOrder>,
+  pub explain_selection: Option<bool>,
+  pub dependencies_file: Option<PathBuf>,
+  pub no_escape_links: Option<bool>,
+  pub lint_crate_refs: Option<bool>,
+  pub keep_going: Option<bool>,
+  pub canonicalize: Option<bool>,
+  pub strip_frontmatter: Option<bool>,
+  pub normalize_headings: Option<bool>,
+  pub nested_headings: Option<bool>,
+  pub line_ending: Option<LineEnding>,
+  pub dedupe: Option<bool>,
+  pub keep_empty: Option<bool>,
+  pub since: Option<String>,
+  pub show_conditions: Option<bool>,
+  pub show_versions: Option<bool>,
+  #[serde(default, deserialize_with = "deserialize_section_template")]
+  pub section_template: Option<SectionTemplate>,
+  pub with_links: Option<bool>,
+  pub with_hashes: Option<bool>,
+  pub toc: Option<bool>,
+  pub group_by_category: Option<bool>,
+  pub strict: Option<bool>,
+  pub strict_markers: Option<bool>,
+  pub strict_encoding: Option<bool>,
+  pub force: Option<bool>,
+  pub marker_prefix: Option<String>,
+  #[serde(default, deserialize_with = "deserialize_section_name")]
+  pub section_name: Option<String>,
+  #[serde(default, deserialize_with = "deserialize_encoding")]
+  pub output_encoding: Option<&'static Encoding>,
+  #[serde(default, deserialize_with = "deserialize_mode")]
+  pub output_mode: Option<u32>,
+  pub dry_run: Option<bool>,
+  pub stdout: Option<bool>,
+  pub stats: Option<ListFormat>,
+  pub watch: Option<bool>,
+  pub interactive: Option<bool>,
+  pub diff: Option<bool>,
+  pub base_file: Option<PathBuf>,
+  pub layout: Option<Vec<LayoutBlock>>,
+}
+
+/// Accepts a single path or a list of paths.
+fn deserialize_paths<'de, D>(deserializer: D) -> Result<Option<Vec<PathBuf>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Paths {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+  }
+
+  Ok(Some(match Paths::deserialize(deserializer)? {
+    Paths::One(path) => vec![path],
+    Paths::Many(paths) => paths,
+  }))
+}
+
+fn deserialize_encoding<'de, D>(deserializer: D) -> Result<Option<&'static Encoding>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let label = String::deserialize(deserializer)?;
+  parse_encoding(&label)
+    .map(Some)
+    .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let mode = String::deserialize(deserializer)?;
+  parse_mode(&mode)
+    .map(Some)
+    .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_section_template<'de, D>(
+  deserializer: D,
+) -> Result<Option<SectionTemplate>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let template = String::deserialize(deserializer)?;
+  SectionTemplate::parse(&template)
+    .map(Some)
+    .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_section_name<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let name = String::deserialize(deserializer)?;
+  parse_section_name(&name)
+    .map(Some)
+    .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_version_filters<'de, D>(
+  deserializer: D,
+) -> Result<Option<Vec<VersionFilter>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let filters = Vec::<String>::deserialize(deserializer)?;
+  filters
+    .iter()
+    .map(|filter| VersionFilter::parse(filter))
+    .collect::<Result<_, _>>()
+    .map(Some)
+    .map_err(serde::de::Error::custom)
+}
+
+/// Finds the nearest `usage-rules.toml` in `start` or one of its ancestors.
+pub fn find_config(start: &Path) -> Option<PathBuf> {
+  start
+    .ancestors()
+    .map(|dir| dir.join(CONFIG_FILENAME))
+    .find(|path| path.is_file())
+}
+
+/// Reads a `usage-rules.toml` file.
+///
+/// Relative paths in the file are resolved against the directory containing
+/// it.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, is not valid TOML, or contains
+/// unknown keys or invalid values.
+pub fn load_config(path: &Path) -> Result<SyncConfig> {
+  let content =
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+  let mut config: SyncConfig =
+    toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+  let base = path.parent().unwrap_or(Path::new(""));
+  let outputs = config.output.iter_mut().flatten();
+  let paths = [
+    &mut config.merge_into,
+    &mut config.link_folder,
+    &mut config.dependencies_file,
+    &mut config.base_file,
+  ];
+  for path in outputs.chain(paths.into_iter().flatten()) {
+    if path.is_relative() {
+      *path = base.join(&*path);
+    }
+  }
+
+  Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_find_config_searches_ancestors() {
+    let temp = TempDir::new().unwrap();
+    let nested = temp.path().join("crates/app");
+    fs::create_dir_all(&nested).unwrap();
+    fs::write(temp.path().join(CONFIG_FILENAME), "").unwrap();
+
+    assert_eq!(
+      find_config(&nested),
+      Some(temp.path().join(CONFIG_FILENAME))
+    );
+  }
+
+  #[test]
+  fn test_load_config_resolves_relative_paths() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join(CONFIG_FILENAME);
+    fs::write(
+      &path,
+      "output = \"CLAUDE.md\"\nremove = [\"old\"]\nlinked = false\nsort = \"none\"\n\
+       output-mode = \"640\"\nlayout = [\"packages\", \"header\"]\n",
+    )
+    .unwrap();
+
+    let config = load_config(&path).unwrap();
+
+    assert_eq!(config.output, Some(vec![temp.path().join("CLAUDE.md")]));
+    assert_eq!(config.remove, Some(vec!["old".to_string()]));
+    assert_eq!(config.linked, Some(false));
+    assert_eq!(config.sort, Some(SortOrder::None));
+    assert_eq!(config.output_mode, Some(0o640));
+    assert_eq!(
+      config.layout,
+      Some(vec![LayoutBlock::Packages, LayoutBlock::Header])
+    );
+  }
+
+  #[test]
+  fn test_load_config_rejects_unknown_keys() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join(CONFIG_FILENAME);
+    fs::write(&path, "outptu = \"typo.md\"\n").unwrap();
+
+    assert!(load_config(&path).is_err());
+  }
+}
@@ -0,0 +1,86 @@
+use anyhow::Result;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+
+/// Parses an encoding label such as `utf-8` or `utf-16le`.
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+  Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+/// Encodes `content` into the bytes of the target `encoding`.
+///
+/// # Errors
+///
+/// Returns an error naming the first character that cannot be represented in
+/// the target encoding.
+pub fn encode_output(content: &str, encoding: &'static Encoding) -> Result<Vec<u8>> {
+  // encoding_rs only encodes to UTF-16 via its decoder-side API, so handle the
+  // UTF-16 variants directly.
+  if encoding == UTF_16LE {
+    return Ok(content.encode_utf16().flat_map(u16::to_le_bytes).collect());
+  }
+  if encoding == UTF_16BE {
+    return Ok(content.encode_utf16().flat_map(u16::to_be_bytes).collect());
+  }
+
+  let (bytes, _, had_errors) = encoding.encode(content);
+  if had_errors {
+    let unmappable = content
+      .chars()
+      .find(|c| encoding.encode(&c.to_string()).2)
+      .unwrap_or(char::REPLACEMENT_CHARACTER);
+    anyhow::bail!(
+      "Character '{}' (U+{:04X}) cannot be encoded as {}",
+      unmappable,
+      unmappable as u32,
+      encoding.name()
+    );
+  }
+
+  Ok(bytes.into_owned())
+}
+
+/// Decodes bytes previously written with `encode_output`.
+pub fn decode_output(bytes: &[u8], encoding: &'static Encoding) -> String {
+  let (content, _, _) = encoding.decode(bytes);
+  content.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use encoding_rs::{SHIFT_JIS, UTF_8, WINDOWS_1252};
+
+  #[test]
+  fn test_parse_encoding_labels() {
+    assert_eq!(parse_encoding("utf-8").unwrap(), UTF_8);
+    assert_eq!(parse_encoding("UTF-16LE").unwrap(), UTF_16LE);
+    assert!(parse_encoding("not-an-encoding").is_err());
+  }
+
+  #[test]
+  fn test_utf16le_round_trip() {
+    let content = "## serde usage\n\nRésumé ✓";
+
+    let bytes = encode_output(content, UTF_16LE).unwrap();
+
+    assert_eq!(&bytes[..4], &[b'#', 0, b'#', 0]);
+    assert_eq!(decode_output(&bytes, UTF_16LE), content);
+  }
+
+  #[test]
+  fn test_legacy_encoding_round_trip() {
+    let content = "日本語のルール";
+
+    let bytes = encode_output(content, SHIFT_JIS).unwrap();
+
+    assert_eq!(decode_output(&bytes, SHIFT_JIS), content);
+  }
+
+  #[test]
+  fn test_unmappable_character_errors() {
+    let err = encode_output("Check ✓ done", WINDOWS_1252).unwrap_err();
+
+    assert!(err.to_string().contains("U+2713"));
+    assert!(err.to_string().contains("windows-1252"));
+  }
+}
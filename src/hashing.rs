@@ -0,0 +1,94 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+/// Digest used for content hashes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+  #[default]
+  Sha256,
+  Sha512,
+  Blake3,
+}
+
+impl HashAlgorithm {
+  /// Returns the lowercase hex-encoded digest of `data`.
+  pub fn hex_digest(self, data: &[u8]) -> String {
+    match self {
+      HashAlgorithm::Sha256 => sha256_hex(data),
+      HashAlgorithm::Sha512 => to_hex(&Sha512::digest(data)),
+      HashAlgorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+    }
+  }
+
+  /// Returns the algorithm's name as given to `--hash-algorithm`.
+  pub fn name(self) -> &'static str {
+    match self {
+      HashAlgorithm::Sha256 => "sha256",
+      HashAlgorithm::Sha512 => "sha512",
+      HashAlgorithm::Blake3 => "blake3",
+    }
+  }
+
+  /// Returns the hex digest of `data` prefixed with the algorithm's name, as
+  /// in `sha256:ba7816bf...`.
+  pub fn tagged_digest(self, data: &[u8]) -> String {
+    format!("{}:{}", self.name(), self.hex_digest(data))
+  }
+}
+
+/// Returns the lowercase hex-encoded SHA-256 digest of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+  to_hex(&Sha256::digest(data))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sha256_hex_known_value() {
+    assert_eq!(
+      sha256_hex(b"abc"),
+      "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+  }
+
+  #[test]
+  fn test_hex_digest_known_values() {
+    assert_eq!(HashAlgorithm::Sha256.hex_digest(b"abc"), sha256_hex(b"abc"));
+    assert!(HashAlgorithm::Sha512
+      .hex_digest(b"abc")
+      .starts_with("ddaf35a193617aba"));
+    assert_eq!(
+      HashAlgorithm::Blake3.hex_digest(b"abc"),
+      "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    );
+  }
+
+  #[test]
+  fn test_tagged_digest_names_algorithm() {
+    for algorithm in HashAlgorithm::value_variants() {
+      let tagged = algorithm.tagged_digest(b"abc");
+      let value = algorithm.to_possible_value().unwrap();
+      assert_eq!(
+        tagged,
+        format!("{}:{}", value.get_name(), algorithm.hex_digest(b"abc"))
+      );
+    }
+  }
+
+  #[test]
+  fn test_hex_digest_is_consistent_per_algorithm() {
+    for algorithm in HashAlgorithm::value_variants() {
+      let first = algorithm.hex_digest(b"usage rules");
+      assert_eq!(first, algorithm.hex_digest(b"usage rules"));
+      assert_ne!(first, algorithm.hex_digest(b"usage rules!"));
+    }
+  }
+}
@@ -0,0 +1,172 @@
+use crate::scanner::{DEFAULT_RULES_DIR, DEFAULT_RULES_FILENAME};
+use anyhow::{Context, Result};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+const MAIN_TEMPLATE: &str = "\
+# {name}
+
+<!--
+  Usage rules for AI coding agents. cargo-usage-rules collects this file from
+  every dependency of a project into a single Agents.md.
+
+  Keep it short and specific to this crate: what agents get wrong, not a copy
+  of the API docs. Replace the placeholders below and delete this comment.
+-->
+
+## Quick Start
+
+<!-- The minimal setup and the one or two calls most users need. -->
+
+## Common Patterns
+
+<!-- Idiomatic usage, with short examples. -->
+
+## Common Mistakes
+
+<!-- Pitfalls, deprecated APIs and misuses to avoid. -->
+";
+
+const SUB_FILE_TEMPLATE: &str = "\
+# Example Topic
+
+<!--
+  Each markdown file in usage_rules/ becomes its own section, named after the
+  file (e.g. usage_rules/async.md becomes \"async\"). Use sub-files for topics
+  only some users need. Rename or delete this example.
+-->
+";
+
+/// Finds the root of the crate containing `start`: the nearest directory with
+/// a Cargo.toml.
+///
+/// # Errors
+///
+/// Returns an error if neither `start` nor any ancestor has a Cargo.toml.
+pub fn find_crate_root(start: &Path) -> Result<PathBuf> {
+  start
+    .ancestors()
+    .find(|dir| dir.join("Cargo.toml").is_file())
+    .map(Path::to_path_buf)
+    .with_context(|| format!("No Cargo.toml found in {} or its parents", start.display()))
+}
+
+/// Returns the package name from the crate's Cargo.toml, falling back to the
+/// directory name.
+fn crate_name(crate_root: &Path) -> String {
+  fs::read_to_string(crate_root.join("Cargo.toml"))
+    .ok()
+    .and_then(|manifest| manifest.parse::<toml::Table>().ok())
+    .and_then(|manifest| {
+      manifest
+        .get("package")?
+        .get("name")?
+        .as_str()
+        .map(str::to_string)
+    })
+    .or_else(|| {
+      crate_root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+    })
+    .unwrap_or_else(|| "My Crate".to_string())
+}
+
+/// Creates a template usage-rules.md in `crate_root`, plus an example
+/// sub-file in `usage_rules/` if `with_subdir` is set.
+///
+/// # Returns
+///
+/// The paths of the created files.
+///
+/// # Errors
+///
+/// Returns an error if a rules file already exists and `force` is not set, or
+/// if a file cannot be written.
+pub fn init_rules(crate_root: &Path, with_subdir: bool, force: bool) -> Result<Vec<PathBuf>> {
+  let main_file = crate_root.join(DEFAULT_RULES_FILENAME);
+  let sub_file = crate_root.join(DEFAULT_RULES_DIR).join("example.md");
+
+  for path in std::iter::once(&main_file).chain(with_subdir.then_some(&sub_file)) {
+    if path.exists() && !force {
+      anyhow::bail!(
+        "{} already exists; pass --force to overwrite it",
+        path.display()
+      );
+    }
+  }
+
+  fs::write(
+    &main_file,
+    MAIN_TEMPLATE.replace("{name}", &crate_name(crate_root)),
+  )
+  .with_context(|| format!("Failed to write {}", main_file.display()))?;
+  let mut created = vec![main_file];
+
+  if with_subdir {
+    if let Some(dir) = sub_file.parent() {
+      fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    }
+    fs::write(&sub_file, SUB_FILE_TEMPLATE)
+      .with_context(|| format!("Failed to write {}", sub_file.display()))?;
+    created.push(sub_file);
+  }
+
+  Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_init_creates_templates() {
+    let temp = TempDir::new().unwrap();
+    fs::write(
+      temp.path().join("Cargo.toml"),
+      "[package]\nname = \"my-crate\"\n",
+    )
+    .unwrap();
+
+    let created = init_rules(temp.path(), true, false).unwrap();
+
+    assert_eq!(created.len(), 2);
+    let main = fs::read_to_string(temp.path().join("usage-rules.md")).unwrap();
+    assert!(main.starts_with("# my-crate\n"));
+    assert!(main.contains("## Common Mistakes"));
+    assert!(temp.path().join("usage_rules/example.md").is_file());
+  }
+
+  #[test]
+  fn test_init_refuses_to_overwrite_without_force() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Hand-written rules").unwrap();
+
+    assert!(init_rules(temp.path(), false, false).is_err());
+    assert_eq!(
+      fs::read_to_string(&main_file).unwrap(),
+      "Hand-written rules"
+    );
+
+    init_rules(temp.path(), false, true).unwrap();
+    assert_ne!(
+      fs::read_to_string(&main_file).unwrap(),
+      "Hand-written rules"
+    );
+  }
+
+  #[test]
+  fn test_find_crate_root_searches_ancestors() {
+    let temp = TempDir::new().unwrap();
+    fs::write(temp.path().join("Cargo.toml"), "").unwrap();
+    let nested = temp.path().join("src/bin");
+    fs::create_dir_all(&nested).unwrap();
+
+    assert_eq!(find_crate_root(&nested).unwrap(), temp.path());
+  }
+}
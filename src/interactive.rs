@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use cargo_usage_rules::{aggregator::SelectionOptions, UsageRules};
+use dialoguer::{theme::ColorfulTheme, MultiSelect};
+use std::io::IsTerminal;
+
+/// A package offered in the interactive checklists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Choice {
+  name: String,
+  label: String,
+  included: bool,
+  inline: bool,
+}
+
+/// The packages picked interactively, expressed as selection flags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InteractiveSelection {
+  /// Packages to leave out, as for `--remove`.
+  pub remove: Vec<String>,
+  /// Packages to inline, as for `--inline`.
+  pub inline: Vec<String>,
+}
+
+/// Lists each package once, checked as the `selection` flags would select
+/// it.
+fn choices(usage_rules: &[UsageRules], selection: &SelectionOptions) -> Vec<Choice> {
+  let mut choices: Vec<Choice> = Vec::new();
+  for rule in usage_rules {
+    if choices.iter().any(|c| c.name == rule.package_name) {
+      continue;
+    }
+    let name = &rule.package_name;
+    let label = match rule.sub_files.len() {
+      0 => format!("{} v{}", name, rule.package_version),
+      count => format!("{} v{} ({} sub-files)", name, rule.package_version, count),
+    };
+    choices.push(Choice {
+      name: name.clone(),
+      label,
+      included: selection.is_included(name) && !selection.any_matches(&selection.remove, name),
+      inline: selection.any_matches(&selection.inline, name),
+    });
+  }
+  choices
+}
+
+/// Turns the indices checked in the checklists into selection flags.
+fn selection_from(
+  choices: &[Choice],
+  included: &[usize],
+  inlined: &[usize],
+) -> InteractiveSelection {
+  let included: Vec<&Choice> = included.iter().map(|&i| &choices[i]).collect();
+  InteractiveSelection {
+    remove: choices
+      .iter()
+      .filter(|c| !included.contains(c))
+      .map(|c| c.name.clone())
+      .collect(),
+    inline: inlined.iter().map(|&i| included[i].name.clone()).collect(),
+  }
+}
+
+/// Asks which scanned packages to write and, in linked mode, which of them
+/// to inline, starting from what the `selection` flags select.
+///
+/// # Errors
+///
+/// Returns an error if stdin or stdout is not a terminal, or the prompt is
+/// aborted.
+pub fn select_packages(
+  usage_rules: &[UsageRules],
+  selection: &SelectionOptions,
+  linked: bool,
+) -> Result<InteractiveSelection> {
+  if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+    anyhow::bail!("--interactive needs a terminal; pass --include, --remove and --inline instead");
+  }
+
+  let choices = choices(usage_rules, selection);
+  let theme = ColorfulTheme::default();
+  let labels: Vec<&str> = choices.iter().map(|c| c.label.as_str()).collect();
+  let defaults: Vec<bool> = choices.iter().map(|c| c.included).collect();
+  let included = MultiSelect::with_theme(&theme)
+    .with_prompt("Packages to include (space to toggle, enter to confirm)")
+    .items(&labels)
+    .defaults(&defaults)
+    .interact()
+    .context("Package selection was aborted")?;
+
+  let inlined = if linked && !included.is_empty() {
+    let labels: Vec<&str> = included
+      .iter()
+      .map(|&i| choices[i].label.as_str())
+      .collect();
+    let defaults: Vec<bool> = included.iter().map(|&i| choices[i].inline).collect();
+    MultiSelect::with_theme(&theme)
+      .with_prompt("Packages to inline instead of linking")
+      .items(&labels)
+      .defaults(&defaults)
+      .interact()
+      .context("Package selection was aborted")?
+  } else {
+    Vec::new()
+  };
+
+  Ok(selection_from(&choices, &included, &inlined))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cargo_usage_rules::scanner::UsageRuleSubFile;
+  use std::path::PathBuf;
+
+  fn rule(name: &str, version: &str, sub_files: usize) -> UsageRules {
+    UsageRules {
+      package_name: name.to_string(),
+      package_version: version.to_string(),
+      main_file: Some(PathBuf::from("usage-rules.md")),
+      sub_files: (0..sub_files)
+        .map(|i| UsageRuleSubFile {
+          relative_path_name: format!("sub{}", i),
+          full_path: PathBuf::from(format!("sub{}.md", i)),
+          order: None,
+        })
+        .collect(),
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
+    }
+  }
+
+  #[test]
+  fn test_choices_start_from_selection_flags() {
+    let rules = [
+      rule("serde", "1.0.0", 2),
+      rule("tokio", "1.0.0", 0),
+      rule("tokio", "0.2.0", 0),
+      rule("old", "1.0.0", 0),
+    ];
+    let selection = SelectionOptions {
+      remove: vec!["old".to_string()],
+      inline: vec!["tokio".to_string()],
+      ..SelectionOptions::default()
+    };
+
+    let choices = choices(&rules, &selection);
+
+    let labels: Vec<_> = choices.iter().map(|c| c.label.as_str()).collect();
+    assert_eq!(
+      labels,
+      vec!["serde v1.0.0 (2 sub-files)", "tokio v1.0.0", "old v1.0.0"]
+    );
+    let included: Vec<_> = choices.iter().map(|c| c.included).collect();
+    assert_eq!(included, vec![true, true, false]);
+    assert!(choices[1].inline);
+  }
+
+  #[test]
+  fn test_selection_from_checked_indices() {
+    let rules = [
+      rule("serde", "1.0.0", 0),
+      rule("tokio", "1.0.0", 0),
+      rule("old", "1.0.0", 0),
+    ];
+    let choices = choices(&rules, &SelectionOptions::default());
+
+    // Inlined indices refer to the included packages
+    let selection = selection_from(&choices, &[0, 1], &[1]);
+
+    assert_eq!(
+      selection,
+      InteractiveSelection {
+        remove: vec!["old".to_string()],
+        inline: vec!["tokio".to_string()],
+      }
+    );
+  }
+}
@@ -0,0 +1,43 @@
+//! Aggregates the `usage-rules.md` files of a project's dependencies into a
+//! single file for AI coding agents.
+//!
+//! This is the library behind `cargo usage-rules`. [`sync`] runs the whole
+//! pipeline; the modules expose each step (scanning, aggregating, writing)
+//! for callers that need finer control.
+//!
+//! ```no_run
+//! use cargo_usage_rules::{sync, SyncOptions};
+//!
+//! let report = sync(&SyncOptions {
+//!   linked: false,
+//!   ..SyncOptions::default()
+//! })?;
+//! println!("Wrote {} packages", report.packages.len());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod aggregator;
+pub mod bench;
+pub mod cache;
+pub mod clean;
+pub mod encoding;
+pub mod hashing;
+pub mod init;
+pub mod links;
+pub mod lint;
+pub mod lockfile;
+pub mod logger;
+pub mod markdown;
+pub mod metadata;
+pub mod patterns;
+pub mod pipeline;
+pub mod scanner;
+pub mod style;
+pub mod warnings;
+pub mod watch;
+pub mod writer;
+
+pub use aggregator::{aggregate_content, extract_agents_md_preamble, PackageContentInfo};
+pub use pipeline::{sync, SyncOptions, SyncReport};
+pub use scanner::{scan_for_usage_rules, UsageRules};
+pub use writer::{write_inline, write_linked, OutputWriter};
@@ -0,0 +1,228 @@
+use crate::{
+  scanner::{read_file_content, UsageRules},
+  warnings::{warn, WarningCategory},
+};
+use anyhow::Result;
+use std::path::Path;
+
+/// Extracts the targets of markdown links (`[text](target)`) and reference
+/// definitions (`[id]: target`) from the given content.
+fn link_targets(content: &str) -> Vec<&str> {
+  let mut targets = Vec::new();
+
+  for line in content.lines() {
+    let mut rest = line;
+    while let Some(start) = rest.find("](") {
+      let after = &rest[start + 2..];
+      let Some(end) = after.find(')') else {
+        break;
+      };
+      targets.push(after[..end].trim());
+      rest = &after[end + 1..];
+    }
+
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('[') {
+      if let Some(pos) = trimmed.find("]:") {
+        if let Some(target) = trimmed[pos + 2..].split_whitespace().next() {
+          targets.push(target);
+        }
+      }
+    }
+  }
+
+  targets
+}
+
+/// Returns true if a relative link target, resolved from a file `depth`
+/// directories below the package folder, points above the package folder.
+fn escapes_package(target: &str, depth: usize) -> bool {
+  if target.contains("://") || target.starts_with('#') || target.starts_with("mailto:") {
+    return false;
+  }
+
+  let path = target.split(['#', '?']).next().unwrap_or_default();
+  if path.starts_with('/') {
+    return false;
+  }
+
+  let mut level = depth as isize;
+  for component in path.split('/') {
+    match component {
+      "" | "." => {}
+      ".." => {
+        level -= 1;
+        if level < 0 {
+          return true;
+        }
+      }
+      _ => level += 1,
+    }
+  }
+
+  false
+}
+
+/// Finds relative links in `content` that would escape the package folder
+/// when the file lives `depth` directories below it.
+pub fn find_escaping_links(content: &str, depth: usize) -> Vec<String> {
+  link_targets(content)
+    .into_iter()
+    .filter(|target| escapes_package(target, depth))
+    .map(str::to_string)
+    .collect()
+}
+
+/// Drops rule files containing links that escape their package folder.
+///
+/// Sub-files with escaping links are removed from their package, and a
+/// package whose main file contains an escaping link is skipped entirely. A
+/// warning naming the package, file and link is printed for each offense.
+///
+/// # Errors
+///
+/// Returns an error if a rule file cannot be read.
+pub fn remove_escaping_files(
+  usage_rules: Vec<UsageRules>,
+  strict_encoding: bool,
+) -> Result<Vec<UsageRules>> {
+  let mut results = Vec::new();
+
+  for mut rule in usage_rules {
+    if let Some(main_file) = &rule.main_file {
+      let escaping = find_escaping_links(&read_file_content(main_file, strict_encoding)?, 0);
+      if !escaping.is_empty() {
+        warn_escaping(&rule.package_name, main_file, &escaping);
+        continue;
+      }
+    }
+
+    let mut sub_files = Vec::new();
+    for sub_file in rule.sub_files {
+      let depth = Path::new(&sub_file.relative_path_name)
+        .components()
+        .count()
+        .saturating_sub(1);
+      let escaping = find_escaping_links(
+        &read_file_content(&sub_file.full_path, strict_encoding)?,
+        depth,
+      );
+      if escaping.is_empty() {
+        sub_files.push(sub_file);
+      } else {
+        warn_escaping(&rule.package_name, &sub_file.full_path, &escaping);
+      }
+    }
+    rule.sub_files = sub_files;
+
+    results.push(rule);
+  }
+
+  Ok(results)
+}
+
+fn warn_escaping(package_name: &str, path: &Path, links: &[String]) {
+  warn(
+    WarningCategory::EscapingLinks,
+    format_args!(
+      "dropping {} from {}: links escape the package folder: {}",
+      path.display(),
+      package_name,
+      links.join(", ")
+    ),
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::scanner::UsageRuleSubFile;
+  use std::fs;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_flags_link_escaping_package() {
+    let content = "See [secrets](../../etc/passwd) for details.";
+
+    let escaping = find_escaping_links(content, 0);
+
+    assert_eq!(escaping, vec!["../../etc/passwd"]);
+  }
+
+  #[test]
+  fn test_allows_links_within_package() {
+    let content = "[builder](../builder.md) and [async](./async.md#runtime)\n[ext]: \
+                   https://example.com/../x";
+
+    assert!(find_escaping_links(content, 1).is_empty());
+    assert_eq!(find_escaping_links(content, 0), vec!["../builder.md"]);
+  }
+
+  #[test]
+  fn test_flags_reference_definitions() {
+    let content = "[passwd]: ../../../etc/passwd";
+
+    assert_eq!(find_escaping_links(content, 1), vec!["../../../etc/passwd"]);
+  }
+
+  #[test]
+  fn test_remove_escaping_files_drops_sub_file() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "Main content").unwrap();
+    let good = temp.path().join("good.md");
+    fs::write(&good, "[main](../pkg.md)").unwrap();
+    let bad = temp.path().join("bad.md");
+    fs::write(&bad, "[passwd](../../etc/passwd)").unwrap();
+
+    let rules = vec![UsageRules {
+      package_name: "pkg".to_string(),
+      package_version: "1.0.0".to_string(),
+      main_file: Some(main_file),
+      sub_files: vec![
+        UsageRuleSubFile {
+          relative_path_name: "nested/good".to_string(),
+          full_path: good,
+          order: None,
+        },
+        UsageRuleSubFile {
+          relative_path_name: "bad".to_string(),
+          full_path: bad,
+          order: None,
+        },
+      ],
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
+    }];
+
+    let results = remove_escaping_files(rules, false).unwrap();
+
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].sub_files.len(), 1);
+    assert_eq!(results[0].sub_files[0].relative_path_name, "nested/good");
+  }
+
+  #[test]
+  fn test_remove_escaping_files_skips_package_with_escaping_main_file() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(&main_file, "[up](../other/other.md)").unwrap();
+
+    let rules = vec![UsageRules {
+      package_name: "pkg".to_string(),
+      package_version: "1.0.0".to_string(),
+      main_file: Some(main_file),
+      sub_files: vec![],
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
+    }];
+
+    let results = remove_escaping_files(rules, false).unwrap();
+
+    assert!(results.is_empty());
+  }
+}
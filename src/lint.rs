@@ -0,0 +1,155 @@
+use crate::{
+  metadata::Dependency,
+  scanner::{read_file_content, UsageRules},
+};
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// Crates that are always available and never appear as dependencies.
+const BUILTIN_CRATES: [&str; 5] = ["std", "core", "alloc", "proc_macro", "test"];
+
+/// A usage rules file referencing a crate that is not a dependency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownCrateRef {
+  pub package_name: String,
+  pub file: PathBuf,
+  pub crate_name: String,
+}
+
+/// Returns true if `s` looks like a crate name.
+fn is_crate_like(s: &str) -> bool {
+  s.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+    && s
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Normalizes a crate name so `foo-bar` and `foo_bar` compare equal.
+fn normalize(name: &str) -> String {
+  name.replace('-', "_")
+}
+
+/// Finds backtick-quoted crate names that the content explicitly calls crates,
+/// as in "the `foo` crate" or "crate `foo`".
+pub fn find_crate_refs(content: &str) -> Vec<String> {
+  let mut refs = Vec::new();
+
+  let spans: Vec<&str> = content.split('`').collect();
+  // Odd indices are the contents of backtick spans
+  for i in (1..spans.len().saturating_sub(1)).step_by(2) {
+    let candidate = spans[i];
+    if !is_crate_like(candidate) {
+      continue;
+    }
+
+    let before = spans[i - 1].trim_end().to_lowercase();
+    let after = spans[i + 1].trim_start().to_lowercase();
+    let called_crate = before.ends_with("crate") || after.starts_with("crate");
+
+    if called_crate && !refs.iter().any(|r| r == candidate) {
+      refs.push(candidate.to_string());
+    }
+  }
+
+  refs
+}
+
+/// Checks every rule file for references to crates missing from
+/// `dependencies`.
+///
+/// # Errors
+///
+/// Returns an error if a rule file cannot be read.
+pub fn lint_crate_refs(
+  usage_rules: &[UsageRules],
+  dependencies: &[Dependency],
+  strict_encoding: bool,
+) -> Result<Vec<UnknownCrateRef>> {
+  let known: Vec<String> = dependencies.iter().map(|d| normalize(&d.name)).collect();
+  let mut warnings = Vec::new();
+
+  for rule in usage_rules {
+    let files = rule
+      .main_file
+      .iter()
+      .chain(rule.sub_files.iter().map(|f| &f.full_path));
+
+    for file in files {
+      for crate_name in find_crate_refs(&read_file_content(file, strict_encoding)?) {
+        let normalized = normalize(&crate_name);
+        if BUILTIN_CRATES.contains(&normalized.as_str())
+          || normalized == normalize(&rule.package_name)
+          || known.contains(&normalized)
+        {
+          continue;
+        }
+
+        warnings.push(UnknownCrateRef {
+          package_name: rule.package_name.clone(),
+          file: file.clone(),
+          crate_name,
+        });
+      }
+    }
+  }
+
+  Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_find_crate_refs() {
+    let content = "Use with the `tokio` crate. Call `hello()` first.\nSee crate `serde_json` \
+                   and the `Foo` type.";
+
+    assert_eq!(find_crate_refs(content), vec!["tokio", "serde_json"]);
+  }
+
+  #[test]
+  fn test_lint_flags_non_dependency_crate() {
+    let temp = TempDir::new().unwrap();
+    let main_file = temp.path().join("usage-rules.md");
+    fs::write(
+      &main_file,
+      "Pair this with the `serde` crate and the `foo-bar` crate. Avoid the `std` crate.",
+    )
+    .unwrap();
+
+    let rules = [UsageRules {
+      package_name: "pkg".to_string(),
+      package_version: "1.0.0".to_string(),
+      main_file: Some(main_file.clone()),
+      sub_files: vec![],
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
+    }];
+    let deps = [Dependency {
+      name: "serde".to_string(),
+      version: "1.0.0".to_string(),
+      path: temp.path().to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    }];
+
+    let warnings = lint_crate_refs(&rules, &deps, false).unwrap();
+
+    assert_eq!(
+      warnings,
+      vec![UnknownCrateRef {
+        package_name: "pkg".to_string(),
+        file: main_file,
+        crate_name: "foo-bar".to_string(),
+      }]
+    );
+  }
+}
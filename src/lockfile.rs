@@ -0,0 +1,228 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fs,
+  path::{Path, PathBuf},
+  process::Command,
+};
+
+/// The versions each package is locked at, by package name.
+pub type LockVersions = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Debug, Deserialize)]
+struct LockFile {
+  #[serde(default)]
+  package: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedPackage {
+  name: String,
+  version: String,
+}
+
+/// Packages whose locked versions differ between two lock files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockChanges {
+  /// Names of the packages added, removed or locked at another version.
+  pub changed: BTreeSet<String>,
+  /// Versions each changed package was locked at before, if any.
+  pub previous: LockVersions,
+}
+
+/// Parses the `[[package]]` entries of a `Cargo.lock`.
+///
+/// # Errors
+///
+/// Returns an error if the content is not a valid lock file.
+pub fn parse_lock_versions(content: &str) -> Result<LockVersions> {
+  let lock: LockFile = toml::from_str(content).context("Failed to parse Cargo.lock")?;
+  let mut versions = LockVersions::new();
+  for package in lock.package {
+    versions
+      .entry(package.name)
+      .or_default()
+      .insert(package.version);
+  }
+  Ok(versions)
+}
+
+/// Compares two parsed lock files, `old` and `new`.
+pub fn diff_locks(old: &LockVersions, new: &LockVersions) -> LockChanges {
+  let mut changes = LockChanges::default();
+  for name in old.keys().chain(new.keys()) {
+    if old.get(name) != new.get(name) {
+      changes.changed.insert(name.clone());
+      if let Some(versions) = old.get(name) {
+        changes.previous.insert(name.clone(), versions.clone());
+      }
+    }
+  }
+  changes
+}
+
+/// Finds the `Cargo.lock` of the project whose manifest is `manifest_path`
+/// (or the current directory's), looking in its directory and each parent
+/// for workspace members.
+///
+/// # Errors
+///
+/// Returns an error if no `Cargo.lock` is found.
+pub fn find_lock_file(manifest_path: Option<&Path>) -> Result<PathBuf> {
+  let start = match manifest_path.and_then(Path::parent) {
+    Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+    _ => PathBuf::from("."),
+  };
+  let start =
+    fs::canonicalize(&start).with_context(|| format!("Failed to resolve {}", start.display()))?;
+  start
+    .ancestors()
+    .map(|dir| dir.join("Cargo.lock"))
+    .find(|path| path.is_file())
+    .with_context(|| format!("No Cargo.lock found in {} or its parents", start.display()))
+}
+
+/// Reads the content `lock_path` had at the git revision `git_ref`.
+///
+/// # Errors
+///
+/// Returns an error if git cannot be run, or the file does not exist at
+/// `git_ref`.
+pub fn read_lock_at(lock_path: &Path, git_ref: &str) -> Result<String> {
+  let dir = lock_path.parent().unwrap_or(Path::new("."));
+  let file_name = lock_path
+    .file_name()
+    .with_context(|| format!("Invalid lock file path: {}", lock_path.display()))?;
+  let output = Command::new("git")
+    .arg("show")
+    .arg(format!("{}:./{}", git_ref, file_name.to_string_lossy()))
+    .current_dir(dir)
+    .output()
+    .context("Failed to run 'git show'")?;
+  if !output.status.success() {
+    bail!(
+      "Failed to read {} at '{}': {}",
+      lock_path.display(),
+      git_ref,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  String::from_utf8(output.stdout).with_context(|| {
+    format!(
+      "{} at '{}' is not valid UTF-8",
+      lock_path.display(),
+      git_ref
+    )
+  })
+}
+
+/// Returns the packages whose locked versions changed in `lock_path` since
+/// the git revision `git_ref`.
+///
+/// # Errors
+///
+/// Returns an error if either version of the lock file cannot be read or
+/// parsed.
+pub fn lock_changes_since(lock_path: &Path, git_ref: &str) -> Result<LockChanges> {
+  let current = fs::read_to_string(lock_path)
+    .with_context(|| format!("Failed to read {}", lock_path.display()))?;
+  let previous = read_lock_at(lock_path, git_ref)?;
+  Ok(diff_locks(
+    &parse_lock_versions(&previous).with_context(|| format!("At '{}'", git_ref))?,
+    &parse_lock_versions(&current)?,
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  const OLD_LOCK: &str = r#"
+version = 3
+
+[[package]]
+name = "anyhow"
+version = "1.0.80"
+
+[[package]]
+name = "serde"
+version = "1.0.190"
+
+[[package]]
+name = "syn"
+version = "1.0.109"
+
+[[package]]
+name = "syn"
+version = "2.0.48"
+
+[[package]]
+name = "removed"
+version = "0.1.0"
+"#;
+
+  const NEW_LOCK: &str = r#"
+version = 3
+
+[[package]]
+name = "anyhow"
+version = "1.0.80"
+
+[[package]]
+name = "serde"
+version = "1.0.200"
+
+[[package]]
+name = "syn"
+version = "2.0.48"
+
+[[package]]
+name = "added"
+version = "0.2.0"
+dependencies = ["serde"]
+"#;
+
+  #[test]
+  fn test_parse_lock_versions() {
+    let versions = parse_lock_versions(OLD_LOCK).unwrap();
+    assert_eq!(versions.len(), 4);
+    assert_eq!(
+      versions["syn"],
+      BTreeSet::from(["1.0.109".to_string(), "2.0.48".to_string()])
+    );
+    assert!(parse_lock_versions("[[package]]\nname = 1").is_err());
+  }
+
+  #[test]
+  fn test_diff_locks() {
+    let changes = diff_locks(
+      &parse_lock_versions(OLD_LOCK).unwrap(),
+      &parse_lock_versions(NEW_LOCK).unwrap(),
+    );
+    assert_eq!(
+      changes.changed.into_iter().collect::<Vec<_>>(),
+      vec!["added", "removed", "serde", "syn"]
+    );
+    assert!(!changes.previous.contains_key("added"));
+    assert_eq!(
+      changes.previous["serde"],
+      BTreeSet::from(["1.0.190".to_string()])
+    );
+  }
+
+  #[test]
+  fn test_find_lock_file_in_parent() {
+    let temp = TempDir::new().unwrap();
+    let member = temp.path().join("crates/member");
+    fs::create_dir_all(&member).unwrap();
+    fs::write(temp.path().join("Cargo.lock"), NEW_LOCK).unwrap();
+
+    let lock = find_lock_file(Some(&member.join("Cargo.toml"))).unwrap();
+    assert_eq!(
+      lock,
+      fs::canonicalize(temp.path()).unwrap().join("Cargo.lock")
+    );
+  }
+}
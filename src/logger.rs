@@ -0,0 +1,128 @@
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{fmt, io::IsTerminal, sync::OnceLock};
+
+/// How much the tool reports about its progress, from least to most.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+  /// Nothing but requested output and errors (`-qq`).
+  Silent,
+  /// Only the final result line (`-q`).
+  Quiet,
+  /// Progress messages.
+  #[default]
+  Normal,
+  /// Progress messages plus per-dependency scan details (`--verbose`).
+  Verbose,
+}
+
+impl Verbosity {
+  /// Derives the verbosity from the number of `--quiet` flags and
+  /// `--verbose`.
+  pub fn from_flags(quiet: u8, verbose: bool) -> Self {
+    match quiet {
+      0 if verbose => Self::Verbose,
+      0 => Self::Normal,
+      1 => Self::Quiet,
+      _ => Self::Silent,
+    }
+  }
+}
+
+struct Logger {
+  verbosity: Verbosity,
+  to_stderr: bool,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets the verbosity for the rest of the run. Messages go to stdout, or to
+/// stderr when `to_stderr` is set so stdout stays machine-readable.
+///
+/// Only the first call has an effect.
+pub fn init(verbosity: Verbosity, to_stderr: bool) {
+  let _ = LOGGER.set(Logger {
+    verbosity,
+    to_stderr,
+  });
+}
+
+fn log(level: Verbosity, message: impl fmt::Display) {
+  let (verbosity, to_stderr) = LOGGER
+    .get()
+    .map_or((Verbosity::default(), false), |logger| {
+      (logger.verbosity, logger.to_stderr)
+    });
+  if verbosity < level {
+    return;
+  }
+
+  if to_stderr {
+    eprintln!("{}", message);
+  } else {
+    println!("{}", message);
+  }
+}
+
+/// Prints the final result of a command, unless running with `-qq`.
+pub fn success(message: impl fmt::Display) {
+  log(Verbosity::Quiet, message);
+}
+
+/// Prints a progress message, unless running with `--quiet`.
+pub fn progress(message: impl fmt::Display) {
+  log(Verbosity::Normal, message);
+}
+
+/// Prints a detail message, only when running with `--verbose`.
+pub fn detail(message: impl fmt::Display) {
+  log(Verbosity::Verbose, message);
+}
+
+/// Returns a progress bar counting up to `len` units, drawn on stdout.
+///
+/// The bar is hidden unless stdout is a terminal that progress messages go
+/// to, and under `--quiet` and `--verbose`, whose detail lines it would
+/// interleave with.
+pub fn progress_bar(len: usize) -> ProgressBar {
+  let (verbosity, to_stderr) = LOGGER
+    .get()
+    .map_or((Verbosity::default(), false), |logger| {
+      (logger.verbosity, logger.to_stderr)
+    });
+  if !shows_progress_bar(verbosity, to_stderr, std::io::stdout().is_terminal()) {
+    return ProgressBar::hidden();
+  }
+
+  let style = ProgressStyle::with_template("{bar:30} {pos}/{len} {wide_msg}")
+    .unwrap_or_else(|_| ProgressStyle::default_bar());
+  ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stdout()).with_style(style)
+}
+
+/// Returns whether to draw a progress bar given the verbosity, whether
+/// messages go to stderr, and whether stdout is a terminal.
+fn shows_progress_bar(verbosity: Verbosity, to_stderr: bool, is_terminal: bool) -> bool {
+  verbosity == Verbosity::Normal && !to_stderr && is_terminal
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_verbosity_from_flags() {
+    assert_eq!(Verbosity::from_flags(0, false), Verbosity::Normal);
+    assert_eq!(Verbosity::from_flags(0, true), Verbosity::Verbose);
+    assert_eq!(Verbosity::from_flags(1, false), Verbosity::Quiet);
+    assert_eq!(Verbosity::from_flags(3, false), Verbosity::Silent);
+    assert!(Verbosity::Quiet < Verbosity::Normal);
+  }
+
+  #[test]
+  fn test_shows_progress_bar() {
+    assert!(shows_progress_bar(Verbosity::Normal, false, true));
+    assert!(!shows_progress_bar(Verbosity::Normal, false, false));
+    assert!(!shows_progress_bar(Verbosity::Normal, true, true));
+    assert!(!shows_progress_bar(Verbosity::Quiet, false, true));
+    assert!(!shows_progress_bar(Verbosity::Verbose, false, true));
+  }
+}
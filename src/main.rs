@@ -1,12 +1,19 @@
-mod aggregator;
 mod cli;
-mod metadata;
-mod scanner;
-mod writer;
+mod config;
+mod interactive;
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use cli::{Cli, Commands, SubCommands};
+use cargo_usage_rules::{
+  aggregator, bench, cache, clean, encoding, hashing, init, logger, markdown, metadata, patterns,
+  pipeline, scanner, style, warnings, watch, writer,
+};
+use clap::{CommandFactory, FromArgMatches};
+use cli::{Cli, Commands, ListArgs, SubCommands, SyncArgs};
+use scanner::ListFormat;
+use std::{
+  io::Write,
+  path::{Path, PathBuf},
+};
 
 fn main() {
   if let Err(e) = run() {
@@ -16,94 +23,516 @@ fn main() {
 }
 
 fn run() -> Result<()> {
-  let cli = Cli::parse();
+  let matches = Cli::command().get_matches();
+  let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
   match cli.command {
-    Commands::UsageRules(args) => {
-      println!("Fetching dependencies...");
-      metadata::fetch_dependencies().context("Failed to fetch dependencies with 'cargo fetch'")?;
+    Commands::UsageRules(mut args) => {
+      warnings::allow(args.allow.clone());
+      if let SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) = &mut args.subcommand {
+        let manifest_dir = match &sync_args.cargo.manifest_path {
+          Some(path) => path.parent().unwrap_or(Path::new("")).to_path_buf(),
+          None => PathBuf::new(),
+        };
+        let start = std::env::current_dir()
+          .context("Failed to determine the current directory")?
+          .join(manifest_dir);
+        if let Some(path) = config::find_config(&start) {
+          let sync_matches = matches
+            .subcommand_matches("usage-rules")
+            .and_then(|m| m.subcommand())
+            .map(|(_, m)| m)
+            .context("Missing sync argument matches")?;
+          sync_args.apply_config(config::load_config(&path)?, sync_matches);
+        }
+      }
+      if let SubCommands::Clean(clean_args) = &mut args.subcommand {
+        let current_dir =
+          std::env::current_dir().context("Failed to determine the current directory")?;
+        if let Some(path) = config::find_config(&current_dir) {
+          let clean_matches = matches
+            .subcommand_matches("usage-rules")
+            .and_then(|m| m.subcommand_matches("clean"))
+            .context("Missing clean argument matches")?;
+          clean_args.apply_config(config::load_config(&path)?, clean_matches);
+        }
+      }
+
+      // Keep stdout parseable when it carries machine-readable output
+      let quiet_stdout = matches!(
+        args.subcommand,
+        SubCommands::List(ListArgs {
+          format: ListFormat::Json,
+          ..
+        }) | SubCommands::Sync(SyncArgs { stdout: true, .. })
+          | SubCommands::Sync(SyncArgs {
+            stats: Some(ListFormat::Json),
+            ..
+          })
+      );
+      // The timed pipeline steps log their progress on every iteration
+      let verbosity = match args.subcommand {
+        SubCommands::Bench(_) => args.verbosity().min(logger::Verbosity::Quiet),
+        _ => args.verbosity(),
+      };
+      logger::init(verbosity, quiet_stdout);
+      style::init(args.color);
+
+      if let SubCommands::Clean(clean_args) = &args.subcommand {
+        let report = clean::clean(&clean_args.clean_options())?;
+        for path in &report.updated {
+          logger::success(format_args!(
+            "{} Removed the generated section from {}",
+            style::green("✓"),
+            path.display()
+          ));
+        }
+        for path in &report.removed {
+          logger::success(format_args!(
+            "{} Removed {}",
+            style::green("✓"),
+            path.display()
+          ));
+        }
+        if report.updated.is_empty() && report.removed.is_empty() {
+          logger::success("Nothing to clean");
+        }
+        return Ok(());
+      }
+
+      if let SubCommands::Completions(completions_args) = &args.subcommand {
+        clap_complete::generate(
+          completions_args.shell,
+          &mut Cli::command(),
+          "cargo-usage-rules",
+          &mut std::io::stdout(),
+        );
+        return Ok(());
+      }
 
-      println!("Reading dependency metadata...");
-      let dependencies =
-        metadata::get_dependencies().context("Failed to get dependency metadata")?;
+      if let SubCommands::Init(init_args) = &args.subcommand {
+        let current_dir =
+          std::env::current_dir().context("Failed to determine the current directory")?;
+        let crate_root = init::find_crate_root(&current_dir)?;
+        for path in init::init_rules(&crate_root, init_args.with_subdir, init_args.force)? {
+          logger::success(format_args!(
+            "{} Created {}",
+            style::green("✓"),
+            path.display()
+          ));
+        }
+        return Ok(());
+      }
+
+      let metadata_options = args.metadata_options();
+      pipeline::fetch(&metadata_options)?;
 
-      println!("Scanning for usage-rules.md files...");
-      let usage_rules =
-        scanner::scan_for_usage_rules(&dependencies).context("Failed to scan for usage rules")?;
+      if let SubCommands::Bench(bench_args) = &args.subcommand {
+        logger::success(format_args!(
+          "Running {} iterations...",
+          bench_args.iterations
+        ));
+        let options = pipeline::SyncOptions {
+          metadata: metadata_options,
+          metadata_cache: args.metadata_cache(),
+          scan: args.scan_options()?,
+          ..pipeline::SyncOptions::default()
+        };
+        let report = bench::run_bench(bench_args.iterations as usize, &options)?;
+        print!("{}", report);
+        return Ok(());
+      }
+
+      let metadata_cache = args.metadata_cache();
+      let metadata::ProjectMetadata {
+        dependencies,
+        settings,
+      } = pipeline::load_dependencies(&metadata_options, metadata_cache.as_ref())?;
 
-      if usage_rules.is_empty() {
-        println!("No usage-rules.md files found in dependencies.");
+      if let SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) = &mut args.subcommand {
+        let sync_matches = matches
+          .subcommand_matches("usage-rules")
+          .and_then(|m| m.subcommand())
+          .map(|(_, m)| m)
+          .context("Missing sync argument matches")?;
+        sync_args.apply_manifest_settings(settings, sync_matches);
       }
 
+      let scan_options = args.scan_options()?;
+      let fail_on_empty = args.fail_on_empty();
+      let checking = matches!(args.subcommand, SubCommands::Check(_));
       match args.subcommand {
-        SubCommands::Sync(sync_args) => {
-          println!("Found {} packages with usage rules:", usage_rules.len());
-          for rule in &usage_rules {
-            println!("  - {} v{}", rule.package_name, rule.package_version);
+        SubCommands::Sync(sync_args) | SubCommands::Check(sync_args) => {
+          let mut options =
+            sync_args.sync_options(scan_options, args.ignore_case, args.hash_algorithm);
+          let discovery = discover(
+            pipeline::scanned_dependencies(&dependencies, &options)?,
+            &options.scan,
+            fail_on_empty,
+          )?;
+          let watching = sync_args.watch && !checking;
+          if sync_args.interactive && !checking && !discovery.usage_rules.is_empty() {
+            let picked = interactive::select_packages(
+              &discovery.usage_rules,
+              &options.selection,
+              options.linked,
+            )?;
+            options.selection = aggregator::SelectionOptions {
+              include: vec![],
+              remove: picked.remove,
+              inline: picked.inline,
+              ..options.selection
+            };
           }
+          let pipeline::Selection {
+            packages: package_content,
+            log: selection_log,
+            skipped,
+            stats,
+          } = pipeline::select_packages(
+            discovery.usage_rules,
+            &dependencies,
+            &discovery.scanned,
+            &options,
+          )?;
 
-          println!("\nAggregating content...");
-          let package_content =
-            aggregator::aggregate_content(usage_rules.clone(), &sync_args.remove)
-              .context("Failed to aggregate content")?;
+          if sync_args.explain_selection {
+            // On stderr, so --stdout and --stats json stay parseable
+            eprintln!("\nSelection:\n{}", selection_log.render());
+          }
 
           if package_content.is_empty() && !sync_args.all {
-            println!("No packages selected for output. Use --all to include all packages.");
+            logger::success("No packages selected for output. Use --all to include all packages.");
+            if watching {
+              options.metadata_cache = metadata_cache;
+              watch::watch(&options)?;
+            }
             return Ok(());
           }
 
-          let preamble = aggregator::extract_agents_md_preamble(&sync_args.output)
-            .context("Failed to merge with existing content")?;
+          let output = std::iter::once(options.target())
+            .chain(options.additional_outputs.iter().map(PathBuf::as_path))
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+          if sync_args.output_mode.is_some() && !cfg!(unix) {
+            warnings::warn(
+              warnings::WarningCategory::UnsupportedOption,
+              "--output-mode is only supported on Unix and will be ignored",
+            );
+          }
 
-          println!("Writing output...");
-          if sync_args.linked {
-            writer::write_linked(
-              &sync_args.output,
-              &sync_args.link_folder,
-              package_content,
-              Some(preamble),
-            )
-            .context("Failed to write linked output")?;
+          let plan = pipeline::plan_sync(&options, package_content)?;
 
-            println!(
-              "✓ Successfully wrote usage rules to {} (linked mode: {})",
-              sync_args.output.display(),
-              sync_args.link_folder.display()
+          if sync_args.stdout && !checking {
+            if sync_args.linked {
+              warnings::warn(
+                warnings::WarningCategory::UnsupportedOption,
+                format_args!(
+                  "--stdout prints only the main file; linked files in {} were not written \
+                   (pass --linked=false to inline every package)",
+                  options.link_folder.display()
+                ),
+              );
+            }
+            if let Some(main_file) = plan.files.last() {
+              std::io::stdout()
+                .write_all(&main_file.expected_content()?)
+                .context("Failed to write to stdout")?;
+            }
+            return Ok(());
+          }
+
+          if sync_args.diff {
+            print!("{}", plan.diff(sync_args.output_encoding)?);
+          }
+
+          if sync_args.dry_run && !checking {
+            if !sync_args.diff {
+              print_dry_run(&plan, sync_args.output_encoding)?;
+            }
+            return Ok(());
+          }
+
+          if checking {
+            let stale = plan
+              .stale_files()
+              .context("Failed to compare output with existing files")?;
+            if stale.is_empty() {
+              logger::success("up to date");
+              return Ok(());
+            }
+
+            println!("Output is out of date:");
+            for file in &stale {
+              match file.first_difference {
+                Some(line) => println!("  {} (differs from line {})", file.path.display(), line),
+                None => println!("  {} (missing)", file.path.display()),
+              }
+            }
+            if sync_args.with_hashes {
+              print_changed_packages(&plan, sync_args.output_encoding)?;
+            }
+            anyhow::bail!(
+              "{} file(s) out of date; run `cargo usage-rules sync` to update",
+              stale.len()
             );
+          }
+
+          logger::progress("Writing output...");
+          let report = plan
+            .write(options.render.mode)
+            .context("Failed to write output")?;
+          if report.written.is_empty() {
+            logger::success(format_args!("{} {} unchanged", style::green("✓"), output));
+          } else if sync_args.linked {
+            logger::success(format_args!(
+              "{} Successfully wrote usage rules to {} (linked mode: {}, {} unchanged files)",
+              style::green("✓"),
+              output,
+              options.link_folder.display(),
+              report.unchanged.len()
+            ));
           } else {
-            writer::write_inline(&sync_args.output, package_content, Some(preamble))
-              .context("Failed to write inline output")?;
+            logger::success(format_args!(
+              "{} Successfully wrote usage rules to {}",
+              style::green("✓"),
+              output
+            ));
+          }
 
-            println!(
-              "✓ Successfully wrote usage rules to {}",
-              sync_args.output.display()
+          let stats = pipeline::SyncStats {
+            bytes_written: report.bytes,
+            ..stats
+          };
+          match sync_args.stats {
+            Some(ListFormat::Json) => println!("{}", serde_json::to_string_pretty(&stats)?),
+            Some(ListFormat::Text) => println!("\n{}", stats),
+            None => logger::detail(format_args!("\n{}", stats)),
+          }
+
+          if skipped != scanner::SkippedFiles::default() {
+            warnings::warn(
+              warnings::WarningCategory::UnreadableFile,
+              format_args!(
+                "skipped {} package(s) and {} sub-file(s) that could not be read",
+                skipped.packages, skipped.sub_files
+              ),
             );
           }
+
+          if watching {
+            options.metadata_cache = metadata_cache;
+            watch::watch(&options)?;
+          }
         }
 
-        SubCommands::List => {
+        SubCommands::List(ListArgs {
+          format: ListFormat::Json,
+          show_missing,
+          ..
+        }) => {
+          let pipeline::Discovery {
+            usage_rules,
+            missing,
+            ..
+          } = discover(dependencies, &scan_options, fail_on_empty)?;
+          let mut summaries: Vec<_> = usage_rules
+            .iter()
+            .map(scanner::PackageSummary::from)
+            .collect();
+          if show_missing {
+            summaries.extend(missing.iter().map(scanner::PackageSummary::from));
+          }
+          println!("{}", serde_json::to_string_pretty(&summaries)?);
+        }
+
+        SubCommands::List(list_args) => {
+          let pipeline::Discovery {
+            usage_rules,
+            missing,
+            ..
+          } = discover(dependencies, &scan_options, fail_on_empty)?;
           if usage_rules.is_empty() {
             println!("No usage-rules.md files found in dependencies.");
           } else {
             println!("Packages with usage rules:\n");
             for rule in usage_rules {
-              let main_file_marker = if rule.main_file.is_some() { "✓" } else { " " };
+              let main_file_marker = if rule.main_file.is_some() {
+                style::green("✓")
+              } else {
+                style::gray(" ")
+              };
               let sub_files_count = if !rule.sub_files.is_empty() {
-                format!(" ({} sub-files)", rule.sub_files.len())
+                format!(
+                  " {}",
+                  style::dim(format_args!("({} sub-files)", rule.sub_files.len()))
+                )
               } else {
                 String::new()
               };
 
               println!(
                 "  [{}] {} v{}{}",
-                main_file_marker, rule.package_name, rule.package_version, sub_files_count
+                main_file_marker,
+                style::bold(&rule.package_name),
+                rule.package_version,
+                sub_files_count
+              );
+            }
+          }
+
+          if list_args.show_missing && !missing.is_empty() {
+            println!("\nPackages without usage rules:\n");
+            for dep in &missing {
+              println!(
+                "  {}",
+                style::gray(format_args!("[ ] {} v{}", dep.name, dep.version))
               );
             }
           }
         }
+
+        SubCommands::ListSubfiles(list_args) => {
+          let selection = aggregator::SelectionOptions {
+            ignore_case: args.ignore_case,
+            ..aggregator::SelectionOptions::default()
+          };
+          let matching: Vec<_> = dependencies
+            .into_iter()
+            .filter(|dep| selection.name_matches(&list_args.package, &dep.name))
+            .collect();
+          if matching.is_empty() {
+            anyhow::bail!(
+              "Package '{}' not found among the project's dependencies",
+              list_args.package
+            );
+          }
+          let usage_rules = discover(matching, &scan_options, fail_on_empty)?.usage_rules;
+          if usage_rules.is_empty() {
+            anyhow::bail!(
+              "Package '{}' has no usage rules (no usage-rules.md found)",
+              list_args.package
+            );
+          }
+
+          for rule in usage_rules {
+            if rule.sub_files.is_empty() {
+              println!(
+                "{} v{} has no sub-files",
+                rule.package_name, rule.package_version
+              );
+              continue;
+            }
+
+            println!(
+              "Sub-files of {} v{}:\n",
+              rule.package_name, rule.package_version
+            );
+            for sub_file in &rule.sub_files {
+              if args.verbose {
+                println!(
+                  "  {} ({})",
+                  sub_file.relative_path_name,
+                  sub_file.full_path.display()
+                );
+              } else {
+                println!("  {}", sub_file.relative_path_name);
+              }
+            }
+          }
+        }
+
+        SubCommands::Bench(_) => unreachable!("bench returns before the shared pipeline"),
+        SubCommands::Init(_) => unreachable!("init returns before the shared pipeline"),
+        SubCommands::Clean(_) => unreachable!("clean returns before the shared pipeline"),
+        SubCommands::Completions(_) => {
+          unreachable!("completions returns before the shared pipeline")
+        }
       }
     }
   }
 
   Ok(())
 }
+
+/// Scans `scanned` for usage rules, failing if none has any and
+/// `fail_on_empty` is set.
+fn discover(
+  scanned: Vec<metadata::Dependency>,
+  options: &scanner::ScanOptions,
+  fail_on_empty: bool,
+) -> Result<pipeline::Discovery> {
+  let discovery = pipeline::discover(scanned, options)?;
+  if discovery.usage_rules.is_empty() {
+    if fail_on_empty {
+      anyhow::bail!("No usage-rules.md files found in dependencies (--fail-on-empty)");
+    }
+    logger::progress("No usage-rules.md files found in dependencies.");
+  }
+  Ok(discovery)
+}
+
+/// Prints the packages whose section hash differs between the existing main
+/// file and the planned one.
+fn print_changed_packages(
+  plan: &writer::OutputPlan,
+  encoding: &'static encoding_rs::Encoding,
+) -> Result<()> {
+  let Some(main_file) = plan.files.last() else {
+    return Ok(());
+  };
+  let Ok(existing) = std::fs::read(&main_file.path) else {
+    return Ok(());
+  };
+  let changed = aggregator::changed_sections(
+    &encoding::decode_output(&existing, encoding),
+    &encoding::decode_output(&main_file.expected_content()?, encoding),
+  );
+  if !changed.is_empty() {
+    println!("Changed sections:");
+    for heading in changed {
+      println!("  {}", heading);
+    }
+  }
+  Ok(())
+}
+
+/// Prints what a sync would write: the directories and linked files it would
+/// create, followed by the generated main output file.
+fn print_dry_run(
+  plan: &writer::OutputPlan,
+  encoding: &'static encoding_rs::Encoding,
+) -> Result<()> {
+  let Some((main_file, linked_files)) = plan.files.split_last() else {
+    return Ok(());
+  };
+
+  println!("Dry run: no files will be written.");
+  if !linked_files.is_empty() {
+    let mut directories: Vec<_> = linked_files
+      .iter()
+      .filter_map(|file| file.path.parent())
+      .filter(|dir| !dir.exists())
+      .collect();
+    directories.sort();
+    directories.dedup();
+
+    if !directories.is_empty() {
+      println!("\nWould create directories:");
+      for dir in directories {
+        println!("  {}", dir.display());
+      }
+    }
+    println!("\nWould write files:");
+    for file in linked_files {
+      println!("  {}", file.path.display());
+    }
+  }
+
+  println!("\nWould write {}:\n", main_file.path.display());
+  let content = main_file.expected_content()?;
+  print!("{}", encoding::decode_output(&content, encoding));
+  Ok(())
+}
@@ -0,0 +1,434 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Tracks whether lines fall inside a fenced code block.
+#[derive(Debug, Default)]
+pub struct FenceTracker {
+  open_fence: Option<String>,
+}
+
+impl FenceTracker {
+  /// Feeds the next line and returns true if it is part of a code block,
+  /// including the opening and closing fence lines.
+  pub fn is_code(&mut self, line: &str) -> bool {
+    let trimmed = line.trim_start();
+
+    if let Some(fence) = &self.open_fence {
+      if trimmed.starts_with(fence.as_str())
+        && trimmed.trim_start_matches(&fence[..1]).trim().is_empty()
+      {
+        self.open_fence = None;
+      }
+      return true;
+    }
+
+    let fence_char = match trimmed.chars().next() {
+      Some(c @ ('`' | '~')) => c,
+      _ => return false,
+    };
+    let fence_len = trimmed.chars().take_while(|&c| c == fence_char).count();
+    if fence_len < 3 {
+      return false;
+    }
+
+    self.open_fence = Some(fence_char.to_string().repeat(fence_len));
+    true
+  }
+
+  /// Returns the fence that would close the code block the lines fed so far
+  /// leave open, if any.
+  pub fn open_fence(&self) -> Option<&str> {
+    self.open_fence.as_deref()
+  }
+}
+
+/// Canonicalizes whitespace outside of code fences: strips trailing spaces
+/// from each line and collapses runs of three or more blank lines to two.
+/// Fenced code blocks are preserved exactly.
+pub fn canonicalize_whitespace(content: &str) -> String {
+  let mut fences = FenceTracker::default();
+  let mut lines = Vec::new();
+  let mut blank_run = 0;
+
+  for line in content.lines() {
+    if fences.is_code(line) {
+      blank_run = 0;
+      lines.push(line);
+      continue;
+    }
+
+    let line = line.trim_end();
+    if line.is_empty() {
+      blank_run += 1;
+      if blank_run > 2 {
+        continue;
+      }
+    } else {
+      blank_run = 0;
+    }
+    lines.push(line);
+  }
+
+  let mut canonical = lines.join("\n");
+  if content.ends_with('\n') {
+    canonical.push('\n');
+  }
+  canonical
+}
+
+/// Removes a leading YAML front-matter block: a first line of `---`, up to
+/// and including the next line of `---` or `...`, plus the blank lines after
+/// it.
+///
+/// Content without a terminated block is returned unchanged, so a document
+/// that merely starts with a `---` rule is left intact.
+pub fn strip_frontmatter(content: &str) -> &str {
+  let mut lines = content.split_inclusive('\n');
+  if lines.next().map(str::trim_end) != Some("---") {
+    return content;
+  }
+
+  let mut offset = content
+    .split_inclusive('\n')
+    .next()
+    .unwrap_or_default()
+    .len();
+  for line in lines {
+    offset += line.len();
+    if matches!(line.trim_end(), "---" | "...") {
+      return content[offset..].trim_start_matches(['\r', '\n']);
+    }
+  }
+
+  content
+}
+
+/// Returns the value of a top-level `key: value` entry in the leading YAML
+/// front-matter block, with surrounding quotes removed.
+///
+/// Returns `None` if there is no terminated block, the key is absent, or its
+/// value is empty.
+pub fn frontmatter_value(content: &str, key: &str) -> Option<String> {
+  let mut lines = content.lines();
+  if lines.next().map(str::trim_end) != Some("---") {
+    return None;
+  }
+
+  let mut value = None;
+  for line in lines {
+    if matches!(line.trim_end(), "---" | "...") {
+      return value;
+    }
+    if value.is_none() {
+      value = line
+        .strip_prefix(key)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .map(|rest| rest.trim().trim_matches(['"', '\'']).trim().to_string())
+        .filter(|rest| !rest.is_empty());
+    }
+  }
+
+  None
+}
+
+/// Returns the level and text of an ATX heading line such as `## Title`.
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+  let trimmed = line.trim_start_matches(' ');
+  if line.len() - trimmed.len() > 3 {
+    return None;
+  }
+
+  let level = trimmed.chars().take_while(|&c| c == '#').count();
+  let rest = &trimmed[level..];
+  if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with([' ', '\t'])) {
+    Some((level, rest.trim_start()))
+  } else {
+    None
+  }
+}
+
+/// Shifts headings outside of code fences down so the shallowest one sits at
+/// `top_level`, keeping their relative nesting. Levels are capped at 6, and
+/// content whose headings are already at or below `top_level` is unchanged.
+pub fn shift_headings(content: &str, top_level: usize) -> String {
+  let mut fences = FenceTracker::default();
+  let shallowest = content
+    .lines()
+    .filter(|line| !fences.is_code(line))
+    .filter_map(|line| parse_heading(line).map(|(level, _)| level))
+    .min();
+  let shift = match shallowest {
+    Some(level) if level < top_level => top_level - level,
+    _ => return content.to_string(),
+  };
+
+  let mut fences = FenceTracker::default();
+  let mut shifted: Vec<String> = content
+    .lines()
+    .map(|line| match (fences.is_code(line), parse_heading(line)) {
+      (false, Some((level, text))) => {
+        let hashes = "#".repeat((level + shift).min(6));
+        if text.is_empty() {
+          hashes
+        } else {
+          format!("{} {}", hashes, text)
+        }
+      }
+      _ => line.to_string(),
+    })
+    .collect();
+  if content.ends_with('\n') {
+    shifted.push(String::new());
+  }
+  shifted.join("\n")
+}
+
+/// Returns the GitHub-style anchor slug for a heading: lowercased, with
+/// punctuation other than `-` and `_` removed and spaces replaced by `-`.
+pub fn heading_slug(heading: &str) -> String {
+  heading
+    .trim()
+    .to_lowercase()
+    .chars()
+    .filter_map(|c| match c {
+      ' ' => Some('-'),
+      c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+      _ => None,
+    })
+    .collect()
+}
+
+/// Markdown renderer whose heading anchors generated links target.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnchorFlavor {
+  /// GitHub: punctuation removed, each space replaced by `-`.
+  #[default]
+  Github,
+  /// GitLab: like GitHub, with runs of `-` squeezed to one.
+  Gitlab,
+  /// CommonMark renderers using markdown-it-anchor: whitespace runs replaced
+  /// by `-`, punctuation kept and percent-encoded.
+  Commonmark,
+}
+
+impl AnchorFlavor {
+  /// Returns the anchor slug the renderer assigns to a heading.
+  pub fn slug(self, heading: &str) -> String {
+    match self {
+      Self::Github => heading_slug(heading),
+      Self::Gitlab => {
+        let mut slug = String::new();
+        for c in heading_slug(heading).chars() {
+          if !(c == '-' && slug.ends_with('-')) {
+            slug.push(c);
+          }
+        }
+        slug
+      }
+      Self::Commonmark => {
+        let lowered = heading.trim().to_lowercase();
+        let joined = lowered.split_whitespace().collect::<Vec<_>>().join("-");
+        let mut slug = String::new();
+        for c in joined.chars() {
+          if c.is_ascii_alphanumeric() || "-_.!~*'()".contains(c) {
+            slug.push(c);
+          } else {
+            for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+              slug.push_str(&format!("%{:02X}", byte));
+            }
+          }
+        }
+        slug
+      }
+    }
+  }
+
+  /// Returns the anchors of `headings` in order, suffixing repeated slugs
+  /// with `-1`, `-2`, ... as each renderer does for repeated headings.
+  pub fn anchors<'a>(self, headings: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut seen: Vec<String> = Vec::new();
+    let mut anchors = Vec::new();
+    for heading in headings {
+      let slug = self.slug(heading);
+      let repeats = seen.iter().filter(|s| **s == slug).count();
+      anchors.push(if repeats == 0 {
+        slug.clone()
+      } else {
+        format!("{}-{}", slug, repeats)
+      });
+      seen.push(slug);
+    }
+    anchors
+  }
+}
+
+/// Line endings of the written output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LineEnding {
+  /// Unix line endings (`\n`).
+  #[default]
+  Lf,
+  /// Windows line endings (`\r\n`).
+  Crlf,
+  /// Leave the line endings of each rule file as they are.
+  Keep,
+}
+
+/// Converts every line ending in `content` to `ending`.
+pub fn normalize_line_endings(content: &str, ending: LineEnding) -> String {
+  match ending {
+    LineEnding::Lf => content.replace("\r\n", "\n"),
+    LineEnding::Crlf => content.replace("\r\n", "\n").replace('\n', "\r\n"),
+    LineEnding::Keep => content.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fence_tracker() {
+    let mut fences = FenceTracker::default();
+    let flags: Vec<bool> = ["text", "````rust", "```", "code", "````", "after"]
+      .iter()
+      .map(|line| fences.is_code(line))
+      .collect();
+
+    // A shorter fence inside a longer one does not close it
+    assert_eq!(flags, vec![false, true, true, true, true, false]);
+  }
+
+  #[test]
+  fn test_canonicalize_whitespace() {
+    let content = "# Title   \n\n\n\n\nBody\t\n```\nlet x = 1;   \n\n\n\n\n```\nEnd\n";
+
+    let canonical = canonicalize_whitespace(content);
+
+    assert_eq!(
+      canonical,
+      "# Title\n\n\nBody\n```\nlet x = 1;   \n\n\n\n\n```\nEnd\n"
+    );
+  }
+
+  #[test]
+  fn test_canonicalize_whitespace_is_idempotent() {
+    let content = "a  \n\n\n\nb\n";
+
+    let once = canonicalize_whitespace(content);
+
+    assert_eq!(canonicalize_whitespace(&once), once);
+  }
+
+  #[test]
+  fn test_heading_slug() {
+    assert_eq!(heading_slug("lib-simple usage"), "lib-simple-usage");
+    assert_eq!(
+      heading_slug("serde_json v1.0.108 usage"),
+      "serde_json-v10108-usage"
+    );
+    assert_eq!(heading_slug("What's New?"), "whats-new");
+  }
+
+  #[test]
+  fn test_anchor_flavor_slugs() {
+    let heading = "tokio - async  usage (v1.0)";
+    assert_eq!(
+      AnchorFlavor::Github.slug(heading),
+      "tokio---async--usage-v10"
+    );
+    assert_eq!(AnchorFlavor::Gitlab.slug(heading), "tokio-async-usage-v10");
+    assert_eq!(
+      AnchorFlavor::Commonmark.slug(heading),
+      "tokio---async-usage-(v1.0)"
+    );
+    assert_eq!(
+      AnchorFlavor::Commonmark.slug("Café: tips"),
+      "caf%C3%A9%3A-tips"
+    );
+  }
+
+  #[test]
+  fn test_anchor_flavor_suffixes_repeated_headings() {
+    assert_eq!(
+      AnchorFlavor::Gitlab.anchors(["serde usage", "serde usage", "other", "serde usage"]),
+      vec!["serde-usage", "serde-usage-1", "other", "serde-usage-2"]
+    );
+  }
+
+  #[test]
+  fn test_strip_frontmatter() {
+    assert_eq!(
+      strip_frontmatter("---\ntitle: Async\ntags: [a, b]\n---\n\n# Async\n"),
+      "# Async\n"
+    );
+    assert_eq!(strip_frontmatter("---\r\nx: 1\r\n...\r\nBody"), "Body");
+    assert_eq!(
+      strip_frontmatter("# Title\n---\nx\n---\n"),
+      "# Title\n---\nx\n---\n"
+    );
+  }
+
+  #[test]
+  fn test_frontmatter_value() {
+    let content = "---\ntitle: Async\ncategory: \"async\"\nempty:\n---\n\ncategory: body\n";
+    assert_eq!(
+      frontmatter_value(content, "category"),
+      Some("async".to_string())
+    );
+    assert_eq!(
+      frontmatter_value(content, "title"),
+      Some("Async".to_string())
+    );
+    assert_eq!(frontmatter_value(content, "empty"), None);
+    assert_eq!(frontmatter_value(content, "missing"), None);
+    assert_eq!(
+      frontmatter_value("---\ncategory: async\n", "category"),
+      None
+    );
+    assert_eq!(
+      frontmatter_value("# Title\ncategory: async\n", "category"),
+      None
+    );
+  }
+
+  #[test]
+  fn test_strip_frontmatter_keeps_unterminated_block() {
+    let content = "---\n\nA document opening with a horizontal rule.\n";
+
+    assert_eq!(strip_frontmatter(content), content);
+  }
+
+  #[test]
+  fn test_shift_headings_respects_code_fences() {
+    let content = "# Title\nIntro\n## Part\n```sh\n# comment\n```\n#hashtag\n";
+
+    assert_eq!(
+      shift_headings(content, 3),
+      "### Title\nIntro\n#### Part\n```sh\n# comment\n```\n#hashtag\n"
+    );
+  }
+
+  #[test]
+  fn test_shift_headings_caps_level_and_keeps_deep_content() {
+    assert_eq!(shift_headings("# A\n##### B", 3), "### A\n###### B");
+    assert_eq!(shift_headings("#### Deep\n", 3), "#### Deep\n");
+  }
+
+  #[test]
+  fn test_normalize_line_endings() {
+    let mixed = "one\r\ntwo\nthree\r\n";
+
+    assert_eq!(
+      normalize_line_endings(mixed, LineEnding::Lf),
+      "one\ntwo\nthree\n"
+    );
+    assert_eq!(
+      normalize_line_endings(mixed, LineEnding::Crlf),
+      "one\r\ntwo\r\nthree\r\n"
+    );
+    assert_eq!(normalize_line_endings(mixed, LineEnding::Keep), mixed);
+  }
+}
@@ -1,32 +1,191 @@
-use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::{path::PathBuf, process::Command};
+use crate::warnings::{warn, WarningCategory};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeMap,
+  fs,
+  path::{Path, PathBuf},
+  process::Command,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dependency {
   pub name: String,
   pub version: String,
   pub path: PathBuf,
+  /// Conditions under which the root package uses this dependency, e.g.
+  /// "feature `rt` enabled". Empty for unconditional dependencies.
+  pub conditions: Vec<String>,
+  /// Where the package comes from, as reported by `cargo metadata`: a
+  /// `registry+` or `git+` URL, or None for a path dependency.
+  pub source: Option<String>,
+  /// The package's `repository` URL, if its manifest declares one.
+  pub repository: Option<String>,
+  /// Shortest distance from the root package in the resolved dependency
+  /// graph: 1 for a direct dependency, 2 for its dependencies, and so on.
+  pub depth: usize,
+  /// Main rules file declared by the package's
+  /// `[package.metadata.usage-rules] file` key, relative to `path`.
+  pub rules_file: Option<PathBuf>,
+}
+
+/// Which dependency sources `get_dependencies` returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceFilter {
+  /// Every dependency.
+  #[default]
+  All,
+  /// Only packages from a registry, such as crates.io.
+  Registry,
+  /// Only path dependencies.
+  Local,
+}
+
+impl SourceFilter {
+  /// Returns true if a package with the given `cargo metadata` source passes
+  /// the filter. Git dependencies only pass `All`.
+  fn matches(self, source: Option<&str>) -> bool {
+    match self {
+      SourceFilter::All => true,
+      SourceFilter::Registry => {
+        source.is_some_and(|s| s.starts_with("registry+") || s.starts_with("sparse+"))
+      }
+      SourceFilter::Local => source.is_none(),
+    }
+  }
+}
+
+/// A single entry of a dependencies file, pinning a package by name and
+/// optionally by exact version (`name@version`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyPin {
+  pub name: String,
+  pub version: Option<String>,
+}
+
+/// Project-level defaults read from the root package's
+/// `[package.metadata.usage-rules]` table. CLI flags take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestSettings {
+  pub remove: Option<Vec<String>>,
+  pub inline: Option<Vec<String>>,
+  pub linked: Option<bool>,
+  pub output: Option<PathBuf>,
+}
+
+/// Options controlling which packages `get_dependencies` returns.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataOptions {
+  /// Walk the full resolved dependency graph instead of only the root
+  /// package's direct dependencies.
+  pub transitive: bool,
+  /// Also consider the root package's dev-dependencies.
+  pub include_dev: bool,
+  /// Also consider the root package's build-dependencies.
+  pub include_build: bool,
+  /// Union the dependencies of every workspace member instead of only the
+  /// root package's.
+  pub workspace: bool,
+  /// Keep only dependencies from these sources.
+  pub source: SourceFilter,
+  /// Run cargo without network access.
+  pub offline: bool,
+  /// Manifest of the project to inspect instead of the one in the current
+  /// directory.
+  pub manifest_path: Option<PathBuf>,
+  /// Features to activate, passed to `cargo metadata` as `--features`.
+  pub features: Vec<String>,
+  /// Activate every feature of the selected packages.
+  pub all_features: bool,
+  /// Do not activate the `default` feature.
+  pub no_default_features: bool,
+}
+
+impl MetadataOptions {
+  /// Returns true if a dependency declared with `kind` should be selected.
+  fn includes_kind(&self, kind: Option<&str>) -> bool {
+    match kind {
+      None | Some("normal") => true,
+      Some("dev") => self.include_dev,
+      Some("build") => self.include_build,
+      _ => false,
+    }
+  }
+}
+
+/// The root package's dependencies along with its usage-rules settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectMetadata {
+  pub dependencies: Vec<Dependency>,
+  pub settings: ManifestSettings,
 }
 
 #[derive(Deserialize)]
 struct CargoMetadata {
   packages: Vec<Package>,
-  #[serde(rename = "workspace_members")]
-  _workspace_members: Vec<String>,
+  workspace_members: Vec<String>,
+  #[serde(default)]
+  resolve: Option<Resolve>,
+}
+
+#[derive(Deserialize)]
+struct Resolve {
+  nodes: Vec<ResolveNode>,
+}
+
+#[derive(Deserialize)]
+struct ResolveNode {
+  id: String,
+  dependencies: Vec<String>,
 }
 
 #[derive(Deserialize)]
 struct Package {
+  #[serde(default)]
+  id: String,
   name: String,
   version: String,
   manifest_path: String,
+  /// None for path dependencies and workspace members.
+  #[serde(default)]
+  source: Option<String>,
+  #[serde(default)]
+  repository: Option<String>,
   dependencies: Vec<PackageDependency>,
+  #[serde(default)]
+  features: BTreeMap<String, Vec<String>>,
+  #[serde(default)]
+  metadata: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize)]
 struct PackageDependency {
   name: String,
+  #[serde(default)]
+  rename: Option<String>,
+  #[serde(default)]
+  optional: bool,
+  #[serde(default)]
+  target: Option<String>,
+  /// "dev", "build", or None for a normal dependency.
+  #[serde(default)]
+  kind: Option<String>,
+}
+
+/// Builds a `cargo <subcommand>` invocation honoring `--manifest-path` and
+/// `--offline`.
+fn cargo_command(subcommand: &str, options: &MetadataOptions) -> Command {
+  let mut command = Command::new("cargo");
+  command.arg(subcommand);
+  if let Some(manifest_path) = &options.manifest_path {
+    command.arg("--manifest-path").arg(manifest_path);
+  }
+  if options.offline {
+    command.arg("--offline");
+  }
+  command
 }
 
 /// Fetches all dependencies for the current Rust project using `cargo fetch`.
@@ -34,6 +193,10 @@ struct PackageDependency {
 /// This ensures that all dependencies are downloaded and available in the local
 /// cargo cache before attempting to scan them for usage rules.
 ///
+/// # Arguments
+///
+/// * `options` - Selects the project manifest to fetch for
+///
 /// # Returns
 ///
 /// `Ok(())` if the fetch succeeds.
@@ -43,9 +206,8 @@ struct PackageDependency {
 /// Returns an error if:
 /// - The `cargo fetch` command fails to execute
 /// - The command exits with a non-zero status code
-pub fn fetch_dependencies() -> Result<()> {
-  let status = Command::new("cargo")
-    .arg("fetch")
+pub fn fetch_dependencies(options: &MetadataOptions) -> Result<()> {
+  let status = cargo_command("fetch", options)
     .status()
     .context("Failed to execute 'cargo fetch'")?;
 
@@ -59,22 +221,40 @@ pub fn fetch_dependencies() -> Result<()> {
 /// Retrieves metadata for all dependencies in the current Rust project.
 ///
 /// Uses `cargo metadata` to get information about all packages in the
-/// dependency graph, including their names, versions, and filesystem paths.
+/// dependency graph, including their names, versions, and filesystem paths,
+/// and reads the root package's `[package.metadata.usage-rules]` settings.
+///
+/// # Arguments
+///
+/// * `options` - Which packages of the dependency graph to return
 ///
 /// # Returns
 ///
-/// A vector of `Dependency` structs containing the name, version, and path for
-/// each package.
+/// A `ProjectMetadata` holding a `Dependency` (name, version, and path) for
+/// each package, and the root package's manifest settings.
 ///
 /// # Errors
 ///
 /// Returns an error if:
 /// - The `cargo metadata` command fails to execute
-/// - The command exits with a non-zero status code
+/// - The command, or the `cargo tree` call naming the root package, exits with
+///   a non-zero status code
 /// - The JSON output cannot be parsed
-pub fn get_dependencies() -> Result<Vec<Dependency>> {
-  let output = Command::new("cargo")
-    .args(["metadata", "--format-version", "1"])
+/// - The root package is not in the metadata
+/// - The `usage-rules` metadata table is malformed
+pub fn get_dependencies(options: &MetadataOptions) -> Result<ProjectMetadata> {
+  let mut command = cargo_command("metadata", options);
+  command.args(["--format-version", "1"]);
+  if !options.features.is_empty() {
+    command.arg("--features").arg(options.features.join(","));
+  }
+  if options.all_features {
+    command.arg("--all-features");
+  }
+  if options.no_default_features {
+    command.arg("--no-default-features");
+  }
+  let output = command
     .output()
     .context("Failed to execute 'cargo metadata'")?;
 
@@ -84,14 +264,18 @@ pub fn get_dependencies() -> Result<Vec<Dependency>> {
   }
 
   // Get all the top level dependencies of the current project.
-  let cargo_package_name_full = String::from_utf8(
-    Command::new("cargo")
-      .args(["tree", "--depth", "0", "--format", "{p}"])
-      .output()
-      .context("Failed to execute 'cargo pkgid'")?
-      .stdout,
-  )
-  .context("Failed to parse cargo tree output as utf-8")?;
+  let tree_output = cargo_command("tree", options)
+    .args(["--depth", "0", "--format", "{p}"])
+    .output()
+    .context("Failed to execute 'cargo tree'")?;
+  if !tree_output.status.success() {
+    anyhow::bail!(
+      "'cargo tree' failed: {}",
+      String::from_utf8_lossy(&tree_output.stderr)
+    );
+  }
+  let cargo_package_name_full =
+    String::from_utf8(tree_output.stdout).context("Failed to parse cargo tree output as utf-8")?;
 
   let cargo_package_name = cargo_package_name_full
     .trim()
@@ -99,41 +283,875 @@ pub fn get_dependencies() -> Result<Vec<Dependency>> {
     .next()
     .context("Cargo tree package output malformed")?;
 
-  let metadata: CargoMetadata =
-    serde_json::from_slice(&output.stdout).context("Failed to parse cargo metadata JSON")?;
+  let metadata =
+    parse_cargo_metadata(&output.stdout).context("Failed to parse cargo metadata JSON")?;
+
+  select_dependencies(&metadata, cargo_package_name, options)
+}
+
+/// Parses the output of `cargo metadata --format-version 1`.
+///
+/// # Errors
+///
+/// Returns an error if the output does not have the expected shape, naming
+/// the first package entry at fault and the problem with it.
+fn parse_cargo_metadata(json: &[u8]) -> Result<CargoMetadata> {
+  serde_json::from_slice(json).map_err(|e| match serde_json::from_slice(json) {
+    Ok(value) => anyhow!(locate_metadata_error(&value).unwrap_or_else(|| e.to_string())),
+    // Not JSON at all, so serde's position is the best there is
+    Err(_) => anyhow!(e),
+  })
+}
+
+/// Finds the part of otherwise valid `cargo metadata` JSON that does not
+/// match the expected shape, by parsing each package entry on its own.
+fn locate_metadata_error(value: &serde_json::Value) -> Option<String> {
+  for field in ["packages", "workspace_members"] {
+    if !value.get(field).is_some_and(serde_json::Value::is_array) {
+      return Some(format!(
+        "missing `{}` array; the cargo in use may not support --format-version 1",
+        field
+      ));
+    }
+  }
+
+  for (index, package) in value["packages"].as_array()?.iter().enumerate() {
+    let Err(e) = serde_json::from_value::<Package>(package.clone()) else {
+      continue;
+    };
+    let name = match package.get("name").and_then(serde_json::Value::as_str) {
+      Some(name) => format!("package '{}' (packages[{}])", name, index),
+      None => format!("package entry packages[{}]", index),
+    };
+    let dependency = package
+      .get("dependencies")
+      .and_then(serde_json::Value::as_array)
+      .into_iter()
+      .flatten()
+      .find_map(|dependency| {
+        let e = serde_json::from_value::<PackageDependency>(dependency.clone()).err()?;
+        let dependency_name = dependency
+          .get("name")
+          .and_then(serde_json::Value::as_str)
+          .unwrap_or("?");
+        Some(format!("dependency '{}': {}", dependency_name, e))
+      });
+    return Some(format!("{}: {}", name, dependency.unwrap_or(e.to_string())));
+  }
+
+  value
+    .get("resolve")
+    .filter(|resolve| !resolve.is_null())
+    .and_then(|resolve| serde_json::from_value::<Resolve>(resolve.clone()).err())
+    .map(|e| format!("`resolve` graph: {}", e))
+}
 
-  let package_dep_names: Vec<_> = metadata
+/// Picks the dependencies of the root package named `root_name`, or of every
+/// workspace member with `options.workspace`, out of the parsed metadata.
+fn select_dependencies(
+  metadata: &CargoMetadata,
+  root_name: &str,
+  options: &MetadataOptions,
+) -> Result<ProjectMetadata> {
+  let root_package = metadata
     .packages
     .iter()
-    .find(|pkg| pkg.name == cargo_package_name)
-    .context(format!(
-      "Cargo package name {cargo_package_name} not found in metadata"
-    ))?
-    .dependencies
-    .iter()
-    .map(|d| d.name.clone())
-    .collect();
+    .find(|pkg| pkg.name == root_name)
+    .with_context(|| {
+      format!(
+        "Package '{}' reported by 'cargo tree' is not in the 'cargo metadata' output; pass \
+         --manifest-path with the Cargo.toml of the package to sync{}",
+        root_name,
+        if options.workspace {
+          ""
+        } else {
+          ", or --workspace to sync every workspace member"
+        }
+      )
+    })?;
 
-  Ok(
+  let settings = parse_manifest_settings(root_package.metadata.as_ref())?;
+
+  let roots: Vec<&Package> = if options.workspace {
     metadata
       .packages
       .iter()
-      .filter_map(|p| {
-        if package_dep_names.contains(&p.name) {
-          let manifest_path = PathBuf::from(&p.manifest_path);
-          let path = manifest_path
-            .parent()
-            .expect("Failed to get package path")
-            .to_path_buf();
-          Some(Dependency {
-            name: p.name.clone(),
-            version: p.version.clone(),
-            path,
+      .filter(|p| metadata.workspace_members.contains(&p.id))
+      .collect()
+  } else {
+    vec![root_package]
+  };
+
+  let mut dependencies: Vec<Dependency> = Vec::new();
+  for root in roots {
+    for (p, depth) in root_dependencies(metadata, root, options)? {
+      if !options.source.matches(p.source.as_deref()) {
+        continue;
+      }
+      if let Some(existing) = dependencies
+        .iter_mut()
+        .find(|d| d.name == p.name && d.version == p.version)
+      {
+        existing.depth = existing.depth.min(depth);
+        continue;
+      }
+
+      let manifest_path = PathBuf::from(&p.manifest_path);
+      let path = manifest_path
+        .parent()
+        .expect("Failed to get package path")
+        .to_path_buf();
+      dependencies.push(Dependency {
+        name: p.name.clone(),
+        version: p.version.clone(),
+        path,
+        conditions: dependency_conditions(root, &p.name),
+        source: p.source.clone(),
+        repository: p.repository.clone(),
+        depth,
+        rules_file: declared_rules_file(p.metadata.as_ref()),
+      });
+    }
+  }
+
+  Ok(ProjectMetadata {
+    dependencies,
+    settings,
+  })
+}
+
+/// Returns the packages `root` depends on, directly or transitively as
+/// configured by `options`, each with its depth.
+///
+/// Only packages in the resolved dependency graph are returned, so optional
+/// dependencies whose feature is disabled are left out.
+fn root_dependencies<'a>(
+  metadata: &'a CargoMetadata,
+  root: &Package,
+  options: &MetadataOptions,
+) -> Result<Vec<(&'a Package, usize)>> {
+  let package_dep_names: Vec<&str> = root
+    .dependencies
+    .iter()
+    .filter(|d| options.includes_kind(d.kind.as_deref()))
+    .map(|d| d.name.as_str())
+    .collect();
+
+  if options.transitive {
+    transitive_packages(metadata, root, &package_dep_names)
+  } else {
+    let resolved = metadata
+      .resolve
+      .as_ref()
+      .and_then(|resolve| resolve.nodes.iter().find(|node| node.id == root.id));
+    Ok(
+      metadata
+        .packages
+        .iter()
+        .filter(|p| {
+          package_dep_names.contains(&p.name.as_str())
+            && resolved.is_none_or(|node| node.dependencies.contains(&p.id))
+        })
+        .map(|p| (p, 1))
+        .collect(),
+    )
+  }
+}
+
+/// Walks the resolved dependency graph breadth-first from `root`, returning
+/// every reachable package once per name (the first one found wins) with its
+/// shortest distance from `root`. Only the root's dependencies named in
+/// `direct_names` are followed.
+fn transitive_packages<'a>(
+  metadata: &'a CargoMetadata,
+  root: &Package,
+  direct_names: &[&str],
+) -> Result<Vec<(&'a Package, usize)>> {
+  let resolve = metadata
+    .resolve
+    .as_ref()
+    .context("'cargo metadata' did not include a resolved dependency graph")?;
+
+  let mut visited = vec![root.id.as_str()];
+  let mut queue = std::collections::VecDeque::from([(root.id.as_str(), 0)]);
+  let mut packages: Vec<(&Package, usize)> = Vec::new();
+
+  while let Some((id, depth)) = queue.pop_front() {
+    let Some(node) = resolve.nodes.iter().find(|n| n.id == id) else {
+      continue;
+    };
+
+    for dep_id in &node.dependencies {
+      let Some(package) = metadata.packages.iter().find(|p| &p.id == dep_id) else {
+        continue;
+      };
+      if visited.contains(&dep_id.as_str())
+        || (id == root.id && !direct_names.contains(&package.name.as_str()))
+      {
+        continue;
+      }
+      visited.push(dep_id);
+      queue.push_back((dep_id, depth + 1));
+
+      if !packages.iter().any(|(p, _)| p.name == package.name) {
+        packages.push((package, depth + 1));
+      }
+    }
+  }
+
+  Ok(packages)
+}
+
+/// Drops dependencies whose source is not on disk, warning about each one.
+///
+/// Used in offline mode, where packages that were never fetched cannot be
+/// downloaded.
+pub fn retain_available(dependencies: Vec<Dependency>) -> Vec<Dependency> {
+  dependencies
+    .into_iter()
+    .filter(|dep| {
+      let available = dep.path.is_dir();
+      if !available {
+        warn(
+          WarningCategory::MissingSource,
+          format_args!(
+            "skipping {} v{}: its source is not available offline ({})",
+            dep.name,
+            dep.version,
+            dep.path.display()
+          ),
+        );
+      }
+      available
+    })
+    .collect()
+}
+
+/// Describes the conditions under which `package` declares the dependency
+/// `dep_name`: the features that enable it when optional, and its target
+/// when platform-specific.
+fn dependency_conditions(package: &Package, dep_name: &str) -> Vec<String> {
+  let declarations: Vec<_> = package
+    .dependencies
+    .iter()
+    .filter(|d| d.name == dep_name)
+    .collect();
+
+  // Any unconditional declaration makes the dependency unconditional
+  if declarations
+    .iter()
+    .any(|d| !d.optional && d.target.is_none())
+  {
+    return Vec::new();
+  }
+
+  let mut conditions = Vec::new();
+  for declaration in declarations {
+    let local_name = declaration.rename.as_deref().unwrap_or(&declaration.name);
+    let mut parts = Vec::new();
+
+    if declaration.optional {
+      let features: Vec<_> = package
+        .features
+        .iter()
+        .filter(|(_, enables)| {
+          enables.iter().any(|e| {
+            e == local_name
+              || *e == format!("dep:{}", local_name)
+              || e.starts_with(&format!("{}/", local_name))
           })
-        } else {
-          None
-        }
-      })
+        })
+        .map(|(feature, _)| format!("`{}`", feature))
+        .collect();
+
+      if features.is_empty() {
+        parts.push(format!("feature `{}` enabled", local_name));
+      } else {
+        parts.push(format!("feature {} enabled", features.join(" or ")));
+      }
+    }
+
+    if let Some(target) = &declaration.target {
+      parts.push(format!("target `{}`", target));
+    }
+
+    let condition = parts.join(", ");
+    if !conditions.contains(&condition) {
+      conditions.push(condition);
+    }
+  }
+
+  conditions
+}
+
+/// Parses the `usage-rules` table out of a package's `metadata` value.
+fn parse_manifest_settings(metadata: Option<&serde_json::Value>) -> Result<ManifestSettings> {
+  match metadata.and_then(|m| m.get("usage-rules")) {
+    Some(table) => serde_json::from_value(table.clone())
+      .context("Failed to parse [package.metadata.usage-rules] in Cargo.toml"),
+    None => Ok(ManifestSettings::default()),
+  }
+}
+
+/// Reads the rules file a package declares in its
+/// `[package.metadata.usage-rules]` table, e.g. `file = "docs/agent.md"`.
+fn declared_rules_file(metadata: Option<&serde_json::Value>) -> Option<PathBuf> {
+  metadata?
+    .get("usage-rules")?
+    .get("file")?
+    .as_str()
+    .map(PathBuf::from)
+}
+
+/// Reads a dependencies file listing the exact packages to scan.
+///
+/// Each non-empty line holds a package name, optionally followed by
+/// `@version`. Lines starting with `#` are treated as comments.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or an entry is malformed.
+pub fn read_dependency_pins(path: &Path) -> Result<Vec<DependencyPin>> {
+  let content = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read dependencies file {}", path.display()))?;
+
+  let mut pins = Vec::new();
+  for (index, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let (name, version) = match line.split_once('@') {
+      Some((name, version)) => (name.trim(), Some(version.trim().to_string())),
+      None => (line, None),
+    };
+    if name.is_empty() || version.as_deref() == Some("") {
+      anyhow::bail!(
+        "Malformed entry '{}' on line {} of {}",
+        line,
+        index + 1,
+        path.display()
+      );
+    }
+
+    pins.push(DependencyPin {
+      name: name.to_string(),
+      version,
+    });
+  }
+
+  Ok(pins)
+}
+
+/// Restricts `dependencies` to exactly the pinned packages.
+///
+/// # Errors
+///
+/// Returns an error naming every pin that has no matching dependency in the
+/// resolved graph.
+pub fn restrict_to_pins(
+  dependencies: Vec<Dependency>,
+  pins: &[DependencyPin],
+  ignore_case: bool,
+) -> Result<Vec<Dependency>> {
+  let pin_matches = |pin: &DependencyPin, dep: &Dependency| {
+    let name_matches = if ignore_case {
+      pin.name.to_lowercase() == dep.name.to_lowercase()
+    } else {
+      pin.name == dep.name
+    };
+    name_matches && pin.version.as_ref().is_none_or(|v| *v == dep.version)
+  };
+
+  let missing: Vec<String> = pins
+    .iter()
+    .filter(|pin| !dependencies.iter().any(|dep| pin_matches(pin, dep)))
+    .map(|pin| match &pin.version {
+      Some(version) => format!("{}@{}", pin.name, version),
+      None => pin.name.clone(),
+    })
+    .collect();
+
+  if !missing.is_empty() {
+    anyhow::bail!(
+      "Pinned packages not found in the dependency graph: {}",
+      missing.join(", ")
+    );
+  }
+
+  Ok(
+    dependencies
+      .into_iter()
+      .filter(|dep| pins.iter().any(|pin| pin_matches(pin, dep)))
       .collect(),
   )
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  fn dependency(name: &str, version: &str) -> Dependency {
+    Dependency {
+      name: name.to_string(),
+      version: version.to_string(),
+      path: PathBuf::from(name),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    }
+  }
+
+  fn pin(name: &str, version: Option<&str>) -> DependencyPin {
+    DependencyPin {
+      name: name.to_string(),
+      version: version.map(str::to_string),
+    }
+  }
+
+  fn declared(name: &str, optional: bool, target: Option<&str>) -> PackageDependency {
+    PackageDependency {
+      name: name.to_string(),
+      rename: None,
+      optional,
+      kind: None,
+      target: target.map(str::to_string),
+    }
+  }
+
+  fn root_package(dependencies: Vec<PackageDependency>, features: &[(&str, &[&str])]) -> Package {
+    Package {
+      id: "root".to_string(),
+      name: "root".to_string(),
+      version: "0.1.0".to_string(),
+      manifest_path: "/root/Cargo.toml".to_string(),
+      source: None,
+      repository: None,
+      dependencies,
+      features: features
+        .iter()
+        .map(|(f, enables)| {
+          (
+            f.to_string(),
+            enables.iter().map(|e| e.to_string()).collect(),
+          )
+        })
+        .collect(),
+      metadata: None,
+    }
+  }
+
+  /// Builds metadata for a root package depending on `a` and `b`, which
+  /// both depend on different versions of `c`, with `t` as a dev-dependency
+  /// and `g` and `b` as build-dependencies. A second workspace member
+  /// depends on `a` and `c`.
+  fn diamond_metadata() -> CargoMetadata {
+    // Dependencies are given as `name` or `name:kind`
+    let package = |id: &str, deps: &[&str]| {
+      let (name, version) = id.split_once('@').unwrap();
+      let dependencies: Vec<_> = deps
+        .iter()
+        .map(|d| match d.split_once(':') {
+          Some((name, kind)) => serde_json::json!({"name": name, "kind": kind}),
+          None => serde_json::json!({"name": d, "kind": null}),
+        })
+        .collect();
+      serde_json::json!({
+        "id": id,
+        "name": name,
+        "version": version,
+        "manifest_path": format!("/registry/{}/Cargo.toml", id),
+        "dependencies": dependencies,
+      })
+    };
+    let node = |id: &str, deps: &[&str]| serde_json::json!({"id": id, "dependencies": deps});
+
+    serde_json::from_value(serde_json::json!({
+      "packages": [
+        package("root@0.1.0", &["a", "b", "t:dev", "b:build", "g:build"]),
+        package("a@1.0.0", &["c"]),
+        package("b@1.0.0", &["c"]),
+        package("c@1.0.0", &[]),
+        package("c@2.0.0", &[]),
+        package("t@1.0.0", &[]),
+        package("g@1.0.0", &[]),
+        package("member@0.1.0", &["a", "c"]),
+      ],
+      "workspace_members": ["root@0.1.0", "member@0.1.0"],
+      "resolve": {
+        "nodes": [
+          node("root@0.1.0", &["a@1.0.0", "b@1.0.0", "t@1.0.0", "g@1.0.0"]),
+          node("a@1.0.0", &["c@1.0.0"]),
+          node("b@1.0.0", &["c@2.0.0"]),
+          node("c@1.0.0", &[]),
+          node("c@2.0.0", &[]),
+          node("t@1.0.0", &[]),
+          node("g@1.0.0", &[]),
+          node("member@0.1.0", &["a@1.0.0", "c@2.0.0"]),
+        ]
+      }
+    }))
+    .unwrap()
+  }
+
+  fn names(project: &ProjectMetadata) -> Vec<String> {
+    project
+      .dependencies
+      .iter()
+      .map(|d| format!("{}@{}", d.name, d.version))
+      .collect()
+  }
+
+  #[test]
+  fn test_parse_cargo_metadata_names_malformed_package() {
+    let json = serde_json::json!({
+      "packages": [
+        {"name": "a", "version": "1.0.0", "manifest_path": "/a/Cargo.toml", "dependencies": []},
+        {"name": "b", "version": "1.0.0", "dependencies": []},
+      ],
+      "workspace_members": [],
+    });
+    let e = parse_cargo_metadata(json.to_string().as_bytes())
+      .err()
+      .unwrap();
+    assert_eq!(
+      e.to_string(),
+      "package 'b' (packages[1]): missing field `manifest_path`"
+    );
+
+    let json = serde_json::json!({
+      "packages": [{
+        "name": "a",
+        "version": "1.0.0",
+        "manifest_path": "/a/Cargo.toml",
+        "dependencies": [{"name": "c", "optional": "yes"}],
+      }],
+      "workspace_members": [],
+    });
+    let e = parse_cargo_metadata(json.to_string().as_bytes())
+      .err()
+      .unwrap();
+    assert!(e
+      .to_string()
+      .starts_with("package 'a' (packages[0]): dependency 'c': invalid type"));
+
+    let e = parse_cargo_metadata(br#"{"packages": []}"#).err().unwrap();
+    assert!(e
+      .to_string()
+      .starts_with("missing `workspace_members` array"));
+  }
+
+  #[test]
+  fn test_select_dependencies_missing_root_suggests_manifest_path() {
+    let e = select_dependencies(&diamond_metadata(), "other", &MetadataOptions::default())
+      .unwrap_err()
+      .to_string();
+    assert!(e.contains("--manifest-path"));
+    assert!(e.contains("--workspace"));
+  }
+
+  #[test]
+  fn test_select_dependencies_direct_only_by_default() {
+    let project =
+      select_dependencies(&diamond_metadata(), "root", &MetadataOptions::default()).unwrap();
+
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0"]);
+  }
+
+  #[test]
+  fn test_select_dependencies_transitive_dedupes_by_name() {
+    let options = MetadataOptions {
+      transitive: true,
+      ..MetadataOptions::default()
+    };
+
+    let project = select_dependencies(&diamond_metadata(), "root", &options).unwrap();
+
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0", "c@1.0.0"]);
+    let depths: Vec<usize> = project.dependencies.iter().map(|d| d.depth).collect();
+    assert_eq!(depths, vec![1, 1, 2]);
+  }
+
+  #[test]
+  fn test_select_dependencies_reads_declared_rules_file() {
+    let mut metadata = diamond_metadata();
+    metadata.packages[1].metadata =
+      Some(serde_json::json!({"usage-rules": {"file": "docs/agent.md"}}));
+
+    let project = select_dependencies(&metadata, "root", &MetadataOptions::default()).unwrap();
+
+    assert_eq!(
+      project.dependencies[0].rules_file,
+      Some(PathBuf::from("docs/agent.md"))
+    );
+    assert_eq!(project.dependencies[1].rules_file, None);
+  }
+
+  #[test]
+  fn test_select_dependencies_include_dev() {
+    let options = MetadataOptions {
+      include_dev: true,
+      ..MetadataOptions::default()
+    };
+
+    let project = select_dependencies(&diamond_metadata(), "root", &options).unwrap();
+
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0", "t@1.0.0"]);
+  }
+
+  #[test]
+  fn test_select_dependencies_workspace_unions_members() {
+    let options = MetadataOptions {
+      workspace: true,
+      ..MetadataOptions::default()
+    };
+
+    let project = select_dependencies(&diamond_metadata(), "root", &options).unwrap();
+
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0", "c@2.0.0"]);
+  }
+
+  #[test]
+  fn test_select_dependencies_skips_unresolved_optional_dependencies() {
+    let mut metadata = diamond_metadata();
+    let optional: Package = serde_json::from_value(serde_json::json!({
+      "id": "o@1.0.0",
+      "name": "o",
+      "version": "1.0.0",
+      "manifest_path": "/registry/o@1.0.0/Cargo.toml",
+      "dependencies": [],
+    }))
+    .unwrap();
+    metadata.packages[0]
+      .dependencies
+      .push(declared("o", true, None));
+    metadata.packages.push(optional);
+
+    let project = select_dependencies(&metadata, "root", &MetadataOptions::default()).unwrap();
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0"]);
+
+    // Enabling the feature adds the package to the resolved graph
+    let resolve = metadata.resolve.as_mut().unwrap();
+    resolve.nodes[0].dependencies.push("o@1.0.0".to_string());
+
+    let project = select_dependencies(&metadata, "root", &MetadataOptions::default()).unwrap();
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0", "o@1.0.0"]);
+  }
+
+  #[test]
+  fn test_select_dependencies_include_build_lists_shared_crate_once() {
+    let options = MetadataOptions {
+      include_build: true,
+      ..MetadataOptions::default()
+    };
+
+    let project = select_dependencies(&diamond_metadata(), "root", &options).unwrap();
+    assert_eq!(names(&project), vec!["a@1.0.0", "b@1.0.0", "g@1.0.0"]);
+
+    let options = MetadataOptions {
+      transitive: true,
+      include_build: true,
+      ..MetadataOptions::default()
+    };
+
+    let project = select_dependencies(&diamond_metadata(), "root", &options).unwrap();
+    assert_eq!(
+      names(&project),
+      vec!["a@1.0.0", "b@1.0.0", "g@1.0.0", "c@1.0.0"]
+    );
+  }
+
+  #[test]
+  fn test_select_dependencies_filters_by_source() {
+    let mut metadata = diamond_metadata();
+    for package in &mut metadata.packages {
+      if package.name == "a" {
+        package.source = Some("registry+https://github.com/rust-lang/crates.io-index".to_string());
+      }
+    }
+
+    let options = MetadataOptions {
+      source: SourceFilter::Registry,
+      ..MetadataOptions::default()
+    };
+    let project = select_dependencies(&metadata, "root", &options).unwrap();
+    assert_eq!(names(&project), vec!["a@1.0.0"]);
+    assert!(project.dependencies[0].source.is_some());
+
+    let options = MetadataOptions {
+      source: SourceFilter::Local,
+      ..MetadataOptions::default()
+    };
+    let project = select_dependencies(&metadata, "root", &options).unwrap();
+    assert_eq!(names(&project), vec!["b@1.0.0"]);
+  }
+
+  #[test]
+  fn test_source_filter_excludes_git_from_registry_and_local() {
+    let git = Some("git+https://github.com/org/repo#abc123");
+
+    assert!(SourceFilter::All.matches(git));
+    assert!(!SourceFilter::Registry.matches(git));
+    assert!(!SourceFilter::Local.matches(git));
+    assert!(SourceFilter::Registry.matches(Some("sparse+https://index.crates.io/")));
+  }
+
+  #[test]
+  fn test_retain_available_drops_missing_sources() {
+    let temp = TempDir::new().unwrap();
+    let dependency = |name: &str, path: PathBuf| Dependency {
+      name: name.to_string(),
+      version: "1.0.0".to_string(),
+      path,
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    };
+
+    let available = retain_available(vec![
+      dependency("present", temp.path().to_path_buf()),
+      dependency("absent", temp.path().join("never-fetched")),
+    ]);
+
+    assert_eq!(available.len(), 1);
+    assert_eq!(available[0].name, "present");
+  }
+
+  #[test]
+  fn test_dependency_conditions_feature_gated() {
+    let package = root_package(
+      vec![declared("tokio", true, None)],
+      &[("rt", &["dep:tokio", "tokio/rt"]), ("default", &[])],
+    );
+
+    assert_eq!(
+      dependency_conditions(&package, "tokio"),
+      vec!["feature `rt` enabled"]
+    );
+  }
+
+  #[test]
+  fn test_dependency_conditions_target_and_implicit_feature() {
+    let package = root_package(vec![declared("nix", true, Some("cfg(unix)"))], &[]);
+
+    assert_eq!(
+      dependency_conditions(&package, "nix"),
+      vec!["feature `nix` enabled, target `cfg(unix)`"]
+    );
+  }
+
+  #[test]
+  fn test_dependency_conditions_unconditional() {
+    let package = root_package(
+      vec![
+        declared("serde", false, None),
+        declared("serde", true, None),
+      ],
+      &[("serde", &["dep:serde"])],
+    );
+
+    assert!(dependency_conditions(&package, "serde").is_empty());
+  }
+
+  #[test]
+  fn test_parse_manifest_settings() {
+    let metadata = serde_json::json!({
+      "usage-rules": {
+        "remove": ["old-crate"],
+        "linked": false,
+        "output": "CLAUDE.md"
+      },
+      "other-tool": { "enabled": true }
+    });
+
+    let settings = parse_manifest_settings(Some(&metadata)).unwrap();
+
+    assert_eq!(
+      settings,
+      ManifestSettings {
+        remove: Some(vec!["old-crate".to_string()]),
+        inline: None,
+        linked: Some(false),
+        output: Some(PathBuf::from("CLAUDE.md")),
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_manifest_settings_missing_table() {
+    let metadata = serde_json::json!({ "other-tool": {} });
+
+    assert_eq!(
+      parse_manifest_settings(Some(&metadata)).unwrap(),
+      ManifestSettings::default()
+    );
+    assert_eq!(
+      parse_manifest_settings(None).unwrap(),
+      ManifestSettings::default()
+    );
+  }
+
+  #[test]
+  fn test_parse_manifest_settings_rejects_wrong_types() {
+    let metadata = serde_json::json!({ "usage-rules": { "linked": "yes" } });
+
+    assert!(parse_manifest_settings(Some(&metadata)).is_err());
+  }
+
+  #[test]
+  fn test_read_dependency_pins() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join("usage-rules.deps");
+    fs::write(&path, "# pinned crates\nserde\n\ntokio@1.40.0\n").unwrap();
+
+    let pins = read_dependency_pins(&path).unwrap();
+
+    assert_eq!(pins, vec![pin("serde", None), pin("tokio", Some("1.40.0"))]);
+  }
+
+  #[test]
+  fn test_read_dependency_pins_rejects_empty_version() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join("usage-rules.deps");
+    fs::write(&path, "serde@\n").unwrap();
+
+    assert!(read_dependency_pins(&path).is_err());
+  }
+
+  #[test]
+  fn test_restrict_to_pins_keeps_only_pinned() {
+    let deps = vec![
+      dependency("serde", "1.0.0"),
+      dependency("tokio", "1.40.0"),
+      dependency("clap", "4.0.0"),
+    ];
+    let pins = [pin("serde", None), pin("tokio", Some("1.40.0"))];
+
+    let result = restrict_to_pins(deps, &pins, false).unwrap();
+
+    let names: Vec<_> = result.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, vec!["serde", "tokio"]);
+  }
+
+  #[test]
+  fn test_restrict_to_pins_errors_on_missing_pin() {
+    let deps = vec![dependency("serde", "1.0.0"), dependency("tokio", "1.40.0")];
+    let pins = [
+      pin("serde", None),
+      pin("tokio", Some("0.2.0")),
+      pin("rand", None),
+    ];
+
+    let err = restrict_to_pins(deps, &pins, false).unwrap_err();
+
+    assert!(err.to_string().contains("tokio@0.2.0"));
+    assert!(err.to_string().contains("rand"));
+    assert!(!err.to_string().contains("serde"));
+  }
+}
@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Compiles glob `patterns` into a set matching any of them.
+///
+/// `*` and `?` do not match `/`, while `**` matches any number of path
+/// segments, so `patterns/**` matches `patterns/builder`.
+///
+/// # Errors
+///
+/// Returns an error naming the first invalid pattern.
+pub fn compile_globs(patterns: &[String]) -> Result<GlobSet> {
+  let mut builder = GlobSetBuilder::new();
+  for pattern in patterns {
+    builder.add(
+      GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid glob pattern '{}'", pattern))?,
+    );
+  }
+  builder.build().context("Failed to compile glob patterns")
+}
+
+/// Characters that make a package name a glob pattern.
+const GLOB_METACHARACTERS: [char; 4] = ['*', '?', '[', '{'];
+
+/// Returns true if `pattern` refers to the package `name`: by glob match if it
+/// contains glob metacharacters (e.g. `tokio-*`), otherwise by exact name.
+/// Invalid globs are compared as exact names.
+pub fn name_matches(pattern: &str, name: &str, ignore_case: bool) -> bool {
+  if pattern.contains(GLOB_METACHARACTERS) {
+    if let Ok(glob) = GlobBuilder::new(pattern)
+      .case_insensitive(ignore_case)
+      .build()
+    {
+      return glob.compile_matcher().is_match(name);
+    }
+  }
+  if ignore_case {
+    pattern.to_lowercase() == name.to_lowercase()
+  } else {
+    pattern == name
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_compile_globs_matches_nested_paths() {
+    let globs = compile_globs(&["async*".to_string(), "patterns/**".to_string()]).unwrap();
+
+    assert!(globs.is_match("async"));
+    assert!(globs.is_match("async_io"));
+    assert!(globs.is_match("patterns/builder"));
+    assert!(globs.is_match("patterns/nested/deep"));
+    assert!(!globs.is_match("builder"));
+    assert!(!globs.is_match("other/async"));
+
+    assert!(compile_globs(&["[".to_string()]).is_err());
+  }
+
+  #[test]
+  fn test_name_matches_globs_and_exact_names() {
+    assert!(name_matches("tokio-*", "tokio-util", false));
+    assert!(name_matches("tokio-*", "tokio-macros", false));
+    assert!(!name_matches("tokio-*", "tokio", false));
+    assert!(name_matches("Tokio-*", "tokio-util", true));
+    assert!(!name_matches("Tokio-*", "tokio-util", false));
+
+    assert!(name_matches("serde", "serde", false));
+    assert!(!name_matches("serde", "serde_json", false));
+    assert!(name_matches("Serde", "serde", true));
+  }
+}
@@ -0,0 +1,976 @@
+use crate::{
+  aggregator::{
+    aggregate_content, extract_agents_md_preamble, extract_surrounding_content, mark_duplicates,
+    read_generated_block, remove_blank_packages, section_heading, sort_usage_rules,
+    split_package_sections, validate_output_markers, ExistingContent, PackageContent,
+    PackageContentInfo, SelectionLog, SelectionOptions, SortOrder,
+  },
+  cache::MetadataCache,
+  links::remove_escaping_files,
+  lint::lint_crate_refs,
+  lockfile::{find_lock_file, lock_changes_since, LockChanges},
+  logger,
+  metadata::{
+    fetch_dependencies, get_dependencies, read_dependency_pins, restrict_to_pins, retain_available,
+    Dependency, MetadataOptions, ProjectMetadata,
+  },
+  scanner::{remove_unreadable_files, scan_dependencies, ScanOptions, SkippedFiles, UsageRules},
+  warnings::{warn, WarningCategory},
+  writer::{
+    InlineWriter, JsonWriter, LayoutBlock, LinkedWriter, OutputFormat, OutputPlan, OutputWriter,
+    Preset, RenderOptions, WriteReport,
+  },
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashMap,
+  fmt,
+  path::{Path, PathBuf},
+};
+
+/// What relative output and link folder paths are resolved against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputRelativeTo {
+  /// The current directory.
+  Cwd,
+  /// The directory of `--manifest-path` if given, else the current
+  /// directory.
+  #[default]
+  Manifest,
+}
+
+impl OutputRelativeTo {
+  /// Resolves `path` against the base this choice selects, given the
+  /// `--manifest-path` if any. Absolute paths are returned unchanged.
+  pub fn resolve(self, path: &Path, manifest_path: Option<&Path>) -> PathBuf {
+    match (self, manifest_path.and_then(Path::parent)) {
+      (Self::Manifest, Some(dir)) if path.is_relative() => dir.join(path),
+      _ => path.to_path_buf(),
+    }
+  }
+}
+
+/// Settings for [`sync`], mirroring the flags of `cargo usage-rules sync`.
+#[derive(Debug)]
+pub struct SyncOptions {
+  /// Dependency graph settings.
+  pub metadata: MetadataOptions,
+  /// Cache of the dependency graph, if enabled.
+  pub metadata_cache: Option<MetadataCache>,
+  /// Dependency scan settings.
+  pub scan: ScanOptions,
+  /// Restricts the scan to the dependencies pinned in this file.
+  pub dependencies_file: Option<PathBuf>,
+  /// Packages included, removed and inlined.
+  pub selection: SelectionOptions,
+  /// Order of the package sections.
+  pub sort: SortOrder,
+  /// Skip unreadable rule files instead of failing.
+  pub keep_going: bool,
+  /// Warn about crates referenced by the rules that are not dependencies.
+  pub lint_crate_refs: bool,
+  /// Drop rule files with links escaping their package folder.
+  pub no_escape_links: bool,
+  /// Replace repeated rules with a reference to their first occurrence.
+  pub dedupe: bool,
+  /// Keep sections of packages whose rule files are all blank.
+  pub keep_empty: bool,
+  /// Write the output even if no package has usage rules.
+  pub all: bool,
+  /// The output file.
+  pub output: PathBuf,
+  /// Further files receiving the same generated section, each keeping its
+  /// own preamble. In linked mode they share `link_folder`.
+  pub additional_outputs: Vec<PathBuf>,
+  /// File whose generated section is replaced instead of `output`, if any.
+  pub merge_into: Option<PathBuf>,
+  /// Copy each package's rules to `link_folder` and link to them.
+  pub linked: bool,
+  /// Folder receiving the package files in linked mode.
+  pub link_folder: PathBuf,
+  /// Format of the output; `linked` only applies to markdown.
+  pub format: OutputFormat,
+  /// Whether malformed section markers in the existing output are an error.
+  pub strict_markers: bool,
+  /// Whether to overwrite an output file whose section markers are malformed
+  /// instead of keeping its content.
+  pub force: bool,
+  /// Git revision whose `Cargo.lock` is compared against; only packages
+  /// whose locked version changed since are rendered again.
+  pub since: Option<String>,
+  /// Rendering settings.
+  pub render: RenderOptions,
+}
+
+impl Default for SyncOptions {
+  fn default() -> Self {
+    Self {
+      metadata: MetadataOptions::default(),
+      metadata_cache: None,
+      scan: ScanOptions::default(),
+      dependencies_file: None,
+      selection: SelectionOptions::default(),
+      sort: SortOrder::default(),
+      keep_going: false,
+      lint_crate_refs: false,
+      no_escape_links: false,
+      dedupe: false,
+      keep_empty: false,
+      all: false,
+      output: Preset::default().default_output(),
+      additional_outputs: Vec::new(),
+      merge_into: None,
+      linked: true,
+      link_folder: PathBuf::from("usage_rules"),
+      format: OutputFormat::default(),
+      strict_markers: false,
+      force: false,
+      since: None,
+      render: RenderOptions::default(),
+    }
+  }
+}
+
+impl SyncOptions {
+  /// The file the generated section is written to.
+  pub fn target(&self) -> &Path {
+    self.merge_into.as_deref().unwrap_or(&self.output)
+  }
+}
+
+/// The packages selected for output.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+  /// The selected packages, in output order.
+  pub packages: Vec<PackageContentInfo>,
+  /// Why each discovered package was or was not selected.
+  pub log: SelectionLog,
+  /// Rule files dropped because they could not be read.
+  pub skipped: SkippedFiles,
+  /// Counts gathered while selecting, with `bytes_written` still zero.
+  pub stats: SyncStats,
+}
+
+/// Counts summarizing a sync, printed by `--stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SyncStats {
+  /// Dependencies scanned for usage rules.
+  pub dependencies_scanned: usize,
+  /// Scanned dependencies that have usage rules.
+  pub with_usage_rules: usize,
+  /// Sub-files of the selected packages.
+  pub sub_files: usize,
+  /// Packages excluded by `--remove`.
+  pub removed: usize,
+  /// Total size of the output files.
+  pub bytes_written: usize,
+}
+
+impl fmt::Display for SyncStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Summary:")?;
+    writeln!(f, "  dependencies scanned: {}", self.dependencies_scanned)?;
+    writeln!(f, "  with usage rules:     {}", self.with_usage_rules)?;
+    writeln!(f, "  sub-files included:   {}", self.sub_files)?;
+    writeln!(f, "  removed by --remove:  {}", self.removed)?;
+    write!(f, "  bytes written:        {}", self.bytes_written)
+  }
+}
+
+/// The outcome of [`sync`].
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+  /// Labels of the packages written to the output.
+  pub packages: Vec<String>,
+  /// Why each discovered package was or was not selected.
+  pub selection: SelectionLog,
+  /// Rule files dropped because they could not be read.
+  pub skipped: SkippedFiles,
+  /// Counts summarizing the sync.
+  pub stats: SyncStats,
+  /// Files written and left unchanged. Empty if no package was selected and
+  /// `all` is not set.
+  pub files: WriteReport,
+}
+
+/// Downloads the project's dependencies with `cargo fetch`, unless
+/// `options.offline` is set.
+///
+/// # Errors
+///
+/// Returns an error if `cargo fetch` fails.
+pub fn fetch(options: &MetadataOptions) -> Result<()> {
+  if !options.offline {
+    logger::progress("Fetching dependencies...");
+    fetch_dependencies(options).context("Failed to fetch dependencies with 'cargo fetch'")?;
+  }
+  Ok(())
+}
+
+/// The dependencies scanned for usage rules and what the scan found.
+#[derive(Debug, Clone, Default)]
+pub struct Discovery {
+  /// The scanned dependencies.
+  pub scanned: Vec<Dependency>,
+  /// Usage rules of the scanned dependencies that have them, in order.
+  pub usage_rules: Vec<UsageRules>,
+  /// Scanned dependencies without usage rules.
+  pub missing: Vec<Dependency>,
+}
+
+/// Returns the dependencies a sync scans: every dependency, or only those
+/// pinned by `options.dependencies_file`.
+///
+/// # Errors
+///
+/// Returns an error if the dependencies file cannot be read or pins a
+/// package that is not a dependency.
+pub fn scanned_dependencies(
+  dependencies: &[Dependency],
+  options: &SyncOptions,
+) -> Result<Vec<Dependency>> {
+  match &options.dependencies_file {
+    Some(path) => {
+      let pins = read_dependency_pins(path)?;
+      restrict_to_pins(dependencies.to_vec(), &pins, options.selection.ignore_case)
+    }
+    None => Ok(dependencies.to_vec()),
+  }
+}
+
+/// Scans `scanned` for usage rules.
+///
+/// # Errors
+///
+/// Returns an error if scanning a dependency fails.
+pub fn discover(scanned: Vec<Dependency>, options: &ScanOptions) -> Result<Discovery> {
+  logger::progress("Scanning for usage-rules.md files...");
+  let results = scan_dependencies(&scanned, options).context("Failed to scan for usage rules")?;
+  if let Some(cache) = &options.cache {
+    logger::progress(format_args!(
+      "Scan cache: {} hits, {} misses",
+      cache.hits(),
+      cache.misses()
+    ));
+  }
+
+  let mut discovery = Discovery::default();
+  for (dependency, rules) in scanned.iter().zip(results) {
+    match rules {
+      Some(rules) => discovery.usage_rules.push(rules),
+      None => discovery.missing.push(dependency.clone()),
+    }
+  }
+  discovery.scanned = scanned;
+  Ok(discovery)
+}
+
+/// Reads the dependency graph, from `cache` if given and up to date, keeping
+/// only dependencies whose sources are available when offline.
+///
+/// # Errors
+///
+/// Returns an error if `cargo metadata` fails or its output cannot be parsed.
+pub fn load_dependencies(
+  options: &MetadataOptions,
+  cache: Option<&MetadataCache>,
+) -> Result<ProjectMetadata> {
+  logger::progress("Reading dependency metadata...");
+  let load = || get_dependencies(options).context("Failed to get dependency metadata");
+  let mut metadata = match cache {
+    Some(cache) => cache.get_or_load(options, load)?,
+    None => load()?,
+  };
+  if options.offline {
+    metadata.dependencies = retain_available(metadata.dependencies);
+  }
+  Ok(metadata)
+}
+
+/// Applies the sync selection steps to scanned usage rules: dropping
+/// unreadable and escaping files, sorting, and filtering by name.
+///
+/// # Arguments
+///
+/// * `usage_rules` - Usage rules found in `scanned`
+/// * `dependencies` - Every dependency of the project
+/// * `scanned` - The dependencies that were scanned
+/// * `options` - Sync settings
+///
+/// # Errors
+///
+/// Returns an error if a rule file cannot be read, unless `keep_going` is set.
+pub fn select_packages(
+  usage_rules: Vec<UsageRules>,
+  dependencies: &[Dependency],
+  scanned: &[Dependency],
+  options: &SyncOptions,
+) -> Result<Selection> {
+  let strict_encoding = options.render.section.content.strict_encoding;
+  let mut stats = SyncStats {
+    dependencies_scanned: scanned.len(),
+    with_usage_rules: usage_rules.len(),
+    ..SyncStats::default()
+  };
+  let mut log = SelectionLog::new(dependencies.iter().map(|d| d.name.as_str()));
+  log.record_step(
+    scanned.iter().map(|d| d.name.as_str()),
+    "not listed in --dependencies-file",
+  );
+  log.record_step(
+    usage_rules.iter().map(|r| r.package_name.as_str()),
+    "no usage-rules.md",
+  );
+
+  let (usage_rules, skipped) = if options.keep_going {
+    remove_unreadable_files(usage_rules, strict_encoding)
+  } else {
+    (usage_rules, SkippedFiles::default())
+  };
+  log.record_step(
+    usage_rules.iter().map(|r| r.package_name.as_str()),
+    "usage-rules.md cannot be read (--keep-going)",
+  );
+
+  logger::progress(format_args!(
+    "Found {} packages with usage rules:",
+    usage_rules.len()
+  ));
+  for rule in &usage_rules {
+    logger::progress(format_args!(
+      "  - {} v{}",
+      rule.package_name, rule.package_version
+    ));
+  }
+
+  if options.lint_crate_refs {
+    for warning in lint_crate_refs(&usage_rules, dependencies, strict_encoding)
+      .context("Failed to lint crate references")?
+    {
+      warn(
+        WarningCategory::UnknownCrateRefs,
+        format_args!(
+          "{} usage rules ({}) reference crate `{}`, which is not a dependency",
+          warning.package_name,
+          warning.file.display(),
+          warning.crate_name
+        ),
+      );
+    }
+  }
+
+  let mut usage_rules = if options.no_escape_links {
+    remove_escaping_files(usage_rules, strict_encoding)
+      .context("Failed to check usage rules for escaping links")?
+  } else {
+    usage_rules
+  };
+  log.record_step(
+    usage_rules.iter().map(|r| r.package_name.as_str()),
+    "links escape the package folder (--no-escape-links)",
+  );
+  sort_usage_rules(&mut usage_rules, options.sort);
+
+  logger::progress("\nAggregating content...");
+  log.record_step(
+    usage_rules
+      .iter()
+      .map(|r| r.package_name.as_str())
+      .filter(|name| options.selection.is_included(name)),
+    "not listed in --include",
+  );
+  log.record_step(
+    usage_rules
+      .iter()
+      .filter(|r| {
+        options
+          .selection
+          .version_allowed(&r.package_name, &r.package_version)
+      })
+      .map(|r| r.package_name.as_str()),
+    "excluded by --version-filter",
+  );
+  let included = usage_rules
+    .iter()
+    .filter(|r| {
+      options.selection.is_included(&r.package_name)
+        && options
+          .selection
+          .version_allowed(&r.package_name, &r.package_version)
+    })
+    .count();
+  let mut packages =
+    aggregate_content(usage_rules, &options.selection).context("Failed to aggregate content")?;
+  stats.removed = included - packages.len();
+  stats.sub_files = packages.iter().map(|p| p.content.sub_files.len()).sum();
+  log.record_step(
+    packages.iter().map(|p| p.name.as_str()),
+    "removed by --remove",
+  );
+
+  if !options.keep_empty {
+    packages = remove_blank_packages(packages, &options.render.section.content)
+      .context("Failed to check usage rules for content")?;
+  }
+  log.record_step(
+    packages.iter().map(|p| p.name.as_str()),
+    "usage rules are empty (--keep-empty keeps them)",
+  );
+
+  if options.dedupe {
+    mark_duplicates(&mut packages, &options.render.section.content)
+      .context("Failed to deduplicate usage rules")?;
+  }
+
+  Ok(Selection {
+    packages,
+    log,
+    skipped,
+    stats,
+  })
+}
+
+/// Reads the content around the generated section of the sync target.
+///
+/// With `force`, an output file whose markers are malformed is overwritten:
+/// none of its content is kept.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be read or decoded, or if its
+/// markers are malformed and `strict_markers` is set.
+pub fn existing_content(options: &SyncOptions) -> Result<ExistingContent> {
+  match &options.merge_into {
+    Some(target) => extract_surrounding_content(
+      target,
+      options.render.encoding,
+      &options.render.markers,
+      options.strict_markers,
+    )
+    .context("Failed to read merge target"),
+    None => output_preamble(options, &options.output),
+  }
+}
+
+/// Reads the preamble and footer of the output file `output`.
+fn output_preamble(options: &SyncOptions, output: &Path) -> Result<ExistingContent> {
+  let encoding = options.render.encoding;
+  let markers = &options.render.markers;
+  if let Some(e) = validate_output_markers(output, encoding, markers)
+    .err()
+    .filter(|_| options.force)
+  {
+    logger::detail(format_args!("{:#}; overwriting it (--force)", e));
+    return Ok(ExistingContent::Preamble {
+      preamble: String::new(),
+      footer: String::new(),
+    });
+  }
+  let (preamble, footer) =
+    extract_agents_md_preamble(output, encoding, markers, options.strict_markers).with_context(
+      || {
+        format!(
+          "Failed to merge with existing content of {}",
+          output.display()
+        )
+      },
+    )?;
+  Ok(ExistingContent::Preamble { preamble, footer })
+}
+
+/// Plans the files a sync of `packages` writes around the existing content
+/// of the target, without touching the filesystem.
+///
+/// # Errors
+///
+/// Returns an error if the existing output cannot be read or the output
+/// cannot be rendered.
+pub fn plan_sync(options: &SyncOptions, packages: Vec<PackageContentInfo>) -> Result<OutputPlan> {
+  let existing = existing_content(options)?;
+  plan_output(options, packages, existing)
+}
+
+/// Plans the files a sync writes without touching the filesystem.
+///
+/// `existing` is the content kept around the generated section of the
+/// target. Each of `additional_outputs` gets the same section around its own
+/// preamble; they are planned just before the target, which stays last.
+///
+/// # Errors
+///
+/// Returns an error if the output cannot be rendered, an additional output's
+/// preamble cannot be read, or the lock file changes since `since` cannot be
+/// determined.
+pub fn plan_output(
+  options: &SyncOptions,
+  packages: Vec<PackageContentInfo>,
+  existing: ExistingContent,
+) -> Result<OutputPlan> {
+  let changes = match &options.since {
+    Some(git_ref) => {
+      let lock_path = find_lock_file(options.metadata.manifest_path.as_deref())?;
+      let changes = lock_changes_since(&lock_path, git_ref)
+        .with_context(|| format!("Failed to compare Cargo.lock against '{}'", git_ref))?;
+      logger::detail(format_args!(
+        "{} packages changed in Cargo.lock since '{}'",
+        changes.changed.len(),
+        git_ref
+      ));
+      Some(changes)
+    }
+    None => None,
+  };
+  let changes = changes.as_ref();
+
+  let mut plan = plan_target(
+    options,
+    options.target(),
+    packages.clone(),
+    existing,
+    changes,
+  )?;
+  for output in &options.additional_outputs {
+    let existing = output_preamble(options, output)?;
+    // Linked files are shared, so only the main file of each output is new
+    let mut output_plan = plan_target(options, output, packages.clone(), existing, changes)?;
+    if let Some(main_file) = output_plan.files.pop() {
+      plan.files.insert(plan.files.len() - 1, main_file);
+    }
+  }
+  Ok(plan)
+}
+
+/// Returns the writer rendering the output in the configured format.
+pub fn output_writer(options: &SyncOptions) -> Box<dyn OutputWriter> {
+  if options.format == OutputFormat::Json {
+    Box::new(JsonWriter)
+  } else if options.linked {
+    Box::new(LinkedWriter {
+      folder_path: options.link_folder.clone(),
+    })
+  } else {
+    Box::new(InlineWriter)
+  }
+}
+
+/// Plans the files written for the output file `output`. With `changes`,
+/// the sections of unchanged packages are kept from its existing content.
+fn plan_target(
+  options: &SyncOptions,
+  output: &Path,
+  packages: Vec<PackageContentInfo>,
+  existing: ExistingContent,
+  changes: Option<&LockChanges>,
+) -> Result<OutputPlan> {
+  let render = match changes {
+    Some(changes) if options.format == OutputFormat::Markdown => RenderOptions {
+      kept_sections: kept_sections(&options.render, output, &packages, changes),
+      ..options.render.clone()
+    },
+    _ => options.render.clone(),
+  };
+  output_writer(options)
+    .plan(output, packages, Some(existing), &render)
+    .context("Failed to render output")
+}
+
+/// Returns the sections of the packages not in `changes` from the generated
+/// block of `output`, by package label.
+///
+/// Empty, so that every package is rendered again, if the block cannot be
+/// split into package sections.
+fn kept_sections(
+  render: &RenderOptions,
+  output: &Path,
+  packages: &[PackageContentInfo],
+  changes: &LockChanges,
+) -> HashMap<String, String> {
+  if render.group_by_category || render.layout.last() != Some(&LayoutBlock::Packages) {
+    logger::detail(
+      "Package sections are not at the end of the generated section; rendering every package",
+    );
+    return HashMap::new();
+  }
+  let Some(block) = read_generated_block(output, render.encoding, &render.markers) else {
+    logger::detail(format_args!(
+      "No generated section in {}; rendering every package",
+      output.display()
+    ));
+    return HashMap::new();
+  };
+
+  // Sections of removed packages and previous versions must end the section
+  // above them too
+  let headings: Vec<String> = packages
+    .iter()
+    .map(|package| section_heading(package, &render.section))
+    .chain(previous_headings(changes, render))
+    .collect();
+  let marker = render.section.template.heading_marker();
+  let Some(mut sections) = split_package_sections(&block, &marker, &headings) else {
+    logger::detail(format_args!(
+      "Could not split the generated section of {} into package sections; rendering every \
+       package",
+      output.display()
+    ));
+    return HashMap::new();
+  };
+
+  packages
+    .iter()
+    .filter(|package| !changes.changed.contains(&package.name))
+    .filter_map(|package| {
+      let section = sections.remove(&section_heading(package, &render.section))?;
+      Some((package.label(), section))
+    })
+    .collect()
+}
+
+/// Returns the headings the changed packages may have had at their previous
+/// versions.
+fn previous_headings<'a>(
+  changes: &'a LockChanges,
+  render: &'a RenderOptions,
+) -> impl Iterator<Item = String> + 'a {
+  changes
+    .previous
+    .iter()
+    .flat_map(|(name, versions)| versions.iter().map(move |version| (name, version)))
+    .flat_map(move |(name, version)| {
+      [false, true].map(|versioned| {
+        let package = PackageContentInfo {
+          name: name.clone(),
+          version: version.clone(),
+          content: PackageContent {
+            main_file: None,
+            sub_files: Vec::new(),
+          },
+          conditions: Vec::new(),
+          repository: None,
+          category: None,
+          duplicate_of: None,
+          inline: false,
+          versioned,
+        };
+        section_heading(&package, &render.section)
+      })
+    })
+}
+
+/// Runs the whole `sync` pipeline: reads the dependency graph, scans it for
+/// usage rules, selects packages and writes the output.
+///
+/// Settings from `usage-rules.toml` and `[package.metadata.usage-rules]` are
+/// not read; `options` is used as given.
+///
+/// # Errors
+///
+/// Returns an error if any step fails.
+pub fn sync(options: &SyncOptions) -> Result<SyncReport> {
+  fetch(&options.metadata)?;
+  let dependencies =
+    load_dependencies(&options.metadata, options.metadata_cache.as_ref())?.dependencies;
+  let discovery = discover(scanned_dependencies(&dependencies, options)?, &options.scan)?;
+
+  let selection = select_packages(
+    discovery.usage_rules,
+    &dependencies,
+    &discovery.scanned,
+    options,
+  )?;
+  let mut report = SyncReport {
+    packages: selection.packages.iter().map(|p| p.label()).collect(),
+    selection: selection.log,
+    skipped: selection.skipped,
+    stats: selection.stats,
+    files: WriteReport::default(),
+  };
+  if selection.packages.is_empty() && !options.all {
+    return Ok(report);
+  }
+
+  logger::progress("Writing output...");
+  report.files = plan_sync(options, selection.packages)?
+    .write(options.render.mode)
+    .context("Failed to write output")?;
+  report.stats.bytes_written = report.files.bytes;
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{aggregator::SectionMarkers, lockfile::LockVersions};
+  use std::{collections::BTreeSet, fs};
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_output_relative_to_resolve() {
+    let manifest = Path::new("crates/app/Cargo.toml");
+    let resolve =
+      |base: OutputRelativeTo, path: &str, manifest| base.resolve(Path::new(path), manifest);
+
+    assert_eq!(
+      resolve(OutputRelativeTo::Manifest, "Agents.md", Some(manifest)),
+      PathBuf::from("crates/app/Agents.md")
+    );
+    assert_eq!(
+      resolve(
+        OutputRelativeTo::Manifest,
+        "Agents.md",
+        Some(Path::new("Cargo.toml"))
+      ),
+      PathBuf::from("Agents.md")
+    );
+    assert_eq!(
+      resolve(OutputRelativeTo::Manifest, "Agents.md", None),
+      PathBuf::from("Agents.md")
+    );
+    assert_eq!(
+      resolve(OutputRelativeTo::Cwd, "Agents.md", Some(manifest)),
+      PathBuf::from("Agents.md")
+    );
+
+    let absolute = std::env::temp_dir().join("Agents.md");
+    assert_eq!(
+      OutputRelativeTo::Manifest.resolve(&absolute, Some(manifest)),
+      absolute
+    );
+  }
+
+  fn dependency(name: &str) -> Dependency {
+    Dependency {
+      name: name.to_string(),
+      version: "1.0.0".to_string(),
+      path: PathBuf::from(name),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    }
+  }
+
+  #[test]
+  fn test_discover_splits_dependencies_by_usage_rules() {
+    let temp = TempDir::new().unwrap();
+    let scanned: Vec<_> = ["with-rules", "plain"]
+      .into_iter()
+      .map(|name| Dependency {
+        path: temp.path().join(name),
+        ..dependency(name)
+      })
+      .collect();
+    for dep in &scanned {
+      fs::create_dir(&dep.path).unwrap();
+    }
+    fs::write(temp.path().join("with-rules/usage-rules.md"), "Rules").unwrap();
+
+    let discovery = discover(scanned, &ScanOptions::default()).unwrap();
+
+    assert_eq!(discovery.scanned.len(), 2);
+    assert_eq!(discovery.usage_rules.len(), 1);
+    assert_eq!(discovery.usage_rules[0].package_name, "with-rules");
+    assert_eq!(discovery.missing.len(), 1);
+    assert_eq!(discovery.missing[0].name, "plain");
+  }
+
+  #[test]
+  fn test_select_packages_applies_selection_and_sorts() {
+    let temp = TempDir::new().unwrap();
+    let usage_rules: Vec<_> = ["zeta", "alpha", "old"]
+      .iter()
+      .map(|name| {
+        let main_file = temp.path().join(format!("{}.md", name));
+        fs::write(&main_file, format!("{} rules", name)).unwrap();
+        UsageRules {
+          package_name: name.to_string(),
+          package_version: "1.0.0".to_string(),
+          main_file: Some(main_file),
+          sub_files: vec![],
+          conditions: vec![],
+          repository: None,
+          category: None,
+          depth: 1,
+        }
+      })
+      .collect();
+    let dependencies: Vec<_> = ["zeta", "alpha", "old", "plain"]
+      .into_iter()
+      .map(dependency)
+      .collect();
+    let options = SyncOptions {
+      selection: SelectionOptions {
+        remove: vec!["old".to_string()],
+        ..SelectionOptions::default()
+      },
+      ..SyncOptions::default()
+    };
+
+    let selection = select_packages(usage_rules, &dependencies, &dependencies, &options).unwrap();
+
+    let names: Vec<_> = selection.packages.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["alpha", "zeta"]);
+    let log = selection.log.render();
+    assert!(log.contains("old      removed by --remove"));
+    assert!(log.contains("plain    no usage-rules.md"));
+    assert_eq!(
+      selection.stats,
+      SyncStats {
+        dependencies_scanned: 4,
+        with_usage_rules: 3,
+        sub_files: 0,
+        removed: 1,
+        bytes_written: 0,
+      }
+    );
+  }
+
+  #[test]
+  fn test_existing_content_force_overwrites_malformed_output() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("Agents.md");
+    fs::write(
+      &output,
+      "Notes\n<!-- cargo-usage-rules-end -->\nOld\n<!-- cargo-usage-rules-start -->\n",
+    )
+    .unwrap();
+    let mut options = SyncOptions {
+      output: output.clone(),
+      ..SyncOptions::default()
+    };
+
+    let ExistingContent::Preamble { preamble, .. } = existing_content(&options).unwrap() else {
+      panic!("expected a preamble");
+    };
+    assert!(preamble.contains("Old"));
+
+    options.force = true;
+    assert_eq!(
+      existing_content(&options).unwrap(),
+      ExistingContent::Preamble {
+        preamble: String::new(),
+        footer: String::new(),
+      }
+    );
+
+    options.force = false;
+    options.strict_markers = true;
+    assert!(existing_content(&options).is_err());
+  }
+
+  #[test]
+  fn test_plan_output_writes_each_output_with_its_own_preamble() {
+    let temp = TempDir::new().unwrap();
+    let agents = temp.path().join("Agents.md");
+    let claude = temp.path().join("CLAUDE.md");
+    fs::write(&agents, "Agents notes\n").unwrap();
+    fs::write(&claude, "Claude notes\n").unwrap();
+    let main_file = temp.path().join("serde.md");
+    fs::write(&main_file, "Serde rules").unwrap();
+    let package = PackageContentInfo {
+      name: "serde".to_string(),
+      version: "1.0.0".to_string(),
+      content: PackageContent {
+        main_file: Some(main_file),
+        sub_files: vec![],
+      },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
+    };
+    let options = SyncOptions {
+      output: agents.clone(),
+      additional_outputs: vec![claude.clone()],
+      linked: false,
+      ..SyncOptions::default()
+    };
+
+    let existing = existing_content(&options).unwrap();
+    plan_output(&options, vec![package], existing)
+      .unwrap()
+      .write(None)
+      .unwrap();
+
+    for (path, preamble) in [(&agents, "Agents notes"), (&claude, "Claude notes")] {
+      let content = fs::read_to_string(path).unwrap();
+      assert!(content.starts_with(preamble), "{}", content);
+      assert!(content.contains("## serde usage\nSerde rules"));
+    }
+    assert!(!fs::read_to_string(&claude)
+      .unwrap()
+      .contains("Agents notes"));
+  }
+
+  #[test]
+  fn test_target_prefers_merge_into() {
+    let mut options = SyncOptions::default();
+    assert_eq!(options.target(), Path::new("Agents.md"));
+
+    options.merge_into = Some(PathBuf::from("README.md"));
+    assert_eq!(options.target(), Path::new("README.md"));
+  }
+
+  #[test]
+  fn test_plan_output_keeps_unchanged_sections() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("AGENTS.md");
+    let markers = SectionMarkers::default();
+    fs::write(
+      &output,
+      format!(
+        "{}\n\n## alpha usage\nalpha old rules\n\n## gone usage\ngone rules\n\n## beta \
+         usage\nbeta old rules\n{}\n",
+        markers.start, markers.end
+      ),
+    )
+    .unwrap();
+    let packages: Vec<_> = ["alpha", "beta"]
+      .iter()
+      .map(|name| {
+        let main_file = temp.path().join(format!("{}.md", name));
+        fs::write(&main_file, format!("{} new rules", name)).unwrap();
+        PackageContentInfo {
+          name: name.to_string(),
+          version: "1.0.0".to_string(),
+          content: PackageContent {
+            main_file: Some(main_file),
+            sub_files: vec![],
+          },
+          conditions: vec![],
+          repository: None,
+          category: None,
+          duplicate_of: None,
+          inline: false,
+          versioned: false,
+        }
+      })
+      .collect();
+    let changes = LockChanges {
+      changed: BTreeSet::from(["beta".to_string(), "gone".to_string()]),
+      previous: LockVersions::from([("gone".to_string(), BTreeSet::from(["0.1.0".to_string()]))]),
+    };
+    let options = SyncOptions {
+      output: output.clone(),
+      linked: false,
+      ..SyncOptions::default()
+    };
+    let existing = output_preamble(&options, &output).unwrap();
+
+    plan_target(&options, &output, packages, existing, Some(&changes))
+      .unwrap()
+      .write(None)
+      .unwrap();
+    let content = fs::read_to_string(&output).unwrap();
+    assert!(content.contains("## alpha usage\nalpha old rules\n\n## beta usage"));
+    assert!(content.contains("beta new rules"));
+    assert!(!content.contains("gone rules"));
+    assert!(!content.contains("beta old rules"));
+  }
+}
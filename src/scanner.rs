@@ -1,6 +1,22 @@
-use crate::metadata::Dependency;
+use crate::{
+  cache::ScanCache,
+  logger,
+  markdown::frontmatter_value,
+  metadata::Dependency,
+  warnings::{warn, WarningCategory},
+};
 use anyhow::{Context, Result};
-use std::{fs, path::PathBuf};
+use clap::ValueEnum;
+use globset::GlobSet;
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashSet,
+  fs,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
 use walkdir::WalkDir;
 
 #[derive(Debug, Clone)]
@@ -9,23 +25,142 @@ pub struct UsageRules {
   pub package_version: String,
   pub main_file: Option<PathBuf>,
   pub sub_files: Vec<UsageRuleSubFile>,
+  /// Conditions under which the package applies (see
+  /// `Dependency::conditions`).
+  pub conditions: Vec<String>,
+  /// The package's repository URL, if known (see `Dependency::repository`).
+  pub repository: Option<String>,
+  /// The category declared in the main file's front matter, e.g. `async`.
+  pub category: Option<String>,
+  /// Distance from the root package (see `Dependency::depth`).
+  pub depth: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsageRuleSubFile {
   pub relative_path_name: String,
   pub full_path: PathBuf,
+  /// Position declared by an `order:` entry in the file's front matter.
+  /// Read on every scan rather than cached, so edits take effect.
+  #[serde(skip)]
+  pub order: Option<i64>,
+}
+
+/// Output format of the `list` command and of `sync --stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListFormat {
+  /// Human-readable lines.
+  #[default]
+  Text,
+  /// A JSON array of package summaries.
+  Json,
+}
+
+/// Machine-readable summary of a package's usage rules.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageSummary {
+  pub package_name: String,
+  pub package_version: String,
+  pub has_main_file: bool,
+  /// Sub-file names relative to the sub-file directory, without extension.
+  pub sub_files: Vec<String>,
+}
+
+impl From<&UsageRules> for PackageSummary {
+  fn from(rules: &UsageRules) -> Self {
+    Self {
+      package_name: rules.package_name.clone(),
+      package_version: rules.package_version.clone(),
+      has_main_file: rules.main_file.is_some(),
+      sub_files: rules
+        .sub_files
+        .iter()
+        .map(|f| f.relative_path_name.clone())
+        .collect(),
+    }
+  }
+}
+
+impl From<&Dependency> for PackageSummary {
+  /// Summarizes a dependency that has no usage rules.
+  fn from(dep: &Dependency) -> Self {
+    Self {
+      package_name: dep.name.clone(),
+      package_version: dep.version.clone(),
+      has_main_file: false,
+      sub_files: vec![],
+    }
+  }
+}
+
+/// Default name of the main rules file in a package.
+pub const DEFAULT_RULES_FILENAME: &str = "usage-rules.md";
+
+/// Default name of the sub-file directory in a package.
+pub const DEFAULT_RULES_DIR: &str = "usage_rules";
+
+/// Default file extensions accepted for sub-files.
+pub const DEFAULT_EXTENSIONS: [&str; 1] = ["md"];
+
+/// Hyphenated spelling of the sub-file directory, matching the main file's
+/// name. Recognized alongside `DEFAULT_RULES_DIR` unless `--rules-dir` is
+/// given.
+pub const ALTERNATE_RULES_DIR: &str = "usage-rules";
+
+/// Settings controlling how dependencies are scanned.
+#[derive(Debug)]
+pub struct ScanOptions {
+  /// Shared cache of sub-file listings, if enabled.
+  pub cache: Option<ScanCache>,
+  /// Preferred locale for translated rules (e.g. "fr"), if any.
+  pub locale: Option<String>,
+  /// Name of the main rules file in each package.
+  pub rules_filename: String,
+  /// Name of the sub-file directory in each package.
+  pub rules_dir: String,
+  /// File extensions accepted for sub-files, without the leading dot.
+  pub extensions: Vec<String>,
+  /// Globs a sub-file's relative name must match to be kept, if any.
+  pub subfile_patterns: Option<GlobSet>,
+  /// Skip sub-files excluded by `.gitignore` or `.ignore` files.
+  pub respect_gitignore: bool,
+  /// How many directories below a package's root to search for its main
+  /// rules file when the root has none; None searches only the root.
+  pub deep_scan: Option<usize>,
+}
+
+impl Default for ScanOptions {
+  fn default() -> Self {
+    Self {
+      cache: None,
+      locale: None,
+      rules_filename: DEFAULT_RULES_FILENAME.to_string(),
+      rules_dir: DEFAULT_RULES_DIR.to_string(),
+      extensions: DEFAULT_EXTENSIONS.map(String::from).to_vec(),
+      subfile_patterns: None,
+      respect_gitignore: false,
+      deep_scan: None,
+    }
+  }
 }
 
 /// Scans dependencies for usage-rules.md files and associated sub-files.
 ///
+/// Dependencies are scanned in parallel; results keep the order of
+/// `dependencies`.
+///
 /// For each dependency, this function looks for:
 /// - A `usage-rules.md` file in the package root
 /// - A `usage-rules/` directory containing additional markdown files
 ///
+/// With a locale set, `usage-rules.<locale>.md` and `usage_rules/<locale>/`
+/// are preferred over their defaults when present.
+///
 /// # Arguments
 ///
 /// * `dependencies` - Slice of dependencies to scan
+/// * `options` - Settings controlling the scan
 ///
 /// # Returns
 ///
@@ -36,61 +171,571 @@ pub struct UsageRuleSubFile {
 /// # Errors
 ///
 /// Returns an error if filesystem operations fail during scanning.
-pub fn scan_for_usage_rules(dependencies: &[Dependency]) -> Result<Vec<UsageRules>> {
-  let mut results = Vec::new();
+pub fn scan_for_usage_rules(
+  dependencies: &[Dependency],
+  options: &ScanOptions,
+) -> Result<Vec<UsageRules>> {
+  Ok(
+    scan_dependencies(dependencies, options)?
+      .into_iter()
+      .flatten()
+      .collect(),
+  )
+}
+
+/// Scans dependencies like [`scan_for_usage_rules`], keeping the ones without
+/// usage rules.
+///
+/// # Returns
+///
+/// One entry per dependency, in the order of `dependencies`: the package's
+/// `UsageRules`, or None if it has no main rules file.
+///
+/// # Errors
+///
+/// Returns an error if filesystem operations fail during scanning.
+pub fn scan_dependencies(
+  dependencies: &[Dependency],
+  options: &ScanOptions,
+) -> Result<Vec<Option<UsageRules>>> {
+  let progress = logger::progress_bar(dependencies.len());
+  // Indexed parallel iterators collect in input order, so the result keeps
+  // the order of `dependencies` regardless of scheduling
+  let scanned = dependencies
+    .par_iter()
+    .map(|dep| {
+      progress.set_message(dep.name.clone());
+      let scanned = scan_dependency(dep, options);
+      progress.inc(1);
+      scanned
+    })
+    .collect();
+  progress.finish_and_clear();
+  scanned
+}
+
+/// Scans a single dependency, returning None if it has no main rules file.
+fn scan_dependency(dep: &Dependency, options: &ScanOptions) -> Result<Option<UsageRules>> {
+  logger::detail(format_args!(
+    "{}: scanning {} (source: {})",
+    dep.name,
+    dep.path.display(),
+    dep.source.as_deref().unwrap_or("path")
+  ));
+  let (main_file_path, sub_dir_paths) = locate_rules(dep, options);
 
-  for dep in dependencies {
-    let main_file_path = dep.path.join("usage-rules.md");
-    let sub_dir_path = dep.path.join("usage_rules");
+  if !main_file_path.is_file() {
+    logger::detail(format_args!(
+      "{}: no {}, skipping",
+      dep.name,
+      main_file_path.display()
+    ));
+    return Ok(None);
+  }
+  logger::detail(format_args!(
+    "{}: found {}",
+    dep.name,
+    main_file_path.display()
+  ));
+
+  if sub_dir_paths.len() > 1 {
+    warn(
+      WarningCategory::AmbiguousRulesDir,
+      format_args!(
+        "{} has both {}/ and {}/; merging them, preferring {}/ for files in both",
+        dep.name, DEFAULT_RULES_DIR, ALTERNATE_RULES_DIR, DEFAULT_RULES_DIR
+      ),
+    );
+  }
 
-    let main_file = if main_file_path.exists() && main_file_path.is_file() {
-      Some(main_file_path)
+  let scan = || {
+    sub_dir_paths
+      .iter()
+      .flat_map(|dir| find_sub_files(dir, &options.extensions, options.respect_gitignore))
+      .collect()
+  };
+  // Cache keys do not cover ignore files, so edits to them would go unnoticed
+  let mut sub_files = match &options.cache {
+    Some(cache) if !options.respect_gitignore => cache.get_or_scan(
+      dep,
+      &main_file_path,
+      &sub_dir_paths,
+      &options.extensions,
+      || Ok(scan()),
+    )?,
+    _ => scan(),
+  };
+  // Traversal order differs across platforms; sort for reproducible output.
+  // Sorting here rather than in `find_sub_files` also covers cache entries
+  // written before sub-files were sorted. The sort is stable, so deduping
+  // keeps the file from the first directory
+  sub_files.sort_by(|a, b| a.relative_path_name.cmp(&b.relative_path_name));
+  sub_files.dedup_by(|a, b| a.relative_path_name == b.relative_path_name);
+  // Files declaring an `order` come first, ascending; the sort is stable, so
+  // the rest stay alphabetical
+  for sub_file in &mut sub_files {
+    sub_file.order = declared_order(&dep.name, &sub_file.full_path);
+  }
+  sub_files.sort_by_key(|sub_file| sub_file.order.map_or((1, 0), |order| (0, order)));
+  if let Some(patterns) = &options.subfile_patterns {
+    sub_files.retain(|sub_file| {
+      let keep = patterns.is_match(&sub_file.relative_path_name);
+      if !keep {
+        logger::detail(format_args!(
+          "{}: skipping {} (does not match --subfile-pattern)",
+          dep.name,
+          sub_file.full_path.display()
+        ));
+      }
+      keep
+    });
+  }
+  for sub_file in &sub_files {
+    logger::detail(format_args!(
+      "{}: found {}",
+      dep.name,
+      sub_file.full_path.display()
+    ));
+  }
+
+  let category = read_frontmatter_value(&main_file_path, "category");
+
+  Ok(Some(UsageRules {
+    package_name: dep.name.clone(),
+    package_version: dep.version.clone(),
+    main_file: Some(main_file_path),
+    sub_files,
+    conditions: dep.conditions.clone(),
+    repository: dep.repository.clone(),
+    category,
+    depth: dep.depth,
+  }))
+}
+
+/// Returns the sub-file directories of the package at `package_path`.
+///
+/// With the default `rules_dir`, a hyphenated `usage-rules/` directory is
+/// recognized too; if both exist, both are returned with `usage_rules/`
+/// first. Otherwise the single configured directory is returned, whether or
+/// not it exists.
+pub fn rules_dirs(package_path: &Path, options: &ScanOptions) -> Vec<PathBuf> {
+  let sub_dir = package_path.join(&options.rules_dir);
+  if options.rules_dir != DEFAULT_RULES_DIR {
+    return vec![sub_dir];
+  }
+
+  let alternate = package_path.join(ALTERNATE_RULES_DIR);
+  match (sub_dir.is_dir(), alternate.is_dir()) {
+    (true, true) => vec![sub_dir, alternate],
+    (false, true) => vec![alternate],
+    _ => vec![sub_dir],
+  }
+}
+
+/// Returns the main rules file and sub-file directories of `dep`, preferring
+/// the variants for the locale when they exist.
+///
+/// A main file the package declares in its manifest metadata is used as is
+/// when it exists. With `deep_scan`, a package without a main file at its
+/// root uses the shallowest directory below it that has one.
+fn locate_rules(dep: &Dependency, options: &ScanOptions) -> (PathBuf, Vec<PathBuf>) {
+  let package_path = dep.path.as_path();
+  if let Some(file) = &dep.rules_file {
+    let declared = package_path.join(file);
+    if declared.is_file() {
+      return (declared, rules_dirs(package_path, options));
+    }
+    logger::detail(format_args!(
+      "{}: declared rules file {} does not exist, looking for {}",
+      dep.name,
+      declared.display(),
+      options.rules_filename
+    ));
+  }
+
+  let located = locate_rules_in(package_path, options);
+  if located.0.is_file() {
+    return located;
+  }
+  let Some(max_depth) = options.deep_scan else {
+    return located;
+  };
+  match find_nested_rules_dir(package_path, &options.rules_filename, max_depth) {
+    Some(dir) => {
+      logger::detail(format_args!(
+        "{}: no {} at the package root; using the one in {} (--deep-scan)",
+        dep.name,
+        options.rules_filename,
+        dir.display()
+      ));
+      locate_rules_in(&dir, options)
+    }
+    None => located,
+  }
+}
+
+/// Returns the shallowest directory at most `max_depth` levels below
+/// `package_path` holding a file named `filename` (case-insensitively), or
+/// None. Hidden directories and `target/` are not searched.
+fn find_nested_rules_dir(package_path: &Path, filename: &str, max_depth: usize) -> Option<PathBuf> {
+  WalkDir::new(package_path)
+    .min_depth(2)
+    .max_depth(max_depth + 1)
+    .sort_by_file_name()
+    .into_iter()
+    .filter_entry(|entry| {
+      let name = entry.file_name().to_string_lossy();
+      !(entry.file_type().is_dir() && (name.starts_with('.') || name == "target"))
+    })
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| {
+      entry.file_type().is_file()
+        && entry
+          .file_name()
+          .to_string_lossy()
+          .eq_ignore_ascii_case(filename)
+    })
+    .min_by_key(|entry| entry.depth())
+    .and_then(|entry| entry.path().parent().map(Path::to_path_buf))
+}
+
+/// Returns the main rules file and sub-file directories in `dir`, preferring
+/// the variants for the locale when they exist.
+fn locate_rules_in(dir: &Path, options: &ScanOptions) -> (PathBuf, Vec<PathBuf>) {
+  let sub_dirs = rules_dirs(dir, options);
+  let main_file = find_file_ignoring_case(dir, &options.rules_filename);
+
+  let Some(locale) = options.locale.as_deref() else {
+    return (main_file, sub_dirs);
+  };
+
+  let localized_main =
+    find_file_ignoring_case(dir, &localized_filename(&options.rules_filename, locale));
+  (
+    if localized_main.is_file() {
+      localized_main
     } else {
-      None
-    };
+      main_file
+    },
+    sub_dirs
+      .into_iter()
+      .map(|sub_dir| {
+        let localized_sub_dir = sub_dir.join(locale);
+        if localized_sub_dir.is_dir() {
+          localized_sub_dir
+        } else {
+          sub_dir
+        }
+      })
+      .collect(),
+  )
+}
 
-    if main_file.is_none() {
+/// Returns the path of the file named `name` in `dir`, matching the name
+/// case-insensitively.
+///
+/// The exact-case name is preferred; otherwise the alphabetically first
+/// matching entry is used and a note is printed. If nothing matches, the
+/// exact-case path is returned so callers can check for its existence.
+fn find_file_ignoring_case(dir: &Path, name: &str) -> PathBuf {
+  let exact = dir.join(name);
+  if exact.is_file() {
+    return exact;
+  }
+
+  let lowercase_name = name.to_lowercase();
+  let mut matches: Vec<PathBuf> = fs::read_dir(dir)
+    .into_iter()
+    .flatten()
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| entry.file_name().to_string_lossy().to_lowercase() == lowercase_name)
+    .map(|entry| entry.path())
+    .filter(|path| path.is_file())
+    .collect();
+  matches.sort();
+
+  match matches.into_iter().next() {
+    Some(path) => {
+      logger::detail(format_args!("Note: using {} as {}", path.display(), name));
+      path
+    }
+    None => exact,
+  }
+}
+
+/// Inserts `locale` before the extension of `filename`, as in
+/// `usage-rules.fr.md`.
+fn localized_filename(filename: &str, locale: &str) -> String {
+  match filename.rsplit_once('.') {
+    Some((stem, extension)) if !stem.is_empty() => format!("{}.{}.{}", stem, locale, extension),
+    _ => format!("{}.{}", filename, locale),
+  }
+}
+
+/// Collects the files with one of `extensions` below a package's
+/// `usage_rules` directory.
+///
+/// Symlinks are followed, but a directory is only visited once: an entry
+/// leading back to a directory that was already scanned (such as a link to an
+/// ancestor) is skipped with a warning.
+///
+/// With `respect_gitignore`, files excluded by `.gitignore` or `.ignore`
+/// files in the directory or its parents are skipped, whether or not the
+/// package is in a git repository.
+fn find_sub_files(
+  sub_dir_path: &Path,
+  extensions: &[String],
+  respect_gitignore: bool,
+) -> Vec<UsageRuleSubFile> {
+  if !sub_dir_path.is_dir() {
+    return Vec::new();
+  }
+
+  let files = if respect_gitignore {
+    walk_unignored_files(sub_dir_path)
+  } else {
+    walk_files(sub_dir_path)
+  };
+
+  let mut sub_files = Vec::new();
+  for path in files {
+    if path
+      .extension()
+      .is_none_or(|ext| !extensions.iter().any(|accepted| ext == accepted.as_str()))
+    {
+      logger::detail(format_args!(
+        "skipping {} (extension not in --extensions)",
+        path.display()
+      ));
       continue;
     }
 
-    let mut sub_files = Vec::new();
+    if let Ok(relative) = path.strip_prefix(sub_dir_path) {
+      let relative_path_name = relative.with_extension("").to_string_lossy().into_owned();
+      sub_files.push(UsageRuleSubFile {
+        relative_path_name,
+        full_path: path.clone(),
+        order: None,
+      });
+    }
+  }
 
-    if sub_dir_path.exists() && sub_dir_path.is_dir() {
-      for entry in WalkDir::new(&sub_dir_path)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-      {
-        let path = entry.path();
-        if path.is_file() && path.extension().is_some_and(|ext| ext == "md") {
-          if let Ok(relative) = path.strip_prefix(&sub_dir_path) {
-            let relative_path_name = relative
-              .to_string_lossy()
-              .trim_end_matches(".md")
-              .to_string();
-            sub_files.push(UsageRuleSubFile {
-              relative_path_name,
-              full_path: path.to_path_buf(),
-            });
-          }
+  sub_files
+}
+
+/// Returns true the first time a directory is reached, and warns about a
+/// symlink cycle otherwise.
+fn first_visit(visited_dirs: &mut HashSet<PathBuf>, path: &Path) -> bool {
+  let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let first = visited_dirs.insert(canonical);
+  if !first {
+    warn_symlink_cycle(path);
+  }
+  first
+}
+
+/// Returns every file below `dir`, following symlinks.
+fn walk_files(dir: &Path) -> Vec<PathBuf> {
+  let mut files = Vec::new();
+  let mut visited_dirs = HashSet::new();
+  let mut walker = WalkDir::new(dir).follow_links(true).into_iter();
+  while let Some(entry) = walker.next() {
+    let entry = match entry {
+      Ok(entry) => entry,
+      Err(err) => {
+        if let Some(ancestor) = err.loop_ancestor() {
+          warn_symlink_cycle(err.path().unwrap_or(ancestor));
         }
+        continue;
+      }
+    };
+
+    let path = entry.path();
+    if entry.file_type().is_dir() {
+      if !first_visit(&mut visited_dirs, path) {
+        walker.skip_current_dir();
       }
+    } else if path.is_file() {
+      files.push(path.to_path_buf());
     }
+  }
+  files
+}
 
-    results.push(UsageRules {
-      package_name: dep.name.clone(),
-      package_version: dep.version.clone(),
-      main_file,
-      sub_files,
+/// Returns every file below `dir` that no ignore file excludes, following
+/// symlinks.
+fn walk_unignored_files(dir: &Path) -> Vec<PathBuf> {
+  let visited_dirs = Arc::new(Mutex::new(HashSet::new()));
+  let walker = WalkBuilder::new(dir)
+    .follow_links(true)
+    .hidden(false)
+    .git_global(false)
+    .git_exclude(false)
+    .require_git(false)
+    .filter_entry(move |entry| {
+      !entry.path().is_dir()
+        || first_visit(
+          &mut visited_dirs.lock().unwrap_or_else(|e| e.into_inner()),
+          entry.path(),
+        )
+    })
+    .build();
+
+  let mut files = Vec::new();
+  for entry in walker {
+    match entry {
+      Ok(entry) if entry.path().is_file() => files.push(entry.into_path()),
+      Ok(_) => {}
+      Err(err) => {
+        if let Some(path) = loop_path(&err) {
+          warn_symlink_cycle(path);
+        }
+      }
+    }
+  }
+  files
+}
+
+/// Returns the path of the symlink behind a directory loop error.
+fn loop_path(err: &ignore::Error) -> Option<&Path> {
+  match err {
+    ignore::Error::Loop { child, .. } => Some(child),
+    ignore::Error::WithPath { err, .. }
+    | ignore::Error::WithDepth { err, .. }
+    | ignore::Error::WithLineNumber { err, .. } => loop_path(err),
+    _ => None,
+  }
+}
+
+/// Returns the value of `key` in the front matter of the rule file at `path`.
+///
+/// Invalid UTF-8 is decoded lossily without a warning, and files that cannot
+/// be read have no value here; both are reported when the file is aggregated,
+/// where --keep-going can skip it.
+fn read_frontmatter_value(path: &Path, key: &str) -> Option<String> {
+  let bytes = fs::read(path).ok()?;
+  let content = String::from_utf8_lossy(&bytes);
+  frontmatter_value(content.trim_start_matches('\u{feff}'), key)
+}
+
+/// Returns the integer `order:` declared in a sub-file's front matter.
+fn declared_order(package_name: &str, path: &Path) -> Option<i64> {
+  let value = read_frontmatter_value(path, "order")?;
+  let order = value.parse().ok();
+  if order.is_none() {
+    logger::detail(format_args!(
+      "{}: ignoring order `{}` of {} (not an integer)",
+      package_name,
+      value,
+      path.display()
+    ));
+  }
+  order
+}
+
+/// Packages and files dropped by [`remove_unreadable_files`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkippedFiles {
+  pub packages: usize,
+  pub sub_files: usize,
+}
+
+/// Drops rule files that cannot be read, instead of failing the run.
+///
+/// Unreadable sub-files are removed from their package, and a package whose
+/// main file cannot be read is skipped entirely. A warning naming the file and
+/// the error is printed for each.
+///
+/// # Returns
+///
+/// The readable rules, and how many packages and sub-files were skipped.
+pub fn remove_unreadable_files(
+  usage_rules: Vec<UsageRules>,
+  strict_encoding: bool,
+) -> (Vec<UsageRules>, SkippedFiles) {
+  let mut skipped = SkippedFiles::default();
+  let mut results = Vec::new();
+
+  for mut rule in usage_rules {
+    if let Some(main_file) = &rule.main_file {
+      if let Err(err) = read_file_content(main_file, strict_encoding) {
+        warn_unreadable(&rule.package_name, &err);
+        skipped.packages += 1;
+        continue;
+      }
+    }
+
+    rule.sub_files.retain(|sub_file| {
+      let readable = read_file_content(&sub_file.full_path, strict_encoding);
+      if let Err(err) = &readable {
+        warn_unreadable(&rule.package_name, err);
+        skipped.sub_files += 1;
+      }
+      readable.is_ok()
     });
+
+    results.push(rule);
   }
 
-  Ok(results)
+  (results, skipped)
 }
 
-pub fn read_file_content(path: &PathBuf) -> Result<String> {
-  fs::read_to_string(path)
-    .with_context(|| anyhow::anyhow!("Failed to read file {}", path.display()))
+fn warn_unreadable(package_name: &str, err: &anyhow::Error) {
+  warn(
+    WarningCategory::UnreadableFile,
+    format_args!("skipping a file of {}: {:#}", package_name, err),
+  );
+}
+
+fn warn_symlink_cycle(path: &Path) {
+  warn(
+    WarningCategory::SymlinkCycle,
+    format_args!(
+      "skipping {}: it leads back to a directory that was already scanned",
+      path.display()
+    ),
+  );
+}
+
+/// Reads a rule file as UTF-8, dropping a leading byte order mark.
+///
+/// Invalid UTF-8 is replaced with U+FFFD and a warning naming the file is
+/// printed once per file, unless `strict_encoding` is set.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, or if it is not valid UTF-8
+/// and `strict_encoding` is set.
+pub fn read_file_content(path: &Path, strict_encoding: bool) -> Result<String> {
+  static WARNED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+  let bytes = fs::read(path).with_context(|| format!("Failed to read file {}", path.display()))?;
+  let content = match String::from_utf8(bytes) {
+    Ok(content) => content,
+    Err(err) => {
+      if strict_encoding {
+        anyhow::bail!("{} is not valid UTF-8", path.display());
+      }
+
+      let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+      if !warned.iter().any(|p| p == path) {
+        warned.push(path.to_path_buf());
+        warn(
+          WarningCategory::InvalidUtf8,
+          format_args!(
+            "{} is not valid UTF-8; invalid bytes were replaced",
+            path.display()
+          ),
+        );
+      }
+      String::from_utf8_lossy(err.as_bytes()).into_owned()
+    }
+  };
+
+  Ok(match content.strip_prefix('\u{feff}') {
+    Some(stripped) => stripped.to_string(),
+    None => content,
+  })
 }
 
 #[cfg(test)]
@@ -98,6 +743,19 @@ mod tests {
   use super::*;
   use tempfile::TempDir;
 
+  fn dependency(name: &str, path: &Path) -> Dependency {
+    Dependency {
+      name: name.to_string(),
+      version: "1.0.0".to_string(),
+      path: path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    }
+  }
+
   #[test]
   fn test_finds_main_file_only() {
     let temp = TempDir::new().unwrap();
@@ -108,15 +766,60 @@ mod tests {
       name: "test".into(),
       version: "1.0.0".into(),
       path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
     };
 
-    let results = scan_for_usage_rules(&[dep]).unwrap();
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
 
     assert_eq!(results.len(), 1);
     assert!(results[0].main_file.is_some());
     assert_eq!(results[0].sub_files.len(), 0);
     assert_eq!(results[0].package_name, "test");
     assert_eq!(results[0].package_version, "1.0.0");
+    assert_eq!(results[0].category, None);
+  }
+
+  #[test]
+  fn test_reads_category_from_frontmatter() {
+    let temp = TempDir::new().unwrap();
+    fs::write(
+      temp.path().join("usage-rules.md"),
+      "---\ncategory: async\n---\n\n# Rules\n",
+    )
+    .unwrap();
+
+    let results =
+      scan_for_usage_rules(&[dependency("tokio", temp.path())], &ScanOptions::default()).unwrap();
+
+    assert_eq!(results[0].category, Some("async".to_string()));
+  }
+
+  #[test]
+  fn test_declared_rules_file_replaces_conventional_name() {
+    let temp = TempDir::new().unwrap();
+    fs::create_dir(temp.path().join("docs")).unwrap();
+    fs::write(temp.path().join("docs/agent.md"), "Declared").unwrap();
+    fs::write(temp.path().join("usage-rules.md"), "Conventional").unwrap();
+
+    let mut dep = dependency("declared", temp.path());
+    dep.rules_file = Some(PathBuf::from("docs/agent.md"));
+    let results = scan_for_usage_rules(&[dep.clone()], &ScanOptions::default()).unwrap();
+    assert_eq!(
+      results[0].main_file,
+      Some(temp.path().join("docs/agent.md"))
+    );
+
+    // A declared file that does not exist falls back to the convention
+    dep.rules_file = Some(PathBuf::from("docs/missing.md"));
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
+    assert_eq!(
+      results[0].main_file,
+      Some(temp.path().join("usage-rules.md"))
+    );
   }
 
   #[test]
@@ -134,9 +837,14 @@ mod tests {
       name: "test".into(),
       version: "1.0.0".into(),
       path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
     };
 
-    let results = scan_for_usage_rules(&[dep]).unwrap();
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
 
     assert_eq!(results.len(), 1);
     assert!(results[0].main_file.is_some());
@@ -158,9 +866,14 @@ mod tests {
       name: "test".into(),
       version: "1.0.0".into(),
       path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
     };
 
-    let results = scan_for_usage_rules(&[dep]).unwrap();
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
 
     // Should be skipped because no main file
     assert_eq!(results.len(), 0);
@@ -180,14 +893,108 @@ mod tests {
       name: "test".into(),
       version: "1.0.0".into(),
       path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
     };
 
-    let results = scan_for_usage_rules(&[dep]).unwrap();
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
 
     assert_eq!(results[0].sub_files.len(), 1);
     assert_eq!(results[0].sub_files[0].relative_path_name, "builder");
   }
 
+  #[test]
+  fn test_deep_scan_finds_nested_rules() {
+    let temp = TempDir::new().unwrap();
+    let pkg_path = temp.path();
+    fs::create_dir_all(pkg_path.join("docs/usage_rules")).unwrap();
+    fs::create_dir_all(pkg_path.join("docs/deeper/still")).unwrap();
+    fs::create_dir_all(pkg_path.join("target/doc")).unwrap();
+    fs::write(pkg_path.join("docs/usage-rules.md"), "Docs").unwrap();
+    fs::write(pkg_path.join("docs/usage_rules/async.md"), "Async").unwrap();
+    fs::write(pkg_path.join("docs/deeper/still/usage-rules.md"), "Deeper").unwrap();
+    fs::write(pkg_path.join("target/doc/usage-rules.md"), "Built").unwrap();
+
+    let dep = Dependency {
+      name: "test".into(),
+      version: "1.0.0".into(),
+      path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    };
+    let scan = |deep_scan| {
+      let options = ScanOptions {
+        deep_scan,
+        ..ScanOptions::default()
+      };
+      scan_for_usage_rules(std::slice::from_ref(&dep), &options).unwrap()
+    };
+
+    assert!(scan(None).is_empty());
+    let results = scan(Some(3));
+    assert_eq!(
+      results[0].main_file.as_deref(),
+      Some(pkg_path.join("docs/usage-rules.md").as_path())
+    );
+    assert_eq!(results[0].sub_files[0].relative_path_name, "async");
+
+    // A file at the root wins over nested ones
+    fs::write(pkg_path.join("usage-rules.md"), "Root").unwrap();
+    assert_eq!(
+      scan(Some(3))[0].main_file.as_deref(),
+      Some(pkg_path.join("usage-rules.md").as_path())
+    );
+  }
+
+  #[test]
+  fn test_sorts_sub_files_by_declared_order() {
+    let temp = TempDir::new().unwrap();
+    let pkg_path = temp.path();
+    fs::write(pkg_path.join("usage-rules.md"), "Main").unwrap();
+
+    let sub_dir = pkg_path.join("usage_rules");
+    fs::create_dir(&sub_dir).unwrap();
+    fs::write(sub_dir.join("advanced.md"), "---\norder: 2\n---\nAdvanced").unwrap();
+    fs::write(sub_dir.join("appendix.md"), "Appendix").unwrap();
+    fs::write(
+      sub_dir.join("getting-started.md"),
+      "---\norder: 1\n---\nStart",
+    )
+    .unwrap();
+    fs::write(sub_dir.join("errors.md"), "---\norder: soon\n---\nErrors").unwrap();
+
+    let dep = Dependency {
+      name: "test".into(),
+      version: "1.0.0".into(),
+      path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    };
+
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
+
+    let names: Vec<_> = results[0]
+      .sub_files
+      .iter()
+      .map(|f| f.relative_path_name.as_str())
+      .collect();
+    assert_eq!(
+      names,
+      vec!["getting-started", "advanced", "appendix", "errors"]
+    );
+    assert_eq!(results[0].sub_files[0].order, Some(1));
+    assert_eq!(results[0].sub_files[3].order, None);
+  }
+
   #[test]
   fn test_handles_multiple_sub_files() {
     let temp = TempDir::new().unwrap();
@@ -203,9 +1010,14 @@ mod tests {
       name: "test".into(),
       version: "1.0.0".into(),
       path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
     };
 
-    let results = scan_for_usage_rules(&[dep]).unwrap();
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
 
     assert_eq!(results[0].sub_files.len(), 2);
   }
@@ -226,9 +1038,14 @@ mod tests {
       name: "test".into(),
       version: "1.0.0".into(),
       path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
     };
 
-    let results = scan_for_usage_rules(&[dep]).unwrap();
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
 
     // Should only find the .md file
     assert_eq!(results[0].sub_files.len(), 1);
@@ -253,15 +1070,25 @@ mod tests {
         name: "pkg1".into(),
         version: "1.0.0".into(),
         path: pkg1_path,
+        conditions: vec![],
+        source: None,
+        repository: None,
+        depth: 1,
+        rules_file: None,
       },
       Dependency {
         name: "pkg2".into(),
         version: "2.0.0".into(),
         path: pkg2_path,
+        conditions: vec![],
+        source: None,
+        repository: None,
+        depth: 1,
+        rules_file: None,
       },
     ];
 
-    let results = scan_for_usage_rules(&deps).unwrap();
+    let results = scan_for_usage_rules(&deps, &ScanOptions::default()).unwrap();
 
     assert_eq!(results.len(), 2);
     assert_eq!(results[0].package_name, "pkg1");
@@ -270,7 +1097,7 @@ mod tests {
 
   #[test]
   fn test_handles_empty_dependency_list() {
-    let results = scan_for_usage_rules(&[]).unwrap();
+    let results = scan_for_usage_rules(&[], &ScanOptions::default()).unwrap();
     assert_eq!(results.len(), 0);
   }
 
@@ -280,14 +1107,39 @@ mod tests {
     let file_path = temp.path().join("test.md");
     fs::write(&file_path, "Test content").unwrap();
 
-    let content = read_file_content(&file_path).unwrap();
+    let content = read_file_content(&file_path, false).unwrap();
     assert_eq!(content, "Test content");
   }
 
+  #[test]
+  fn test_read_file_content_strips_bom() {
+    let temp = TempDir::new().unwrap();
+    let file_path = temp.path().join("bom.md");
+    fs::write(&file_path, b"\xef\xbb\xbf# Rules\n").unwrap();
+
+    assert_eq!(read_file_content(&file_path, false).unwrap(), "# Rules\n");
+  }
+
+  #[test]
+  fn test_read_file_content_decodes_invalid_utf8_lossily() {
+    let temp = TempDir::new().unwrap();
+    let file_path = temp.path().join("latin1.md");
+    fs::write(&file_path, b"Caf\xe9 rules\n").unwrap();
+
+    assert_eq!(
+      read_file_content(&file_path, false).unwrap(),
+      "Caf\u{fffd} rules\n"
+    );
+    assert!(read_file_content(&file_path, true)
+      .unwrap_err()
+      .to_string()
+      .contains("is not valid UTF-8"));
+  }
+
   #[test]
   fn test_read_file_content_error() {
     let non_existent = PathBuf::from("/nonexistent/path/file.md");
-    let result = read_file_content(&non_existent);
+    let result = read_file_content(&non_existent, false);
 
     assert!(result.is_err());
     assert!(result
@@ -295,4 +1147,471 @@ mod tests {
       .to_string()
       .contains("Failed to read file"));
   }
+
+  #[test]
+  fn test_locale_prefers_translated_files() {
+    let temp = TempDir::new().unwrap();
+    let pkg_path = temp.path();
+    fs::write(pkg_path.join("usage-rules.md"), "English").unwrap();
+    fs::write(pkg_path.join("usage-rules.fr.md"), "Français").unwrap();
+    let sub_dir = pkg_path.join("usage_rules");
+    fs::create_dir_all(sub_dir.join("fr")).unwrap();
+    fs::write(sub_dir.join("async.md"), "Async").unwrap();
+    fs::write(sub_dir.join("fr/async.md"), "Asynchrone").unwrap();
+
+    let dep = Dependency {
+      name: "test".into(),
+      version: "1.0.0".into(),
+      path: pkg_path.to_path_buf(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    };
+    let options = ScanOptions {
+      locale: Some("fr".to_string()),
+      ..ScanOptions::default()
+    };
+
+    let results = scan_for_usage_rules(std::slice::from_ref(&dep), &options).unwrap();
+    assert_eq!(
+      results[0].main_file,
+      Some(pkg_path.join("usage-rules.fr.md"))
+    );
+    assert_eq!(results[0].sub_files.len(), 1);
+    assert_eq!(
+      results[0].sub_files[0].full_path,
+      sub_dir.join("fr/async.md")
+    );
+
+    // Locales without translations fall back to the defaults
+    let options = ScanOptions {
+      locale: Some("de".to_string()),
+      ..ScanOptions::default()
+    };
+    let results = scan_for_usage_rules(&[dep], &options).unwrap();
+    assert_eq!(results[0].main_file, Some(pkg_path.join("usage-rules.md")));
+  }
+
+  #[test]
+  fn test_package_summary_serializes_to_json() {
+    let rules = UsageRules {
+      package_name: "pkg".to_string(),
+      package_version: "1.2.3".to_string(),
+      main_file: None,
+      sub_files: vec![UsageRuleSubFile {
+        relative_path_name: "nested/async".to_string(),
+        full_path: PathBuf::from("/pkg/usage_rules/nested/async.md"),
+        order: None,
+      }],
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
+    };
+
+    let json = serde_json::to_value(PackageSummary::from(&rules)).unwrap();
+
+    assert_eq!(
+      json,
+      serde_json::json!({
+        "package_name": "pkg",
+        "package_version": "1.2.3",
+        "has_main_file": false,
+        "sub_files": ["nested/async"],
+      })
+    );
+  }
+
+  #[test]
+  fn test_scan_dependencies_keeps_packages_without_rules() {
+    let temp = TempDir::new().unwrap();
+    let with_rules = temp.path().join("with");
+    let without_rules = temp.path().join("without");
+    fs::create_dir_all(&with_rules).unwrap();
+    fs::create_dir_all(&without_rules).unwrap();
+    fs::write(with_rules.join("usage-rules.md"), "Rules").unwrap();
+
+    let deps = [
+      Dependency {
+        name: "without".to_string(),
+        version: "1.0.0".to_string(),
+        path: without_rules,
+        conditions: vec![],
+        source: None,
+        repository: None,
+        depth: 1,
+        rules_file: None,
+      },
+      Dependency {
+        name: "with".to_string(),
+        version: "1.0.0".to_string(),
+        path: with_rules,
+        conditions: vec![],
+        source: None,
+        repository: None,
+        depth: 1,
+        rules_file: None,
+      },
+    ];
+    let results = scan_dependencies(&deps, &ScanOptions::default()).unwrap();
+
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_none());
+    assert_eq!(results[1].as_ref().unwrap().package_name, "with");
+  }
+
+  #[test]
+  fn test_custom_rules_filename_and_dir() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("agents")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Default").unwrap();
+    fs::write(pkg.join("AGENTS.md"), "Agents").unwrap();
+    fs::write(pkg.join("agents/async.md"), "Async").unwrap();
+
+    let dep = Dependency {
+      name: "pkg".to_string(),
+      version: "1.0.0".to_string(),
+      path: pkg.clone(),
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    };
+    let options = ScanOptions {
+      rules_filename: "AGENTS.md".to_string(),
+      rules_dir: "agents".to_string(),
+      ..ScanOptions::default()
+    };
+    let results = scan_for_usage_rules(&[dep], &options).unwrap();
+
+    assert_eq!(results[0].main_file, Some(pkg.join("AGENTS.md")));
+    assert_eq!(results[0].sub_files.len(), 1);
+    assert_eq!(results[0].sub_files[0].relative_path_name, "async");
+  }
+
+  #[test]
+  fn test_finds_hyphenated_sub_file_dir() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("usage-rules")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+    fs::write(pkg.join("usage-rules/async.md"), "Async").unwrap();
+
+    let results =
+      scan_for_usage_rules(&[dependency("pkg", &pkg)], &ScanOptions::default()).unwrap();
+
+    assert_eq!(results[0].sub_files.len(), 1);
+    assert_eq!(results[0].sub_files[0].relative_path_name, "async");
+    assert_eq!(
+      results[0].sub_files[0].full_path,
+      pkg.join("usage-rules/async.md")
+    );
+  }
+
+  #[test]
+  fn test_extensions_select_sub_files() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("usage_rules")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+    fs::write(pkg.join("usage_rules/async.md"), "Async").unwrap();
+    fs::write(pkg.join("usage_rules/sync.markdown"), "Sync").unwrap();
+    let deps = [dependency("pkg", &pkg)];
+
+    let results = scan_for_usage_rules(&deps, &ScanOptions::default()).unwrap();
+    assert_eq!(results[0].sub_files.len(), 1);
+
+    let options = ScanOptions {
+      extensions: vec!["md".to_string(), "markdown".to_string()],
+      ..ScanOptions::default()
+    };
+    let results = scan_for_usage_rules(&deps, &options).unwrap();
+    let names: Vec<_> = results[0]
+      .sub_files
+      .iter()
+      .map(|sub| sub.relative_path_name.as_str())
+      .collect();
+    assert_eq!(names, ["async", "sync"]);
+  }
+
+  #[test]
+  fn test_subfile_patterns_filter_sub_files() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("usage_rules/patterns")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+    for name in [
+      "async.md",
+      "async_io.md",
+      "errors.md",
+      "patterns/builder.md",
+    ] {
+      fs::write(pkg.join("usage_rules").join(name), name).unwrap();
+    }
+
+    let options = ScanOptions {
+      subfile_patterns: Some(
+        crate::patterns::compile_globs(&["async*".to_string(), "patterns/**".to_string()]).unwrap(),
+      ),
+      ..ScanOptions::default()
+    };
+    let results = scan_for_usage_rules(&[dependency("pkg", &pkg)], &options).unwrap();
+
+    let names: Vec<_> = results[0]
+      .sub_files
+      .iter()
+      .map(|sub| sub.relative_path_name.as_str())
+      .collect();
+    assert_eq!(names, ["async", "async_io", "patterns/builder"]);
+  }
+
+  #[test]
+  fn test_merges_both_sub_file_dirs_preferring_underscore() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("usage_rules")).unwrap();
+    fs::create_dir_all(pkg.join("usage-rules")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+    fs::write(pkg.join("usage_rules/async.md"), "Async").unwrap();
+    fs::write(pkg.join("usage_rules/shared.md"), "Underscore").unwrap();
+    fs::write(pkg.join("usage-rules/shared.md"), "Hyphen").unwrap();
+    fs::write(pkg.join("usage-rules/testing.md"), "Testing").unwrap();
+
+    let results =
+      scan_for_usage_rules(&[dependency("pkg", &pkg)], &ScanOptions::default()).unwrap();
+
+    let names: Vec<_> = results[0]
+      .sub_files
+      .iter()
+      .map(|f| f.relative_path_name.as_str())
+      .collect();
+    assert_eq!(names, vec!["async", "shared", "testing"]);
+    assert_eq!(
+      results[0].sub_files[1].full_path,
+      pkg.join("usage_rules/shared.md")
+    );
+  }
+
+  #[test]
+  fn test_custom_rules_dir_ignores_hyphenated_dir() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("usage-rules")).unwrap();
+
+    let options = ScanOptions {
+      rules_dir: "agents".to_string(),
+      ..ScanOptions::default()
+    };
+
+    assert_eq!(rules_dirs(&pkg, &options), vec![pkg.join("agents")]);
+    assert_eq!(
+      rules_dirs(&pkg, &ScanOptions::default()),
+      vec![pkg.join("usage-rules")]
+    );
+  }
+
+  #[test]
+  fn test_localized_filename() {
+    assert_eq!(
+      localized_filename("usage-rules.md", "fr"),
+      "usage-rules.fr.md"
+    );
+    assert_eq!(localized_filename("llms.txt", "de"), "llms.de.txt");
+    assert_eq!(localized_filename("AGENTS", "es"), "AGENTS.es");
+  }
+
+  #[test]
+  fn test_finds_main_file_ignoring_case() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(&pkg).unwrap();
+    fs::write(pkg.join("USAGE-RULES.md"), "Upper").unwrap();
+    fs::write(pkg.join("Usage-Rules.md"), "Title").unwrap();
+
+    // On case-insensitive filesystems both names refer to one file
+    let main_file = find_file_ignoring_case(&pkg, "usage-rules.md");
+    assert!(main_file.is_file());
+    assert_eq!(
+      main_file
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_lowercase(),
+      "usage-rules.md"
+    );
+
+    fs::write(pkg.join("usage-rules.md"), "Exact").unwrap();
+    assert_eq!(
+      fs::read_to_string(find_file_ignoring_case(&pkg, "usage-rules.md")).unwrap(),
+      "Exact"
+    );
+  }
+
+  #[test]
+  fn test_scan_preserves_dependency_order() {
+    let temp = TempDir::new().unwrap();
+    let dependencies: Vec<Dependency> = (0..32)
+      .map(|i| {
+        let pkg = temp.path().join(format!("pkg-{}", i));
+        fs::create_dir_all(&pkg).unwrap();
+        // Leave some packages without rules to exercise filtering
+        if i % 3 != 0 {
+          fs::write(pkg.join("usage-rules.md"), "Rules").unwrap();
+        }
+        Dependency {
+          name: format!("pkg-{}", i),
+          version: "1.0.0".to_string(),
+          path: pkg,
+          conditions: vec![],
+          source: None,
+          repository: None,
+          depth: 1,
+          rules_file: None,
+        }
+      })
+      .collect();
+
+    let results = scan_for_usage_rules(&dependencies, &ScanOptions::default()).unwrap();
+
+    let expected: Vec<String> = (0..32)
+      .filter(|i| i % 3 != 0)
+      .map(|i| format!("pkg-{}", i))
+      .collect();
+    let names: Vec<String> = results.into_iter().map(|r| r.package_name).collect();
+    assert_eq!(names, expected);
+  }
+
+  #[test]
+  fn test_sub_files_are_sorted_by_relative_path() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    fs::create_dir_all(pkg.join("usage_rules/patterns")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+    for name in ["zeta", "alpha", "patterns/builder", "patterns/async", "mid"] {
+      fs::write(pkg.join(format!("usage_rules/{}.md", name)), name).unwrap();
+    }
+
+    let dep = Dependency {
+      name: "pkg".to_string(),
+      version: "1.0.0".to_string(),
+      path: pkg,
+      conditions: vec![],
+      source: None,
+      repository: None,
+      depth: 1,
+      rules_file: None,
+    };
+    let results = scan_for_usage_rules(&[dep], &ScanOptions::default()).unwrap();
+
+    let names: Vec<&str> = results[0]
+      .sub_files
+      .iter()
+      .map(|f| f.relative_path_name.as_str())
+      .collect();
+    assert_eq!(
+      names,
+      vec!["alpha", "mid", "patterns/async", "patterns/builder", "zeta"]
+    );
+  }
+
+  #[test]
+  fn test_remove_unreadable_files() {
+    let temp = TempDir::new().unwrap();
+    let good_main = temp.path().join("good.md");
+    fs::write(&good_main, "Main").unwrap();
+    let bad_sub = temp.path().join("deleted-sub.md");
+
+    let rule = |name: &str, main_file: PathBuf, sub_files: Vec<UsageRuleSubFile>| UsageRules {
+      package_name: name.to_string(),
+      package_version: "1.0.0".to_string(),
+      main_file: Some(main_file),
+      sub_files,
+      conditions: vec![],
+      repository: None,
+      category: None,
+      depth: 1,
+    };
+    let rules = vec![
+      rule(
+        "partial",
+        good_main.clone(),
+        vec![UsageRuleSubFile {
+          relative_path_name: "bad".to_string(),
+          full_path: bad_sub,
+          order: None,
+        }],
+      ),
+      rule("missing", temp.path().join("missing.md"), vec![]),
+    ];
+
+    let (results, skipped) = remove_unreadable_files(rules, false);
+
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].package_name, "partial");
+    assert!(results[0].sub_files.is_empty());
+    assert_eq!(
+      skipped,
+      SkippedFiles {
+        packages: 1,
+        sub_files: 1
+      }
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_sub_file_scan_breaks_symlink_cycles() {
+    let temp = TempDir::new().unwrap();
+    let pkg = temp.path().join("pkg");
+    let rules_dir = pkg.join("usage_rules");
+    fs::create_dir_all(rules_dir.join("shared")).unwrap();
+    fs::write(pkg.join("usage-rules.md"), "Main").unwrap();
+    fs::write(rules_dir.join("shared/topic.md"), "Topic").unwrap();
+    std::os::unix::fs::symlink(&rules_dir, rules_dir.join("shared/loop")).unwrap();
+    std::os::unix::fs::symlink(rules_dir.join("shared"), rules_dir.join("alias")).unwrap();
+
+    let sub_files = find_sub_files(&rules_dir, &["md".to_string()], false);
+
+    let names: Vec<&str> = sub_files
+      .iter()
+      .map(|f| f.relative_path_name.as_str())
+      .collect();
+    assert_eq!(names.len(), 1);
+    assert!(names[0] == "shared/topic" || names[0] == "alias/topic");
+
+    let sub_files = find_sub_files(&rules_dir, &["md".to_string()], true);
+    assert_eq!(sub_files.len(), 1);
+  }
+
+  #[test]
+  fn test_sub_file_scan_respects_gitignore() {
+    let temp = TempDir::new().unwrap();
+    let rules_dir = temp.path().join("usage_rules");
+    fs::create_dir_all(rules_dir.join("generated")).unwrap();
+    fs::write(temp.path().join(".gitignore"), "draft.md\n").unwrap();
+    fs::write(rules_dir.join(".gitignore"), "generated/\n").unwrap();
+    fs::write(rules_dir.join("kept.md"), "Kept").unwrap();
+    fs::write(rules_dir.join("draft.md"), "Draft").unwrap();
+    fs::write(rules_dir.join("generated/intermediate.md"), "Generated").unwrap();
+    let extensions = ["md".to_string()];
+
+    let names = |respect_gitignore| {
+      let mut names: Vec<String> = find_sub_files(&rules_dir, &extensions, respect_gitignore)
+        .into_iter()
+        .map(|f| f.relative_path_name.replace('\\', "/"))
+        .collect();
+      names.sort();
+      names
+    };
+
+    assert_eq!(
+      names(false),
+      vec!["draft", "generated/intermediate", "kept"]
+    );
+    assert_eq!(names(true), vec!["kept"]);
+  }
 }
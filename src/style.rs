@@ -0,0 +1,113 @@
+use clap::ValueEnum;
+use std::{fmt, io::IsTerminal, sync::OnceLock};
+
+/// When to color terminal output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+  /// Color when stdout is a terminal and `NO_COLOR` is not set.
+  #[default]
+  Auto,
+  /// Always color.
+  Always,
+  /// Never color.
+  Never,
+}
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+/// Decides whether to color output for the rest of the run.
+///
+/// Only the first call has an effect. Without a call, output is not colored.
+pub fn init(choice: ColorChoice) {
+  let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+  let _ = ENABLED.set(should_color(
+    choice,
+    no_color,
+    std::io::stdout().is_terminal(),
+  ));
+}
+
+/// Returns whether to color output given the `--color` choice, whether
+/// `NO_COLOR` is set, and whether stdout is a terminal.
+fn should_color(choice: ColorChoice, no_color: bool, is_terminal: bool) -> bool {
+  match choice {
+    ColorChoice::Always => true,
+    ColorChoice::Never => false,
+    ColorChoice::Auto => !no_color && is_terminal,
+  }
+}
+
+/// Text wrapped in an ANSI style when coloring is enabled, and displayed
+/// unchanged otherwise.
+pub struct Styled<T> {
+  code: &'static str,
+  value: T,
+  enabled: bool,
+}
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.enabled {
+      write!(f, "\x1b[{}m{}\x1b[0m", self.code, self.value)
+    } else {
+      self.value.fmt(f)
+    }
+  }
+}
+
+fn styled<T: fmt::Display>(code: &'static str, value: T) -> Styled<T> {
+  Styled {
+    code,
+    value,
+    enabled: ENABLED.get().copied().unwrap_or(false),
+  }
+}
+
+/// Bold text, e.g. package names.
+pub fn bold<T: fmt::Display>(value: T) -> Styled<T> {
+  styled("1", value)
+}
+
+/// Green text, e.g. success checkmarks.
+pub fn green<T: fmt::Display>(value: T) -> Styled<T> {
+  styled("32", value)
+}
+
+/// Gray text, e.g. packages without usage rules.
+pub fn gray<T: fmt::Display>(value: T) -> Styled<T> {
+  styled("90", value)
+}
+
+/// Dimmed text, e.g. sub-file counts.
+pub fn dim<T: fmt::Display>(value: T) -> Styled<T> {
+  styled("2", value)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_should_color() {
+    assert!(should_color(ColorChoice::Auto, false, true));
+    assert!(!should_color(ColorChoice::Auto, true, true));
+    assert!(!should_color(ColorChoice::Auto, false, false));
+    assert!(should_color(ColorChoice::Always, true, false));
+    assert!(!should_color(ColorChoice::Never, false, true));
+  }
+
+  #[test]
+  fn test_styled_display() {
+    let text = |enabled| {
+      Styled {
+        code: "1",
+        value: "serde",
+        enabled,
+      }
+      .to_string()
+    };
+
+    assert_eq!(text(true), "\x1b[1mserde\x1b[0m");
+    assert_eq!(text(false), "serde");
+  }
+}
@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use std::{fmt, sync::OnceLock};
+
+/// Stable identifiers for the kinds of warnings the tool emits, used to
+/// suppress them with `--allow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WarningCategory {
+  /// Usage rules refer to a crate that is not a dependency.
+  UnknownCrateRefs,
+  /// A rule file was dropped for linking outside its package folder.
+  EscapingLinks,
+  /// A scan cache entry could not be written.
+  CacheWrite,
+  /// An option has no effect on the current platform.
+  UnsupportedOption,
+  /// A dependency's source is not on disk.
+  MissingSource,
+  /// A package named on the command line matches no package.
+  UnknownPackage,
+  /// A symlink in a rules directory leads back to a directory already scanned.
+  SymlinkCycle,
+  /// A rule file could not be read and was skipped (`--keep-going`).
+  UnreadableFile,
+  /// A rule file is not valid UTF-8 and was decoded lossily.
+  InvalidUtf8,
+  /// A package has both a `usage_rules/` and a `usage-rules/` directory.
+  AmbiguousRulesDir,
+  /// The output file has a lone or out-of-order section marker.
+  MalformedMarkers,
+  /// A package's rule files are all blank, so its section was left out.
+  EmptyRules,
+  /// `clean` kept a link folder that holds no generated index.
+  UnknownLinkFolder,
+}
+
+impl fmt::Display for WarningCategory {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let value = self
+      .to_possible_value()
+      .expect("warning categories are never skipped");
+    f.write_str(value.get_name())
+  }
+}
+
+static ALLOWED: OnceLock<Vec<WarningCategory>> = OnceLock::new();
+
+/// Suppresses warnings of the given categories for the rest of the run.
+///
+/// Only the first call has an effect.
+pub fn allow(categories: Vec<WarningCategory>) {
+  let _ = ALLOWED.set(categories);
+}
+
+/// Returns true if warnings of `category` have been suppressed.
+pub fn is_allowed(category: WarningCategory) -> bool {
+  ALLOWED
+    .get()
+    .is_some_and(|allowed| allowed.contains(&category))
+}
+
+/// Prints a warning tagged with its category unless the category is allowed.
+pub fn warn(category: WarningCategory, message: impl fmt::Display) {
+  if !is_allowed(category) {
+    eprintln!("Warning: {} [{}]", message, category);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_category_ids_are_kebab_case() {
+    assert_eq!(
+      WarningCategory::UnknownCrateRefs.to_string(),
+      "unknown-crate-refs"
+    );
+    assert_eq!(
+      WarningCategory::from_str("escaping-links", false),
+      Ok(WarningCategory::EscapingLinks)
+    );
+  }
+}
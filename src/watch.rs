@@ -0,0 +1,218 @@
+use crate::{
+  logger,
+  pipeline::{load_dependencies, sync, SyncOptions},
+  scanner::rules_dirs,
+  style,
+};
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+  path::{Path, PathBuf},
+  sync::mpsc,
+  time::Duration,
+};
+
+/// How long the filesystem must be quiet before a change triggers a sync.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The project manifest and lock file names whose changes trigger a sync.
+const PROJECT_FILES: [&str; 2] = ["Cargo.toml", "Cargo.lock"];
+
+/// The directories being watched.
+#[derive(Debug, Default)]
+struct WatchSet {
+  /// Directories holding the project's Cargo.toml and Cargo.lock.
+  project_dirs: Vec<PathBuf>,
+  /// Package roots of the dependencies.
+  dependency_dirs: Vec<PathBuf>,
+  /// Sub-file directories of the dependencies, watched recursively.
+  rules_dirs: Vec<PathBuf>,
+  /// Files and folders written by the sync, whose changes are ignored.
+  outputs: Vec<PathBuf>,
+}
+
+impl WatchSet {
+  /// Returns true if a change to `path` should trigger a sync.
+  fn is_relevant(&self, path: &Path) -> bool {
+    !self.outputs.iter().any(|output| path.starts_with(output))
+      && (self.is_project_file(path)
+        || self.dependency_dirs.iter().any(|dir| path.starts_with(dir)))
+  }
+
+  /// Returns the paths changed by `event` that should trigger a sync. Reads
+  /// are ignored, since the sync itself reads every watched file.
+  fn changed_paths(&self, event: Event) -> Vec<PathBuf> {
+    if matches!(event.kind, EventKind::Access(_)) {
+      return Vec::new();
+    }
+    event
+      .paths
+      .into_iter()
+      .filter(|path| self.is_relevant(path))
+      .collect()
+  }
+
+  /// Returns true if `path` is the project's Cargo.toml or Cargo.lock, so the
+  /// dependency graph may have changed.
+  fn is_project_file(&self, path: &Path) -> bool {
+    path
+      .file_name()
+      .is_some_and(|name| PROJECT_FILES.iter().any(|file| name == *file))
+      && path
+        .parent()
+        .is_some_and(|dir| self.project_dirs.iter().any(|p| p == dir))
+  }
+}
+
+/// Re-runs [`sync`] whenever the project's Cargo.toml or Cargo.lock, or the
+/// usage rules of a dependency, change. Runs until the process is
+/// interrupted.
+///
+/// Changes are debounced, and changes to the files the sync itself writes are
+/// ignored. A failed sync is reported and watching continues. Since every
+/// file is replaced atomically, interrupting with Ctrl-C never leaves a
+/// partially written output.
+///
+/// # Errors
+///
+/// Returns an error if the project directory cannot be determined or the
+/// filesystem watcher cannot be started.
+pub fn watch(options: &SyncOptions) -> Result<()> {
+  let current_dir = std::env::current_dir().context("Failed to determine the current directory")?;
+  let (tx, rx) = mpsc::channel();
+  let mut watcher = notify::recommended_watcher(tx).context("Failed to start file watcher")?;
+  let mut watch_set = watch_paths(&mut watcher, options, &current_dir)?;
+
+  logger::progress("\nWatching for changes (press Ctrl-C to stop)...");
+  while let Ok(result) = rx.recv() {
+    // Collect every change until the filesystem has been quiet for DEBOUNCE
+    let mut changed = Vec::new();
+    let mut next = Some(result);
+    while let Some(result) = next {
+      match result {
+        Ok(event) => changed.extend(watch_set.changed_paths(event)),
+        Err(e) => eprintln!("Error: file watcher failed: {:?}", e),
+      }
+      next = rx.recv_timeout(DEBOUNCE).ok();
+    }
+    let Some(first) = changed.first() else {
+      continue;
+    };
+
+    logger::progress(format_args!("\nChange detected in {}", first.display()));
+    match sync(options) {
+      Ok(report) => logger::success(format_args!(
+        "{} Regenerated {}: {} packages, {} files written, {} unchanged",
+        style::green("✓"),
+        options.target().display(),
+        report.packages.len(),
+        report.files.written.len(),
+        report.files.unchanged.len()
+      )),
+      Err(e) => eprintln!("Error: {:?}", e),
+    }
+
+    if changed.iter().any(|path| watch_set.is_project_file(path)) {
+      for dir in watch_set
+        .project_dirs
+        .iter()
+        .chain(&watch_set.dependency_dirs)
+        .chain(&watch_set.rules_dirs)
+      {
+        let _ = watcher.unwatch(dir);
+      }
+      watch_set = watch_paths(&mut watcher, options, &current_dir)?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Watches the project directories and the root of every dependency, plus
+/// each dependency's sub-file directory recursively.
+fn watch_paths(
+  watcher: &mut RecommendedWatcher,
+  options: &SyncOptions,
+  current_dir: &Path,
+) -> Result<WatchSet> {
+  let manifest_path = current_dir.join(
+    options
+      .metadata
+      .manifest_path
+      .as_deref()
+      .unwrap_or(Path::new("Cargo.toml")),
+  );
+  let manifest_dir = manifest_path.parent().unwrap_or(current_dir);
+  let mut project_dirs = vec![manifest_dir.to_path_buf()];
+  if let Some(lock_dir) = manifest_dir
+    .ancestors()
+    .find(|dir| dir.join("Cargo.lock").is_file())
+    .filter(|dir| *dir != manifest_dir)
+  {
+    project_dirs.push(lock_dir.to_path_buf());
+  }
+
+  let mut watch_set = WatchSet {
+    project_dirs,
+    dependency_dirs: Vec::new(),
+    rules_dirs: Vec::new(),
+    outputs: [options.target(), &options.link_folder]
+      .into_iter()
+      .chain(options.additional_outputs.iter().map(PathBuf::as_path))
+      .map(|path| current_dir.join(path))
+      .collect(),
+  };
+
+  let dependencies = load_dependencies(&options.metadata, options.metadata_cache.as_ref())?;
+  for dep in dependencies.dependencies {
+    let path = current_dir.join(&dep.path);
+    for rules_dir in rules_dirs(&path, &options.scan) {
+      if rules_dir.is_dir() {
+        watcher
+          .watch(&rules_dir, RecursiveMode::Recursive)
+          .with_context(|| format!("Failed to watch {}", rules_dir.display()))?;
+        watch_set.rules_dirs.push(rules_dir);
+      }
+    }
+    watch_set.dependency_dirs.push(path);
+  }
+
+  for dir in watch_set
+    .project_dirs
+    .iter()
+    .chain(&watch_set.dependency_dirs)
+  {
+    watcher
+      .watch(dir, RecursiveMode::NonRecursive)
+      .with_context(|| format!("Failed to watch {}", dir.display()))?;
+  }
+
+  Ok(watch_set)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_watch_set_ignores_outputs_and_unrelated_files() {
+    let watch_set = WatchSet {
+      project_dirs: vec![PathBuf::from("/project")],
+      dependency_dirs: vec![PathBuf::from("/registry/serde-1.0.0")],
+      rules_dirs: vec![],
+      outputs: vec![
+        PathBuf::from("/project/Agents.md"),
+        PathBuf::from("/project/usage_rules"),
+      ],
+    };
+
+    assert!(watch_set.is_relevant(Path::new("/project/Cargo.lock")));
+    assert!(watch_set.is_relevant(Path::new("/registry/serde-1.0.0/usage-rules.md")));
+    assert!(!watch_set.is_relevant(Path::new("/project/Agents.md")));
+    assert!(!watch_set.is_relevant(Path::new("/project/usage_rules/serde/serde.md")));
+    assert!(!watch_set.is_relevant(Path::new("/project/src/main.rs")));
+
+    assert!(watch_set.is_project_file(Path::new("/project/Cargo.toml")));
+    assert!(!watch_set.is_project_file(Path::new("/registry/serde-1.0.0/Cargo.toml")));
+  }
+}
@@ -1,31 +1,400 @@
-use crate::aggregator::{format_package_section, PackageContentInfo};
+use crate::{
+  aggregator::{
+    format_package_section, link_target, section_heading, ContentOptions, ExistingContent,
+    LinkStyle, PackageContentInfo, SectionMarkers, SectionOptions,
+  },
+  encoding::{decode_output, encode_output},
+  logger,
+  markdown::{normalize_line_endings, LineEnding},
+  scanner::read_file_content,
+};
 use anyhow::{Context, Result};
-use std::{fs, path::Path};
+use clap::ValueEnum;
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+};
+
+/// Name of the overview file written at the root of the linked folder.
+pub const INDEX_FILENAME: &str = "index.md";
+
+/// A block of the generated usage-rules section that can be positioned with
+/// `--layout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutBlock {
+  /// The "IMPORTANT" instructions for agents.
+  Header,
+  /// The general Rust guidance from base.md.
+  Base,
+  /// The per-package usage rules sections.
+  Packages,
+}
 
-/// Generates the standard header for the output file usage-rules section.
-pub fn generate_header(use_folder_mode: bool) -> String {
-  let mut header = "IMPORTANT: Consult these usage rules early and often when working with the \
-                    packages listed below. Before attempting to use any of these packages or to \
-                    discover if you should use them, review their usage rules to understand the \
-                    correct patterns, conventions, and best practices.\n\nThere are general rules \
-                    for rust, cargo, etc also contained directly in this file."
-    .to_string();
+/// The default arrangement of blocks within the generated section.
+pub const DEFAULT_LAYOUT: [LayoutBlock; 3] = [
+  LayoutBlock::Header,
+  LayoutBlock::Base,
+  LayoutBlock::Packages,
+];
+
+/// Settings controlling how the generated section is rendered.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+  /// Order of the blocks within the generated section.
+  pub layout: Vec<LayoutBlock>,
+  /// Encoding of the written output file.
+  pub encoding: &'static Encoding,
+  /// Formatting of each package section.
+  pub section: SectionOptions,
+  /// Permission bits applied to every written file (Unix only).
+  pub mode: Option<u32>,
+  /// Whether to list links to the package sections after the header.
+  pub toc: bool,
+  /// Whether to group package sections under a heading per front-matter
+  /// category.
+  pub group_by_category: bool,
+  /// Agent tool whose header wording is used.
+  pub preset: Preset,
+  /// Markers delimiting the generated section.
+  pub markers: SectionMarkers,
+  /// File replacing the bundled general Rust guidance, if any.
+  pub base_file: Option<PathBuf>,
+  /// Sections kept verbatim from the existing output instead of being
+  /// rendered again, by package label.
+  pub kept_sections: HashMap<String, String>,
+}
 
-  if use_folder_mode {
-    header.push_str(
-      "\n\nEach package's usage rules are contained in separate files within the linked folder. \
-       Please refer to the individual files for detailed usage instructions.",
-    );
+impl Default for RenderOptions {
+  fn default() -> Self {
+    Self {
+      layout: DEFAULT_LAYOUT.to_vec(),
+      encoding: UTF_8,
+      section: SectionOptions::default(),
+      mode: None,
+      toc: false,
+      group_by_category: false,
+      preset: Preset::default(),
+      markers: SectionMarkers::default(),
+      base_file: None,
+      kept_sections: HashMap::new(),
+    }
   }
+}
+
+/// Parses an octal permission mode such as `664` or `0o640`.
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+  let digits = value.trim_start_matches("0o");
+  match u32::from_str_radix(digits, 8) {
+    Ok(mode) if mode <= 0o7777 => Ok(mode),
+    _ => Err(format!("'{}' is not an octal permission mode", value)),
+  }
+}
+
+/// Applies the configured permission bits to a written file.
+fn apply_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+  let Some(mode) = mode else {
+    return Ok(());
+  };
+
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+      .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+  }
+  #[cfg(not(unix))]
+  let _ = (path, mode);
+
+  Ok(())
+}
 
-  header.push_str(&format!(
-    "## General Rust Usage\n\n{}",
-    include_str!("../base.md")
+/// Writes `content` to a temporary file next to `path` and renames it over
+/// `path`, so readers see either the old or the new file but never a partial
+/// one.
+///
+/// The replaced file's permissions are kept unless `mode` is given.
+fn write_atomic(path: &Path, content: &[u8], mode: Option<u32>) -> Result<()> {
+  let file_name = path
+    .file_name()
+    .with_context(|| format!("Invalid output path: {}", path.display()))?;
+  let temp_path = path.with_file_name(format!(
+    ".{}.{}.tmp",
+    file_name.to_string_lossy(),
+    std::process::id()
   ));
 
+  let result = (|| {
+    fs::write(&temp_path, content)
+      .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+    if let Ok(metadata) = fs::metadata(path) {
+      fs::set_permissions(&temp_path, metadata.permissions())
+        .with_context(|| format!("Failed to set permissions on {}", temp_path.display()))?;
+    }
+    apply_mode(&temp_path, mode)?;
+    fs::rename(&temp_path, path)
+      .with_context(|| format!("Failed to replace output file: {}", path.display()))
+  })();
+
+  if result.is_err() {
+    let _ = fs::remove_file(&temp_path);
+  }
+  result
+}
+
+/// Agent tool whose conventions the output follows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+  /// Agents.md, read by most agent tools.
+  #[default]
+  Agents,
+  /// CLAUDE.md for Claude Code.
+  Claude,
+  /// .cursorrules for Cursor, with terse header wording.
+  Cursor,
+}
+
+impl Preset {
+  /// Returns the output file used when `--output` is not given.
+  pub fn default_output(self) -> PathBuf {
+    PathBuf::from(match self {
+      Preset::Agents => "Agents.md",
+      Preset::Claude => "CLAUDE.md",
+      Preset::Cursor => ".cursorrules",
+    })
+  }
+}
+
+/// Generates the standard header for the output file usage-rules section,
+/// worded for the given preset.
+pub fn generate_header(use_folder_mode: bool, preset: Preset) -> String {
+  let mut header = match preset {
+    Preset::Agents => {
+      "IMPORTANT: Consult these usage rules early and often when working with the \
+                       packages listed below. Before attempting to use any of these packages or \
+                       to discover if you should use them, review their usage rules to \
+                       understand the correct patterns, conventions, and best practices.\n\nThere \
+                       are general rules for rust, cargo, etc also contained directly in this \
+                       file."
+    }
+    Preset::Claude => {
+      "IMPORTANT: Read the usage rules of a package listed below before writing \
+                       code that uses it or deciding whether to use it. They describe the correct \
+                       patterns, conventions, and best practices for each package.\n\nGeneral \
+                       rules for rust, cargo, etc are also contained directly in this file."
+    }
+    Preset::Cursor => {
+      "Follow these usage rules when using the packages below, and the general \
+                       Rust rules in this file."
+    }
+  }
+  .to_string();
+
+  if use_folder_mode {
+    header.push_str(match preset {
+      Preset::Cursor => " Each package's rules are in the linked files.",
+      Preset::Agents | Preset::Claude => {
+        "\n\nEach package's usage rules are contained in separate files within the linked \
+         folder. Please refer to the individual files for detailed usage instructions."
+      }
+    });
+  }
+
   header
 }
 
+/// Generates the general Rust guidance section from `base_file`, or from the
+/// bundled base.md if none is given.
+///
+/// # Errors
+///
+/// Returns an error if `base_file` cannot be read.
+pub fn generate_base_section(base_file: Option<&Path>) -> Result<String> {
+  let base = match base_file {
+    Some(path) => fs::read_to_string(path)
+      .with_context(|| format!("Failed to read base file: {}", path.display()))?,
+    None => include_str!("../base.md").to_string(),
+  };
+  Ok(format!("## General Rust Usage\n\n{}", base))
+}
+
+/// A format the generated output is rendered in.
+///
+/// Implementations only plan the files; writing them, and skipping files
+/// that already have the planned content, is shared.
+pub trait OutputWriter {
+  /// Plans the files written for the output file `output_path` without
+  /// touching the filesystem. The output file itself comes last.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the output cannot be rendered.
+  fn plan(
+    &self,
+    output_path: &Path,
+    packages: Vec<PackageContentInfo>,
+    existing: Option<ExistingContent>,
+    options: &RenderOptions,
+  ) -> Result<OutputPlan>;
+
+  /// Renders and writes the output, applying `options.mode` to every file.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the output cannot be rendered or written.
+  fn write(
+    &self,
+    output_path: &Path,
+    packages: Vec<PackageContentInfo>,
+    existing: Option<ExistingContent>,
+    options: &RenderOptions,
+  ) -> Result<WriteReport> {
+    self
+      .plan(output_path, packages, existing, options)?
+      .write(options.mode)
+  }
+}
+
+/// Format of the sync output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+  /// Markdown for AI coding agents, with rules inlined or linked.
+  #[default]
+  Markdown,
+  /// The aggregated rules of every package as a JSON document.
+  Json,
+}
+
+/// Markdown with every package's rules inlined in the output file (see
+/// [`plan_inline`]).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InlineWriter;
+
+impl OutputWriter for InlineWriter {
+  fn plan(
+    &self,
+    output_path: &Path,
+    packages: Vec<PackageContentInfo>,
+    existing: Option<ExistingContent>,
+    options: &RenderOptions,
+  ) -> Result<OutputPlan> {
+    plan_inline(output_path, packages, existing, options)
+  }
+}
+
+/// Markdown linking to copies of each package's rules in `folder_path` (see
+/// [`plan_linked`]).
+#[derive(Debug, Clone)]
+pub struct LinkedWriter {
+  pub folder_path: PathBuf,
+}
+
+impl OutputWriter for LinkedWriter {
+  fn plan(
+    &self,
+    output_path: &Path,
+    packages: Vec<PackageContentInfo>,
+    existing: Option<ExistingContent>,
+    options: &RenderOptions,
+  ) -> Result<OutputPlan> {
+    plan_linked(output_path, &self.folder_path, packages, existing, options)
+  }
+}
+
+/// The aggregated rules written by [`JsonWriter`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RulesExport {
+  pub packages: Vec<PackageExport>,
+}
+
+/// A package's rules in the JSON export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageExport {
+  pub name: String,
+  pub version: String,
+  /// Content of the main rules file, if the package has one.
+  pub content: Option<String>,
+  pub sub_files: Vec<SubFileExport>,
+}
+
+/// A sub-file's rules in the JSON export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubFileExport {
+  pub relative_path_name: String,
+  pub content: String,
+}
+
+impl PackageExport {
+  /// Reads the rule files of `package`, applying the `options`
+  /// transformations to each.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a rule file cannot be read.
+  pub fn read(package: &PackageContentInfo, options: &ContentOptions) -> Result<Self> {
+    Ok(Self {
+      name: package.name.clone(),
+      version: package.version.clone(),
+      content: package
+        .content
+        .main_file
+        .as_deref()
+        .map(|path| options.read(path))
+        .transpose()?,
+      sub_files: package
+        .content
+        .sub_files
+        .iter()
+        .map(|sub_file| {
+          Ok(SubFileExport {
+            relative_path_name: sub_file.relative_path_name.clone(),
+            content: options.read(&sub_file.full_path)?,
+          })
+        })
+        .collect::<Result<_>>()?,
+    })
+  }
+}
+
+/// Every package's rules as one JSON document, for tools that post-process
+/// them.
+///
+/// The existing content of the output file is not kept, and the document is
+/// always UTF-8.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonWriter;
+
+impl OutputWriter for JsonWriter {
+  fn plan(
+    &self,
+    output_path: &Path,
+    packages: Vec<PackageContentInfo>,
+    _existing: Option<ExistingContent>,
+    options: &RenderOptions,
+  ) -> Result<OutputPlan> {
+    let export = RulesExport {
+      packages: packages
+        .iter()
+        .map(|package| PackageExport::read(package, &options.section.content))
+        .collect::<Result<_>>()?,
+    };
+    let json = serde_json::to_string_pretty(&export).context("Failed to serialize usage rules")?;
+
+    Ok(OutputPlan {
+      files: vec![PlannedFile {
+        path: output_path.to_path_buf(),
+        source: FileSource::Generated(format!("{}\n", json).into_bytes()),
+      }],
+    })
+  }
+}
+
 /// Writes package content inline to a single output file.
 ///
 /// All package content is written directly into the main output file, with each
@@ -35,130 +404,563 @@ pub fn generate_header(use_folder_mode: bool) -> String {
 ///
 /// * `output_path` - Path where the output file should be written
 /// * `packages` - Vector of package content to write
-/// * `preamble` - Optional custom preamble to use instead of the default header
+/// * `existing` - Content of the existing file to keep around the generated
+///   section: a preamble above it, or the exact text surrounding its block
+/// * `options` - Settings controlling how the generated section is rendered
 ///
 /// # Returns
 ///
-/// `Ok(())` if the file is written successfully.
+/// Which files were written and which already had the rendered content.
 ///
 /// # Errors
 ///
-/// Returns an error if the file cannot be written to the specified path.
+/// Returns an error if the file cannot be written to the specified path, the
+/// layout is invalid, or the content cannot be represented in the output
+/// encoding.
 pub fn write_inline(
   output_path: &Path,
   packages: Vec<PackageContentInfo>,
-  preamble: Option<String>,
-) -> Result<()> {
-  let content = create_main_agents_file(packages, preamble, None)?;
-  fs::write(output_path, content)
-    .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+  existing: Option<ExistingContent>,
+  options: &RenderOptions,
+) -> Result<WriteReport> {
+  plan_inline(output_path, packages, existing, options)?.write(options.mode)
+}
 
-  Ok(())
+/// Plans the files `write_inline` writes without touching the filesystem.
+///
+/// # Errors
+///
+/// Returns an error if the layout is invalid, a rule file cannot be read, or
+/// the content cannot be represented in the output encoding.
+pub fn plan_inline(
+  output_path: &Path,
+  packages: Vec<PackageContentInfo>,
+  existing: Option<ExistingContent>,
+  options: &RenderOptions,
+) -> Result<OutputPlan> {
+  let content = create_main_agents_file(packages, existing, None, options)?;
+
+  Ok(OutputPlan {
+    files: vec![PlannedFile {
+      path: output_path.to_path_buf(),
+      source: FileSource::Generated(encode_output(&content, options.encoding)?),
+    }],
+  })
 }
 
 fn create_main_agents_file(
   packages: Vec<PackageContentInfo>,
-  preamble: Option<String>,
+  existing: Option<ExistingContent>,
   link_folder_name: Option<&str>,
+  options: &RenderOptions,
 ) -> Result<String> {
-  let header = generate_header(link_folder_name.is_some());
+  validate_layout(&options.layout)?;
 
-  let mut package_sections = Vec::new();
-  for pkg in &packages {
-    package_sections.push(format_package_section(pkg, link_folder_name)?);
+  let groups = if options.group_by_category {
+    category_groups(packages)
+  } else {
+    vec![(None, packages)]
+  };
+  let mut blocks = Vec::new();
+  let toc = (options.toc && groups.iter().any(|(_, group)| !group.is_empty()))
+    .then(|| generate_toc(groups.iter().flat_map(|(_, group)| group), &options.section));
+  if !options.layout.contains(&LayoutBlock::Header) {
+    blocks.extend(toc.clone());
+  }
+  for block in &options.layout {
+    match block {
+      LayoutBlock::Header => {
+        blocks.push(generate_header(link_folder_name.is_some(), options.preset));
+        blocks.extend(toc.clone());
+      }
+      LayoutBlock::Base => blocks.push(generate_base_section(options.base_file.as_deref())?),
+      LayoutBlock::Packages => {
+        let mut package_sections = Vec::new();
+        for (category, group) in &groups {
+          package_sections.extend(category.as_ref().map(|c| format!("# {}", c)));
+          for pkg in group {
+            match options.kept_sections.get(&pkg.label()) {
+              Some(section) => package_sections.push(section.clone()),
+              None => package_sections.push(format_package_section(
+                pkg,
+                link_folder_name,
+                &options.section,
+              )?),
+            }
+          }
+        }
+        blocks.push(package_sections.join("\n\n"));
+      }
+    }
   }
 
   // Wrap the generated content with cargo-usage-rules markers
   let generated_section = format!(
-    "<!-- cargo-usage-rules-start -->\n\n{}\n{}\n<!-- cargo-usage-rules-end -->\n\n",
-    header,
-    package_sections.join("\n\n")
+    "{}\n\n{}\n{}",
+    options.markers.start,
+    blocks.join("\n\n"),
+    options.markers.end
   );
 
-  Ok(if let Some(pre) = preamble {
-    if pre.is_empty() {
-      generated_section
-    } else {
-      format!("{}\n\n{}", pre, generated_section)
+  let content = match existing {
+    Some(ExistingContent::Preamble { preamble, footer }) => {
+      let mut content = String::new();
+      if !preamble.is_empty() {
+        content.push_str(&format!("{}\n\n", preamble));
+      }
+      content.push_str(&format!("{}\n\n", generated_section));
+      if !footer.is_empty() {
+        content.push_str(&format!("{}\n", footer));
+      }
+      content
     }
-  } else {
-    generated_section
-  })
+    Some(ExistingContent::Surrounding { before, after }) => {
+      format!("{}{}{}", before, generated_section, after)
+    }
+    None => format!("{}\n\n", generated_section),
+  };
+  Ok(normalize_line_endings(
+    &content,
+    options.section.content.line_ending,
+  ))
+}
+
+/// Heading of the group holding packages that declare no category.
+const UNCATEGORIZED: &str = "Uncategorized";
+
+/// Groups packages by their front-matter category, capitalized for use as a
+/// heading.
+///
+/// Groups are sorted by name with "Uncategorized" last, and packages keep
+/// their order within each group.
+fn category_groups(
+  packages: Vec<PackageContentInfo>,
+) -> Vec<(Option<String>, Vec<PackageContentInfo>)> {
+  let mut groups: Vec<(Option<String>, Vec<PackageContentInfo>)> = Vec::new();
+  for pkg in packages {
+    let category = pkg
+      .category
+      .as_deref()
+      .map(capitalize)
+      .unwrap_or_else(|| UNCATEGORIZED.to_string());
+    match groups
+      .iter_mut()
+      .find(|(name, _)| name.as_deref() == Some(category.as_str()))
+    {
+      Some((_, group)) => group.push(pkg),
+      None => groups.push((Some(category), vec![pkg])),
+    }
+  }
+  groups.sort_by_key(|(name, _)| {
+    let name = name.clone().unwrap_or_default();
+    (name == UNCATEGORIZED, name)
+  });
+  groups
+}
+
+/// Uppercases the first character of `text`.
+fn capitalize(text: &str) -> String {
+  let mut chars = text.chars();
+  match chars.next() {
+    Some(first) => first.to_uppercase().chain(chars).collect(),
+    None => String::new(),
+  }
+}
+
+/// Generates a bulleted list of links to each package section, using the
+/// anchors the `anchor_flavor` renderer assigns to their headings.
+fn generate_toc<'a>(
+  packages: impl IntoIterator<Item = &'a PackageContentInfo>,
+  options: &SectionOptions,
+) -> String {
+  let headings: Vec<String> = packages
+    .into_iter()
+    .map(|pkg| section_heading(pkg, options))
+    .collect();
+  let anchors = options
+    .anchor_flavor
+    .anchors(headings.iter().map(String::as_str));
+
+  let mut lines = vec!["## Contents".to_string(), String::new()];
+  for (heading, anchor) in headings.iter().zip(anchors) {
+    lines.push(format!("- [{}](#{})", heading, anchor));
+  }
+  lines.join("\n")
+}
+
+/// Ensures each block appears at most once in the layout.
+fn validate_layout(layout: &[LayoutBlock]) -> Result<()> {
+  for (i, block) in layout.iter().enumerate() {
+    if layout[..i].contains(block) {
+      anyhow::bail!("Layout block '{:?}' appears more than once", block);
+    }
+  }
+  Ok(())
 }
 
 /// Writes package content in folder mode with separate files and links.
+///
+/// Files that already have the planned content are left untouched.
 pub fn write_linked(
   output_path: &Path,
   folder_path: &Path,
   packages: Vec<PackageContentInfo>,
-  preamble: Option<String>,
-) -> Result<()> {
-  for pkg in packages.iter() {
-    // Create package subdirectory in usage_rules folder
-    let pkg_dir = folder_path.join(&pkg.name);
-    fs::create_dir_all(&pkg_dir)
-      .with_context(|| format!("Failed to create package dir: {}", pkg_dir.display()))?;
-
-    // Copy usage-rules.md main file to the output folder with the package
-    // name, and copy it's own usage_rules directory to the output folder with
-    // a subdirectory equal to the package name.
+  existing: Option<ExistingContent>,
+  options: &RenderOptions,
+) -> Result<WriteReport> {
+  plan_linked(output_path, folder_path, packages, existing, options)?.write(options.mode)
+}
+
+/// Plans the files `write_linked` writes without touching the filesystem.
+///
+/// Each package's main usage-rules.md file is copied to
+/// `folder_path/<package>/<package>.md` and its sub-files to the same folder,
+/// preserving their directory structure, and an `index.md` listing them is
+/// written at the root of `folder_path`. Packages marked `inline` are rendered
+/// in full in the main file instead. Unless the line ending is `Keep`, copies
+/// have their line endings converted.
+///
+/// # Errors
+///
+/// Returns an error if the layout is invalid, a rule file cannot be read, or
+/// the content cannot be represented in the output encoding.
+pub fn plan_linked(
+  output_path: &Path,
+  folder_path: &Path,
+  packages: Vec<PackageContentInfo>,
+  existing: Option<ExistingContent>,
+  options: &RenderOptions,
+) -> Result<OutputPlan> {
+  let mut files = Vec::new();
+  let anchored = options.section.link_style == LinkStyle::Anchor;
+
+  // Packages too large to inline are linked instead of truncated
+  let mut packages = packages;
+  for pkg in packages.iter_mut().filter(|pkg| pkg.inline) {
+    if pkg.is_oversized(&options.section.content)? {
+      logger::detail(format_args!(
+        "{}: rules exceed --max-bytes-per-package, linking instead of inlining",
+        pkg.label()
+      ));
+      pkg.inline = false;
+    }
+  }
+
+  // Duplicates refer to the first package's section and inlined packages
+  // carry their rules in the main file, so neither has files copied. With
+  // anchor links every package's rules go in the index instead.
+  for pkg in packages
+    .iter()
+    .filter(|pkg| pkg.duplicate_of.is_none() && !pkg.inline && !anchored)
+  {
+    let pkg_dir = folder_path.join(pkg.folder_name());
+
     if let Some(main_file_path) = &pkg.content.main_file {
-      let dest_main_file = pkg_dir.join(format!("{}.md", pkg.name));
-      fs::copy(main_file_path, &dest_main_file).with_context(|| {
-        format!(
-          "Failed to copy main usage-rules.md for package {}: {}",
-          pkg.name,
-          dest_main_file.display()
-        )
-      })?;
+      files.push(PlannedFile {
+        path: pkg_dir.join(format!("{}.md", pkg.name)),
+        source: copy_source(main_file_path, &options.section.content)?,
+      });
     }
 
-    // Copy sub-files preserving directory structure
     for sub_file in &pkg.content.sub_files {
-      let dest_sub_file_path = folder_path
-        .join(&pkg.name)
-        .join(&sub_file.relative_path_name)
-        .with_extension("md");
-
-      if let Some(parent) = dest_sub_file_path.parent() {
-        fs::create_dir_all(parent).with_context(|| {
-          format!(
-            "Failed to create parent directory for sub-file {}: {}",
-            sub_file.relative_path_name,
-            parent.display()
-          )
-        })?;
-      }
-
-      fs::copy(&sub_file.full_path, &dest_sub_file_path).with_context(|| {
-        format!(
-          "Failed to copy sub-file {} for package {}: {}",
-          sub_file.relative_path_name,
-          pkg.name,
-          dest_sub_file_path.display()
-        )
-      })?;
+      files.push(PlannedFile {
+        path: pkg_dir
+          .join(&sub_file.relative_path_name)
+          .with_extension("md"),
+        source: copy_source(&sub_file.full_path, &options.section.content)?,
+      });
     }
   }
 
+  let index = normalize_line_endings(
+    &generate_index(&packages, &options.section)?,
+    options.section.content.line_ending,
+  );
+  files.push(PlannedFile {
+    path: folder_path.join(INDEX_FILENAME),
+    source: FileSource::Generated(index.into_bytes()),
+  });
+
   // Extract folder name from the path for generating relative links
   let folder_name = folder_path
     .file_name()
     .and_then(|n| n.to_str())
     .unwrap_or("usage_rules");
 
-  let content = create_main_agents_file(packages, preamble, Some(folder_name))?;
+  let folder_link = match options.section.link_style {
+    // The folder actually written, wherever it sits relative to the output
+    LinkStyle::Absolute => std::path::absolute(folder_path)
+      .context("Failed to resolve the link folder")?
+      .to_string_lossy()
+      .into_owned(),
+    LinkStyle::Relative | LinkStyle::Anchor => folder_name.to_string(),
+  };
+
+  let content = create_main_agents_file(packages, existing, Some(&folder_link), options)?;
+  files.push(PlannedFile {
+    path: output_path.to_path_buf(),
+    source: FileSource::Generated(encode_output(&content, options.encoding)?),
+  });
+
+  Ok(OutputPlan { files })
+}
+
+/// Generates the `index.md` written at the root of the linked folder: a list
+/// of every package with files in the folder, linking to its main file, with
+/// a nested list of its sub-files.
+///
+/// With `LinkStyle::Anchor`, the index instead links to a section per
+/// package that follows the list and holds the package's rules.
+///
+/// # Errors
+///
+/// Returns an error if a rule file included in the index cannot be read.
+fn generate_index(packages: &[PackageContentInfo], options: &SectionOptions) -> Result<String> {
+  let anchored = options.link_style == LinkStyle::Anchor;
+  let mut index = String::from("# Usage rules\n\n");
+  let mut sections = Vec::new();
+
+  for pkg in packages
+    .iter()
+    .filter(|pkg| pkg.duplicate_of.is_none() && !pkg.inline)
+  {
+    let folder = pkg.folder_name();
+    if anchored {
+      let heading = section_heading(pkg, options);
+      index.push_str(&format!(
+        "- [{}](#{})\n",
+        pkg.label(),
+        options.anchor_flavor.slug(&heading)
+      ));
+      sections.push(format!(
+        "{} {}\n{}",
+        options.template.heading_marker(),
+        heading,
+        pkg.get_full_content(&options.content)?
+      ));
+    } else if pkg.content.main_file.is_some() {
+      index.push_str(&format!(
+        "- [{}](./{}/{}.md)\n",
+        pkg.label(),
+        folder,
+        pkg.name
+      ));
+    } else {
+      index.push_str(&format!("- {}\n", pkg.label()));
+    }
+
+    for sub_file in &pkg.content.sub_files {
+      let name = link_target(&sub_file.relative_path_name);
+      if anchored {
+        index.push_str(&format!("  - {}\n", name));
+      } else {
+        index.push_str(&format!("  - [{}](./{}/{}.md)\n", name, folder, name));
+      }
+    }
+  }
 
-  fs::write(output_path, content)
-    .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+  if !sections.is_empty() {
+    index.push_str(&format!("\n{}\n", sections.join("\n\n")));
+  }
+  Ok(index)
+}
 
-  Ok(())
+/// Returns the source of a rule file copied in linked mode: the file itself,
+/// or its content with converted line endings unless the line ending is
+/// `Keep`.
+fn copy_source(path: &Path, content: &ContentOptions) -> Result<FileSource> {
+  Ok(match content.line_ending {
+    LineEnding::Keep => FileSource::Copy(path.to_path_buf()),
+    line_ending => FileSource::Generated(
+      normalize_line_endings(
+        &read_file_content(path, content.strict_encoding)?,
+        line_ending,
+      )
+      .into_bytes(),
+    ),
+  })
+}
+
+/// Where the content of a planned file comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileSource {
+  /// Rendered content, already encoded.
+  Generated(Vec<u8>),
+  /// A rule file copied verbatim.
+  Copy(PathBuf),
+}
+
+/// A file that a sync writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedFile {
+  pub path: PathBuf,
+  pub source: FileSource,
+}
+
+impl PlannedFile {
+  /// Returns the bytes this file should contain.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the source of a copied file cannot be read.
+  pub fn expected_content(&self) -> Result<Vec<u8>> {
+    match &self.source {
+      FileSource::Generated(bytes) => Ok(bytes.clone()),
+      FileSource::Copy(source) => {
+        fs::read(source).with_context(|| format!("Failed to read file {}", source.display()))
+      }
+    }
+  }
+}
+
+/// A planned file whose current content does not match the plan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaleFile {
+  pub path: PathBuf,
+  /// First line that differs, or None if the file does not exist.
+  pub first_difference: Option<usize>,
+}
+
+/// The outcome of writing an `OutputPlan`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteReport {
+  /// Files created or overwritten.
+  pub written: Vec<PathBuf>,
+  /// Files skipped because they already had the planned content.
+  pub unchanged: Vec<PathBuf>,
+  /// Total size in bytes of every planned file, written or unchanged.
+  pub bytes: usize,
+}
+
+impl WriteReport {
+  /// Returns true if `path` was skipped because it was already up to date.
+  pub fn is_unchanged(&self, path: &Path) -> bool {
+    self.unchanged.iter().any(|p| p == path)
+  }
+}
+
+/// Every file a sync writes, in write order. The main output file comes last.
+#[derive(Debug, Clone, Default)]
+pub struct OutputPlan {
+  pub files: Vec<PlannedFile>,
+}
+
+impl OutputPlan {
+  /// Writes every planned file, creating parent directories as needed, and
+  /// applies `mode` to each.
+  ///
+  /// Files whose current content already matches the plan are not rewritten,
+  /// so their modification time is preserved. Every other file is replaced
+  /// atomically, so an interrupted write never leaves it truncated.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a directory or file cannot be written.
+  pub fn write(&self, mode: Option<u32>) -> Result<WriteReport> {
+    let mut report = WriteReport::default();
+
+    for file in &self.files {
+      let expected = file.expected_content()?;
+      report.bytes += expected.len();
+      if fs::read(&file.path).is_ok_and(|actual| actual == expected) {
+        apply_mode(&file.path, mode)?;
+        report.unchanged.push(file.path.clone());
+        continue;
+      }
+
+      if let Some(parent) = file.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+          .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+      }
+
+      write_atomic(&file.path, &expected, mode)?;
+      report.written.push(file.path.clone());
+    }
+
+    Ok(report)
+  }
+
+  /// Compares the plan against the filesystem and returns the files that are
+  /// missing or differ.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a planned file's source cannot be read.
+  pub fn stale_files(&self) -> Result<Vec<StaleFile>> {
+    let mut stale = Vec::new();
+
+    for file in &self.files {
+      let expected = file.expected_content()?;
+      match fs::read(&file.path) {
+        Ok(actual) if actual == expected => {}
+        Ok(actual) => stale.push(StaleFile {
+          path: file.path.clone(),
+          first_difference: Some(first_differing_line(&actual, &expected)),
+        }),
+        Err(_) => stale.push(StaleFile {
+          path: file.path.clone(),
+          first_difference: None,
+        }),
+      }
+    }
+
+    Ok(stale)
+  }
+
+  /// Renders a unified diff from the current content of each planned file to
+  /// its planned content, both decoded with `encoding`.
+  ///
+  /// Unchanged files are omitted, and missing files are diffed against an
+  /// empty file.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the source of a copied file cannot be read.
+  pub fn diff(&self, encoding: &'static Encoding) -> Result<String> {
+    let mut diff = String::new();
+
+    for file in &self.files {
+      let expected = decode_output(&file.expected_content()?, encoding);
+      let (actual, old_header) = match fs::read(&file.path) {
+        Ok(bytes) => (
+          decode_output(&bytes, encoding),
+          format!("a/{}", file.path.display()),
+        ),
+        Err(_) => (String::new(), "/dev/null".to_string()),
+      };
+      if actual == expected {
+        continue;
+      }
+
+      diff.push_str(
+        &TextDiff::from_lines(&actual, &expected)
+          .unified_diff()
+          .header(&old_header, &format!("b/{}", file.path.display()))
+          .to_string(),
+      );
+    }
+
+    Ok(diff)
+  }
+}
+
+/// Returns the 1-based number of the first line at which `a` and `b` differ.
+fn first_differing_line(a: &[u8], b: &[u8]) -> usize {
+  let offset = a
+    .iter()
+    .zip(b)
+    .position(|(x, y)| x != y)
+    .unwrap_or(a.len().min(b.len()));
+  a[..offset].iter().filter(|&&byte| byte == b'\n').count() + 1
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::aggregator::PackageContent;
+  use crate::{
+    aggregator::{PackageContent, SectionTemplate},
+    markdown::AnchorFlavor,
+  };
   use tempfile::TempDir;
 
   fn create_test_package(name: &str, main_content: &str) -> (PackageContentInfo, TempDir) {
@@ -172,6 +974,13 @@ mod tests {
         main_file: Some(main_file),
         sub_files: vec![],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     };
 
     (package, temp)
@@ -179,19 +988,40 @@ mod tests {
 
   #[test]
   fn test_generate_header_inline_mode() {
-    let header = generate_header(false);
+    let header = generate_header(false, Preset::Agents);
     assert!(header.contains("IMPORTANT"));
-    assert!(header.contains("General Rust Usage"));
+    assert!(!header.contains("General Rust Usage"));
     assert!(!header.contains("separate files"));
   }
 
+  #[test]
+  fn test_generate_base_section() {
+    let base = generate_base_section(None).unwrap();
+    assert!(base.starts_with("## General Rust Usage"));
+    assert!(base.contains("Defensive Programming Patterns"));
+  }
+
   #[test]
   fn test_generate_header_folder_mode() {
-    let header = generate_header(true);
+    let header = generate_header(true, Preset::Agents);
     assert!(header.contains("IMPORTANT"));
     assert!(header.contains("separate files"));
   }
 
+  #[test]
+  fn test_generate_header_per_preset() {
+    let agents = generate_header(true, Preset::Agents);
+    let cursor = generate_header(true, Preset::Cursor);
+
+    assert!(generate_header(false, Preset::Claude).contains("IMPORTANT"));
+    assert!(cursor.len() < agents.len() / 2);
+    assert!(cursor.contains("linked files"));
+    assert_eq!(
+      Preset::Cursor.default_output(),
+      PathBuf::from(".cursorrules")
+    );
+  }
+
   #[test]
   fn test_write_inline_creates_file() {
     let temp = TempDir::new().unwrap();
@@ -200,7 +1030,7 @@ mod tests {
     let (pkg, _pkg_temp) = create_test_package("test-pkg", "Test content");
     let packages = vec![pkg];
 
-    write_inline(&output, packages, None).unwrap();
+    write_inline(&output, packages, None, &RenderOptions::default()).unwrap();
 
     assert!(output.exists());
     let content = fs::read_to_string(&output).unwrap();
@@ -220,13 +1050,60 @@ mod tests {
     let packages = vec![pkg];
     let preamble = "# My Custom Header\n\nCustom preamble text".to_string();
 
-    write_inline(&output, packages, Some(preamble.clone())).unwrap();
+    write_inline(
+      &output,
+      packages,
+      Some(ExistingContent::Preamble {
+        preamble: preamble.clone(),
+        footer: String::new(),
+      }),
+      &RenderOptions::default(),
+    )
+    .unwrap();
 
     let content = fs::read_to_string(&output).unwrap();
     assert!(content.starts_with("# My Custom Header"));
     assert!(content.contains("Custom preamble text"));
   }
 
+  #[test]
+  fn test_write_inline_merges_into_document_block() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("CONTRIBUTING.md");
+    let before = "# Contributing\n\nIntro  \n\n## Agents\n\n";
+    let after = "\n\n## Testing\n\nRun `cargo test`.\n";
+    fs::write(
+      &output,
+      format!(
+        "{}<!-- cargo-usage-rules-start -->\nstale\n<!-- cargo-usage-rules-end -->{}",
+        before, after
+      ),
+    )
+    .unwrap();
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Fresh content");
+    let existing = crate::aggregator::extract_surrounding_content(
+      &output,
+      UTF_8,
+      &SectionMarkers::default(),
+      false,
+    )
+    .unwrap();
+    write_inline(
+      &output,
+      vec![pkg],
+      Some(existing),
+      &RenderOptions::default(),
+    )
+    .unwrap();
+
+    let content = fs::read_to_string(&output).unwrap();
+    assert!(content.starts_with(&format!("{}<!-- cargo-usage-rules-start -->", before)));
+    assert!(content.ends_with(&format!("<!-- cargo-usage-rules-end -->{}", after)));
+    assert!(content.contains("Fresh content"));
+    assert!(!content.contains("stale"));
+  }
+
   #[test]
   fn test_write_inline_empty_preamble_uses_default() {
     let temp = TempDir::new().unwrap();
@@ -235,7 +1112,16 @@ mod tests {
     let (pkg, _pkg_temp) = create_test_package("test-pkg", "Content");
     let packages = vec![pkg];
 
-    write_inline(&output, packages, Some(String::new())).unwrap();
+    write_inline(
+      &output,
+      packages,
+      Some(ExistingContent::Preamble {
+        preamble: String::new(),
+        footer: String::new(),
+      }),
+      &RenderOptions::default(),
+    )
+    .unwrap();
 
     let content = fs::read_to_string(&output).unwrap();
     assert!(content.contains("IMPORTANT"));
@@ -257,9 +1143,16 @@ mod tests {
         main_file: Some(main_file),
         sub_files: vec![],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     }];
 
-    write_linked(&output, &folder, packages, None).unwrap();
+    write_linked(&output, &folder, packages, None, &RenderOptions::default()).unwrap();
 
     // Check output file exists
     assert!(output.exists());
@@ -275,6 +1168,195 @@ mod tests {
     assert!(main_content.contains("<!-- cargo-usage-rules-end -->"));
   }
 
+  #[test]
+  fn test_write_linked_separates_versions_of_one_package() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (mut old, _old_temp) = create_test_package("serde", "Old rules");
+    let (mut new, _new_temp) = create_test_package("serde", "New rules");
+    new.version = "2.0.0".to_string();
+    old.versioned = true;
+    new.versioned = true;
+
+    write_linked(
+      &output,
+      &folder,
+      vec![old, new],
+      None,
+      &RenderOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+      fs::read_to_string(folder.join("serde-1.0.0/serde.md")).unwrap(),
+      "Old rules"
+    );
+    assert_eq!(
+      fs::read_to_string(folder.join("serde-2.0.0/serde.md")).unwrap(),
+      "New rules"
+    );
+    let main_content = fs::read_to_string(&output).unwrap();
+    assert!(main_content.contains("## serde v1.0.0 usage"));
+    assert!(main_content.contains("[serde v2.0.0 usage rules](./usage_rules/serde-2.0.0/serde.md)"));
+  }
+
+  #[test]
+  fn test_line_endings_are_normalized() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "One\r\nTwo\n");
+
+    write_linked(
+      &output,
+      &folder,
+      vec![pkg.clone()],
+      None,
+      &RenderOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+      fs::read_to_string(folder.join("test-pkg/test-pkg.md")).unwrap(),
+      "One\nTwo\n"
+    );
+
+    let mut options = RenderOptions::default();
+    options.section.content.line_ending = LineEnding::Crlf;
+    write_inline(&output, vec![pkg.clone()], None, &options).unwrap();
+    let content = fs::read_to_string(&output).unwrap();
+    assert!(content.contains("One\r\nTwo\r\n"));
+    assert!(!content.replace("\r\n", "").contains('\n'));
+
+    options.section.content.line_ending = LineEnding::Keep;
+    write_linked(&output, &folder, vec![pkg], None, &options).unwrap();
+    assert_eq!(
+      fs::read_to_string(folder.join("test-pkg/test-pkg.md")).unwrap(),
+      "One\r\nTwo\n"
+    );
+  }
+
+  #[test]
+  fn test_write_linked_inlines_marked_packages() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (linked, _linked_temp) = create_test_package("linked-pkg", "Linked rules");
+    let (mut inlined, _inlined_temp) = create_test_package("inlined-pkg", "Inlined rules");
+    inlined.inline = true;
+
+    write_linked(
+      &output,
+      &folder,
+      vec![linked, inlined],
+      None,
+      &RenderOptions::default(),
+    )
+    .unwrap();
+
+    let main_content = fs::read_to_string(&output).unwrap();
+    assert!(
+      main_content.contains("[linked-pkg usage rules](./usage_rules/linked-pkg/linked-pkg.md)")
+    );
+    assert!(!main_content.contains("Linked rules"));
+    assert!(main_content.contains("## inlined-pkg usage\nInlined rules"));
+    assert!(folder.join("linked-pkg/linked-pkg.md").exists());
+    assert!(!folder.join("inlined-pkg").exists());
+  }
+
+  #[test]
+  fn test_output_writers_plan_like_their_functions() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Rules");
+    let options = RenderOptions::default();
+
+    let writers: [(Box<dyn OutputWriter>, OutputPlan); 2] = [
+      (
+        Box::new(InlineWriter),
+        plan_inline(&output, vec![pkg.clone()], None, &options).unwrap(),
+      ),
+      (
+        Box::new(LinkedWriter {
+          folder_path: folder.clone(),
+        }),
+        plan_linked(&output, &folder, vec![pkg.clone()], None, &options).unwrap(),
+      ),
+    ];
+    for (writer, expected) in writers {
+      let plan = writer
+        .plan(&output, vec![pkg.clone()], None, &options)
+        .unwrap();
+      let paths =
+        |plan: &OutputPlan| -> Vec<PathBuf> { plan.files.iter().map(|f| f.path.clone()).collect() };
+      assert_eq!(paths(&plan), paths(&expected));
+      assert_eq!(
+        plan.files.last().unwrap().expected_content().unwrap(),
+        expected.files.last().unwrap().expected_content().unwrap()
+      );
+    }
+  }
+
+  #[test]
+  fn test_json_writer_exports_rules() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("rules.json");
+    let (mut pkg, pkg_temp) = create_test_package("test-pkg", "Main rules");
+    let sub_file = pkg_temp.path().join("async.md");
+    fs::write(&sub_file, "Async rules").unwrap();
+    pkg
+      .content
+      .sub_files
+      .push(crate::scanner::UsageRuleSubFile {
+        relative_path_name: "async".to_string(),
+        full_path: sub_file,
+        order: None,
+      });
+
+    JsonWriter
+      .write(&output, vec![pkg], None, &RenderOptions::default())
+      .unwrap();
+
+    let json: serde_json::Value =
+      serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+    assert_eq!(
+      json,
+      serde_json::json!({
+        "packages": [{
+          "name": "test-pkg",
+          "version": "1.0.0",
+          "content": "Main rules",
+          "sub_files": [{"relative_path_name": "async", "content": "Async rules"}],
+        }]
+      })
+    );
+  }
+
+  #[test]
+  fn test_write_linked_links_oversized_inline_packages() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (mut small, _small_temp) = create_test_package("small-pkg", "Small");
+    small.inline = true;
+    let (mut large, _large_temp) = create_test_package("large-pkg", &"Large ".repeat(20));
+    large.inline = true;
+    let mut options = RenderOptions::default();
+    options.section.content.max_bytes = Some(10);
+
+    write_linked(&output, &folder, vec![small, large], None, &options).unwrap();
+
+    let main_content = fs::read_to_string(&output).unwrap();
+    assert!(main_content.contains("## small-pkg usage\nSmall"));
+    assert!(main_content.contains("[large-pkg usage rules](./usage_rules/large-pkg/large-pkg.md)"));
+    assert!(!main_content.contains("truncated"));
+    assert!(folder.join("large-pkg/large-pkg.md").exists());
+  }
+
   #[test]
   fn test_write_linked_copies_sub_files() {
     let temp = TempDir::new().unwrap();
@@ -295,11 +1377,19 @@ mod tests {
         sub_files: vec![crate::scanner::UsageRuleSubFile {
           relative_path_name: "async".to_string(),
           full_path: sub_file,
+          order: None,
         }],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     }];
 
-    write_linked(&output, &folder, packages, None).unwrap();
+    write_linked(&output, &folder, packages, None, &RenderOptions::default()).unwrap();
 
     // Check sub-file was copied
     assert!(folder.join("test-pkg/async.md").exists());
@@ -307,6 +1397,82 @@ mod tests {
     assert_eq!(sub_content, "Async content");
   }
 
+  #[test]
+  fn test_write_linked_generates_index() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (mut pkg, pkg_temp) = create_test_package("test-pkg", "Main");
+    let sub_file = pkg_temp.path().join("async.md");
+    fs::write(&sub_file, "Async").unwrap();
+    pkg
+      .content
+      .sub_files
+      .push(crate::scanner::UsageRuleSubFile {
+        relative_path_name: "async".to_string(),
+        full_path: sub_file,
+        order: None,
+      });
+    let (mut inlined, _inlined_temp) = create_test_package("inlined-pkg", "Inlined");
+    inlined.inline = true;
+
+    write_linked(
+      &output,
+      &folder,
+      vec![pkg, inlined],
+      None,
+      &RenderOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+      fs::read_to_string(folder.join(INDEX_FILENAME)).unwrap(),
+      "# Usage rules\n\n- [test-pkg](./test-pkg/test-pkg.md)\n  - [async](./test-pkg/async.md)\n"
+    );
+  }
+
+  #[test]
+  fn test_write_linked_anchor_style_combines_rules_in_index() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Main rules");
+
+    let mut options = RenderOptions::default();
+    options.section.link_style = LinkStyle::Anchor;
+    write_linked(&output, &folder, vec![pkg], None, &options).unwrap();
+
+    assert!(!folder.join("test-pkg").exists());
+    assert_eq!(
+      fs::read_to_string(folder.join(INDEX_FILENAME)).unwrap(),
+      "# Usage rules\n\n- [test-pkg](#test-pkg-usage)\n\n## test-pkg usage\nMain rules\n"
+    );
+    let main_content = fs::read_to_string(&output).unwrap();
+    assert!(main_content.contains("[test-pkg usage rules](./usage_rules/index.md#test-pkg-usage)"));
+  }
+
+  #[test]
+  fn test_write_linked_absolute_style_links_the_written_folder() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("docs/rules");
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Main rules");
+
+    let mut options = RenderOptions::default();
+    options.section.link_style = LinkStyle::Absolute;
+    write_linked(&output, &folder, vec![pkg], None, &options).unwrap();
+
+    let main_content = fs::read_to_string(&output).unwrap();
+    let target = main_content
+      .split("[test-pkg usage rules](")
+      .nth(1)
+      .and_then(|rest| rest.split(')').next())
+      .unwrap();
+    assert!(target.ends_with("docs/rules/test-pkg/test-pkg.md"));
+    assert!(Path::new(target).is_file());
+  }
+
   #[test]
   fn test_write_linked_handles_multiple_sub_files() {
     let temp = TempDir::new().unwrap();
@@ -331,16 +1497,25 @@ mod tests {
           crate::scanner::UsageRuleSubFile {
             relative_path_name: "async".to_string(),
             full_path: sub_file1,
+            order: None,
           },
           crate::scanner::UsageRuleSubFile {
             relative_path_name: "builder".to_string(),
             full_path: sub_file2,
+            order: None,
           },
         ],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     }];
 
-    write_linked(&output, &folder, packages, None).unwrap();
+    write_linked(&output, &folder, packages, None, &RenderOptions::default()).unwrap();
 
     // Check both sub-files were copied
     assert!(folder.join("test-pkg/async.md").exists());
@@ -363,13 +1538,340 @@ mod tests {
         main_file: Some(main_file),
         sub_files: vec![],
       },
+      conditions: vec![],
+      repository: None,
+      category: None,
+      version: "1.0.0".to_string(),
+      duplicate_of: None,
+      inline: false,
+      versioned: false,
     }];
 
     let preamble = "# Custom Header".to_string();
 
-    write_linked(&output, &folder, packages, Some(preamble)).unwrap();
+    write_linked(
+      &output,
+      &folder,
+      packages,
+      Some(ExistingContent::Preamble {
+        preamble,
+        footer: String::new(),
+      }),
+      &RenderOptions::default(),
+    )
+    .unwrap();
 
     let content = fs::read_to_string(&output).unwrap();
     assert!(content.starts_with("# Custom Header"));
   }
+
+  #[test]
+  fn test_layout_places_packages_before_base() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Test content");
+    let options = RenderOptions {
+      layout: vec![
+        LayoutBlock::Header,
+        LayoutBlock::Packages,
+        LayoutBlock::Base,
+      ],
+      encoding: UTF_8,
+      section: SectionOptions::default(),
+      mode: None,
+      toc: false,
+      group_by_category: false,
+      preset: Preset::default(),
+      markers: SectionMarkers::default(),
+      base_file: None,
+      kept_sections: HashMap::new(),
+    };
+
+    write_inline(&output, vec![pkg], None, &options).unwrap();
+
+    let content = fs::read_to_string(&output).unwrap();
+    let header_pos = content.find("IMPORTANT").unwrap();
+    let packages_pos = content.find("## test-pkg usage").unwrap();
+    let base_pos = content.find("## General Rust Usage").unwrap();
+    assert!(header_pos < packages_pos);
+    assert!(packages_pos < base_pos);
+    assert!(base_pos < content.find("<!-- cargo-usage-rules-end -->").unwrap());
+  }
+
+  #[test]
+  fn test_layout_rejects_duplicate_blocks() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Content");
+    let options = RenderOptions {
+      layout: vec![LayoutBlock::Packages, LayoutBlock::Packages],
+      encoding: UTF_8,
+      section: SectionOptions::default(),
+      mode: None,
+      toc: false,
+      group_by_category: false,
+      preset: Preset::default(),
+      markers: SectionMarkers::default(),
+      base_file: None,
+      kept_sections: HashMap::new(),
+    };
+
+    let result = write_inline(&output, vec![pkg], None, &options);
+
+    assert!(result.is_err());
+    assert!(!output.exists());
+  }
+
+  #[test]
+  fn test_write_inline_utf16le_round_trip() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Résumé ✓");
+    let options = RenderOptions {
+      layout: DEFAULT_LAYOUT.to_vec(),
+      encoding: encoding_rs::UTF_16LE,
+      section: SectionOptions::default(),
+      mode: None,
+      toc: false,
+      group_by_category: false,
+      preset: Preset::default(),
+      markers: SectionMarkers::default(),
+      base_file: None,
+      kept_sections: HashMap::new(),
+    };
+
+    write_inline(&output, vec![pkg], None, &options).unwrap();
+
+    let bytes = fs::read(&output).unwrap();
+    assert!(std::str::from_utf8(&bytes).is_err());
+    let content = crate::encoding::decode_output(&bytes, encoding_rs::UTF_16LE);
+    assert!(content.contains("<!-- cargo-usage-rules-start -->"));
+    assert!(content.contains("## test-pkg usage"));
+    assert!(content.contains("Résumé ✓"));
+  }
+
+  #[test]
+  fn test_parse_mode() {
+    assert_eq!(parse_mode("664"), Ok(0o664));
+    assert_eq!(parse_mode("0o640"), Ok(0o640));
+    assert!(parse_mode("9").is_err());
+    assert!(parse_mode("17777").is_err());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_output_mode_applied_to_written_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Content");
+    let options = RenderOptions {
+      mode: Some(0o640),
+      ..RenderOptions::default()
+    };
+
+    write_linked(&output, &folder, vec![pkg], None, &options).unwrap();
+
+    for path in [output, folder.join("test-pkg/test-pkg.md")] {
+      let mode = fs::metadata(&path).unwrap().permissions().mode();
+      assert_eq!(mode & 0o7777, 0o640, "{}", path.display());
+    }
+  }
+
+  #[test]
+  fn test_stale_files_reports_missing_and_changed_files() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Content");
+    let plan = plan_linked(&output, &folder, vec![pkg], None, &RenderOptions::default()).unwrap();
+
+    assert_eq!(plan.stale_files().unwrap().len(), 3);
+    assert!(!output.exists(), "Planning must not write files");
+
+    plan.write(None).unwrap();
+    assert!(plan.stale_files().unwrap().is_empty());
+
+    let mut content = fs::read_to_string(&output).unwrap();
+    content = content.replace("## test-pkg usage", "## edited");
+    fs::write(&output, content).unwrap();
+
+    let stale = plan.stale_files().unwrap();
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale[0].path, output);
+    assert!(stale[0].first_difference.is_some());
+  }
+
+  #[test]
+  fn test_diff_shows_changed_lines_only_for_changed_files() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Old rule");
+    write_inline(&output, vec![pkg], None, &RenderOptions::default()).unwrap();
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "New rule");
+    let plan = plan_inline(&output, vec![pkg], None, &RenderOptions::default()).unwrap();
+    let diff = plan.diff(UTF_8).unwrap();
+
+    assert!(diff.starts_with(&format!("--- a/{}", output.display())));
+    assert!(diff.contains("\n-Old rule\n"));
+    assert!(diff.contains("\n+New rule\n"));
+
+    plan.write(None).unwrap();
+    assert!(plan.diff(UTF_8).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_write_skips_unchanged_files() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let folder = temp.path().join("usage_rules");
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Content");
+    let first = write_linked(
+      &output,
+      &folder,
+      vec![pkg.clone()],
+      None,
+      &RenderOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(first.written.len(), 3);
+    assert!(first.unchanged.is_empty());
+
+    let second =
+      write_linked(&output, &folder, vec![pkg], None, &RenderOptions::default()).unwrap();
+    assert!(second.written.is_empty());
+    assert!(second.is_unchanged(&output));
+    assert!(second.is_unchanged(&folder.join("test-pkg/test-pkg.md")));
+  }
+
+  #[test]
+  fn test_toc_links_each_package_section() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (pkg1, _temp1) = create_test_package("pkg-one", "One");
+    let (pkg2, _temp2) = create_test_package("pkg_two", "Two");
+    let options = RenderOptions {
+      toc: true,
+      ..RenderOptions::default()
+    };
+    write_inline(&output, vec![pkg1, pkg2], None, &options).unwrap();
+
+    let content = fs::read_to_string(&output).unwrap();
+    let toc_pos = content.find("- [pkg-one usage](#pkg-one-usage)").unwrap();
+    assert!(content.contains("- [pkg_two usage](#pkg_two-usage)"));
+    assert!(toc_pos > content.find("<!-- cargo-usage-rules-start -->").unwrap());
+    assert!(toc_pos > content.find("IMPORTANT").unwrap());
+    assert!(toc_pos < content.find("## pkg-one usage").unwrap());
+  }
+
+  #[test]
+  fn test_toc_uses_anchor_flavor() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (pkg, _temp) = create_test_package("pkg-one", "One");
+    let options = RenderOptions {
+      toc: true,
+      section: SectionOptions {
+        template: SectionTemplate::parse("## {name} - rules").unwrap(),
+        anchor_flavor: AnchorFlavor::Gitlab,
+        ..SectionOptions::default()
+      },
+      ..RenderOptions::default()
+    };
+    write_inline(&output, vec![pkg], None, &options).unwrap();
+
+    let content = fs::read_to_string(&output).unwrap();
+    assert!(content.contains("- [pkg-one - rules](#pkg-one-rules)"));
+  }
+
+  #[test]
+  fn test_group_by_category_adds_category_headings() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+
+    let (plain, _temp1) = create_test_package("plain", "Plain");
+    let (mut serde, _temp2) = create_test_package("serde", "Serde");
+    serde.category = Some("serialization".to_string());
+    let (mut tokio, _temp3) = create_test_package("tokio", "Tokio");
+    tokio.category = Some("async".to_string());
+    let options = RenderOptions {
+      group_by_category: true,
+      ..RenderOptions::default()
+    };
+    write_inline(&output, vec![plain, serde, tokio], None, &options).unwrap();
+
+    let content = fs::read_to_string(&output).unwrap();
+    let positions: Vec<usize> = [
+      "# Async\n\n## tokio usage",
+      "# Serialization\n\n## serde usage",
+      "# Uncategorized\n\n## plain usage",
+    ]
+    .iter()
+    .map(|needle| content.find(needle).unwrap())
+    .collect();
+    assert!(positions.is_sorted());
+  }
+
+  #[test]
+  fn test_write_atomic_replaces_file_without_leftovers() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    fs::write(&output, "old").unwrap();
+
+    write_atomic(&output, b"new", None).unwrap();
+
+    assert_eq!(fs::read_to_string(&output).unwrap(), "new");
+    let entries: Vec<_> = fs::read_dir(temp.path()).unwrap().collect();
+    assert_eq!(entries.len(), 1, "Temporary file was left behind");
+  }
+
+  #[test]
+  fn test_custom_marker_prefix_round_trips() {
+    let temp = TempDir::new().unwrap();
+    let output = temp.path().join("output.md");
+    let markers = SectionMarkers::from_prefix("deps");
+    let options = RenderOptions {
+      markers: markers.clone(),
+      ..RenderOptions::default()
+    };
+
+    let (pkg, _pkg_temp) = create_test_package("test-pkg", "Content");
+    write_inline(&output, vec![pkg.clone()], None, &options).unwrap();
+    let content = fs::read_to_string(&output).unwrap();
+    assert!(content.contains("<!-- deps-start -->"));
+    assert!(content.contains("<!-- deps-end -->"));
+    assert!(!content.contains("cargo-usage-rules-start"));
+
+    fs::write(&output, format!("My preamble\n\n{}", content)).unwrap();
+    let (preamble, _) =
+      crate::aggregator::extract_agents_md_preamble(&output, UTF_8, &markers, true).unwrap();
+    assert_eq!(preamble, "My preamble");
+  }
+
+  #[test]
+  fn test_base_file_replaces_bundled_rules() {
+    let temp = TempDir::new().unwrap();
+    let base_file = temp.path().join("org-rules.md");
+    fs::write(&base_file, "Always use our logging crate.").unwrap();
+
+    let base = generate_base_section(Some(&base_file)).unwrap();
+    assert!(base.starts_with("## General Rust Usage"));
+    assert!(base.contains("Always use our logging crate."));
+    assert!(!base.contains("Defensive Programming Patterns"));
+
+    let missing = generate_base_section(Some(&temp.path().join("missing.md")));
+    assert!(missing.is_err());
+  }
 }